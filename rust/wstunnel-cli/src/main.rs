@@ -1,13 +1,14 @@
 use clap::Parser;
 use std::io;
 use std::str::FromStr;
+use tokio::select;
 use tracing::warn;
 use tracing_subscriber::EnvFilter;
 use tracing_subscriber::filter::Directive;
 use wstunnel::LocalProtocol;
-use wstunnel::config::{Client, Server};
+use wstunnel::config::{Client, Hash, Server};
 use wstunnel::executor::DefaultTokioExecutor;
-use wstunnel::{run_client, run_server};
+use wstunnel::{run_client, run_hash, run_server};
 
 #[cfg(feature = "jemalloc")]
 use tikv_jemallocator::Jemalloc;
@@ -57,6 +58,7 @@ pub struct Wstunnel {
 pub enum Commands {
     Client(Box<Client>),
     Server(Box<Server>),
+    Hash(Hash),
 }
 
 #[tokio::main]
@@ -94,20 +96,41 @@ async fn main() -> anyhow::Result<()> {
 
     match args.commands {
         Commands::Client(args) => {
-            run_client(*args, DefaultTokioExecutor::default())
+            run_client(*args, DefaultTokioExecutor::default(), termination_signal())
                 .await
                 .unwrap_or_else(|err| {
                     panic!("Cannot start wstunnel client: {err:?}");
                 });
         }
         Commands::Server(args) => {
-            run_server(*args, DefaultTokioExecutor::default())
+            run_server(*args, DefaultTokioExecutor::default(), termination_signal())
                 .await
                 .unwrap_or_else(|err| {
                     panic!("Cannot start wstunnel server: {err:?}");
                 });
         }
+        Commands::Hash(args) => run_hash(args)?,
     }
 
     Ok(())
 }
+
+/// Resolve on ctrl+c or SIGTERM, to gracefully stop the client or the server
+async fn termination_signal() {
+    #[cfg(unix)]
+    {
+        let Ok(mut sigterm) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) else {
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        };
+        select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = sigterm.recv() => {},
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
@@ -1,6 +1,8 @@
 use crate::tunnel::LocalProtocol;
+use crate::tunnel::transport::keepalive::KeepaliveProfile;
 pub use hyper::http::{HeaderName, HeaderValue};
-use std::net::SocketAddr;
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::time::Duration;
 use tokio_rustls::rustls::pki_types::DnsName;
@@ -16,19 +18,33 @@ pub struct Client {
     /// 'tcp://1212:google.com:443'      =>       listen locally on tcp on port 1212 and forward to google.com on port 443
     /// 'tcp://2:n.lan:4?proxy_protocol' =>       listen locally on tcp on port 2 and forward to n.lan on port 4
     ///                                           Send a proxy protocol header v2 when establishing connection to n.lan
+    /// 'tcp://1212:g.com:443?idle_timeout_sec=300' close the tunnel after 300sec without traffic. Set it to 0 to disable the timeout [default: --tcp-idle-timeout]
     ///
     /// 'udp://1212:1.1.1.1:53'          =>       listen locally on udp on port 1212 and forward to cloudflare dns 1.1.1.1 on port 53
     /// 'udp://1212:1.1.1.1:53?timeout_sec=10'    timeout_sec on udp force close the tunnel after 10sec. Set it to 0 to disable the timeout [default: 30]
     ///
     /// 'socks5://[::1]:1212'            =>       listen locally with socks5 on port 1212 and forward dynamically requested tunnel
     /// 'socks5://[::1]:1212?login=admin&password=admin' => listen locally with socks5 on port 1212 and only accept connection with login=admin and password=admin
+    ///                                           SOCKS4/SOCKS4a clients are accepted on the same port, with 'admin:admin' as userid when credentials are set
     ///
     /// 'http://[::1]:1212'              =>       start a http proxy on port 1212 and forward dynamically requested tunnel
     /// 'http://[::1]:1212?login=admin&password=admin' => start a http proxy on port 1212 and only accept connection with login=admin and password=admin
     ///
+    /// 'mixed://[::1]:1212'             =>       listen locally on port 1212 for socks5, socks4 and http proxy requests and forward dynamically requested tunnel
+    /// 'mixed://[::1]:1212?login=admin&password=admin' => same as above, and only accept connection with login=admin and password=admin
+    ///
+    /// 'dns://5353:1.1.1.1:53'          =>       listen locally on udp and tcp on port 5353 for dns queries and forward them to cloudflare dns 1.1.1.1 on port 53
+    /// 'dns://5353:1.1.1.1:53?protocol=tcp'      forward dns queries as DNS-over-TCP instead of udp. Responses are cached according to their TTL
+    ///
     /// 'tproxy+tcp://[::1]:1212'        =>       listen locally on tcp on port 1212 as a *transparent proxy* and forward dynamically requested tunnel
     /// 'tproxy+udp://[::1]:1212?timeout_sec=10'  listen locally on udp on port 1212 as a *transparent proxy* and forward dynamically requested tunnel
     ///                                           linux only and requires sudo/CAP_NET_ADMIN
+    /// 'fakedns://[::1]:5353?pool=198.18.0.0/15' listen locally on udp on port 5353 and answer dns queries with fake ips from the pool [default: 198.18.0.0/15]
+    ///                                           tproxy listeners then use the original domain as destination when receiving a connection to a fake ip
+    ///                                           only one fakedns listener can be configured
+    ///
+    /// 'tun://3?mtu=1500&timeout_sec=30' =>      read ip packets from the already opened tun file descriptor 3 (i.e: from Android VpnService)
+    ///                                           and forward each tcp connection and udp flow to its original destination [default mtu: 1500]
     ///
     /// 'stdio://google.com:443'         =>       listen for data from stdio, mainly for `ssh -o ProxyCommand="wstunnel client -L stdio://%h:%p ws://localhost:8080" my-server`
     ///
@@ -51,6 +67,16 @@ pub struct Client {
     #[cfg_attr(feature = "clap", arg(long, value_name = "INT", verbatim_doc_comment))]
     pub socket_so_mark: Option<u32>,
 
+    /// Bind the sockets used to reach the server to this local ip address.
+    /// Useful on multi-homed devices to choose which network the tunnel goes through
+    #[cfg_attr(feature = "clap", arg(long, value_name = "IP", verbatim_doc_comment))]
+    pub socket_bind_address: Option<IpAddr>,
+
+    /// (linux/android/macos only) Bind the sockets used to reach the server to this network interface (i.e: wlan0, rmnet0).
+    /// On linux it uses SO_BINDTODEVICE, which requires {root, sudo, capabilities} on older kernels
+    #[cfg_attr(feature = "clap", arg(long, value_name = "INTERFACE", verbatim_doc_comment))]
+    pub socket_bind_interface: Option<String>,
+
     /// Client will maintain a pool of open connection to the server, in order to speed up the connection process.
     /// This option set the maximum number of connection that will be kept open.
     /// This is useful if you plan to create/destroy a lot of tunnel (i.e: with socks5 to navigate with a browser)
@@ -86,6 +112,52 @@ pub struct Client {
     ))]
     pub reverse_tunnel_connection_retry_max_backoff: Duration,
 
+    /// Delay between connection attempts to the different ips of the server (Happy Eyeballs, RFC8305).
+    /// A new attempt is started when this delay elapses or when the previous attempt fails, the first one to succeed is used.
+    /// Ipv4 and ipv6 addresses are interleaved. Set to zero to try all of them at once
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(ms|s|m|h)",
+        default_value = "250ms",
+        value_parser = parsers::parse_duration_sec,
+        verbatim_doc_comment
+    ))]
+    pub happy_eyeballs_delay: Duration,
+
+    /// Close tcp tunnels without traffic in either direction during this delay, to not leak connections of idle apps.
+    /// It can be overridden per tunnel with the idle_timeout_sec option of tcp://
+    /// Set to zero to disable.
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(s|m|h)",
+        default_value = "0s",
+        value_parser = parsers::parse_duration_sec,
+        verbatim_doc_comment
+    ))]
+    pub tcp_idle_timeout: Option<Duration>,
+
+    /// Once one side of a tcp tunnel is closed, wait at most this delay for the other side to finish before closing the tunnel.
+    /// Set to zero to disable.
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(s|m|h)",
+        default_value = "30s",
+        value_parser = parsers::parse_duration_sec,
+        verbatim_doc_comment
+    ))]
+    pub tcp_half_close_timeout: Option<Duration>,
+
+    /// On SIGINT/SIGTERM, stop accepting new connections and wait for the tunnels to close gracefully.
+    /// Tunnels in flight keep running, and are forcibly closed if they have not finished after this delay
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(s|m|h)",
+        default_value = "10s",
+        value_parser = parsers::parse_duration_sec,
+        verbatim_doc_comment
+    ))]
+    pub drain_timeout: Duration,
+
     /// Domain name that will be used as SNI during TLS handshake
     /// Warning: If you are behind a CDN (i.e: Cloudflare) you must set this domain also in the http HOST header.
     ///          or it will be flagged as fishy and your request rejected
@@ -177,6 +249,27 @@ pub struct Client {
     ))]
     pub websocket_ping_frequency: Option<Duration>,
 
+    /// Close the tunnel if the server does not answer a ping within this delay, to detect dead connections.
+    /// i.e: half-open connections behind a NAT, that would otherwise hang until the tcp timeout
+    /// Set to zero to disable.
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(s|m|h)",
+        default_value = "10s",
+        value_parser = parsers::parse_duration_sec,
+        verbatim_doc_comment
+    ))]
+    pub websocket_pong_timeout: Option<Duration>,
+
+    /// Adapt the keepalive of the tunnels to save battery, it overrides websocket_ping_frequency when set.
+    /// Pings back off while a tunnel is idle and stay below the NAT timeout detected on the network.
+    /// Idle connections of the pool are closed when no tunnel is opened for a while.
+    /// aggressive: pings every 10s-30s, idle pool kept 30m
+    /// balanced: pings every 30s-2m, idle pool kept 10m
+    /// battery: pings every 1m-5m, idle pool kept 2m
+    #[cfg_attr(feature = "clap", arg(long, value_name = "PROFILE", value_enum, verbatim_doc_comment))]
+    pub keepalive_profile: Option<KeepaliveProfile>,
+
     /// Enable the masking of websocket frames. Default is false
     /// Enable this option only if you use unsecure (non TLS) websocket server, and you see some issues. Otherwise, it is just overhead.
     #[cfg_attr(feature = "clap", arg(long, default_value = "false", verbatim_doc_comment))]
@@ -262,6 +355,52 @@ pub struct Server {
     #[cfg_attr(feature = "clap", arg(long, value_name = "INT", verbatim_doc_comment))]
     pub socket_so_mark: Option<u32>,
 
+    /// Delay between connection attempts to the different ips of a tunnel destination (Happy Eyeballs, RFC8305).
+    /// A new attempt is started when this delay elapses or when the previous attempt fails, the first one to succeed is used.
+    /// Set to zero to try all of them at once
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(ms|s|m|h)",
+        default_value = "250ms",
+        value_parser = parsers::parse_duration_sec,
+        verbatim_doc_comment
+    ))]
+    pub happy_eyeballs_delay: Duration,
+
+    /// Close tcp tunnels without traffic in either direction during this delay, to not leak connections of idle apps.
+    /// Tunnels requesting a shorter idle_timeout_sec use their own.
+    /// Set to zero to disable.
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(s|m|h)",
+        default_value = "0s",
+        value_parser = parsers::parse_duration_sec,
+        verbatim_doc_comment
+    ))]
+    pub tcp_idle_timeout: Option<Duration>,
+
+    /// Once one side of a tcp tunnel is closed, wait at most this delay for the other side to finish before closing the tunnel.
+    /// Set to zero to disable.
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(s|m|h)",
+        default_value = "30s",
+        value_parser = parsers::parse_duration_sec,
+        verbatim_doc_comment
+    ))]
+    pub tcp_half_close_timeout: Option<Duration>,
+
+    /// On SIGINT/SIGTERM, stop accepting new connections and wait for the tunnels to close gracefully, i.e: for zero-downtime restarts.
+    /// Tunnels in flight keep running, and are forcibly closed if they have not finished after this delay
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(s|m|h)",
+        default_value = "10s",
+        value_parser = parsers::parse_duration_sec,
+        verbatim_doc_comment
+    ))]
+    pub drain_timeout: Duration,
+
     /// Frequency at which the server will send websocket ping to client.
     /// Set to zero to disable.
     #[cfg_attr(feature = "clap", arg(
@@ -274,6 +413,18 @@ pub struct Server {
     ))]
     pub websocket_ping_frequency: Option<Duration>,
 
+    /// Close the tunnel if the client does not answer a ping within this delay, to detect dead connections.
+    /// i.e: half-open connections behind a NAT, that would otherwise hang until the tcp timeout
+    /// Set to zero to disable.
+    #[cfg_attr(feature = "clap", arg(
+        long,
+        value_name = "DURATION(s|m|h)",
+        default_value = "10s",
+        value_parser = parsers::parse_duration_sec,
+        verbatim_doc_comment
+    ))]
+    pub websocket_pong_timeout: Option<Duration>,
+
     /// Enable the masking of websocket frames. Default is false
     /// Enable this option only if you use unsecure (non TLS) websocket server, and you see some issues. Otherwise, it is just overhead.
     #[cfg_attr(feature = "clap", arg(long, default_value = "false", verbatim_doc_comment))]
@@ -399,6 +550,104 @@ pub struct Server {
         verbatim_doc_comment,
     ))]
     pub remote_to_local_server_idle_timeout: Duration,
+
+    /// Expose Prometheus metrics on http://<ADDR>/metrics (upgrade requests, active tunnels, bytes transferred per restriction, ...)
+    /// Disabled by default. Do not expose it publicly, i.e: --metrics-bind 127.0.0.1:9090
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, value_name = "ADDR", verbatim_doc_comment, env = "WSTUNNEL_METRICS_BIND")
+    )]
+    pub metrics_bind: Option<SocketAddr>,
+
+    /// Expose the admin API on http://<ADDR> to list and close tunnels and reverse tunnel listeners, and to reload the restrictions
+    /// Disabled by default. Requires --admin-token, do not expose it publicly, i.e: --admin-bind 127.0.0.1:9091
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, value_name = "ADDR", requires = "admin_token", verbatim_doc_comment, env = "WSTUNNEL_ADMIN_BIND")
+    )]
+    pub admin_bind: Option<SocketAddr>,
+
+    /// Token the admin API requests must provide in the header `Authorization: Bearer <TOKEN>`
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, value_name = "TOKEN", verbatim_doc_comment, env = "WSTUNNEL_ADMIN_TOKEN")
+    )]
+    pub admin_token: Option<String>,
+
+    /// Serve the static files of this directory to requests that are not a valid tunnel, i.e: wrong path prefix, plain GET, ...
+    /// Instead of answering them with a bad request, that reveals the server to active probers
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, value_name = "DIRECTORY", conflicts_with = "fallback_upstream", verbatim_doc_comment)
+    )]
+    pub fallback_dir: Option<PathBuf>,
+
+    /// Forward the requests that are not a valid tunnel to this upstream http server, i.e: --fallback-upstream http://127.0.0.1:8081
+    /// Instead of answering them with a bad request, that reveals the server to active probers
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, value_name = "URL", value_parser = parsers::parse_fallback_upstream, verbatim_doc_comment)
+    )]
+    pub fallback_upstream: Option<Url>,
+
+    /// Persist the traffic used by the restrictions having a quota to this JSON file, so it survives a restart
+    /// Without it, the usage is reset when the server restarts
+    #[cfg_attr(feature = "clap", arg(long, value_name = "FILE_PATH", verbatim_doc_comment))]
+    pub quota_state_file: Option<PathBuf>,
+
+    /// Trust the X-Forwarded-For, or Forwarded, header of the requests coming from these proxies to know the ip of the client.
+    /// Without it, the headers are ignored as any client can set them to spoof its ip. Can be specified multiple time
+    /// The chain of proxies is walked from right to left, and the first ip that is not a trusted proxy is the client
+    /// i.e: --trusted-proxy 127.0.0.1/32 --trusted-proxy 10.0.0.0/8
+    #[cfg_attr(
+        feature = "clap",
+        arg(
+            long = "trusted-proxy",
+            value_name = "CIDR",
+            value_delimiter = ',',
+            verbatim_doc_comment,
+            env = "WSTUNNEL_TRUSTED_PROXIES"
+        )
+    )]
+    pub trusted_proxies: Vec<IpNet>,
+
+    /// Read a PROXY protocol header, v1 or v2, at the start of the connections coming from these load balancers
+    /// to know the address of the client, i.e: when running behind HAProxy or a cloud L4 load balancer
+    /// The connections from these load balancers must start with a header. Can be specified multiple time
+    /// i.e: --accept-proxy-protocol-from 10.0.0.0/8
+    #[cfg_attr(
+        feature = "clap",
+        arg(
+            long = "accept-proxy-protocol-from",
+            value_name = "CIDR",
+            value_delimiter = ',',
+            verbatim_doc_comment,
+            env = "WSTUNNEL_ACCEPT_PROXY_PROTOCOL_FROM"
+        )
+    )]
+    pub accept_proxy_protocol: Vec<IpNet>,
+}
+
+/// Hash a password, or a bearer token, read from stdin for the `users` section of the restrictions config file
+/// i.e: echo -n 'my-password' | wstunnel hash --algorithm bcrypt
+#[derive(Debug)]
+#[cfg_attr(feature = "clap", derive(clap::Args))]
+pub struct Hash {
+    /// Algorithm of the hash. Use argon2, bcrypt or sha-crypt for a password_hash, and sha256 for a token_sha256
+    #[cfg_attr(
+        feature = "clap",
+        arg(long, value_name = "ALGORITHM", value_enum, default_value = "argon2", verbatim_doc_comment)
+    )]
+    pub algorithm: HashAlgorithm,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum HashAlgorithm {
+    Argon2,
+    Bcrypt,
+    ShaCrypt,
+    Sha256,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -415,6 +664,7 @@ mod parsers {
     use crate::tunnel::transport::TransportScheme;
     use base64::Engine;
     use hyper::http::{HeaderName, HeaderValue};
+    use ipnet::Ipv4Net;
     use std::cmp::max;
     use std::collections::BTreeMap;
     use std::io;
@@ -429,6 +679,15 @@ mod parsers {
     pub fn parse_duration_sec(arg: &str) -> Result<Duration, io::Error> {
         use std::io::Error;
 
+        if let Some(millis) = arg.strip_suffix("ms") {
+            return millis.parse::<u64>().map(Duration::from_millis).map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("cannot parse duration of milliseconds from {arg}"),
+                )
+            });
+        }
+
         let (arg, multiplier) = match &arg[max(0, arg.len() - 1)..] {
             "s" => (&arg[..arg.len() - 1], 1),
             "m" => (&arg[..arg.len() - 1], 60),
@@ -549,6 +808,10 @@ mod parsers {
                 Ok(LocalToRemote {
                     local_protocol: LocalProtocol::Tcp {
                         proxy_protocol: get_proxy_protocol(&options),
+                        idle_timeout: options
+                            .get("idle_timeout_sec")
+                            .and_then(|x| x.parse::<u64>().ok())
+                            .map(Duration::from_secs),
                     },
                     local: local_bind,
                     remote: (dest_host, dest_port),
@@ -610,6 +873,83 @@ mod parsers {
                     remote: (dest_host, dest_port),
                 })
             }
+            "mixed" => {
+                let (local_bind, remaining) = parse_local_bind(tunnel_info)?;
+                let x = format!("0.0.0.0:0?{remaining}");
+                let (dest_host, dest_port, options) = parse_tunnel_dest(&x)?;
+                Ok(LocalToRemote {
+                    local_protocol: LocalProtocol::Mixed {
+                        timeout: get_timeout(&options),
+                        credentials: get_credentials(&options),
+                    },
+                    local: local_bind,
+                    remote: (dest_host, dest_port),
+                })
+            }
+            "dns" => {
+                let (local_bind, remaining) = parse_local_bind(tunnel_info)?;
+                let (dest_host, dest_port, options) = parse_tunnel_dest(remaining)?;
+                let over_tcp = match options.get("protocol").map(String::as_str) {
+                    None | Some("udp") => false,
+                    Some("tcp") => true,
+                    Some(proto) => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("Invalid dns protocol {proto} for tunnel {arg}, only tcp or udp are supported"),
+                        ));
+                    }
+                };
+
+                Ok(LocalToRemote {
+                    local_protocol: LocalProtocol::Dns {
+                        timeout: get_timeout(&options),
+                        over_tcp,
+                    },
+                    local: local_bind,
+                    remote: (dest_host, dest_port),
+                })
+            }
+            "fakedns" => {
+                let (local_bind, remaining) = parse_local_bind(tunnel_info)?;
+                let x = format!("0.0.0.0:0?{remaining}");
+                let (dest_host, dest_port, options) = parse_tunnel_dest(&x)?;
+                let pool = match options.get("pool") {
+                    Some(pool) => pool.parse().map_err(|err| {
+                        Error::new(ErrorKind::InvalidInput, format!("Invalid fake ip pool {pool}: {err}"))
+                    })?,
+                    None => Ipv4Net::new(Ipv4Addr::new(198, 18, 0, 0), 15).unwrap(),
+                };
+
+                Ok(LocalToRemote {
+                    local_protocol: LocalProtocol::FakeDns { pool },
+                    local: local_bind,
+                    remote: (dest_host, dest_port),
+                })
+            }
+            "tun" => {
+                let (fd, remaining) = tunnel_info.split_once('?').unwrap_or((tunnel_info, ""));
+                let fd = fd.parse::<i32>().map_err(|err| {
+                    Error::new(ErrorKind::InvalidInput, format!("Invalid tun file descriptor {fd}: {err}"))
+                })?;
+                let x = format!("0.0.0.0:0?{remaining}");
+                let (dest_host, dest_port, options) = parse_tunnel_dest(&x)?;
+                let mtu = match options.get("mtu") {
+                    Some(mtu) => mtu
+                        .parse::<u16>()
+                        .map_err(|err| Error::new(ErrorKind::InvalidInput, format!("Invalid tun mtu {mtu}: {err}")))?,
+                    None => 1500,
+                };
+
+                Ok(LocalToRemote {
+                    local_protocol: LocalProtocol::Tun {
+                        fd,
+                        mtu,
+                        timeout: get_timeout(&options),
+                    },
+                    local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(0), 0)),
+                    remote: (dest_host, dest_port),
+                })
+            }
             "stdio" => {
                 let (dest_host, dest_port, options) = parse_tunnel_dest(tunnel_info)?;
                 Ok(LocalToRemote {
@@ -668,6 +1008,10 @@ mod parsers {
             | LocalProtocol::ReverseUnix { .. }
             | LocalProtocol::TProxyTcp
             | LocalProtocol::TProxyUdp { .. }
+            | LocalProtocol::Mixed { .. }
+            | LocalProtocol::Dns { .. }
+            | LocalProtocol::FakeDns { .. }
+            | LocalProtocol::Tun { .. }
             | LocalProtocol::Stdio { .. } => {
                 return Err(io::Error::new(
                     ErrorKind::InvalidInput,
@@ -745,14 +1089,38 @@ mod parsers {
         Ok(url)
     }
 
+    pub fn parse_fallback_upstream(arg: &str) -> Result<Url, io::Error> {
+        let Ok(url) = Url::parse(arg) else {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("cannot parse fallback upstream url {arg}"),
+            ));
+        };
+
+        // Only plain http, the upstream is expected to be a local web server
+        if url.scheme() != "http" {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid scheme {}, only http is supported", url.scheme()),
+            ));
+        }
+
+        if url.host().is_none() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, format!("invalid upstream host {arg}")));
+        }
+
+        Ok(url)
+    }
+
     #[cfg(test)]
     mod test {
-        use super::{LocalToRemote, parse_local_bind, parse_tunnel_arg, parse_tunnel_dest};
+        use super::{LocalToRemote, parse_duration_sec, parse_local_bind, parse_tunnel_arg, parse_tunnel_dest};
         use crate::tunnel::LocalProtocol;
         use collection_macros::btreemap;
         use std::collections::BTreeMap;
         use std::io;
         use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+        use std::time::Duration;
         use test_case::test_case;
         use url::Host;
 
@@ -777,15 +1145,31 @@ mod parsers {
             parse_local_bind(input)
         }
 
+        #[test_case("30" => Duration::from_secs(30) ; "without unit")]
+        #[test_case("30s" => Duration::from_secs(30) ; "with seconds")]
+        #[test_case("5m" => Duration::from_secs(300) ; "with minutes")]
+        #[test_case("250ms" => Duration::from_millis(250) ; "with milliseconds")]
+        #[test_case("ams" => panics "" ; "with invalid milliseconds")]
+        fn test_parse_duration_sec(input: &str) -> Duration {
+            parse_duration_sec(input).unwrap()
+        }
+
         #[test_case("domain.com:443" => panics ""; "with no protocol")]
         #[test_case("sdsf://443:domain.com:443" => panics ""; "with invalid protocol")]
         #[test_case("tcp://443:domain.com:4443" =>
             LocalToRemote {
-                local_protocol: LocalProtocol::Tcp { proxy_protocol: false },
+                local_protocol: LocalProtocol::Tcp { proxy_protocol: false, idle_timeout: None },
                 local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 443)),
                 remote: (Host::Domain("domain.com".to_string()), 4443),
             }
         ; "with no local bind")]
+        #[test_case("tcp://443:domain.com:4443?idle_timeout_sec=60" =>
+            LocalToRemote {
+                local_protocol: LocalProtocol::Tcp { proxy_protocol: false, idle_timeout: Some(std::time::Duration::from_secs(60)) },
+                local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 443)),
+                remote: (Host::Domain("domain.com".to_string()), 4443),
+            }
+        ; "with idle timeout")]
         #[test_case("udp://[::1]:443:toto.com:4443?timeout_sec=30" =>
             LocalToRemote {
                 local_protocol: LocalProtocol::Udp { timeout: Some(std::time::Duration::from_secs(30)) },
@@ -800,6 +1184,34 @@ mod parsers {
                 remote: (Host::Ipv6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 4443),
             }
         ; "with full ipv6 tunnel")]
+        #[test_case("mixed://127.0.0.1:1080?login=admin&password=secret" =>
+            LocalToRemote {
+                local_protocol: LocalProtocol::Mixed { timeout: Some(std::time::Duration::from_secs(30)), credentials: Some(("admin".to_string(), "secret".to_string())) },
+                local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 1080)),
+                remote: (Host::Ipv4(Ipv4Addr::UNSPECIFIED), 0),
+            }
+        ; "with mixed proxy")]
+        #[test_case("dns://5353:1.1.1.1:53?protocol=tcp" =>
+            LocalToRemote {
+                local_protocol: LocalProtocol::Dns { timeout: Some(std::time::Duration::from_secs(30)), over_tcp: true },
+                local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 5353)),
+                remote: (Host::Ipv4(Ipv4Addr::new(1, 1, 1, 1)), 53),
+            }
+        ; "with dns over tcp")]
+        #[test_case("fakedns://5353" =>
+            LocalToRemote {
+                local_protocol: LocalProtocol::FakeDns { pool: "198.18.0.0/15".parse().unwrap() },
+                local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 5353)),
+                remote: (Host::Ipv4(Ipv4Addr::UNSPECIFIED), 0),
+            }
+        ; "with fake dns")]
+        #[test_case("tun://3?mtu=1400" =>
+            LocalToRemote {
+                local_protocol: LocalProtocol::Tun { fd: 3, mtu: 1400, timeout: Some(std::time::Duration::from_secs(30)) },
+                local: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
+                remote: (Host::Ipv4(Ipv4Addr::UNSPECIFIED), 0),
+            }
+        ; "with tun fd")]
         fn test_parse_tunnel_arg(input: &str) -> LocalToRemote {
             parse_tunnel_arg(input).unwrap()
         }
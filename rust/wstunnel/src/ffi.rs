@@ -1,12 +1,18 @@
-use crate::config::Client;
+use crate::config::{Client, LocalToRemote};
 use crate::executor::DefaultTokioExecutor;
 use crate::tunnel::LocalProtocol;
+use crate::tunnel::client::WsClient;
+use crate::tunnel::transport::keepalive::KeepaliveProfile;
 use crate::create_client_tunnels;
+#[cfg(unix)]
+use crate::socket_protect::{SocketProtector, set_socket_protector};
 use parking_lot::Mutex;
+use serde::Deserialize;
 use std::collections::VecDeque;
 use std::ffi::{CStr, CString};
 use std::io::{self, LineWriter, Write};
 use std::os::raw::{c_char, c_int};
+use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 use tokio::runtime::Runtime;
@@ -14,12 +20,19 @@ use url::Url;
 
 // Global state for managing runtime and client
 struct WstunnelState {
-    runtime_thread: Option<std::thread::JoinHandle<()>>,
     stop_tx: Option<tokio::sync::oneshot::Sender<()>>,
+    // Client of the tunnels of this start, to notify it of the changes of network. It is set by the runtime thread
+    // once the tunnels are created, so a client that is stopped, or replaced, meanwhile is never reachable
+    client: Arc<Mutex<Option<WsClient>>>,
 }
 
 static STATE: Mutex<Option<WstunnelState>> = Mutex::new(None);
 
+// Client of the running tunnels, if they are created and not stopped yet
+fn running_client() -> Option<WsClient> {
+    STATE.lock().as_ref().and_then(|state| state.client.lock().clone())
+}
+
 // Thread-safe queue for logs (instead of direct callback calls)
 static LOG_QUEUE: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
 const MAX_LOG_QUEUE_SIZE: usize = 1000;
@@ -29,6 +42,10 @@ type LogCallback = extern "C" fn(*const c_char);
 
 static LOG_CALLBACK: Mutex<Option<LogCallback>> = Mutex::new(None);
 
+// Callback to protect sockets from being routed into the VPN, receives the file descriptor of the socket
+#[cfg(unix)]
+type ProtectCallback = extern "C" fn(c_int) -> bool;
+
 // Flag to track tracing subscriber initialization
 static TRACING_INITIALIZED: AtomicBool = AtomicBool::new(false);
 
@@ -207,6 +224,17 @@ pub extern "C" fn wstunnel_set_log_callback(callback: LogCallback) {
     *LOG_CALLBACK.lock() = Some(callback);
 }
 
+/// Set callback invoked with the file descriptor of every socket opened towards the network, before it connects
+/// i.e: to call VpnService.protect on Android, so the traffic of the tunnel does not loop back into the VPN
+/// The callback must return true if the socket can be used. Pass null to remove it
+#[cfg(unix)]
+#[unsafe(no_mangle)]
+pub extern "C" fn wstunnel_set_protect_callback(callback: Option<ProtectCallback>) {
+    log_message(&format!("[PROTECT] wstunnel_set_protect_callback called, callback set: {}", callback.is_some()));
+    let protector = callback.map(|callback| Arc::new(move |fd| callback(fd)) as SocketProtector);
+    set_socket_protector(protector);
+}
+
 /// Get next message from log queue
 /// Returns pointer to CString or null if queue is empty
 /// Caller must free memory via wstunnel_free_log_message
@@ -234,6 +262,181 @@ pub extern "C" fn wstunnel_free_log_message(ptr: *mut c_char) {
     }
 }
 
+// Create client configuration with the defaults used by the mobile applications
+fn new_client_config(
+    local_to_remote: Vec<LocalToRemote>,
+    remote_addr: Url,
+    http_upgrade_path_prefix: &str,
+    connection_min_idle: u32,
+) -> Client {
+    Client {
+        local_to_remote,
+        remote_to_local: vec![],
+        socket_so_mark: None,
+        socket_bind_address: None,
+        socket_bind_interface: None,
+        connection_min_idle,
+        connection_retry_max_backoff: Duration::from_secs(300),
+        reverse_tunnel_connection_retry_max_backoff: Duration::from_secs(1),
+        happy_eyeballs_delay: crate::protocols::tcp::HAPPY_EYEBALLS_DELAY,
+        tcp_idle_timeout: None,
+        tcp_half_close_timeout: Some(Duration::from_secs(30)),
+        drain_timeout: Duration::from_secs(5),
+        tls_sni_override: None,
+        tls_sni_disable: false,
+        tls_ech_enable: false,
+        tls_verify_certificate: false,
+        http_proxy: None,
+        http_proxy_login: None,
+        http_proxy_password: None,
+        http_upgrade_path_prefix: http_upgrade_path_prefix.to_string(),
+        http_upgrade_credentials: None,
+        websocket_ping_frequency: Some(Duration::from_secs(30)),
+        websocket_pong_timeout: Some(Duration::from_secs(10)),
+        keepalive_profile: None,
+        websocket_mask_frame: false,
+        http_headers: vec![],
+        http_headers_file: None,
+        remote_addr,
+        tls_certificate: None,
+        tls_private_key: None,
+        dns_resolver: vec![],
+        dns_resolver_prefer_ipv4: false,
+    }
+}
+
+// Run the client tunnels in a dedicated runtime thread, until wstunnel_stop is called
+fn spawn_client(client_config: Client) -> c_int {
+    log_message("Creating stop channel...");
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel();
+    log_message("Stop channel created");
+    let running_client = Arc::new(Mutex::new(None));
+    let state_client = running_client.clone();
+
+    // Start runtime in separate thread
+    log_message("Spawning runtime thread...");
+    // The thread is detached, so wstunnel_stop does not wait for the tunnels to be drained
+    std::thread::spawn(move || {
+        log_message("[THREAD] Runtime thread started");
+        
+        // Test call to tracing::info!() from runtime thread
+        tracing::info!("[TRACING TEST FROM THREAD] This is a test message from runtime thread");
+        
+        // Create runtime in this thread
+        log_message("[THREAD] Creating Tokio runtime...");
+        let runtime = match Runtime::new() {
+            Ok(rt) => {
+                log_message("[THREAD] Tokio runtime created successfully");
+                // Another test call after creating runtime
+                tracing::info!("[TRACING TEST FROM THREAD] Tokio runtime created, testing tracing again");
+                rt
+            }
+            Err(e) => {
+                log_message(&format!("[THREAD] Error creating runtime: {}", e));
+                return;
+            }
+        };
+
+        log_message("[THREAD] Entering runtime.block_on...");
+        runtime.block_on(async {
+            log_message("[ASYNC] Starting wstunnel client...");
+            
+            log_message("[ASYNC] Getting Tokio handle...");
+            let handle = tokio::runtime::Handle::current();
+            log_message("[ASYNC] Creating executor...");
+            let executor = DefaultTokioExecutor::new(handle.clone());
+            log_message("[ASYNC] Executor created");
+            
+            log_message("[ASYNC] Calling create_client_tunnels...");
+            // Test call to tracing::info!() from async context
+            tracing::info!("[TRACING TEST FROM ASYNC] This is a test message from async context before create_client_tunnels");
+            
+            match create_client_tunnels(client_config, executor).await {
+                Ok((client, tunnels)) => {
+                    *running_client.lock() = Some(client.clone());
+                    log_message(&format!("[ASYNC] Created {} tunnels", tunnels.len()));
+                    // Another test call after creating tunnels
+                    tracing::info!("[TRACING TEST FROM ASYNC] Tunnels created successfully, testing tracing again");
+                    
+                    if tunnels.is_empty() {
+                        log_message("[ASYNC] WARNING: No tunnels were created! This is likely a configuration issue.");
+                    }
+                    
+                    // Start all tunnels in parallel
+                    log_message("[ASYNC] Spawning tunnel tasks...");
+                    let mut join_handles = Vec::new();
+                    for (i, tunnel) in tunnels.into_iter().enumerate() {
+                        log_message(&format!("[ASYNC] Spawning tunnel {}...", i));
+                        let start_time = std::time::Instant::now();
+                        let tunnel_handle = handle.spawn(async move {
+                            log_message(&format!("[TUNNEL {}] Tunnel task started", i));
+                            tunnel.await;
+                            let duration = start_time.elapsed();
+                            log_message(&format!("[TUNNEL {}] Tunnel task completed after {:?}", i, duration));
+                            if duration.as_secs() < 1 {
+                                log_message(&format!("[TUNNEL {}] WARNING: Tunnel completed very quickly ({}ms), this might indicate an error", i, duration.as_millis()));
+                            }
+                        });
+                        join_handles.push(tunnel_handle);
+                    }
+                    log_message(&format!("[ASYNC] Spawned {} tunnel tasks", join_handles.len()));
+
+                    // Wait for either stop signal or completion of all tunnels
+                    log_message("[ASYNC] Setting up select! to wait for tunnels or stop signal...");
+                    let wait_all = async {
+                        log_message("[ASYNC] Waiting for all tunnels to complete...");
+                        // Wait for all tunnels to complete
+                        for (i, handle) in join_handles.into_iter().enumerate() {
+                            log_message(&format!("[ASYNC] Waiting for tunnel {}...", i));
+                            match handle.await {
+                                Ok(_) => log_message(&format!("[ASYNC] Tunnel {} completed successfully", i)),
+                                Err(e) => log_message(&format!("[ASYNC] Tunnel {} panicked or was cancelled: {:?}", i, e)),
+                            }
+                        }
+                        log_message("[ASYNC] All tunnels finished");
+                    };
+                    
+                    log_message("[ASYNC] Entering tokio::select!...");
+                    tokio::select! {
+                        _ = stop_rx => {
+                            log_message("[ASYNC] Stop signal received, draining tunnels...");
+                            if client.shutdown().await {
+                                log_message("[ASYNC] All tunnels drained");
+                            } else {
+                                log_message("[ASYNC] Drain timeout elapsed, remaining tunnels are cancelled on exit");
+                            }
+                        }
+                        _ = wait_all => {
+                            log_message("[ASYNC] All tunnels finished (from select)");
+                            running_client.lock().take();
+                        }
+                    }
+                    log_message("[ASYNC] Exited tokio::select!");
+                }
+                Err(e) => {
+                    log_message(&format!("[ASYNC] Error creating tunnels: {}", e));
+                    log_message(&format!("[ASYNC] Error details: {:?}", e));
+                }
+            }
+            log_message("[ASYNC] Async block completed");
+        });
+        log_message("[THREAD] Runtime block_on completed");
+    });
+    log_message("Runtime thread spawned successfully");
+
+    // Save state
+    log_message("Saving state to global STATE...");
+    *STATE.lock() = Some(WstunnelState {
+        stop_tx: Some(stop_tx),
+        client: state_client,
+    });
+    log_message("State saved successfully");
+
+    log_message("Wstunnel client started successfully");
+    log_message("Returning 0 from spawn_client");
+    0
+}
+
 /// Start wstunnel client
 /// 
 /// Parameters:
@@ -317,8 +520,8 @@ pub extern "C" fn wstunnel_start_client(
         
         // Create client configuration
         // For socks5 tunnel, remote is not used as it's a dynamic proxy
-        let client_config = Client {
-            local_to_remote: vec![crate::config::LocalToRemote {
+        let client_config = new_client_config(
+            vec![crate::config::LocalToRemote {
                 local_protocol: LocalProtocol::Socks5 {
                     timeout: Some(Duration::from_secs(30)),
                     credentials: None,
@@ -329,30 +532,10 @@ pub extern "C" fn wstunnel_start_client(
                     0,
                 ), // Not used for socks5
             }],
-            remote_to_local: vec![],
-            socket_so_mark: None,
-            connection_min_idle: connection_min_idle as u32,
-            connection_retry_max_backoff: Duration::from_secs(300),
-            reverse_tunnel_connection_retry_max_backoff: Duration::from_secs(1),
-            tls_sni_override: None,
-            tls_sni_disable: false,
-            tls_ech_enable: false,
-            tls_verify_certificate: false,
-            http_proxy: None,
-            http_proxy_login: None,
-            http_proxy_password: None,
-            http_upgrade_path_prefix: path_prefix.to_string(),
-            http_upgrade_credentials: None,
-            websocket_ping_frequency: Some(Duration::from_secs(30)),
-            websocket_mask_frame: false,
-            http_headers: vec![],
-            http_headers_file: None,
-            remote_addr: remote_url_parsed,
-            tls_certificate: None,
-            tls_private_key: None,
-            dns_resolver: vec![],
-            dns_resolver_prefer_ipv4: false,
-        };
+            remote_url_parsed,
+            path_prefix,
+            connection_min_idle as u32,
+        );
         log_message("Client configuration created successfully");
         log_message(&format!("[CONFIG] local_to_remote count: {}", client_config.local_to_remote.len()));
         log_message(&format!("[CONFIG] remote_to_local count: {}", client_config.remote_to_local.len()));
@@ -360,136 +543,139 @@ pub extern "C" fn wstunnel_start_client(
             log_message(&format!("[CONFIG] First tunnel: local={:?}, protocol={:?}", tunnel.local, tunnel.local_protocol));
         }
 
-        log_message("Creating stop channel...");
-        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel();
-        log_message("Stop channel created");
+        spawn_client(client_config)
+    }
+}
 
-        // Start runtime in separate thread
-        log_message("Spawning runtime thread...");
-        let runtime_thread = std::thread::spawn(move || {
-            log_message("[THREAD] Runtime thread started");
-            
-            // Test call to tracing::info!() from runtime thread
-            tracing::info!("[TRACING TEST FROM THREAD] This is a test message from runtime thread");
-            
-            // Create runtime in this thread
-            log_message("[THREAD] Creating Tokio runtime...");
-            let runtime = match Runtime::new() {
-                Ok(rt) => {
-                    log_message("[THREAD] Tokio runtime created successfully");
-                    // Another test call after creating runtime
-                    tracing::info!("[TRACING TEST FROM THREAD] Tokio runtime created, testing tracing again");
-                    rt
-                }
-                Err(e) => {
-                    log_message(&format!("[THREAD] Error creating runtime: {}", e));
-                    return;
-                }
-            };
-
-            log_message("[THREAD] Entering runtime.block_on...");
-            runtime.block_on(async {
-                log_message("[ASYNC] Starting wstunnel client...");
-                
-                log_message("[ASYNC] Getting Tokio handle...");
-                let handle = tokio::runtime::Handle::current();
-                log_message("[ASYNC] Creating executor...");
-                let executor = DefaultTokioExecutor::new(handle.clone());
-                log_message("[ASYNC] Executor created");
-                
-                log_message("[ASYNC] Calling create_client_tunnels...");
-                // Test call to tracing::info!() from async context
-                tracing::info!("[TRACING TEST FROM ASYNC] This is a test message from async context before create_client_tunnels");
-                
-                match create_client_tunnels(client_config, executor).await {
-                    Ok(tunnels) => {
-                        log_message(&format!("[ASYNC] Created {} tunnels", tunnels.len()));
-                        // Another test call after creating tunnels
-                        tracing::info!("[TRACING TEST FROM ASYNC] Tunnels created successfully, testing tracing again");
-                        
-                        if tunnels.is_empty() {
-                            log_message("[ASYNC] WARNING: No tunnels were created! This is likely a configuration issue.");
-                        }
-                        
-                        // Start all tunnels in parallel
-                        log_message("[ASYNC] Spawning tunnel tasks...");
-                        let mut join_handles = Vec::new();
-                        for (i, tunnel) in tunnels.into_iter().enumerate() {
-                            log_message(&format!("[ASYNC] Spawning tunnel {}...", i));
-                            let start_time = std::time::Instant::now();
-                            let tunnel_handle = handle.spawn(async move {
-                                log_message(&format!("[TUNNEL {}] Tunnel task started", i));
-                                tunnel.await;
-                                let duration = start_time.elapsed();
-                                log_message(&format!("[TUNNEL {}] Tunnel task completed after {:?}", i, duration));
-                                if duration.as_secs() < 1 {
-                                    log_message(&format!("[TUNNEL {}] WARNING: Tunnel completed very quickly ({}ms), this might indicate an error", i, duration.as_millis()));
-                                }
-                            });
-                            join_handles.push(tunnel_handle);
-                        }
-                        log_message(&format!("[ASYNC] Spawned {} tunnel tasks", join_handles.len()));
-
-                        // Wait for either stop signal or completion of all tunnels
-                        log_message("[ASYNC] Setting up select! to wait for tunnels or stop signal...");
-                        let wait_all = async {
-                            log_message("[ASYNC] Waiting for all tunnels to complete...");
-                            // Wait for all tunnels to complete
-                            for (i, handle) in join_handles.into_iter().enumerate() {
-                                log_message(&format!("[ASYNC] Waiting for tunnel {}...", i));
-                                match handle.await {
-                                    Ok(_) => log_message(&format!("[ASYNC] Tunnel {} completed successfully", i)),
-                                    Err(e) => log_message(&format!("[ASYNC] Tunnel {} panicked or was cancelled: {:?}", i, e)),
-                                }
-                            }
-                            log_message("[ASYNC] All tunnels finished");
-                        };
-                        
-                        log_message("[ASYNC] Entering tokio::select!...");
-                        tokio::select! {
-                            _ = stop_rx => {
-                                log_message("[ASYNC] Stop signal received");
-                                // Cancel all tunnels - they will be cancelled on exit from select
-                            }
-                            _ = wait_all => {
-                                log_message("[ASYNC] All tunnels finished (from select)");
-                            }
-                        }
-                        log_message("[ASYNC] Exited tokio::select!");
-                    }
-                    Err(e) => {
-                        log_message(&format!("[ASYNC] Error creating tunnels: {}", e));
-                        log_message(&format!("[ASYNC] Error details: {:?}", e));
-                    }
-                }
-                log_message("[ASYNC] Async block completed");
-            });
-            log_message("[THREAD] Runtime block_on completed");
-        });
-        log_message("Runtime thread spawned successfully");
+fn default_http_upgrade_path_prefix() -> String {
+    "v1".to_string()
+}
 
-        // Save state
-        log_message("Saving state to global STATE...");
-        *STATE.lock() = Some(WstunnelState {
-            runtime_thread: Some(runtime_thread),
-            stop_tx: Some(stop_tx),
-        });
-        log_message("State saved successfully");
+fn default_tun_udp_timeout_sec() -> u64 {
+    30
+}
 
-        log_message("Wstunnel client started successfully");
-        log_message("Returning 0 from wstunnel_start_client");
-        0
+fn default_drain_timeout_sec() -> u64 {
+    5
+}
+
+// Configuration of wstunnel_start_tun, given as a json object
+#[derive(Deserialize)]
+struct TunClientConfig {
+    remote_url: String,
+    #[serde(default = "default_http_upgrade_path_prefix")]
+    http_upgrade_path_prefix: String,
+    #[serde(default)]
+    connection_min_idle: u32,
+    #[serde(default)]
+    tls_verify_certificate: bool,
+    #[serde(default = "default_tun_udp_timeout_sec")]
+    udp_timeout_sec: u64,
+    #[serde(default)]
+    keepalive_profile: Option<KeepaliveProfile>,
+    #[serde(default = "default_drain_timeout_sec")]
+    drain_timeout_sec: u64,
+}
+
+/// Start wstunnel client forwarding all the traffic of a tun device
+///
+/// Parameters:
+/// - fd: file descriptor of the tun device (e.g., returned by Android VpnService.Builder.establish())
+///   It is not closed by wstunnel, the caller stays the owner of it
+/// - mtu: mtu of the tun device
+/// - config_json: client configuration as json, only remote_url is mandatory, e.g.
+///   {"remote_url": "wss://example.com", "http_upgrade_path_prefix": "v1", "connection_min_idle": 0, "tls_verify_certificate": false, "udp_timeout_sec": 30, "keepalive_profile": "battery", "drain_timeout_sec": 5}
+///   keepalive_profile is one of aggressive, balanced or battery, and is not set by default
+///   drain_timeout_sec is how long wstunnel_stop waits for the tunnels in flight to finish
+///
+/// Returns: 0 on success, -1 on error
+///
+/// # Safety
+/// config_json must be null or a valid pointer to a nul terminated string
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn wstunnel_start_tun(fd: c_int, mtu: c_int, config_json: *const c_char) -> c_int {
+    log_message("[START] wstunnel_start_tun called");
+    init_tracing_subscriber();
+    log_message(&format!("[START] Parameters: fd={}, mtu={}", fd, mtu));
+
+    if config_json.is_null() {
+        log_message("Error: config_json is null");
+        return -1;
     }
+
+    let config_str = match unsafe { CStr::from_ptr(config_json) }.to_str() {
+        Ok(s) => s,
+        Err(_) => {
+            log_message("Error: Invalid config_json");
+            return -1;
+        }
+    };
+
+    // Json is a subset of yaml, so no need for an extra parser
+    let config: TunClientConfig = match serde_yaml::from_str(config_str) {
+        Ok(config) => config,
+        Err(e) => {
+            log_message(&format!("Error parsing config_json: {}", e));
+            return -1;
+        }
+    };
+
+    let remote_url = match Url::parse(&config.remote_url) {
+        Ok(url) => {
+            log_message(&format!("Remote URL parsed successfully: {}", url));
+            url
+        }
+        Err(e) => {
+            log_message(&format!("Error parsing remote URL: {}", e));
+            return -1;
+        }
+    };
+
+    let mtu = match u16::try_from(mtu) {
+        Ok(mtu) if mtu > 0 => mtu,
+        _ => {
+            log_message(&format!("Error: Invalid mtu {}", mtu));
+            return -1;
+        }
+    };
+
+    log_message("Creating client configuration...");
+    let mut client_config = new_client_config(
+        vec![LocalToRemote {
+            local_protocol: LocalProtocol::Tun {
+                fd,
+                mtu,
+                timeout: match config.udp_timeout_sec {
+                    0 => None,
+                    timeout => Some(Duration::from_secs(timeout)),
+                },
+            },
+            local: "0.0.0.0:0".parse().unwrap(), // Not used for tun
+            remote: (url::Host::Domain("0.0.0.0".to_string()), 0), // Not used for tun
+        }],
+        remote_url,
+        &config.http_upgrade_path_prefix,
+        config.connection_min_idle,
+    );
+    client_config.tls_verify_certificate = config.tls_verify_certificate;
+    client_config.keepalive_profile = config.keepalive_profile;
+    client_config.drain_timeout = Duration::from_secs(config.drain_timeout_sec);
+    log_message("Client configuration created successfully");
+
+    spawn_client(client_config)
 }
 
-/// Stop wstunnel client
+/// Stop wstunnel client, tunnels in flight are given the drain timeout to close gracefully
+///
+/// It returns right away, i.e: it can be called from the UI thread. The local listeners are closed and the tunnels
+/// are drained in the background, up to the drain timeout (5s by default, or drain_timeout_sec of wstunnel_start_tun)
 #[unsafe(no_mangle)]
 pub extern "C" fn wstunnel_stop() {
     unsafe {
         log_message("[STOP] wstunnel_stop called");
-        let mut state = STATE.lock();
+        let state = STATE.lock().take();
         log_message("[STOP] State lock acquired");
-        if let Some(mut s) = state.take() {
+        if let Some(mut s) = state {
             log_message("[STOP] State found, stopping...");
             if let Some(tx) = s.stop_tx.take() {
                 log_message("[STOP] Sending stop signal...");
@@ -500,17 +686,7 @@ pub extern "C" fn wstunnel_stop() {
             } else {
                 log_message("[STOP] No stop_tx found");
             }
-            // Wait for runtime thread to complete
-            if let Some(thread) = s.runtime_thread.take() {
-                log_message("[STOP] Waiting for runtime thread to join...");
-                match thread.join() {
-                    Ok(_) => log_message("[STOP] Runtime thread joined successfully"),
-                    Err(e) => log_message(&format!("[STOP] Error joining runtime thread: {:?}", e)),
-                }
-            } else {
-                log_message("[STOP] No runtime_thread found");
-            }
-            log_message("[STOP] Wstunnel client stopped");
+            log_message("[STOP] Wstunnel client stopping, tunnels are drained in the background");
         } else {
             log_message("[STOP] Wstunnel client is not running");
         }
@@ -518,6 +694,55 @@ pub extern "C" fn wstunnel_stop() {
     }
 }
 
+/// Notify wstunnel that the network of the device has changed (i.e: switch between wifi and cellular)
+/// Connections to the server are re-established, dns caches are flushed and reverse tunnels reconnect right away.
+/// If close_streams is true, the tunnels in flight are closed too, as they are likely stuck on the previous network
+///
+/// Returns: 0 on success, -1 if the client is not running
+#[unsafe(no_mangle)]
+pub extern "C" fn wstunnel_notify_network_changed(close_streams: bool) -> c_int {
+    log_message(&format!("[NETWORK] wstunnel_notify_network_changed called, close_streams: {}", close_streams));
+    let Some(client) = running_client() else {
+        log_message("[NETWORK] Wstunnel client is not running");
+        return -1;
+    };
+
+    client.notify_network_changed(close_streams);
+    0
+}
+
+/// Pause wstunnel, i.e: when the screen is off or the device enters doze mode
+/// Local ports stay bound but new connections wait to be resumed, up to the connect timeout.
+/// Idle connections to the server are closed, reverse tunnels stop reconnecting and pings are not sent anymore
+///
+/// Returns: 0 on success, -1 if the client is not running
+#[unsafe(no_mangle)]
+pub extern "C" fn wstunnel_pause() -> c_int {
+    log_message("[PAUSE] wstunnel_pause called");
+    let Some(client) = running_client() else {
+        log_message("[PAUSE] Wstunnel client is not running");
+        return -1;
+    };
+
+    client.pause();
+    0
+}
+
+/// Resume wstunnel after a call to wstunnel_pause
+///
+/// Returns: 0 on success, -1 if the client is not running
+#[unsafe(no_mangle)]
+pub extern "C" fn wstunnel_resume() -> c_int {
+    log_message("[PAUSE] wstunnel_resume called");
+    let Some(client) = running_client() else {
+        log_message("[PAUSE] Wstunnel client is not running");
+        return -1;
+    };
+
+    client.resume();
+    0
+}
+
 /// Check if client is running
 #[unsafe(no_mangle)]
 pub extern "C" fn wstunnel_is_running() -> c_int {
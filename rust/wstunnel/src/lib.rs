@@ -4,24 +4,28 @@ pub mod executor;
 pub mod ffi;
 mod protocols;
 mod restrictions;
+mod socket_bind;
+mod socket_protect;
 mod somark;
 #[cfg(test)]
 mod test_integrations;
 pub mod tunnel;
 
-use crate::config::{Client, DEFAULT_CLIENT_UPGRADE_PATH_PREFIX, Server};
+use crate::config::{Client, DEFAULT_CLIENT_UPGRADE_PATH_PREFIX, Hash, Server};
 use crate::executor::{TokioExecutor, TokioExecutorRef};
-use crate::protocols::dns::DnsResolver;
+use crate::protocols::dns::{DnsResolver, FakeDnsServer, FakeIpPool};
 use crate::protocols::tls;
 use crate::restrictions::types::RestrictionsRules;
+use crate::socket_bind::SocketBind;
 use crate::somark::SoMark;
 pub use crate::tunnel::LocalProtocol;
 pub use crate::tunnel::client::{TlsClientConfig, WsClient, WsClientConfig};
 use crate::tunnel::connectors::{Socks5TunnelConnector, TcpTunnelConnector, UdpTunnelConnector};
 use crate::tunnel::listeners::{
-    HttpProxyTunnelListener, Socks5TunnelListener, TcpTunnelListener, UdpTunnelListener, new_stdio_listener,
+    DnsTunnelListener, HttpProxyTunnelListener, Socks5TunnelListener, TcpTunnelListener, UdpTunnelListener,
+    new_stdio_listener,
 };
-use crate::tunnel::server::{TlsServerConfig, WsServer, WsServerConfig};
+use crate::tunnel::server::{FallbackConfig, TlsServerConfig, WsServer, WsServerConfig};
 use crate::tunnel::transport::{TransportAddr, TransportScheme};
 use crate::tunnel::{RemoteAddr, to_host_port};
 use anyhow::{Context, anyhow};
@@ -39,8 +43,14 @@ use tokio::task::JoinSet;
 use tracing::{error, info};
 use url::Url;
 
-pub async fn run_client(args: Client, executor: impl TokioExecutor) -> anyhow::Result<()> {
-    let tunnels = create_client_tunnels(args, executor.ref_clone()).await?;
+/// Run the client until all its tunnels are done.
+/// Once shutdown_signal resolves, no new tunnel is accepted and the ones in flight are drained, up to the drain timeout
+pub async fn run_client(
+    args: Client,
+    executor: impl TokioExecutor,
+    shutdown_signal: impl Future<Output = ()>,
+) -> anyhow::Result<()> {
+    let (client, tunnels) = create_client_tunnels(args, executor.ref_clone()).await?;
 
     // Start all tunnels
     let (tx, rx) = oneshot::channel();
@@ -49,15 +59,17 @@ pub async fn run_client(args: Client, executor: impl TokioExecutor) -> anyhow::R
         let _ = tx.send(());
     });
 
-    // wait for all tunnels to finish
-    rx.await?;
+    // wait for all tunnels to finish, or drain them on shutdown
+    select! {
+        ret = rx => ret?,
+        _ = shutdown_signal => {
+            client.shutdown().await;
+        }
+    }
     Ok(())
 }
 
-pub async fn create_client(
-    args: Client,
-    executor: impl TokioExecutorRef,
-) -> anyhow::Result<WsClient<impl TokioExecutorRef>> {
+pub async fn create_client<E: TokioExecutorRef>(args: Client, executor: E) -> anyhow::Result<WsClient<E>> {
     let (tls_certificate, tls_key) = if let (Some(cert), Some(key)) =
         (args.tls_certificate.as_ref(), args.tls_private_key.as_ref())
     {
@@ -81,10 +93,13 @@ pub async fn create_client(
     };
 
     let http_proxy = mk_http_proxy(args.http_proxy, args.http_proxy_login, args.http_proxy_password)?;
+    let socket_bind = SocketBind::new(args.socket_bind_address, args.socket_bind_interface);
     let dns_resolver = DnsResolver::new_from_urls(
         &args.dns_resolver,
         http_proxy.clone(),
         SoMark::new(args.socket_so_mark),
+        socket_bind.clone(),
+        args.happy_eyeballs_delay,
         !args.dns_resolver_prefer_ipv4,
     )
     .expect("cannot create dns resolver");
@@ -153,16 +168,23 @@ pub async fn create_client(
         )
         .unwrap(),
         socket_so_mark: SoMark::new(args.socket_so_mark),
+        socket_bind,
         http_upgrade_path_prefix,
         http_upgrade_credentials: args.http_upgrade_credentials,
         http_headers: args.http_headers.into_iter().filter(|(k, _)| k != HOST).collect(),
         http_headers_file: args.http_headers_file,
         http_header_host: host_header,
         timeout_connect: Duration::from_secs(10),
+        happy_eyeballs_delay: args.happy_eyeballs_delay,
+        tcp_idle_timeout: args.tcp_idle_timeout.filter(|d| !d.is_zero()),
+        tcp_half_close_timeout: args.tcp_half_close_timeout.filter(|d| !d.is_zero()),
+        drain_timeout: args.drain_timeout,
         websocket_ping_frequency: args
             .websocket_ping_frequency
             .or(Some(Duration::from_secs(30)))
             .filter(|d| d.as_secs() > 0),
+        websocket_pong_timeout: args.websocket_pong_timeout.filter(|d| !d.is_zero()),
+        keepalive_profile: args.keepalive_profile,
         websocket_mask_frame: args.websocket_mask_frame,
         dns_resolver,
         http_proxy,
@@ -181,10 +203,10 @@ pub async fn create_client(
     Ok(client)
 }
 
-async fn create_client_tunnels(
+async fn create_client_tunnels<E: TokioExecutorRef>(
     mut args: Client,
-    executor: impl TokioExecutorRef,
-) -> anyhow::Result<Vec<BoxFuture<'static, ()>>> {
+    executor: E,
+) -> anyhow::Result<(WsClient<E>, Vec<BoxFuture<'static, ()>>)> {
     let remote_to_local = std::mem::take(&mut args.remote_to_local);
     let local_to_remote = std::mem::take(&mut args.local_to_remote);
     let client = create_client(args, executor).await?;
@@ -211,6 +233,7 @@ async fn create_client_tunnels(
                         tunnel.remote.1,
                         cfg.socket_so_mark,
                         cfg.timeout_connect,
+                        cfg.happy_eyeballs_delay,
                         &cfg.dns_resolver,
                     );
                     let (host, port) = to_host_port(tunnel.local);
@@ -239,6 +262,7 @@ async fn create_client_tunnels(
                         tunnel.remote.1,
                         cfg.socket_so_mark,
                         cfg.timeout_connect,
+                        cfg.happy_eyeballs_delay,
                         &cfg.dns_resolver,
                     );
 
@@ -258,8 +282,12 @@ async fn create_client_tunnels(
                         host,
                         port,
                     };
-                    let socks_connector =
-                        Socks5TunnelConnector::new(cfg.socket_so_mark, cfg.timeout_connect, &cfg.dns_resolver);
+                    let socks_connector = Socks5TunnelConnector::new(
+                        cfg.socket_so_mark,
+                        cfg.timeout_connect,
+                        cfg.happy_eyeballs_delay,
+                        &cfg.dns_resolver,
+                    );
 
                     if let Err(err) = client.run_reverse_tunnel(remote, socks_connector).await {
                         error!("{:?}", err);
@@ -282,6 +310,7 @@ async fn create_client_tunnels(
                         tunnel.remote.1,
                         cfg.socket_so_mark,
                         cfg.timeout_connect,
+                        cfg.happy_eyeballs_delay,
                         &cfg.dns_resolver,
                     );
 
@@ -300,6 +329,7 @@ async fn create_client_tunnels(
                         tunnel.remote.1,
                         cfg.socket_so_mark,
                         cfg.timeout_connect,
+                        cfg.happy_eyeballs_delay,
                         &cfg.dns_resolver,
                     );
 
@@ -320,6 +350,10 @@ async fn create_client_tunnels(
             | LocalProtocol::Tcp { .. }
             | LocalProtocol::Udp { .. }
             | LocalProtocol::Socks5 { .. }
+            | LocalProtocol::Mixed { .. }
+            | LocalProtocol::Dns { .. }
+            | LocalProtocol::FakeDns { .. }
+            | LocalProtocol::Tun { .. }
             | LocalProtocol::HttpProxy { .. } => {}
             LocalProtocol::Unix { .. } => {
                 panic!("Invalid protocol for reverse tunnel");
@@ -327,12 +361,32 @@ async fn create_client_tunnels(
         }
     }
 
+    // All the transparent proxy listeners share the pool of the fake dns server, if any.
+    // They cannot tell which server gave out a fake ip, so only one of them is allowed
+    let mut fake_dns_pools = local_to_remote
+        .iter()
+        .filter_map(|tunnel| match &tunnel.local_protocol {
+            LocalProtocol::FakeDns { pool } => Some(pool),
+            _ => None,
+        });
+    let fake_ip_pool = fake_dns_pools
+        .next()
+        .map(|pool| FakeIpPool::new(*pool).map(Arc::new))
+        .transpose()?;
+    if fake_dns_pools.next().is_some() {
+        return Err(anyhow!("Only one fakedns listener can be configured"));
+    }
+
     for tunnel in local_to_remote.into_iter() {
         let client = client.clone();
 
         match &tunnel.local_protocol {
-            LocalProtocol::Tcp { proxy_protocol } => {
-                let server = TcpTunnelListener::new(tunnel.local, tunnel.remote.clone(), *proxy_protocol).await?;
+            LocalProtocol::Tcp {
+                proxy_protocol,
+                idle_timeout,
+            } => {
+                let server =
+                    TcpTunnelListener::new(tunnel.local, tunnel.remote.clone(), *proxy_protocol, *idle_timeout).await?;
                 spawn_tunnel! {
                     if let Err(err) = client.run_tunnel(server).await {
                         error!("{:?}", err);
@@ -342,7 +396,7 @@ async fn create_client_tunnels(
             #[cfg(target_os = "linux")]
             LocalProtocol::TProxyTcp => {
                 use crate::tunnel::listeners::TproxyTcpTunnelListener;
-                let server = TproxyTcpTunnelListener::new(tunnel.local, false).await?;
+                let server = TproxyTcpTunnelListener::new(tunnel.local, false, fake_ip_pool.clone()).await?;
 
                 spawn_tunnel! {
                     if let Err(err) = client.run_tunnel(server).await {
@@ -368,7 +422,7 @@ async fn create_client_tunnels(
             #[cfg(target_os = "linux")]
             LocalProtocol::TProxyUdp { timeout } => {
                 use crate::tunnel::listeners::new_tproxy_udp;
-                let server = new_tproxy_udp(tunnel.local, *timeout).await?;
+                let server = new_tproxy_udp(tunnel.local, *timeout, fake_ip_pool.clone()).await?;
                 spawn_tunnel! {
                     if let Err(err) = client.run_tunnel(server).await {
                         error!("{:?}", err);
@@ -395,6 +449,46 @@ async fn create_client_tunnels(
                     }
                 }
             }
+            LocalProtocol::Mixed { timeout, credentials } => {
+                let server = Socks5TunnelListener::new_mixed(tunnel.local, *timeout, credentials.clone()).await?;
+                spawn_tunnel! {
+                    if let Err(err) = client.run_tunnel(server).await {
+                        error!("{:?}", err);
+                    }
+                }
+            }
+            LocalProtocol::FakeDns { .. } => {
+                let pool = fake_ip_pool
+                    .clone()
+                    .expect("fake ip pool is created with the fake dns listener");
+                let server = FakeDnsServer::bind(tunnel.local, pool).await?;
+                spawn_tunnel! {
+                    server.serve().await
+                }
+            }
+            #[cfg(unix)]
+            LocalProtocol::Tun { fd, mtu, timeout } => {
+                use crate::tunnel::listeners::TunTunnelListener;
+                let server = TunTunnelListener::new(*fd, *mtu, *timeout)?;
+                spawn_tunnel! {
+                    if let Err(err) = client.run_tunnel(server).await {
+                        error!("{:?}", err);
+                    }
+                }
+            }
+            #[cfg(not(unix))]
+            LocalProtocol::Tun { .. } => {
+                panic!("Tun device is not available for non Unix platform")
+            }
+            LocalProtocol::Dns { timeout, over_tcp } => {
+                let server =
+                    DnsTunnelListener::new(tunnel.local, tunnel.remote.clone(), *timeout, *over_tcp).await?;
+                spawn_tunnel! {
+                    if let Err(err) = client.run_tunnel(server).await {
+                        error!("{:?}", err);
+                    }
+                }
+            }
             LocalProtocol::HttpProxy {
                 timeout,
                 credentials,
@@ -432,21 +526,49 @@ async fn create_client_tunnels(
         }
     }
 
-    Ok(tunnels)
+    Ok((client, tunnels))
 }
 
-pub async fn run_server(args: Server, executor: impl TokioExecutor) -> anyhow::Result<()> {
+/// Run the server until shutdown_signal resolves, the tunnels in flight are then drained, up to the drain timeout
+pub async fn run_server(
+    args: Server,
+    executor: impl TokioExecutor,
+    shutdown_signal: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<()> {
     let (tx, rx) = oneshot::channel();
     let exec = executor.ref_clone();
     executor.spawn(async move {
-        let ret = run_server_impl(args, exec).await;
+        let ret = run_server_impl(args, exec, shutdown_signal).await;
         let _ = tx.send(ret);
     });
 
     rx.await?
 }
 
-async fn run_server_impl(args: Server, executor: impl TokioExecutorRef) -> anyhow::Result<()> {
+/// Print the hash of the secret read from stdin, for the `users` section of the restrictions config file
+pub fn run_hash(args: Hash) -> anyhow::Result<()> {
+    let mut secret = String::new();
+    std::io::stdin()
+        .read_line(&mut secret)
+        .context("Cannot read the secret from stdin")?;
+    let secret = secret.trim_end_matches(['\r', '\n']);
+    if secret.is_empty() {
+        return Err(anyhow!("The secret to hash must not be empty"));
+    }
+
+    println!("{}", restrictions::credentials::hash_secret(secret, args.algorithm)?);
+    Ok(())
+}
+
+async fn run_server_impl(
+    args: Server,
+    executor: impl TokioExecutorRef,
+    shutdown_signal: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<()> {
+    if args.admin_bind.is_some() && args.admin_token.as_ref().is_none_or(|token| token.is_empty()) {
+        return Err(anyhow!("--admin-bind requires a non empty --admin-token"));
+    }
+
     let tls_config = if args.remote_addr.scheme() == "wss" {
         let tls_certificate = if let Some(cert_path) = &args.tls_certificate {
             tls::load_certificates_from_pem(cert_path).expect("Cannot load tls certificate")
@@ -510,19 +632,37 @@ async fn run_server_impl(args: Server, executor: impl TokioExecutorRef) -> anyho
             .websocket_ping_frequency
             .or(Some(Duration::from_secs(30)))
             .filter(|d| d.as_secs() > 0),
+        websocket_pong_timeout: args.websocket_pong_timeout.filter(|d| !d.is_zero()),
         timeout_connect: Duration::from_secs(10),
+        happy_eyeballs_delay: args.happy_eyeballs_delay,
+        tcp_idle_timeout: args.tcp_idle_timeout.filter(|d| !d.is_zero()),
+        tcp_half_close_timeout: args.tcp_half_close_timeout.filter(|d| !d.is_zero()),
+        drain_timeout: args.drain_timeout,
         websocket_mask_frame: args.websocket_mask_frame,
         tls: tls_config,
         dns_resolver: DnsResolver::new_from_urls(
             &args.dns_resolver,
             None,
             SoMark::new(args.socket_so_mark),
+            SocketBind::NONE,
+            args.happy_eyeballs_delay,
             !args.dns_resolver_prefer_ipv4,
         )
         .expect("Cannot create DNS resolver"),
         restriction_config: args.restrict_config,
         http_proxy,
         remote_server_idle_timeout: args.remote_to_local_server_idle_timeout,
+        metrics_bind: args.metrics_bind,
+        admin_bind: args.admin_bind,
+        admin_token: args.admin_token,
+        fallback: match (args.fallback_dir, args.fallback_upstream) {
+            (Some(dir), _) => Some(FallbackConfig::Directory(dir)),
+            (None, Some(upstream)) => Some(FallbackConfig::ReverseProxy(upstream)),
+            (None, None) => None,
+        },
+        quota_state_file: args.quota_state_file,
+        trusted_proxies: args.trusted_proxies,
+        accept_proxy_protocol: args.accept_proxy_protocol,
     };
     let server = WsServer::new(server_config, executor);
 
@@ -532,6 +672,13 @@ async fn run_server_impl(args: Server, executor: impl TokioExecutorRef) -> anyho
         server.config
     );
     debug!("Restriction rules: {restrictions:#?}");
+    server.executor.spawn({
+        let server = server.clone();
+        async move {
+            shutdown_signal.await;
+            server.shutdown();
+        }
+    });
     server.serve(restrictions).await
 }
 
@@ -0,0 +1,241 @@
+//! Fake-IP DNS server, to keep the domain of the destination when using transparent proxy listeners.
+//!
+//! Every A query is answered with an address from a reserved pool, and the mapping ip -> domain is remembered.
+//! When a transparent proxy receives a connection for one of those addresses, the original domain is used as the
+//! destination of the tunnel, so the resolution happens on the server side.
+
+use ahash::AHashMap;
+use anyhow::{Context, anyhow};
+use hickory_resolver::proto::op::{Message, MessageType, ResponseCode};
+use hickory_resolver::proto::rr::rdata::A;
+use hickory_resolver::proto::rr::{RData, Record, RecordType};
+use ipnet::Ipv4Net;
+use log::{debug, warn};
+use parking_lot::Mutex;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tracing::info;
+use url::Host;
+
+// Keep it low, so clients don't keep using an address after the mapping has been recycled
+const FAKE_IP_TTL: u32 = 1;
+
+struct FakeIpPoolInner {
+    next_offset: u32,
+    domain_by_ip: AHashMap<Ipv4Addr, String>,
+    ip_by_domain: AHashMap<String, Ipv4Addr>,
+}
+
+pub struct FakeIpPool {
+    network: Ipv4Net,
+    inner: Mutex<FakeIpPoolInner>,
+}
+
+impl FakeIpPool {
+    pub fn new(network: Ipv4Net) -> anyhow::Result<Self> {
+        if network.prefix_len() > 30 {
+            return Err(anyhow!("fake ip pool {network} is too small"));
+        }
+
+        Ok(Self {
+            network: network.trunc(),
+            inner: Mutex::new(FakeIpPoolInner {
+                next_offset: 0,
+                domain_by_ip: AHashMap::new(),
+                ip_by_domain: AHashMap::new(),
+            }),
+        })
+    }
+
+    /// Return the fake ip of the domain, allocating a new one if needed.
+    /// When the pool is exhausted, the oldest allocated addresses are recycled.
+    pub fn allocate(&self, domain: &str) -> Ipv4Addr {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let mut inner = self.inner.lock();
+        if let Some(ip) = inner.ip_by_domain.get(&domain) {
+            return *ip;
+        }
+
+        // Skip the network and broadcast addresses
+        let nb_hosts = ((1u64 << (32 - self.network.prefix_len())) - 2) as u32;
+        let offset = inner.next_offset;
+        inner.next_offset = (offset + 1) % nb_hosts;
+        let ip = Ipv4Addr::from(u32::from(self.network.network()) + 1 + offset);
+
+        if let Some(old_domain) = inner.domain_by_ip.insert(ip, domain.clone()) {
+            debug!("Recycling fake ip {ip} of {old_domain}");
+            inner.ip_by_domain.remove(&old_domain);
+        }
+        inner.ip_by_domain.insert(domain, ip);
+
+        ip
+    }
+
+    pub fn domain_of(&self, ip: IpAddr) -> Option<String> {
+        let ip = match ip {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(ip) => ip.to_ipv4_mapped()?,
+        };
+        if !self.network.contains(&ip) {
+            return None;
+        }
+
+        let domain = self.inner.lock().domain_by_ip.get(&ip).cloned();
+        if domain.is_none() {
+            warn!("No domain is known for fake ip {ip}, the mapping may have been recycled");
+        }
+        domain
+    }
+
+    /// Same as [crate::tunnel::to_host_port], but with the original domain if the address is a fake ip
+    pub fn to_host_port(&self, addr: SocketAddr) -> (Host, u16) {
+        match self.domain_of(addr.ip()) {
+            Some(domain) => (Host::Domain(domain), addr.port()),
+            None => crate::tunnel::to_host_port(addr),
+        }
+    }
+
+    fn handle_query(&self, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let request = Message::from_vec(query).context("invalid dns query")?;
+        if request.message_type() != MessageType::Query {
+            return Err(anyhow!("dns message is not a query"));
+        }
+
+        let mut response = Message::new();
+        response
+            .set_id(request.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(request.op_code())
+            .set_recursion_desired(request.recursion_desired())
+            .set_recursion_available(true)
+            .set_response_code(ResponseCode::NoError)
+            .add_queries(request.queries().to_vec());
+
+        // Only A queries get an answer, other types get an empty response so clients fallback on ipv4
+        for query in request.queries() {
+            if query.query_type() != RecordType::A {
+                continue;
+            }
+
+            let ip = self.allocate(&query.name().to_ascii());
+            response.add_answer(Record::from_rdata(query.name().clone(), FAKE_IP_TTL, RData::A(A(ip))));
+        }
+
+        Ok(response.to_vec()?)
+    }
+}
+
+pub struct FakeDnsServer {
+    socket: UdpSocket,
+    pool: Arc<FakeIpPool>,
+}
+
+impl FakeDnsServer {
+    pub async fn bind(bind: SocketAddr, pool: Arc<FakeIpPool>) -> anyhow::Result<Self> {
+        info!(
+            "Starting fake ip DNS server listening on {bind} with pool {}",
+            pool.network
+        );
+        let socket = UdpSocket::bind(bind)
+            .await
+            .with_context(|| format!("Cannot create fake ip DNS server {bind:?}"))?;
+
+        Ok(Self { socket, pool })
+    }
+
+    pub async fn serve(self) {
+        let mut buf = vec![0; u16::MAX as usize];
+        loop {
+            let (len, peer) = match self.socket.recv_from(&mut buf).await {
+                Ok(ret) => ret,
+                Err(err) => {
+                    warn!("Error while receiving fake dns query: {err}");
+                    continue;
+                }
+            };
+
+            let response = match self.pool.handle_query(&buf[..len]) {
+                Ok(response) => response,
+                Err(err) => {
+                    warn!("Cannot answer fake dns query from {peer}: {err:?}");
+                    continue;
+                }
+            };
+
+            if let Err(err) = self.socket.send_to(&response, peer).await {
+                warn!("Cannot send fake dns response to {peer}: {err}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_resolver::proto::op::Query;
+    use hickory_resolver::proto::rr::Name;
+    use std::net::SocketAddrV4;
+
+    #[test]
+    fn test_allocate() {
+        let pool = FakeIpPool::new("198.18.0.0/15".parse().unwrap()).unwrap();
+        let ip = pool.allocate("google.com.");
+        assert_eq!(ip, Ipv4Addr::new(198, 18, 0, 1));
+        assert_eq!(pool.allocate("GOOGLE.com"), ip);
+        assert_eq!(pool.allocate("example.com"), Ipv4Addr::new(198, 18, 0, 2));
+
+        assert_eq!(pool.domain_of(IpAddr::V4(ip)), Some("google.com".to_string()));
+        assert_eq!(
+            pool.domain_of(IpAddr::V6(ip.to_ipv6_mapped())),
+            Some("google.com".to_string())
+        );
+        assert_eq!(pool.domain_of(IpAddr::V4(Ipv4Addr::new(198, 18, 0, 3))), None);
+        assert_eq!(pool.domain_of(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1))), None);
+
+        assert_eq!(
+            pool.to_host_port(SocketAddr::V4(SocketAddrV4::new(ip, 443))),
+            (Host::Domain("google.com".to_string()), 443)
+        );
+        assert_eq!(
+            pool.to_host_port(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(1, 1, 1, 1), 443))),
+            (Host::Ipv4(Ipv4Addr::new(1, 1, 1, 1)), 443)
+        );
+    }
+
+    #[test]
+    fn test_allocate_recycle() {
+        // Only 2 usable addresses
+        let pool = FakeIpPool::new("10.0.0.0/30".parse().unwrap()).unwrap();
+        let ip1 = pool.allocate("a.com");
+        let ip2 = pool.allocate("b.com");
+        assert_ne!(ip1, ip2);
+
+        assert_eq!(pool.allocate("c.com"), ip1);
+        assert_eq!(pool.domain_of(IpAddr::V4(ip1)), Some("c.com".to_string()));
+        assert_eq!(pool.allocate("a.com"), ip2);
+        assert_eq!(pool.domain_of(IpAddr::V4(ip2)), Some("a.com".to_string()));
+
+        assert!(FakeIpPool::new("10.0.0.0/31".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_handle_query() {
+        let pool = FakeIpPool::new("198.18.0.0/15".parse().unwrap()).unwrap();
+        let name = Name::from_ascii("google.com.").unwrap();
+
+        let mut request = Message::new();
+        request.set_id(42).add_query(Query::query(name.clone(), RecordType::A));
+        let response = Message::from_vec(&pool.handle_query(&request.to_vec().unwrap()).unwrap()).unwrap();
+        assert_eq!(response.id(), 42);
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.answers().len(), 1);
+        assert_eq!(response.answers()[0].data(), &RData::A(A(Ipv4Addr::new(198, 18, 0, 1))));
+
+        let mut request = Message::new();
+        request.set_id(43).add_query(Query::query(name, RecordType::AAAA));
+        let response = Message::from_vec(&pool.handle_query(&request.to_vec().unwrap()).unwrap()).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.answers().is_empty());
+    }
+}
@@ -1,3 +1,8 @@
+mod fake_ip;
 mod resolver;
+mod server;
 
+pub use fake_ip::{FakeDnsServer, FakeIpPool};
 pub use resolver::DnsResolver;
+pub use server::DnsListener;
+pub use server::run_server;
@@ -1,4 +1,5 @@
 use crate::protocols;
+use crate::socket_bind::SocketBind;
 use crate::somark::SoMark;
 use anyhow::{Context, anyhow};
 use futures_util::{FutureExt, TryFutureExt};
@@ -42,7 +43,9 @@ fn sort_socket_addrs(socket_addrs: &[SocketAddr], prefer_ipv6: bool) -> impl Ite
 #[allow(clippy::large_enum_variant)] // System variant never used mostly
 #[derive(Clone, Debug)]
 pub enum DnsResolver {
-    System,
+    System {
+        prefer_ipv4: bool,
+    },
     TrustDns {
         resolver: Box<Resolver<GenericConnector<TokioRuntimeProviderWithSoMark>>>,
         prefer_ipv6: bool,
@@ -52,7 +55,12 @@ pub enum DnsResolver {
 impl DnsResolver {
     pub async fn lookup_host(&self, domain: &str, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
         let addrs = match self {
-            Self::System => tokio::net::lookup_host(format!("{domain}:{port}")).await?.collect(),
+            Self::System { prefer_ipv4 } => {
+                // Unless ipv4 is preferred, keep the family preference of the system, given by its first address
+                let addrs: Vec<_> = tokio::net::lookup_host(format!("{domain}:{port}")).await?.collect();
+                let prefer_ipv6 = !prefer_ipv4 && addrs.first().is_some_and(|addr| addr.is_ipv6());
+                sort_socket_addrs(&addrs, prefer_ipv6).copied().collect()
+            }
             Self::TrustDns { resolver, prefer_ipv6 } => {
                 let addrs: Vec<_> = resolver
                     .lookup_ip(domain)
@@ -70,6 +78,15 @@ impl DnsResolver {
         Ok(addrs)
     }
 
+    /// Flush the cached dns records, i.e: when the network of the device changes
+    pub fn clear_cache(&self) {
+        match self {
+            // libc resolver does not expose its cache
+            Self::System { .. } => {}
+            Self::TrustDns { resolver, .. } => resolver.clear_cache(),
+        }
+    }
+
     #[cfg(feature = "aws-lc-rs")]
     pub async fn lookup_ech_config(&self, domain: &Host) -> Result<Option<EchConfig>, ResolveError> {
         use hickory_resolver::proto::rr::rdata::svcb::{SvcParamKey, SvcParamValue};
@@ -118,13 +135,14 @@ impl DnsResolver {
         resolvers: &[Url],
         proxy: Option<Url>,
         so_mark: SoMark,
+        socket_bind: SocketBind,
+        happy_eyeballs_delay: Duration,
         prefer_ipv6: bool,
     ) -> anyhow::Result<Self> {
         fn mk_resolver(
             cfg: ResolverConfig,
             mut opts: ResolverOpts,
-            proxy: Option<Url>,
-            so_mark: SoMark,
+            provider: TokioRuntimeProviderWithSoMark,
         ) -> Resolver<GenericConnector<TokioRuntimeProviderWithSoMark>> {
             opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
             opts.timeout = Duration::from_secs(1);
@@ -137,10 +155,7 @@ impl DnsResolver {
                 opts.num_concurrent_reqs = cfg.name_servers().len();
             }
 
-            let mut builder = Resolver::builder_with_config(
-                cfg,
-                GenericConnector::new(TokioRuntimeProviderWithSoMark::new(proxy, so_mark)),
-            );
+            let mut builder = Resolver::builder_with_config(cfg, GenericConnector::new(provider));
             *builder.options_mut() = opts;
             builder.build()
         }
@@ -182,24 +197,30 @@ impl DnsResolver {
             Ok(ns)
         }
 
+        let provider = TokioRuntimeProviderWithSoMark::new(proxy, so_mark, socket_bind, happy_eyeballs_delay);
+
         // no dns resolver specified, fall-back to default one
         if resolvers.is_empty() {
             let Ok((cfg, opts)) = hickory_resolver::system_conf::read_system_conf() else {
                 warn!(
                     "Fall-backing to system dns resolver. You should consider specifying a dns resolver. To avoid performance issue"
                 );
-                return Ok(Self::System);
+                return Ok(Self::System {
+                    prefer_ipv4: !prefer_ipv6,
+                });
             };
 
             return Ok(Self::TrustDns {
-                resolver: Box::new(mk_resolver(cfg, opts, proxy, so_mark)),
+                resolver: Box::new(mk_resolver(cfg, opts, provider)),
                 prefer_ipv6,
             });
         };
 
         // if one is specified as system, use the default one from libc
         if resolvers.iter().any(|r| r.scheme() == "system") {
-            return Ok(Self::System);
+            return Ok(Self::System {
+                prefer_ipv4: !prefer_ipv6,
+            });
         }
 
         // otherwise, use the specified resolvers
@@ -209,7 +230,7 @@ impl DnsResolver {
         }
 
         Ok(Self::TrustDns {
-            resolver: Box::new(mk_resolver(cfg, ResolverOpts::default(), proxy, so_mark)),
+            resolver: Box::new(mk_resolver(cfg, ResolverOpts::default(), provider)),
             prefer_ipv6,
         })
     }
@@ -220,14 +241,18 @@ pub struct TokioRuntimeProviderWithSoMark {
     runtime: TokioRuntimeProvider,
     proxy: Option<Arc<Url>>,
     so_mark: SoMark,
+    socket_bind: SocketBind,
+    happy_eyeballs_delay: Duration,
 }
 
 impl TokioRuntimeProviderWithSoMark {
-    fn new(proxy: Option<Url>, so_mark: SoMark) -> Self {
+    fn new(proxy: Option<Url>, so_mark: SoMark, socket_bind: SocketBind, happy_eyeballs_delay: Duration) -> Self {
         Self {
             runtime: TokioRuntimeProvider::default(),
             proxy: proxy.map(Arc::new),
             so_mark,
+            socket_bind,
+            happy_eyeballs_delay,
         }
     }
 }
@@ -251,6 +276,8 @@ impl RuntimeProvider for TokioRuntimeProviderWithSoMark {
         timeout: Option<Duration>,
     ) -> Pin<Box<dyn Send + Future<Output = std::io::Result<Self::Tcp>>>> {
         let so_mark = self.so_mark;
+        let socket_bind = self.socket_bind.clone();
+        let happy_eyeballs_delay = self.happy_eyeballs_delay;
         let proxy = self.proxy.clone();
         let socket = async move {
             let host = match server_addr.ip() {
@@ -264,8 +291,11 @@ impl RuntimeProvider for TokioRuntimeProviderWithSoMark {
                     &host,
                     server_addr.port(),
                     so_mark,
+                    &socket_bind,
                     timeout.unwrap_or(Duration::from_secs(10)),
-                    &DnsResolver::System, // not going to be used as host is directly an ip address
+                    happy_eyeballs_delay,
+                    // not going to be used as host is directly an ip address
+                    &DnsResolver::System { prefer_ipv4: false },
                 )
                 .map_err(std::io::Error::other)
                 .map(|s| s.map(AsyncIoTokioAsStd))
@@ -275,8 +305,11 @@ impl RuntimeProvider for TokioRuntimeProviderWithSoMark {
                     &host,
                     server_addr.port(),
                     so_mark,
+                    &socket_bind,
                     timeout.unwrap_or(Duration::from_secs(10)),
-                    &DnsResolver::System, // not going to be used as host is directly an ip address
+                    happy_eyeballs_delay,
+                    // not going to be used as host is directly an ip address
+                    &DnsResolver::System { prefer_ipv4: false },
                 )
                 .map_err(std::io::Error::other)
                 .map(|s| s.map(AsyncIoTokioAsStd))
@@ -290,9 +323,14 @@ impl RuntimeProvider for TokioRuntimeProviderWithSoMark {
     fn bind_udp(
         &self,
         local_addr: SocketAddr,
-        _server_addr: SocketAddr,
+        server_addr: SocketAddr,
     ) -> Pin<Box<dyn Send + Future<Output = std::io::Result<Self::Udp>>>> {
-        let socket = UdpSocket::bind(local_addr);
+        let socket_bind = self.socket_bind.clone();
+        let socket = async move {
+            socket_bind
+                .bind_udp(local_addr, &server_addr)
+                .and_then(UdpSocket::from_std)
+        };
 
         #[cfg(target_os = "linux")]
         let socket = {
@@ -307,6 +345,14 @@ impl RuntimeProvider for TokioRuntimeProviderWithSoMark {
             })
         };
 
+        #[cfg(unix)]
+        let socket = socket.map(|sock| {
+            if let Ok(ref sock) = sock {
+                crate::socket_protect::protect_socket(socket2::SockRef::from(sock))?;
+            }
+            sock
+        });
+
         Box::pin(socket)
     }
 }
@@ -335,4 +381,29 @@ mod tests {
         let actual: Vec<_> = sort_socket_addrs(&addrs, true).copied().collect();
         assert_eq!(expected, *actual);
     }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_bind_udp_with_socket_bind() {
+        let socket_bind = SocketBind::new(Some("127.0.0.2".parse().unwrap()), Some("lo".to_string()));
+        let provider = TokioRuntimeProviderWithSoMark::new(
+            None,
+            SoMark::new(None),
+            socket_bind,
+            protocols::tcp::HAPPY_EYEBALLS_DELAY,
+        );
+
+        let socket = provider
+            .bind_udp("0.0.0.0:0".parse().unwrap(), "127.0.0.1:53".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            socket.local_addr().unwrap().ip(),
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2))
+        );
+        assert_eq!(
+            socket2::SockRef::from(&socket).device().unwrap().as_deref(),
+            Some(b"lo".as_slice())
+        );
+    }
 }
@@ -0,0 +1,410 @@
+use ahash::AHashMap;
+use anyhow::{Context, anyhow};
+use futures_util::Stream;
+use hickory_resolver::proto::op::{Message, MessageType, ResponseCode};
+use hickory_resolver::proto::rr::{DNSClass, Name, Record, RecordType};
+use log::{debug, warn};
+use parking_lot::Mutex;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::task::{AbortHandle, JoinSet};
+use tracing::info;
+
+const MAX_DNS_MESSAGE_SIZE: usize = u16::MAX as usize;
+const MAX_CACHE_ENTRIES: usize = 10_000;
+const MAX_CACHE_TTL: u32 = 24 * 60 * 60;
+
+/// Stream of tunnels to open towards the upstream resolver.
+/// Each tunnel carries a single DNS query, framed according to the transport used (UDP datagram or TCP length prefix).
+pub struct DnsListener {
+    tunnels_rx: mpsc::Receiver<DuplexStream>,
+    server_task: AbortHandle,
+}
+
+impl Stream for DnsListener {
+    type Item = anyhow::Result<DuplexStream>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().tunnels_rx.poll_recv(cx).map(|x| x.map(Ok))
+    }
+}
+
+impl Drop for DnsListener {
+    fn drop(&mut self) {
+        self.server_task.abort();
+    }
+}
+
+type CacheKey = (Name, RecordType, DNSClass);
+
+struct CacheEntry {
+    response: Message,
+    inserted_at: Instant,
+    ttl: u32,
+}
+
+/// In-memory DNS cache, entries expire according to the smallest TTL of the records of the response
+#[derive(Default)]
+struct DnsCache {
+    entries: Mutex<AHashMap<CacheKey, CacheEntry>>,
+}
+
+impl DnsCache {
+    fn cache_key(request: &Message) -> Option<CacheKey> {
+        // Multiple questions in a single message are not used in practice, don't bother caching them
+        let [query] = request.queries() else {
+            return None;
+        };
+
+        Some((query.name().to_lowercase(), query.query_type(), query.query_class()))
+    }
+
+    fn get(&self, request: &Message) -> Option<Message> {
+        let key = Self::cache_key(request)?;
+        let mut entries = self.entries.lock();
+        let entry = entries.get(&key)?;
+
+        let elapsed = entry.inserted_at.elapsed().as_secs();
+        if elapsed >= entry.ttl as u64 {
+            entries.remove(&key);
+            return None;
+        }
+
+        let remaining_ttl = entry.ttl - elapsed as u32;
+        let mut response = entry.response.clone();
+        drop(entries);
+
+        response.set_id(request.id());
+        // Keep the case of the question as sent by the client (i.e: dns 0x20 encoding)
+        response.take_queries();
+        response.add_queries(request.queries().to_vec());
+        let set_ttl = |records: &mut Vec<Record>| {
+            for record in records.iter_mut() {
+                record.set_ttl(record.ttl().min(remaining_ttl));
+            }
+        };
+        set_ttl(response.answers_mut());
+        set_ttl(response.name_servers_mut());
+        set_ttl(response.additionals_mut());
+
+        Some(response)
+    }
+
+    fn insert(&self, request: &Message, response: Message) {
+        let Some(key) = Self::cache_key(request) else {
+            return;
+        };
+
+        if response.truncated() || !matches!(response.response_code(), ResponseCode::NoError | ResponseCode::NXDomain) {
+            return;
+        }
+
+        // Without any record, we don't know for how long the response is valid
+        let Some(ttl) = response.all_sections().map(|r| r.ttl()).min() else {
+            return;
+        };
+        if ttl == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock();
+        if entries.len() >= MAX_CACHE_ENTRIES {
+            entries.retain(|_, e| e.inserted_at.elapsed().as_secs() < e.ttl as u64);
+            if entries.len() >= MAX_CACHE_ENTRIES {
+                return;
+            }
+        }
+
+        entries.insert(
+            key,
+            CacheEntry {
+                response,
+                inserted_at: Instant::now(),
+                ttl: ttl.min(MAX_CACHE_TTL),
+            },
+        );
+    }
+}
+
+struct DnsForwarder {
+    cache: DnsCache,
+    tunnels_tx: mpsc::Sender<DuplexStream>,
+    timeout: Duration,
+    over_tcp: bool,
+}
+
+impl DnsForwarder {
+    async fn resolve(&self, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let request = Message::from_vec(query).context("invalid dns query")?;
+        if request.message_type() != MessageType::Query {
+            return Err(anyhow!("dns message is not a query"));
+        }
+
+        if let Some(response) = self.cache.get(&request) {
+            debug!("Dns cache hit for {:?}", request.queries());
+            return Ok(response.to_vec()?);
+        }
+
+        let response = tokio::time::timeout(self.timeout, self.forward(query))
+            .await
+            .map_err(|_| anyhow!("timeout while waiting for dns upstream response"))??;
+
+        match Message::from_vec(&response) {
+            Ok(msg) => self.cache.insert(&request, msg),
+            Err(err) => warn!("Cannot parse dns upstream response: {err}"),
+        }
+
+        Ok(response)
+    }
+
+    async fn forward(&self, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let (mut local, remote) = tokio::io::duplex(MAX_DNS_MESSAGE_SIZE);
+        self.tunnels_tx
+            .send(remote)
+            .await
+            .map_err(|_| anyhow!("dns listener is closed"))?;
+
+        if self.over_tcp {
+            write_tcp_message(&mut local, query).await?;
+            return read_tcp_message(&mut local).await;
+        }
+
+        // The tunnel forwards each write as a single datagram
+        local.write_all(query).await?;
+        let mut response = vec![0; MAX_DNS_MESSAGE_SIZE];
+        let len = local.read(&mut response).await?;
+        if len == 0 {
+            return Err(anyhow!("dns tunnel closed before receiving a response"));
+        }
+        response.truncate(len);
+
+        Ok(response)
+    }
+}
+
+async fn read_tcp_message(stream: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Vec<u8>> {
+    let len = stream.read_u16().await?;
+    let mut msg = vec![0; len as usize];
+    stream.read_exact(&mut msg).await?;
+    Ok(msg)
+}
+
+async fn write_tcp_message(stream: &mut (impl AsyncWrite + Unpin), msg: &[u8]) -> anyhow::Result<()> {
+    let len = u16::try_from(msg.len()).map_err(|_| anyhow!("dns message is too big"))?;
+    stream.write_u16(len).await?;
+    stream.write_all(msg).await?;
+    Ok(())
+}
+
+async fn serve_udp(forwarder: Arc<DnsForwarder>, socket: UdpSocket) {
+    let socket = Arc::new(socket);
+    let mut tasks = JoinSet::new();
+    let mut buf = vec![0; MAX_DNS_MESSAGE_SIZE];
+
+    loop {
+        while tasks.try_join_next().is_some() {}
+
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(ret) => ret,
+            Err(err) => {
+                // On Windows, a previous send_to to an unreachable peer returns an error here. Not fatal
+                warn!("Error while receiving dns query: {err}");
+                continue;
+            }
+        };
+
+        let query = buf[..len].to_vec();
+        let socket = socket.clone();
+        let forwarder = forwarder.clone();
+        tasks.spawn(async move {
+            match forwarder.resolve(&query).await {
+                Ok(response) => {
+                    if let Err(err) = socket.send_to(&response, peer).await {
+                        warn!("Cannot send dns response to {peer}: {err}");
+                    }
+                }
+                Err(err) => warn!("Cannot resolve dns query from {peer}: {err:?}"),
+            }
+        });
+    }
+}
+
+async fn serve_tcp(forwarder: Arc<DnsForwarder>, listener: TcpListener) {
+    let mut tasks = JoinSet::new();
+
+    loop {
+        while tasks.try_join_next().is_some() {}
+
+        let (stream, peer) = match listener.accept().await {
+            Ok(ret) => ret,
+            Err(err) => {
+                warn!("Error while accepting dns connection: {err}");
+                continue;
+            }
+        };
+
+        tasks.spawn(handle_tcp_connection(forwarder.clone(), stream, peer));
+    }
+}
+
+async fn handle_tcp_connection(forwarder: Arc<DnsForwarder>, mut stream: TcpStream, peer: SocketAddr) {
+    // A client can send multiple queries on the same connection, we answer them in order
+    while let Ok(query) = read_tcp_message(&mut stream).await {
+        let response = match forwarder.resolve(&query).await {
+            Ok(response) => response,
+            Err(err) => {
+                warn!("Cannot resolve dns query from {peer}: {err:?}");
+                return;
+            }
+        };
+
+        if let Err(err) = write_tcp_message(&mut stream, &response).await {
+            warn!("Cannot send dns response to {peer}: {err}");
+            return;
+        }
+    }
+}
+
+/// Start a local DNS server, listening on both UDP and TCP, that forwards queries to the upstream resolver
+/// through the tunnels returned by the listener. When `over_tcp` is set, queries are sent as DNS-over-TCP.
+pub async fn run_server(bind: SocketAddr, timeout: Duration, over_tcp: bool) -> anyhow::Result<DnsListener> {
+    info!(
+        "Starting DNS server listening cnx on {bind} (forwarding over {})",
+        if over_tcp { "tcp" } else { "udp" }
+    );
+
+    let udp_socket = UdpSocket::bind(bind)
+        .await
+        .with_context(|| format!("Cannot create UDP dns server {bind:?}"))?;
+    // Use the same port for TCP, in case the OS picked one for us
+    let tcp_listener = TcpListener::bind(udp_socket.local_addr()?)
+        .await
+        .with_context(|| format!("Cannot create TCP dns server {bind:?}"))?;
+
+    let (tunnels_tx, tunnels_rx) = mpsc::channel(128);
+    let forwarder = Arc::new(DnsForwarder {
+        cache: DnsCache::default(),
+        tunnels_tx,
+        timeout,
+        over_tcp,
+    });
+
+    let server_task = tokio::spawn(async move {
+        select! {
+            _ = serve_udp(forwarder.clone(), udp_socket) => {},
+            _ = serve_tcp(forwarder, tcp_listener) => {},
+        }
+    });
+
+    Ok(DnsListener {
+        tunnels_rx,
+        server_task: server_task.abort_handle(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_resolver::proto::op::Query;
+    use hickory_resolver::proto::rr::RData;
+    use hickory_resolver::proto::rr::rdata::A;
+    use rstest::rstest;
+    use std::net::Ipv4Addr;
+
+    fn dns_query(id: u16, name: &str) -> Message {
+        let mut msg = Message::new();
+        msg.set_id(id)
+            .add_query(Query::query(Name::from_ascii(name).unwrap(), RecordType::A));
+        msg
+    }
+
+    fn dns_response(query: &Message, ttl: u32) -> Message {
+        let mut msg = query.clone();
+        let name = query.queries()[0].name().clone();
+        msg.set_message_type(MessageType::Response)
+            .add_answer(Record::from_rdata(name, ttl, RData::A(A(Ipv4Addr::new(1, 2, 3, 4)))));
+        msg
+    }
+
+    #[test]
+    fn test_cache() {
+        let cache = DnsCache::default();
+        let query = dns_query(1, "example.com.");
+        assert!(cache.get(&query).is_none());
+
+        cache.insert(&query, dns_response(&query, 60));
+        let response = cache.get(&dns_query(42, "ExAmPlE.com.")).unwrap();
+        assert_eq!(response.id(), 42);
+        assert_eq!(response.queries()[0].name().to_ascii(), "ExAmPlE.com.");
+        assert_eq!(response.answers().len(), 1);
+        assert!(response.answers()[0].ttl() <= 60);
+
+        // A TTL of 0 means the response must not be cached
+        let query = dns_query(1, "google.com.");
+        cache.insert(&query, dns_response(&query, 0));
+        assert!(cache.get(&query).is_none());
+
+        // Nor should be errors
+        let mut response = dns_response(&query, 60);
+        response.set_response_code(ResponseCode::ServFail);
+        cache.insert(&query, response);
+        assert!(cache.get(&query).is_none());
+    }
+
+    #[rstest]
+    #[case(true)]
+    #[case(false)]
+    #[timeout(Duration::from_secs(10))]
+    #[tokio::test]
+    async fn test_forwarder(#[case] over_tcp: bool) {
+        let (tunnels_tx, mut tunnels_rx) = mpsc::channel(8);
+        let forwarder = Arc::new(DnsForwarder {
+            cache: DnsCache::default(),
+            tunnels_tx,
+            timeout: Duration::from_secs(5),
+            over_tcp,
+        });
+
+        // Fake upstream resolver, on the other side of the tunnel
+        let upstream = tokio::spawn(async move {
+            let mut tunnel = tunnels_rx.recv().await.unwrap();
+            let query = if over_tcp {
+                read_tcp_message(&mut tunnel).await.unwrap()
+            } else {
+                let mut buf = vec![0; MAX_DNS_MESSAGE_SIZE];
+                let len = tunnel.read(&mut buf).await.unwrap();
+                buf[..len].to_vec()
+            };
+
+            let response = dns_response(&Message::from_vec(&query).unwrap(), 60).to_vec().unwrap();
+            if over_tcp {
+                write_tcp_message(&mut tunnel, &response).await.unwrap();
+            } else {
+                tunnel.write_all(&response).await.unwrap();
+            }
+
+            // Only one tunnel should be opened, the second query must be served from the cache
+            assert!(tunnels_rx.recv().await.is_none());
+        });
+
+        let query = dns_query(1, "example.com.");
+        let response = Message::from_vec(&forwarder.resolve(&query.to_vec().unwrap()).await.unwrap()).unwrap();
+        assert_eq!(response.id(), 1);
+        assert_eq!(response.answers().len(), 1);
+
+        let query = dns_query(2, "example.com.");
+        let response = Message::from_vec(&forwarder.resolve(&query.to_vec().unwrap()).await.unwrap()).unwrap();
+        assert_eq!(response.id(), 2);
+        assert_eq!(response.answers().len(), 1);
+
+        drop(forwarder);
+        upstream.await.unwrap();
+    }
+}
@@ -1,4 +1,7 @@
 mod server;
 
 pub use server::HttpProxyListener;
+pub use server::HttpProxyReadHalf;
+pub use server::HttpProxyWriteHalf;
 pub use server::run_server;
+pub(crate) use server::{HttpForwardReceiver, HttpProxyConfig, handle_new_connection, new_proxy_config};
@@ -1,37 +1,87 @@
-use anyhow::Context;
+use anyhow::{Context, anyhow};
 use bytes::Bytes;
-use log::{debug, error};
+use log::{debug, error, warn};
+use std::convert::Infallible;
 use std::future::Future;
+use std::io::{Error, IoSlice};
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::task::Poll;
 
 use crate::protocols::tcp;
 use crate::somark::SoMark;
 use base64::Engine;
 use futures_util::{Stream, future, stream};
-use http_body_util::Empty;
+use http_body_util::{Either, Empty};
 use hyper::body::Incoming;
+use hyper::header::{CONNECTION, HOST, HeaderMap, HeaderName, HeaderValue, UPGRADE};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper::{Request, Response};
-use hyper_util::rt::TokioTimer;
+use hyper::{Request, Response, StatusCode, Uri};
+use hyper_util::rt::{TokioIo, TokioTimer};
 use parking_lot::Mutex;
 use socket2::SockRef;
 use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf, ReadHalf, WriteHalf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::select;
+use tokio::sync::mpsc;
 use tokio::task::JoinSet;
 use tracing::log::info;
-use url::{Host, Url};
+use url::Host;
+
+const FORWARD_BUFFER_SIZE: usize = 64 * 1024;
 
 #[allow(clippy::type_complexity)]
 pub struct HttpProxyListener {
-    listener: Pin<Box<dyn Stream<Item = anyhow::Result<(TcpStream, (Host, u16))>> + Send>>,
+    listener: Pin<Box<dyn Stream<Item = anyhow::Result<(HttpProxyStream, (Host, u16))>> + Send>>,
+}
+
+/// A stream to tunnel. Either the client socket itself for HTTP CONNECT requests,
+/// or a pipe carrying the rewritten plain HTTP requests for one destination.
+pub enum HttpProxyStream {
+    Tcp(TcpStream),
+    Forward(DuplexStream),
+}
+
+pub enum HttpProxyReadHalf {
+    Tcp(OwnedReadHalf),
+    Forward(ReadHalf<DuplexStream>),
+}
+
+pub enum HttpProxyWriteHalf {
+    Tcp(OwnedWriteHalf),
+    Forward(WriteHalf<DuplexStream>),
 }
 
+impl HttpProxyStream {
+    pub fn into_split(self) -> (HttpProxyReadHalf, HttpProxyWriteHalf) {
+        match self {
+            Self::Tcp(s) => {
+                let (r, w) = s.into_split();
+                (HttpProxyReadHalf::Tcp(r), HttpProxyWriteHalf::Tcp(w))
+            }
+            Self::Forward(s) => {
+                let (r, w) = tokio::io::split(s);
+                (HttpProxyReadHalf::Forward(r), HttpProxyWriteHalf::Forward(w))
+            }
+        }
+    }
+}
+
+pub(crate) struct HttpProxyConfig {
+    auth_header: Option<String>,
+    http1: http1::Builder,
+    // Plain HTTP requests are forwarded through pipes that need to be tunneled by the listener
+    forward_tx: mpsc::Sender<(DuplexStream, (Host, u16))>,
+}
+
+pub(crate) type HttpForwardReceiver = mpsc::Receiver<(DuplexStream, (Host, u16))>;
+
 impl Stream for HttpProxyListener {
-    type Item = anyhow::Result<(TcpStream, (Host, u16))>;
+    type Item = anyhow::Result<(HttpProxyStream, (Host, u16))>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Option<Self::Item>> {
         unsafe { self.map_unchecked_mut(|x| &mut x.listener) }.poll_next(cx)
@@ -42,12 +92,12 @@ fn handle_http_connect_request(
     credentials: &Option<String>,
     dest: &Mutex<Option<(Host, u16)>>,
     req: Request<Incoming>,
-) -> impl Future<Output = Result<Response<Empty<Bytes>>, &'static str>> {
+) -> impl Future<Output = Result<Response<Empty<Bytes>>, Infallible>> {
     let ok_response = |forward_to: (Host, u16)| -> Result<Response<Empty<Bytes>>, _> {
         *dest.lock() = Some(forward_to);
         Ok(Response::builder().status(200).body(Empty::new()).unwrap())
     };
-    fn err_response() -> Result<Response<Empty<Bytes>>, &'static str> {
+    fn err_response() -> Result<Response<Empty<Bytes>>, Infallible> {
         info!("Un-authorized connection to http proxy");
         Ok(Response::builder().status(401).body(Empty::new()).unwrap())
     }
@@ -94,47 +144,61 @@ fn verify_credentials(credentials: &Option<String>, header_value: &Option<&str>)
     auth.starts_with(PROXY_AUTHORIZATION_PREFIX) && &auth[PROXY_AUTHORIZATION_PREFIX.len()..] == token
 }
 
-async fn handle_new_connection(
-    proxy_cfg: Arc<(Option<String>, http1::Builder)>,
+pub(crate) fn new_proxy_config(
+    timeout: Option<Duration>,
+    credentials: Option<(String, String)>,
+) -> (Arc<HttpProxyConfig>, HttpForwardReceiver) {
+    let http1 = {
+        let mut builder = http1::Builder::new();
+        builder
+            .timer(TokioTimer::new())
+            .header_read_timeout(timeout)
+            .keep_alive(false);
+        builder
+    };
+    let auth_header =
+        credentials.map(|(user, pass)| base64::engine::general_purpose::STANDARD.encode(format!("{user}:{pass}")));
+
+    let (forward_tx, forward_rx) = mpsc::channel(128);
+    let proxy_cfg = HttpProxyConfig {
+        auth_header,
+        http1,
+        forward_tx,
+    };
+
+    (Arc::new(proxy_cfg), forward_rx)
+}
+
+/// Handle a new client connection.
+///
+/// For an HTTP CONNECT request, the stream is returned once the handshake is done, so it can be tunneled as is.
+/// Plain HTTP requests are proxied until the client closes the connection, and their tunnels are
+/// sent to the [HttpForwardReceiver] of the config, so `None` is returned.
+pub(crate) async fn handle_new_connection(
+    proxy_cfg: Arc<HttpProxyConfig>,
     mut stream: TcpStream,
 ) -> Option<(TcpStream, (Host, u16))> {
     // We need to know if the http request if a CONNECT method or a regular one.
     // HTTP CONNECT requires doing a handshake with client (which is easier)
-    // While for regular method, we need to replay the request as if it was done by the client.
+    // While for regular method, we need to replay each request as if it was done by the client.
     // Non HTTP CONNECT method only works for non TLS connection/request.
-
-    // to drop the request_buf early when not needed anymore
-    {
-        // Get a pick at data to analyze http request
-        const CONNECT_METHOD: &[u8] = b"CONNECT ";
-        let mut request_buf = [0; 512];
-
-        // it is possible that the data is not yet available to us.
-        // ideally, we should delay and retry the call until we have read enough bytes, or deadline elapsed.
-        // But in practice and for the case of wstunnel, it is an edge case not worth handling.
-        // So we parse what we have and reject the request if not enough bytes already.
-        let buf_size = stream.peek(&mut request_buf).await.ok()?;
-
-        if request_buf[..CONNECT_METHOD.len()] != *CONNECT_METHOD {
-            // If no creds/auth is expected don't bother with headers
-            let mut headers = {
-                let headers_len = if proxy_cfg.0.is_some() { 32 } else { 0 };
-                vec![httparse::EMPTY_HEADER; headers_len]
-            };
-            let mut http_parser = httparse::Request::new(&mut headers);
-            let _ = http_parser.parse(&request_buf[..buf_size]);
-
-            // if it is not an HTTP CONNECT request handle it directly
-            return handle_regular_http_request(&http_parser, &proxy_cfg.0).map(|x| (stream, x));
-        }
+    const CONNECT_METHOD: &[u8] = b"CONNECT ";
+    let mut request_buf = [0; CONNECT_METHOD.len()];
+
+    // it is possible that the data is not yet available to us.
+    // ideally, we should delay and retry the call until we have read enough bytes, or deadline elapsed.
+    // But in practice and for the case of wstunnel, it is an edge case not worth handling.
+    let buf_size = stream.peek(&mut request_buf).await.ok()?;
+    if request_buf[..buf_size] != *CONNECT_METHOD {
+        serve_http_forward(&proxy_cfg, stream).await;
+        return None;
     }
 
     // Handle HTTP CONNECT request
-    let (auth_header, http1) = proxy_cfg.as_ref();
     let forward_to = Mutex::new(None);
-    let conn_fut = http1.serve_connection(
-        hyper_util::rt::TokioIo::new(&mut stream),
-        service_fn(|req| handle_http_connect_request(auth_header, &forward_to, req)),
+    let conn_fut = proxy_cfg.http1.serve_connection(
+        TokioIo::new(&mut stream),
+        service_fn(|req| handle_http_connect_request(&proxy_cfg.auth_header, &forward_to, req)),
     );
 
     match conn_fut.await {
@@ -157,24 +221,22 @@ pub async fn run_server(
         .await
         .with_context(|| format!("Cannot create TCP server {bind:?}"))?;
 
-    let http1 = {
-        let mut builder = http1::Builder::new();
-        builder
-            .timer(TokioTimer::new())
-            .header_read_timeout(timeout)
-            .keep_alive(false);
-        builder
-    };
-    let auth_header =
-        credentials.map(|(user, pass)| base64::engine::general_purpose::STANDARD.encode(format!("{user}:{pass}")));
     let tasks = JoinSet::<Option<(TcpStream, (Host, u16))>>::new();
 
-    let proxy_cfg = Arc::new((auth_header, http1));
-    let listener = stream::unfold((listener, tasks, proxy_cfg), |(listener, mut tasks, proxy_cfg)| async {
+    let (proxy_cfg, forward_rx) = new_proxy_config(timeout, credentials);
+    let state = (listener, tasks, proxy_cfg, forward_rx);
+    let listener = stream::unfold(state, |(listener, mut tasks, proxy_cfg, mut forward_rx)| async {
         loop {
             let (stream, forward_to) = select! {
                 biased;
 
+                forward = forward_rx.recv() => {
+                    // Cannot be None, as we hold a sender in proxy_cfg
+                    let Some((stream, forward_to)) = forward else { continue };
+                    let state = (listener, tasks, proxy_cfg, forward_rx);
+                    return Some((Ok((HttpProxyStream::Forward(stream), forward_to)), state));
+                },
+
                 cnx = tasks.join_next(), if !tasks.is_empty() => {
                     match cnx {
                         Some(Ok(Some((stream, f)))) => (stream, Some(f)),
@@ -200,7 +262,8 @@ pub async fn run_server(
             // We have a new connection to forward
             if let Some(forward_to) = forward_to {
                 let _ = tcp::configure_socket(SockRef::from(&stream), SoMark::new(None));
-                return Some((Ok((stream, forward_to)), (listener, tasks, proxy_cfg)));
+                let state = (listener, tasks, proxy_cfg, forward_rx);
+                return Some((Ok((HttpProxyStream::Tcp(stream), forward_to)), state));
             }
 
             // New incoming connection, parse and route the http request
@@ -215,26 +278,261 @@ pub async fn run_server(
     })
 }
 
-fn handle_regular_http_request(http_parser: &httparse::Request, auth_header: &Option<String>) -> Option<(Host, u16)> {
-    const DEFAULT_HTTP_PORT: u16 = 80;
+struct HttpForwarder {
+    target: (Host, u16),
+    sender: hyper::client::conn::http1::SendRequest<Incoming>,
+}
 
-    let header = http_parser.headers.iter().find_map(|h| {
-        if h.name == hyper::header::PROXY_AUTHORIZATION {
-            Some(String::from_utf8_lossy(h.value))
-        } else {
-            None
+type ForwardResponse = Response<Either<Incoming, Empty<Bytes>>>;
+
+fn empty_response(status: StatusCode) -> ForwardResponse {
+    Response::builder()
+        .status(status)
+        .body(Either::Right(Empty::new()))
+        .unwrap()
+}
+
+async fn serve_http_forward(proxy_cfg: &HttpProxyConfig, stream: TcpStream) {
+    // Contrary to CONNECT, the client is free to re-use the connection for other requests
+    let mut http1 = proxy_cfg.http1.clone();
+    http1.keep_alive(true);
+
+    let forwarder = Mutex::new(None);
+    let conn_fut = http1.serve_connection(
+        TokioIo::new(stream),
+        service_fn(|req| forward_http_request(proxy_cfg, &forwarder, req)),
+    );
+
+    // Upgrade requests, i.e: websocket, are tunneled as is once the destination accepts them
+    let conn_fut = conn_fut.with_upgrades();
+
+    if let Err(err) = conn_fut.await {
+        info!("Error while serving connection: {err}");
+    }
+}
+
+async fn forward_http_request(
+    proxy_cfg: &HttpProxyConfig,
+    forwarder: &Mutex<Option<HttpForwarder>>,
+    mut req: Request<Incoming>,
+) -> Result<ForwardResponse, Infallible> {
+    let header = req
+        .headers()
+        .get(hyper::header::PROXY_AUTHORIZATION)
+        .and_then(|h| h.to_str().ok());
+    if !verify_credentials(&proxy_cfg.auth_header, &header) {
+        info!("Un-authorized connection to http proxy");
+        return Ok(empty_response(StatusCode::UNAUTHORIZED));
+    }
+
+    let Some(target) = request_target(&req) else {
+        return Ok(empty_response(StatusCode::BAD_REQUEST));
+    };
+    debug!("HTTP Proxy {} request to {}", req.method(), req.uri());
+
+    // On a persistent connection, the client can send its next request to another host.
+    // In this case, the tunnel of the previous destination is closed and a new one is opened.
+    let current = forwarder
+        .lock()
+        .take()
+        .filter(|f| f.target == target && !f.sender.is_closed());
+    let mut current = match current {
+        Some(current) => current,
+        None => match new_forwarder(proxy_cfg, target).await {
+            Ok(forwarder) => forwarder,
+            Err(err) => {
+                warn!("Cannot forward http request: {err:?}");
+                return Ok(empty_response(StatusCode::BAD_GATEWAY));
+            }
+        },
+    };
+
+    let is_upgrade = is_upgrade_request(req.headers());
+    let client_upgrade = is_upgrade.then(|| hyper::upgrade::on(&mut req));
+    rewrite_request(&mut req, &current.target, is_upgrade);
+    let response = match current.sender.ready().await {
+        Ok(_) => current.sender.send_request(req).await,
+        Err(err) => Err(err),
+    };
+
+    let mut response = match response {
+        Ok(response) => response,
+        Err(err) => {
+            warn!("Cannot forward http request: {err}");
+            *forwarder.lock() = Some(current);
+            return Ok(empty_response(StatusCode::BAD_GATEWAY));
+        }
+    };
+
+    let is_upgraded = response.status() == StatusCode::SWITCHING_PROTOCOLS;
+    match client_upgrade {
+        // The tunnel now belongs to the upgraded connection, it cannot carry other requests
+        Some(client_upgrade) if is_upgraded => {
+            let remote_upgrade = hyper::upgrade::on(&mut response);
+            tokio::spawn(async move {
+                let (client, remote) = match tokio::try_join!(client_upgrade, remote_upgrade) {
+                    Ok(upgraded) => upgraded,
+                    Err(err) => {
+                        warn!("Cannot upgrade http connection: {err}");
+                        return;
+                    }
+                };
+                let _ = tokio::io::copy_bidirectional(&mut TokioIo::new(client), &mut TokioIo::new(remote)).await;
+            });
+        }
+        _ => *forwarder.lock() = Some(current),
+    }
+
+    remove_hop_by_hop_headers(response.headers_mut(), is_upgraded);
+    Ok(response.map(Either::Left))
+}
+
+async fn new_forwarder(proxy_cfg: &HttpProxyConfig, target: (Host, u16)) -> anyhow::Result<HttpForwarder> {
+    let (local, remote) = tokio::io::duplex(FORWARD_BUFFER_SIZE);
+    proxy_cfg
+        .forward_tx
+        .send((remote, target.clone()))
+        .await
+        .map_err(|_| anyhow!("http proxy listener is closed"))?;
+
+    let (sender, cnx) = hyper::client::conn::http1::handshake(TokioIo::new(local)).await?;
+    tokio::spawn(async move {
+        if let Err(err) = cnx.with_upgrades().await {
+            debug!("Http forward connection closed with error: {err}");
         }
     });
 
-    if !verify_credentials(auth_header, &header.as_deref()) {
-        return None;
+    Ok(HttpForwarder { target, sender })
+}
+
+fn request_target<B>(req: &Request<B>) -> Option<(Host, u16)> {
+    const DEFAULT_HTTP_PORT: u16 = 80;
+
+    // Proxy clients use the absolute-form, but fallback on the Host header for origin-form requests
+    let authority = match req.uri().authority() {
+        Some(authority) => authority.clone(),
+        None => req.headers().get(HOST)?.to_str().ok()?.parse().ok()?,
+    };
+
+    let host = Host::parse(authority.host()).ok()?;
+    Some((host, authority.port_u16().unwrap_or(DEFAULT_HTTP_PORT)))
+}
+
+fn rewrite_request<B>(req: &mut Request<B>, target: &(Host, u16), is_upgrade: bool) {
+    // absolute-form to origin-form
+    let path = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    *req.uri_mut() = Uri::try_from(path).unwrap_or_else(|_| Uri::from_static("/"));
+
+    remove_hop_by_hop_headers(req.headers_mut(), is_upgrade);
+
+    if !req.headers().contains_key(HOST) {
+        let host = match target {
+            (host, 80) => host.to_string(),
+            (host, port) => format!("{host}:{port}"),
+        };
+        if let Ok(host) = HeaderValue::from_str(&host) {
+            req.headers_mut().insert(HOST, host);
+        }
+    }
+}
+
+fn connection_tokens(headers: &HeaderMap) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
+fn is_upgrade_request(headers: &HeaderMap) -> bool {
+    headers.contains_key(UPGRADE) && connection_tokens(headers).any(|token| token.eq_ignore_ascii_case("upgrade"))
+}
+
+/// Remove the headers that only make sense for a single hop, i.e: between the client and us.
+/// When the connection is upgraded, the Upgrade header is kept so the destination can switch protocol too
+fn remove_hop_by_hop_headers(headers: &mut HeaderMap, keep_upgrade: bool) {
+    const HOP_BY_HOP_HEADERS: [&str; 5] = ["connection", "keep-alive", "te", "trailer", "transfer-encoding"];
+
+    let mut names: Vec<HeaderName> = connection_tokens(headers)
+        .filter_map(|token| HeaderName::try_from(token).ok())
+        .collect();
+    names.extend(HOP_BY_HOP_HEADERS.iter().map(|name| HeaderName::from_static(name)));
+    // Proxy-* headers are meant for us, not for the destination
+    names.extend(
+        headers
+            .keys()
+            .filter(|name| name.as_str().starts_with("proxy-"))
+            .cloned(),
+    );
+
+    for name in names {
+        if keep_upgrade && name == UPGRADE {
+            continue;
+        }
+        headers.remove(name);
+    }
+
+    if keep_upgrade {
+        headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    } else {
+        headers.remove(UPGRADE);
+    }
+}
+
+impl AsyncRead for HttpProxyReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            Self::Forward(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for HttpProxyWriteHalf {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            Self::Forward(s) => Pin::new(s).poll_write(cx, buf),
+        }
     }
 
-    let url = Url::parse(http_parser.path.unwrap_or("")).ok()?;
-    let host = url.host()?.to_owned();
-    let port = url.port_or_known_default().unwrap_or(DEFAULT_HTTP_PORT);
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_flush(cx),
+            Self::Forward(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
 
-    Some((host, port))
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            Self::Forward(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize, Error>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+            Self::Forward(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Tcp(s) => s.is_write_vectored(),
+            Self::Forward(s) => s.is_write_vectored(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -255,37 +553,171 @@ mod tests {
         (client, stream)
     }
 
+    fn proxy_config(auth: Option<&str>) -> (Arc<HttpProxyConfig>, HttpForwardReceiver) {
+        let (forward_tx, forward_rx) = mpsc::channel(8);
+        let proxy_cfg = HttpProxyConfig {
+            auth_header: auth.map(|x| x.to_string()),
+            http1: http1::Builder::new(),
+            forward_tx,
+        };
+        (Arc::new(proxy_cfg), forward_rx)
+    }
+
+    async fn read_http_head(stream: &mut (impl AsyncRead + Unpin)) -> String {
+        let mut buf = Vec::with_capacity(1024);
+        while !buf.ends_with(b"\r\n\r\n") {
+            buf.push(stream.read_u8().await.unwrap());
+        }
+        String::from_utf8(buf).unwrap()
+    }
+
     #[rstest]
+    #[case(
+        "GET http://google.com/ HTTP/1.1\r\nHost: google.com\r\n\r\n",
+        None,
+        Ok(((Host::Domain("google.com".to_string()), 80), "GET / HTTP/1.1\r\nhost: google.com\r\n\r\n"))
+    )]
+    #[case(
+        "GET http://google.com:8080/search?q=wstunnel HTTP/1.1\r\nProxy-Authorization: Basic toto\r\nProxy-Connection: keep-alive\r\n\r\n",
+        Some("toto"),
+        Ok(((Host::Domain("google.com".to_string()), 8080), "GET /search?q=wstunnel HTTP/1.1\r\nhost: google.com:8080\r\n\r\n"))
+    )]
+    // hop-by-hop headers, and the ones listed in Connection, are not forwarded
+    #[case(
+        "GET http://google.com/ HTTP/1.1\r\nHost: google.com\r\nConnection: keep-alive, X-Hop\r\nKeep-Alive: timeout=5\r\nX-Hop: 1\r\nTE: trailers\r\nUpgrade: h2c\r\nAccept: */*\r\n\r\n",
+        None,
+        Ok(((Host::Domain("google.com".to_string()), 80), "GET / HTTP/1.1\r\nhost: google.com\r\naccept: */*\r\n\r\n"))
+    )]
+    // origin-form request, the destination is taken from the Host header
+    #[case(
+        "GET /index.html HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n",
+        None,
+        Ok(((Host::Ipv6(std::net::Ipv6Addr::LOCALHOST), 8080), "GET /index.html HTTP/1.1\r\nhost: [::1]:8080\r\n\r\n"))
+    )]
     // No host available, it should fail
-    #[case("GET / HTTP/1.1\r\n\r\n", None, None)]
-    // Partial request should fail
-    #[case("GET ", None, None)]
-    // Url is too long, it should fail. Limit is 512 bytes for the whole request
-    #[case(String::from_iter(["GET google.com/".to_string(), "a".repeat(512), "  HTTP/1.1\r\n\r\n".to_string()]), None, None)]
-    #[case("GET http://google.com/ HTTP/1.1\r\n\r\n", None, Some((Host::Domain("google.com".to_string()), 80)))]
-    #[case("GET http://google.com/ HTTP/1.1\r\nProxy-Authorization: Basic toto\r\n\r\n", Some("toto"), Some((Host::Domain("google.com".to_string()), 80)))]
+    #[case("GET / HTTP/1.1\r\n\r\n", None, Err("HTTP/1.1 400 Bad Request\r\n"))]
     #[case(
         "GET http://google.com/ HTTP/1.1\r\nProxy-Authorization: Basic toto\r\n\r\n",
         Some("tata"),
-        None
+        Err("HTTP/1.1 401 Unauthorized\r\n")
     )]
     #[timeout(Duration::from_secs(10))]
     #[tokio::test]
     #[awt]
     async fn test_handle_new_connection(
         #[future] connected_client: (TcpStream, TcpStream),
-        #[case] input: impl AsRef<[u8]>,
+        #[case] input: &str,
         #[case] auth: Option<&str>,
-        #[case] expected_result: Option<(Host, u16)>,
+        #[case] expected_result: Result<((Host, u16), &str), &str>,
     ) {
         let (mut client, stream) = connected_client;
-        let auth_header = auth.map(|x| x.to_string());
-        let proxy_cfg = Arc::new((auth_header, http1::Builder::new()));
+        let (proxy_cfg, mut forward_rx) = proxy_config(auth);
+        let server = tokio::spawn(handle_new_connection(proxy_cfg, stream));
+
+        client.write_all(input.as_bytes()).await.unwrap();
+
+        match expected_result {
+            Ok((expected_target, expected_request)) => {
+                let (mut pipe, target) = forward_rx.recv().await.unwrap();
+                assert_eq!(target, expected_target);
+                assert_eq!(read_http_head(&mut pipe).await, expected_request);
+
+                pipe.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok")
+                    .await
+                    .unwrap();
+                let response = read_http_head(&mut client).await;
+                assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            }
+            Err(expected_response) => {
+                let response = read_http_head(&mut client).await;
+                assert!(response.starts_with(expected_response));
+                assert!(forward_rx.try_recv().is_err());
+            }
+        }
 
-        client.write_all(input.as_ref()).await.unwrap();
+        drop(client);
+        assert!(server.await.unwrap().is_none());
+    }
 
-        let ret = handle_new_connection(proxy_cfg.clone(), stream).await;
-        assert_eq!(ret.map(|(_, x)| x), expected_result);
+    #[rstest]
+    #[timeout(Duration::from_secs(10))]
+    #[tokio::test]
+    #[awt]
+    async fn test_keep_alive_connection_to_another_host(#[future] connected_client: (TcpStream, TcpStream)) {
+        let (mut client, stream) = connected_client;
+        let (proxy_cfg, mut forward_rx) = proxy_config(None);
+        tokio::spawn(handle_new_connection(proxy_cfg, stream));
+
+        client
+            .write_all(b"GET http://google.com/ HTTP/1.1\r\nHost: google.com\r\n\r\n")
+            .await
+            .unwrap();
+        let (mut pipe, target) = forward_rx.recv().await.unwrap();
+        assert_eq!(target, (Host::Domain("google.com".to_string()), 80));
+        read_http_head(&mut pipe).await;
+        pipe.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+        read_http_head(&mut client).await;
+
+        // Same host, the tunnel is re-used
+        client
+            .write_all(b"GET http://google.com/other HTTP/1.1\r\nHost: google.com\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(read_http_head(&mut pipe).await.starts_with("GET /other HTTP/1.1\r\n"));
+        pipe.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+        read_http_head(&mut client).await;
+
+        // Another host on the same connection, a new tunnel must be opened and the previous one closed
+        client
+            .write_all(b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+        let (mut new_pipe, target) = forward_rx.recv().await.unwrap();
+        assert_eq!(target, (Host::Domain("example.com".to_string()), 80));
+        assert_eq!(
+            read_http_head(&mut new_pipe).await,
+            "GET / HTTP/1.1\r\nhost: example.com\r\n\r\n"
+        );
+
+        let mut buf = Vec::new();
+        pipe.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.is_empty());
+    }
+
+    #[rstest]
+    #[timeout(Duration::from_secs(10))]
+    #[tokio::test]
+    #[awt]
+    async fn test_upgrade_connection(#[future] connected_client: (TcpStream, TcpStream)) {
+        let (mut client, stream) = connected_client;
+        let (proxy_cfg, mut forward_rx) = proxy_config(None);
+        tokio::spawn(handle_new_connection(proxy_cfg, stream));
+
+        client
+            .write_all(b"GET http://google.com/chat HTTP/1.1\r\nHost: google.com\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n")
+            .await
+            .unwrap();
+        let (mut pipe, _) = forward_rx.recv().await.unwrap();
+        let request = read_http_head(&mut pipe).await;
+        assert!(request.starts_with("GET /chat HTTP/1.1\r\n"));
+        assert!(request.contains("upgrade: websocket\r\n"));
+        assert!(request.contains("connection: upgrade\r\n"));
+
+        pipe.write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_http_head(&mut client).await;
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("upgrade: websocket\r\n"));
+
+        // Once upgraded, bytes flow as is in both directions
+        let mut buf = [0u8; 4];
+        client.write_all(b"ping").await.unwrap();
+        pipe.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        pipe.write_all(b"pong").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
     }
 
     #[rstest]
@@ -309,12 +741,11 @@ mod tests {
         #[case] expected_result: Option<(Host, u16)>,
     ) {
         let (mut client, stream) = connected_client;
-        let auth_header = auth.map(|x| x.to_string());
-        let proxy_cfg = Arc::new((auth_header, http1::Builder::new()));
+        let (proxy_cfg, _forward_rx) = proxy_config(auth);
 
         client.write_all(input.as_ref()).await.unwrap();
 
-        let ret = handle_new_connection(proxy_cfg.clone(), stream).await;
+        let ret = handle_new_connection(proxy_cfg, stream).await;
         assert_eq!(ret.map(|(_, x)| x), expected_result);
 
         let mut buf = Vec::with_capacity(1024);
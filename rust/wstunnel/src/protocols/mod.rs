@@ -4,6 +4,8 @@ pub mod socks5;
 pub mod stdio;
 pub mod tcp;
 pub mod tls;
+#[cfg(unix)]
+pub mod tun;
 pub mod udp;
 #[cfg(unix)]
 pub mod unix_sock;
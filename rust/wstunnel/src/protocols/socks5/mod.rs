@@ -1,7 +1,9 @@
+mod socks4;
 mod tcp_server;
 mod udp_server;
 
 pub use tcp_server::Socks5Listener;
 pub use tcp_server::Socks5ReadHalf;
 pub use tcp_server::Socks5WriteHalf;
+pub use tcp_server::run_mixed_server;
 pub use tcp_server::run_server;
@@ -0,0 +1,129 @@
+//! Server side of the SOCKS4/SOCKS4a handshake.
+//!
+//! Only the CONNECT command is supported. Legacy clients reach us on the same port as the SOCKS5 server,
+//! the version byte of the first packet is used to know which protocol the client speaks.
+
+use anyhow::anyhow;
+use std::net::Ipv4Addr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use url::Host;
+
+pub const SOCKS4_VERSION: u8 = 0x04;
+const SOCKS4_CMD_CONNECT: u8 = 0x01;
+const SOCKS4_REPLY_VERSION: u8 = 0x00;
+const SOCKS4_REPLY_GRANTED: u8 = 0x5a;
+const SOCKS4_REPLY_REJECTED: u8 = 0x5b;
+const SOCKS4_REPLY_INVALID_USER: u8 = 0x5d;
+
+// Userid and domain are NUL terminated strings, put a bound on them to avoid reading forever
+const MAX_FIELD_LEN: usize = 255;
+
+/// Read a SOCKS4/SOCKS4a CONNECT request from the stream and reply to it.
+///
+/// SOCKS4 only carries a userid, so when credentials are configured the client must send `login:password` as userid.
+/// On success, the stream is ready to be forwarded and the requested destination is returned.
+pub async fn handshake<S>(stream: &mut S, credentials: Option<&(String, String)>) -> anyhow::Result<(Host, u16)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // +----+----+----+----+----+----+----+----+----+----+....+----+
+    // | VN | CD | DSTPORT |      DSTIP        | USERID       |NULL|
+    // +----+----+----+----+----+----+----+----+----+----+....+----+
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header).await?;
+    if header[0] != SOCKS4_VERSION {
+        return Err(anyhow!("invalid socks4 version {}", header[0]));
+    }
+
+    let port = u16::from_be_bytes([header[2], header[3]]);
+    let ip = Ipv4Addr::new(header[4], header[5], header[6], header[7]);
+    let userid = read_nul_terminated(stream).await?;
+
+    // SOCKS4a: an ip of 0.0.0.x with x != 0 means that the domain follows the userid
+    let is_socks4a = ip.octets()[..3] == [0, 0, 0] && ip.octets()[3] != 0;
+    let host = if is_socks4a {
+        let domain = read_nul_terminated(stream).await?;
+        let domain = String::from_utf8(domain).map_err(|_| anyhow!("invalid socks4a domain"))?;
+        Host::parse(&domain).map_err(|err| anyhow!("invalid socks4a domain {domain}: {err}"))?
+    } else {
+        Host::Ipv4(ip)
+    };
+
+    if let Some((login, password)) = credentials
+        && userid != format!("{login}:{password}").as_bytes()
+    {
+        let _ = stream.write_all(&new_reply(SOCKS4_REPLY_INVALID_USER)).await;
+        return Err(anyhow!("invalid socks4 userid"));
+    }
+
+    if header[1] != SOCKS4_CMD_CONNECT {
+        let _ = stream.write_all(&new_reply(SOCKS4_REPLY_REJECTED)).await;
+        return Err(anyhow!("unsupported socks4 command {}", header[1]));
+    }
+
+    stream.write_all(&new_reply(SOCKS4_REPLY_GRANTED)).await?;
+    Ok((host, port))
+}
+
+async fn read_nul_terminated<S: AsyncRead + Unpin>(stream: &mut S) -> anyhow::Result<Vec<u8>> {
+    let mut field = Vec::with_capacity(32);
+    loop {
+        match stream.read_u8().await? {
+            0 => return Ok(field),
+            b if field.len() < MAX_FIELD_LEN => field.push(b),
+            _ => return Err(anyhow!("socks4 field is too long")),
+        }
+    }
+}
+
+fn new_reply(status: u8) -> [u8; 8] {
+    // Clients ignore DSTPORT and DSTIP for CONNECT, so we don't bother sending the bind addr
+    [SOCKS4_REPLY_VERSION, status, 0, 0, 0, 0, 0, 0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn credentials() -> (String, String) {
+        ("admin".to_string(), "secret".to_string())
+    }
+
+    #[rstest]
+    #[case(b"\x04\x01\x01\xbb\x7f\x00\x00\x01\x00".to_vec(), None, Some((Host::Ipv4(Ipv4Addr::LOCALHOST), 443)), SOCKS4_REPLY_GRANTED)]
+    #[case(b"\x04\x01\x00\x50\x00\x00\x00\x01\x00google.com\x00".to_vec(), None, Some((Host::Domain("google.com".to_string()), 80)), SOCKS4_REPLY_GRANTED)]
+    #[case(b"\x04\x01\x00\x50\x00\x00\x00\x01admin:secret\x00google.com\x00".to_vec(), Some(credentials()), Some((Host::Domain("google.com".to_string()), 80)), SOCKS4_REPLY_GRANTED)]
+    #[case(b"\x04\x01\x00\x50\x00\x00\x00\x01admin\x00google.com\x00".to_vec(), Some(credentials()), None, SOCKS4_REPLY_INVALID_USER)]
+    #[case(b"\x04\x01\x00\x50\x7f\x00\x00\x01\x00".to_vec(), Some(credentials()), None, SOCKS4_REPLY_INVALID_USER)]
+    // BIND is not supported
+    #[case(b"\x04\x02\x00\x50\x7f\x00\x00\x01\x00".to_vec(), None, None, SOCKS4_REPLY_REJECTED)]
+    #[tokio::test]
+    async fn test_socks4_handshake(
+        #[case] request: Vec<u8>,
+        #[case] credentials: Option<(String, String)>,
+        #[case] expected_result: Option<(Host, u16)>,
+        #[case] expected_reply: u8,
+    ) {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&request).await.unwrap();
+
+        let ret = handshake(&mut server, credentials.as_ref()).await;
+        assert_eq!(ret.ok(), expected_result);
+
+        let mut reply = [0u8; 8];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[0], SOCKS4_REPLY_VERSION);
+        assert_eq!(reply[1], expected_reply);
+    }
+
+    #[tokio::test]
+    async fn test_socks4_handshake_field_too_long() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let mut request = b"\x04\x01\x00\x50\x7f\x00\x00\x01".to_vec();
+        request.extend(std::iter::repeat_n(b'a', MAX_FIELD_LEN + 1));
+        client.write_all(&request).await.unwrap();
+
+        assert!(handshake(&mut server, None).await.is_err());
+    }
+}
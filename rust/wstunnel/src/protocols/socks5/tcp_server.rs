@@ -1,23 +1,29 @@
 use super::udp_server::{Socks5UdpStream, Socks5UdpStreamWriter};
+use crate::protocols::http_proxy;
 use crate::tunnel::LocalProtocol;
 use anyhow::Context;
-use fast_socks5::server::{Config, DenyAuthentication, SimpleUserPassword, Socks5Server};
+use fast_socks5::server::{Config, SimpleUserPassword, Socks5Socket};
 use fast_socks5::util::target_addr::TargetAddr;
 use fast_socks5::{ReplyError, consts};
-use futures_util::{Stream, StreamExt, stream};
+use futures_util::{Stream, StreamExt, future, stream};
 use std::io::{Error, IoSlice};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::Poll;
 use std::time::Duration;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf, ReadHalf, WriteHalf};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::select;
 use tokio::task::JoinSet;
 use tracing::{info, warn};
 use url::Host;
 
+use super::socks4;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[allow(clippy::type_complexity)]
 pub struct Socks5Listener {
     socks_server: Pin<Box<dyn Stream<Item = anyhow::Result<(Socks5Stream, (Host, u16))>> + Send>>,
@@ -26,22 +32,33 @@ pub struct Socks5Listener {
 pub enum Socks5ReadHalf {
     Tcp(OwnedReadHalf),
     Udp(Socks5UdpStream),
+    HttpForward(ReadHalf<DuplexStream>),
 }
 
 pub enum Socks5WriteHalf {
     Tcp(OwnedWriteHalf),
     Udp(Socks5UdpStreamWriter),
+    HttpForward(WriteHalf<DuplexStream>),
 }
 
 pub enum Socks5Stream {
     Tcp(TcpStream),
     Udp((Socks5UdpStream, Socks5UdpStreamWriter)),
+    // Plain HTTP requests forwarded by the mixed server
+    HttpForward(DuplexStream),
 }
 
 impl Socks5Stream {
     pub fn local_protocol(&self) -> LocalProtocol {
         match self {
-            Self::Tcp(_) => LocalProtocol::Tcp { proxy_protocol: false }, // TODO: Implement proxy protocol
+            Self::Tcp(_) => LocalProtocol::Tcp {
+                proxy_protocol: false,
+                idle_timeout: None,
+            }, // TODO: Implement proxy protocol
+            Self::HttpForward(_) => LocalProtocol::Tcp {
+                proxy_protocol: false,
+                idle_timeout: None,
+            },
             Self::Udp(s) => LocalProtocol::Udp {
                 timeout: s.0.watchdog_deadline.as_ref().map(|x| x.period()),
             },
@@ -55,10 +72,45 @@ impl Socks5Stream {
                 (Socks5ReadHalf::Tcp(r), Socks5WriteHalf::Tcp(w))
             }
             Self::Udp((r, w)) => (Socks5ReadHalf::Udp(r), Socks5WriteHalf::Udp(w)),
+            Self::HttpForward(s) => {
+                let (r, w) = tokio::io::split(s);
+                (Socks5ReadHalf::HttpForward(r), Socks5WriteHalf::HttpForward(w))
+            }
         }
     }
 }
 
+struct SocksServer {
+    listener: TcpListener,
+    handshake: Arc<HandshakeConfig>,
+    // Pending handshakes, so a slow client does not block the accept loop
+    handshakes: JoinSet<Option<(Socks5Stream, (Host, u16))>>,
+    // Plain HTTP requests of the mixed server, forwarded through pipes
+    http_forward_rx: Option<http_proxy::HttpForwardReceiver>,
+}
+
+struct HandshakeConfig {
+    socks5: Arc<Config<SimpleUserPassword>>,
+    credentials: Option<(String, String)>,
+    // When set, connections that are not SOCKS are handled as an HTTP proxy request
+    http_proxy: Option<Arc<http_proxy::HttpProxyConfig>>,
+    // Address of the udp server returned to UDP associate requests
+    udp_bind: SocketAddr,
+}
+
+async fn next_http_forward_stream(
+    forward_rx: &mut Option<http_proxy::HttpForwardReceiver>,
+) -> (Socks5Stream, (Host, u16)) {
+    let Some(forward_rx) = forward_rx else {
+        return future::pending().await;
+    };
+
+    match forward_rx.recv().await {
+        Some((stream, forward_to)) => (Socks5Stream::HttpForward(stream), forward_to),
+        None => future::pending().await,
+    }
+}
+
 impl Stream for Socks5Listener {
     type Item = anyhow::Result<(Socks5Stream, (Host, u16))>;
 
@@ -73,16 +125,40 @@ pub async fn run_server(
     credentials: Option<(String, String)>,
 ) -> Result<Socks5Listener, anyhow::Error> {
     info!(
-        "Starting SOCKS5 server listening cnx on {} with credentials {:?}",
+        "Starting SOCKS5/SOCKS4 server listening cnx on {} with credentials {:?}",
         bind, credentials
     );
 
-    let server = Socks5Server::<DenyAuthentication>::bind(bind)
+    run_server_impl(bind, timeout, credentials, None).await
+}
+
+/// Start a server accepting SOCKS5, SOCKS4/SOCKS4a and HTTP proxy (CONNECT or plain) requests on the same port
+pub async fn run_mixed_server(
+    bind: SocketAddr,
+    timeout: Option<Duration>,
+    credentials: Option<(String, String)>,
+) -> Result<Socks5Listener, anyhow::Error> {
+    info!(
+        "Starting mixed SOCKS5/SOCKS4/HTTP proxy server listening cnx on {} with credentials {:?}",
+        bind, credentials
+    );
+
+    let http_proxy = http_proxy::new_proxy_config(timeout, credentials.clone());
+    run_server_impl(bind, timeout, credentials, Some(http_proxy)).await
+}
+
+async fn run_server_impl(
+    bind: SocketAddr,
+    timeout: Option<Duration>,
+    credentials: Option<(String, String)>,
+    http_proxy: Option<(Arc<http_proxy::HttpProxyConfig>, http_proxy::HttpForwardReceiver)>,
+) -> Result<Socks5Listener, anyhow::Error> {
+    let listener = TcpListener::bind(bind)
         .await
         .with_context(|| format!("Cannot create socks5 server {bind:?}"))?;
 
-    let mut cfg = Config::default();
-    cfg = if let Some((username, password)) = credentials {
+    let mut cfg = Config::<SimpleUserPassword>::default();
+    cfg = if let Some((username, password)) = credentials.clone() {
         cfg.set_allow_no_auth(false);
         cfg.with_authentication(SimpleUserPassword { username, password })
     } else {
@@ -95,99 +171,58 @@ pub async fn run_server(
     cfg.set_udp_support(true);
 
     let udp_server = super::udp_server::run_server(bind, timeout).await?;
-    let server = server.with_config(cfg);
+    let (http_proxy, http_forward_rx) = http_proxy.unzip();
+    let server = SocksServer {
+        listener,
+        handshake: Arc::new(HandshakeConfig {
+            socks5: Arc::new(cfg),
+            credentials,
+            http_proxy,
+            udp_bind: bind,
+        }),
+        handshakes: JoinSet::new(),
+        http_forward_rx,
+    };
     let stream = stream::unfold(
-        (server, Box::pin(udp_server), JoinSet::new()),
-        move |(server, mut udp_server, mut tasks)| async move {
-            let mut acceptor = server.incoming();
+        (server, Box::pin(udp_server)),
+        move |(mut server, mut udp_server)| async move {
             loop {
-                let cnx = select! {
+                select! {
                     biased;
 
-                    cnx = acceptor.next() => match cnx {
-                        None => return None,
-                        Some(Err(err)) => {
-                            drop(acceptor);
-                            return Some((Err(anyhow::Error::new(err)), (server, udp_server, tasks)));
+                    cnx = server.listener.accept() => match cnx {
+                        Err(err) => {
+                            return Some((Err(anyhow::Error::new(err)), (server, udp_server)));
+                        }
+                        Ok((cnx, _)) => {
+                            server.handshakes.spawn(handshake(cnx, server.handshake.clone()));
                         }
-                        Some(Ok(cnx)) => cnx,
+                    },
+
+                    Some(cnx) = server.handshakes.join_next() => match cnx {
+                        Ok(Some((stream, target))) => return Some((Ok((stream, target)), (server, udp_server))),
+                        Ok(None) => {}
+                        Err(err) => warn!("Error while joining socks handshake task: {:?}", err),
+                    },
+
+                    // new http proxy stream from the mixed server
+                    (stream, forward_to) = next_http_forward_stream(&mut server.http_forward_rx) => {
+                        return Some((Ok((stream, forward_to)), (server, udp_server)));
                     },
 
                     // new incoming udp stream
                     udp_conn = udp_server.next() => {
-                        drop(acceptor);
                         return match udp_conn {
                             Some(Ok(stream)) => {
                                 let dest = stream.destination();
                                 let writer = stream.writer();
-                                Some((Ok((Socks5Stream::Udp((stream, writer)), dest)), (server, udp_server, tasks)))
-                            }
-                            Some(Err(err)) => {
-                                Some((Err(anyhow::Error::new(err)), (server, udp_server, tasks)))
-                            }
-                            None => {
-                                None
+                                Some((Ok((Socks5Stream::Udp((stream, writer)), dest)), (server, udp_server)))
                             }
+                            Some(Err(err)) => Some((Err(anyhow::Error::new(err)), (server, udp_server))),
+                            None => None,
                         };
                     }
-                };
-
-                let cnx = match cnx.upgrade_to_socks5().await {
-                    Ok(cnx) => cnx,
-                    Err(err) => {
-                        warn!("Rejecting socks5 cnx: {}", err);
-                        continue;
-                    }
-                };
-
-                let Some(target) = cnx.target_addr() else {
-                    warn!("Rejecting socks5 cnx: no target addr");
-                    continue;
-                };
-
-                let (host, port) = match target {
-                    TargetAddr::Ip(SocketAddr::V4(ip)) => (Host::Ipv4(*ip.ip()), ip.port()),
-                    TargetAddr::Ip(SocketAddr::V6(ip)) => (Host::Ipv6(*ip.ip()), ip.port()),
-                    TargetAddr::Domain(host, port) => (Host::Domain(host.clone()), *port),
-                };
-
-                // Special case for UDP Associate where we return the bind addr of the udp server
-                if matches!(cnx.cmd(), Some(fast_socks5::Socks5Command::UDPAssociate)) {
-                    let mut cnx = cnx.into_inner();
-                    let ret = cnx.write_all(&new_reply(&ReplyError::Succeeded, bind)).await;
-
-                    if let Err(err) = ret {
-                        warn!("Cannot reply to socks5 udp client: {}", err);
-                        continue;
-                    }
-                    tasks.spawn(async move {
-                        let mut buf = [0u8; 8];
-                        loop {
-                            match cnx.read(&mut buf).await {
-                                Ok(0) => return,
-                                Err(_) => return,
-                                _ => {}
-                            }
-                        }
-                    });
-                    continue;
-                };
-
-                let mut cnx = cnx.into_inner();
-                let ret = cnx
-                    .write_all(&new_reply(
-                        &ReplyError::Succeeded,
-                        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
-                    ))
-                    .await;
-
-                if let Err(err) = ret {
-                    warn!("Cannot reply to socks5 client: {}", err);
-                    continue;
                 }
-
-                drop(acceptor);
-                return Some((Ok((Socks5Stream::Tcp(cnx), (host, port))), (server, udp_server, tasks)));
             }
         },
     );
@@ -199,6 +234,95 @@ pub async fn run_server(
     Ok(listener)
 }
 
+async fn handshake(mut cnx: TcpStream, config: Arc<HandshakeConfig>) -> Option<(Socks5Stream, (Host, u16))> {
+    // SOCKS4/SOCKS4a and HTTP clients share the same port, the first byte of the request tells us the protocol
+    let mut version = [0u8; 1];
+    if !matches!(
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, cnx.peek(&mut version)).await,
+        Ok(Ok(1))
+    ) {
+        warn!("Rejecting socks cnx: cannot read protocol version");
+        return None;
+    }
+
+    if version[0] == socks4::SOCKS4_VERSION {
+        let handshake = socks4::handshake(&mut cnx, config.credentials.as_ref());
+        return match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+            Ok(Ok(target)) => Some((Socks5Stream::Tcp(cnx), target)),
+            Ok(Err(err)) => {
+                warn!("Rejecting socks4 cnx: {}", err);
+                None
+            }
+            Err(_) => {
+                warn!("Rejecting socks4 cnx: handshake timeout");
+                None
+            }
+        };
+    }
+
+    if let Some(http_proxy) = &config.http_proxy
+        && version[0] != consts::SOCKS5_VERSION
+    {
+        let (cnx, target) = http_proxy::handle_new_connection(http_proxy.clone(), cnx).await?;
+        return Some((Socks5Stream::Tcp(cnx), target));
+    }
+
+    let cnx = match Socks5Socket::new(cnx, config.socks5.clone()).upgrade_to_socks5().await {
+        Ok(cnx) => cnx,
+        Err(err) => {
+            warn!("Rejecting socks5 cnx: {}", err);
+            return None;
+        }
+    };
+
+    let Some(target) = cnx.target_addr() else {
+        warn!("Rejecting socks5 cnx: no target addr");
+        return None;
+    };
+
+    let (host, port) = match target {
+        TargetAddr::Ip(SocketAddr::V4(ip)) => (Host::Ipv4(*ip.ip()), ip.port()),
+        TargetAddr::Ip(SocketAddr::V6(ip)) => (Host::Ipv6(*ip.ip()), ip.port()),
+        TargetAddr::Domain(host, port) => (Host::Domain(host.clone()), *port),
+    };
+
+    // Special case for UDP Associate where we return the bind addr of the udp server
+    if matches!(cnx.cmd(), Some(fast_socks5::Socks5Command::UDPAssociate)) {
+        let mut cnx = cnx.into_inner();
+        let ret = cnx.write_all(&new_reply(&ReplyError::Succeeded, config.udp_bind)).await;
+
+        if let Err(err) = ret {
+            warn!("Cannot reply to socks5 udp client: {}", err);
+            return None;
+        }
+
+        // The association lives as long as the tcp connection is open
+        let mut buf = [0u8; 8];
+        loop {
+            match cnx.read(&mut buf).await {
+                Ok(0) => return None,
+                Err(_) => return None,
+                _ => {}
+            }
+        }
+    };
+
+    let mut cnx = cnx.into_inner();
+    let ret = cnx
+        .write_all(&new_reply(
+            &ReplyError::Succeeded,
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
+        ))
+        .await;
+
+    if let Err(err) = ret {
+        warn!("Cannot reply to socks5 client: {}", err);
+        return None;
+    }
+
+    Some((Socks5Stream::Tcp(cnx), (host, port)))
+}
+
 fn new_reply(error: &ReplyError, sock_addr: SocketAddr) -> Vec<u8> {
     let (addr_type, mut ip_oct, mut port) = match sock_addr {
         SocketAddr::V4(sock) => (
@@ -235,6 +359,7 @@ impl AsyncRead for Socks5ReadHalf {
         match self.get_mut() {
             Self::Tcp(s) => Pin::new(s).poll_read(cx, buf),
             Self::Udp(s) => Pin::new(s).poll_read(cx, buf),
+            Self::HttpForward(s) => Pin::new(s).poll_read(cx, buf),
         }
     }
 }
@@ -244,6 +369,7 @@ impl AsyncWrite for Socks5WriteHalf {
         match self.get_mut() {
             Self::Tcp(s) => Pin::new(s).poll_write(cx, buf),
             Self::Udp(s) => Pin::new(s).poll_write(cx, buf),
+            Self::HttpForward(s) => Pin::new(s).poll_write(cx, buf),
         }
     }
 
@@ -251,6 +377,7 @@ impl AsyncWrite for Socks5WriteHalf {
         match self.get_mut() {
             Self::Tcp(s) => Pin::new(s).poll_flush(cx),
             Self::Udp(s) => Pin::new(s).poll_flush(cx),
+            Self::HttpForward(s) => Pin::new(s).poll_flush(cx),
         }
     }
 
@@ -258,6 +385,7 @@ impl AsyncWrite for Socks5WriteHalf {
         match self.get_mut() {
             Self::Tcp(s) => Pin::new(s).poll_shutdown(cx),
             Self::Udp(s) => Pin::new(s).poll_shutdown(cx),
+            Self::HttpForward(s) => Pin::new(s).poll_shutdown(cx),
         }
     }
 
@@ -269,6 +397,7 @@ impl AsyncWrite for Socks5WriteHalf {
         match self.get_mut() {
             Self::Tcp(s) => Pin::new(s).poll_write_vectored(cx, bufs),
             Self::Udp(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+            Self::HttpForward(s) => Pin::new(s).poll_write_vectored(cx, bufs),
         }
     }
 
@@ -276,6 +405,55 @@ impl AsyncWrite for Socks5WriteHalf {
         match self {
             Self::Tcp(s) => s.is_write_vectored(),
             Self::Udp(s) => s.is_write_vectored(),
+            Self::HttpForward(s) => s.is_write_vectored(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddrV4;
+
+    #[tokio::test]
+    async fn test_mixed_server_dispatch() {
+        let bind = {
+            let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+                .await
+                .unwrap();
+            listener.local_addr().unwrap()
+        };
+        let mut server = run_mixed_server(bind, None, None).await.unwrap();
+
+        // A client that never sends its request must not block the others
+        let _idle_client = TcpStream::connect(bind).await.unwrap();
+
+        let requests: [(&[u8], (Host, u16)); 3] = [
+            (
+                b"\x04\x01\x01\xbb\x7f\x00\x00\x01\x00",
+                (Host::Ipv4(Ipv4Addr::LOCALHOST), 443),
+            ),
+            (
+                b"\x05\x01\x00\x05\x01\x00\x03\x0agoogle.com\x00\x50",
+                (Host::Domain("google.com".to_string()), 80),
+            ),
+            (
+                b"CONNECT google.com:443 HTTP/1.1\r\nHost: google.com:443\r\n\r\n",
+                (Host::Domain("google.com".to_string()), 443),
+            ),
+        ];
+
+        for (request, expected_target) in requests {
+            let mut client = TcpStream::connect(bind).await.unwrap();
+            client.write_all(request).await.unwrap();
+
+            let (stream, target) = tokio::time::timeout(Duration::from_secs(5), server.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            assert!(matches!(stream, Socks5Stream::Tcp(_)));
+            assert_eq!(target, expected_target);
         }
     }
 }
@@ -1,5 +1,6 @@
 mod server;
 
+pub use server::HAPPY_EYEBALLS_DELAY;
 pub use server::configure_socket;
 pub use server::connect;
 pub use server::connect_with_http_proxy;
@@ -9,15 +9,21 @@ use socket2::{SockRef, TcpKeepalive};
 use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
 
 use crate::protocols::dns::DnsResolver;
+use crate::socket_bind::SocketBind;
+use crate::socket_protect::protect_socket;
 use crate::somark::SoMark;
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::select;
 use tokio::time::{sleep, timeout};
 use tokio_stream::wrappers::TcpListenerStream;
 use tracing::{debug, info, instrument};
 use url::{Host, Url};
 
+/// Delay between two connection attempts recommended by RFC8305
+pub const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
 pub fn configure_socket(socket: SockRef, so_mark: SoMark) -> Result<(), anyhow::Error> {
     socket
         .set_tcp_nodelay(true)
@@ -50,7 +56,9 @@ pub async fn connect(
     host: &Host<String>,
     port: u16,
     so_mark: SoMark,
+    socket_bind: &SocketBind,
     connect_timeout: Duration,
+    happy_eyeballs_delay: Duration,
     dns_resolver: &DnsResolver,
 ) -> Result<TcpStream, anyhow::Error> {
     info!("Opening TCP connection to {host}:{port}");
@@ -64,46 +72,70 @@ pub async fn connect(
         Host::Ipv6(ip) => vec![SocketAddr::V6(SocketAddrV6::new(*ip, port, 0, 0))],
     };
 
-    let mut cnx = None;
+    connect_to_addrs(socket_addrs, so_mark, socket_bind, connect_timeout, happy_eyeballs_delay)
+        .await
+        .map_err(|err| anyhow!("Cannot connect to tcp endpoint {host}:{port} {err:#}"))
+}
+
+async fn connect_to_addrs(
+    socket_addrs: Vec<SocketAddr>,
+    so_mark: SoMark,
+    socket_bind: &SocketBind,
+    connect_timeout: Duration,
+    happy_eyeballs_delay: Duration,
+) -> Result<TcpStream, anyhow::Error> {
+    // Happy eyeballs as per RFC8305, addresses are already interleaved by family by the dns resolver.
+    // A new connection attempt is started every happy_eyeballs_delay, or as soon as the previous one has failed.
+    // See https://datatracker.ietf.org/doc/html/rfc8305#section-5
+    let mut socket_addrs = socket_addrs.into_iter().peekable();
     let mut last_err = None;
     let mut join_set = JoinSet::new();
-
-    for (ix, addr) in socket_addrs.into_iter().enumerate() {
-        let socket = match &addr {
-            SocketAddr::V4(_) => TcpSocket::new_v4(),
-            SocketAddr::V6(_) => TcpSocket::new_v6(),
-        };
-        let socket = match socket {
-            Ok(s) => s,
-            Err(err) => {
+    loop {
+        if let Some(addr) = socket_addrs.next() {
+            let socket = match &addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4(),
+                SocketAddr::V6(_) => TcpSocket::new_v6(),
+            };
+            let socket = match socket {
+                Ok(s) => s,
+                Err(err) => {
+                    last_err = Some(err);
+                    continue;
+                }
+            };
+            configure_socket(socket2::SockRef::from(&socket), so_mark)?;
+            if let Err(err) = socket_bind.bind(socket2::SockRef::from(&socket), &addr) {
+                debug!("Cannot bind socket to connect to {addr} reason {err}");
                 last_err = Some(err);
                 continue;
             }
+            protect_socket(socket2::SockRef::from(&socket)).context("cannot protect socket")?;
+
+            join_set.spawn(async move {
+                debug!("Connecting to {}", addr);
+                match timeout(connect_timeout, socket.connect(addr)).await {
+                    Ok(Ok(s)) => Ok(Ok(s)),
+                    Ok(Err(e)) => Ok(Err((addr, e))),
+                    Err(e) => Err((addr, e)),
+                }
+            });
+        }
+
+        if join_set.is_empty() {
+            break;
+        }
+
+        // Wait for an attempt to finish, or for the delay to elapse before starting the next one
+        let has_next_attempt = socket_addrs.peek().is_some();
+        let res = select! {
+            biased;
+            res = join_set.join_next() => res,
+            _ = sleep(happy_eyeballs_delay), if has_next_attempt => continue,
         };
-        configure_socket(socket2::SockRef::from(&socket), so_mark)?;
-
-        // Spawn the connection attempt in the join set.
-        // We include a delay of ix * 250 milliseconds, as per RFC8305.
-        // See https://datatracker.ietf.org/doc/html/rfc8305#section-5
-        let fut = async move {
-            if ix > 0 {
-                sleep(Duration::from_millis(250 * ix as u64)).await;
-            }
-            debug!("Connecting to {}", addr);
-            match timeout(connect_timeout, socket.connect(addr)).await {
-                Ok(Ok(s)) => Ok(Ok(s)),
-                Ok(Err(e)) => Ok(Err((addr, e))),
-                Err(e) => Err((addr, e)),
-            }
+        let Some(res) = res else {
+            continue;
         };
-        join_set.spawn(fut);
-    }
 
-    // Wait for the next future that finishes in the join set, until we got one
-    // that resulted in a successful connection.
-    // If cnx is no longer None, we exit the loop, since this means that we got
-    // a successful connection.
-    while let (None, Some(res)) = (&cnx, join_set.join_next().await) {
         match res? {
             Ok(Ok(stream)) => {
                 // We've got a successful connection, so we can abort all other
@@ -114,7 +146,7 @@ pub async fn connect(
                     "Connected to tcp endpoint {}, aborted all other connection attempts",
                     stream.peer_addr()?
                 );
-                cnx = Some(stream);
+                return Ok(stream);
             }
             Ok(Err((addr, err))) => {
                 debug!("Cannot connect to tcp endpoint {addr} reason {err}");
@@ -129,23 +161,35 @@ pub async fn connect(
         }
     }
 
-    cnx.ok_or_else(|| anyhow!("Cannot connect to tcp endpoint {host}:{port} reason {last_err:?}"))
+    Err(anyhow!("reason {last_err:?}"))
 }
 
+#[allow(clippy::too_many_arguments)]
 #[instrument(level = "info", name = "http_proxy", skip_all)]
 pub async fn connect_with_http_proxy(
     proxy: &Url,
     host: &Host<String>,
     port: u16,
     so_mark: SoMark,
+    socket_bind: &SocketBind,
     connect_timeout: Duration,
+    happy_eyeballs_delay: Duration,
     dns_resolver: &DnsResolver,
 ) -> Result<TcpStream, anyhow::Error> {
     let proxy_host = proxy.host().context("Cannot parse proxy host")?.to_owned();
     let proxy_port = proxy.port_or_known_default().unwrap_or(80);
 
     info!("Connecting to http proxy {}:{}", proxy_host, proxy_port);
-    let mut socket = connect(&proxy_host, proxy_port, so_mark, connect_timeout, dns_resolver).await?;
+    let mut socket = connect(
+        &proxy_host,
+        proxy_port,
+        so_mark,
+        socket_bind,
+        connect_timeout,
+        happy_eyeballs_delay,
+        dns_resolver,
+    )
+    .await?;
     debug!("Connected to http proxy {}", socket.peer_addr()?);
 
     let authorization = if let Some((user, password)) = proxy.password().map(|p| (proxy.username(), p)) {
@@ -258,6 +302,35 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_happy_eyeballs_blackholed_address() {
+        // A listener with a full accept queue drops the SYNs it receives, so connecting to it hangs
+        let blackhole = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+        blackhole.bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into()).unwrap();
+        blackhole.listen(0).unwrap();
+        let blackhole_addr = blackhole.local_addr().unwrap().as_socket().unwrap();
+        let mut queued = vec![];
+        while let Ok(stream) = timeout(Duration::from_millis(100), TcpStream::connect(blackhole_addr)).await {
+            queued.push(stream.unwrap());
+        }
+
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let start = tokio::time::Instant::now();
+        let stream = connect_to_addrs(
+            vec![blackhole_addr, server_addr],
+            SoMark::new(None),
+            &SocketBind::NONE,
+            Duration::from_secs(10),
+            Duration::from_millis(50),
+        )
+        .await
+        .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), server_addr);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
     #[tokio::test]
     async fn test_proxy_connection() {
         let (network_name, host) = if cfg!(not(target_os = "macos")) {
@@ -288,8 +361,10 @@ mod tests {
             &Host::Domain(host.to_string()),
             server_port,
             SoMark::new(None),
+            &SocketBind::NONE,
             Duration::from_secs(1),
-            &DnsResolver::System,
+            HAPPY_EYEBALLS_DELAY,
+            &DnsResolver::System { prefer_ipv4: false },
         )
         .await
         .unwrap();
@@ -8,3 +8,4 @@ pub use server::tls_acceptor;
 pub use server::tls_connector;
 pub use utils::cn_from_certificate;
 pub use utils::find_leaf_certificate;
+pub use utils::sans_from_certificate;
@@ -1,4 +1,6 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
 use tokio_rustls::rustls::pki_types::CertificateDer;
+use x509_parser::extensions::GeneralName;
 use x509_parser::parse_x509_certificate;
 use x509_parser::prelude::X509Certificate;
 
@@ -25,3 +27,25 @@ pub fn cn_from_certificate(tls_certificate_x509: &X509Certificate) -> Option<Str
         .next()
         .map(|cn| cn.to_string())
 }
+
+/// Returns the subject alternative names (SAN) of the supplied certificate: dns names, emails, uris and ip addresses.
+pub fn sans_from_certificate(tls_certificate_x509: &X509Certificate) -> Vec<String> {
+    let Ok(Some(sans)) = tls_certificate_x509.subject_alternative_name() else {
+        return vec![];
+    };
+
+    sans.value
+        .general_names
+        .iter()
+        .filter_map(|name| match name {
+            GeneralName::DNSName(name) | GeneralName::RFC822Name(name) | GeneralName::URI(name) => {
+                Some(name.to_string())
+            }
+            GeneralName::IPAddress(ip) => match <[u8; 4]>::try_from(*ip) {
+                Ok(ip) => Some(Ipv4Addr::from(ip).to_string()),
+                Err(_) => <[u8; 16]>::try_from(*ip).ok().map(|ip| Ipv6Addr::from(ip).to_string()),
+            },
+            _ => None,
+        })
+        .collect()
+}
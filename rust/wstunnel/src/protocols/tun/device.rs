@@ -0,0 +1,86 @@
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use std::collections::VecDeque;
+
+// Stop accepting packets from the stack when the tun device is not able to keep up
+const MAX_QUEUED_PACKETS: usize = 1024;
+
+/// Device of the userspace stack.
+/// Packets are exchanged with the tun file descriptor through in-memory queues, filled and drained by the stack task.
+pub struct TunDevice {
+    mtu: usize,
+    pub rx_queue: VecDeque<Vec<u8>>,
+    pub tx_queue: VecDeque<Vec<u8>>,
+}
+
+impl TunDevice {
+    pub fn new(mtu: usize) -> Self {
+        Self {
+            mtu,
+            rx_queue: VecDeque::new(),
+            tx_queue: VecDeque::new(),
+        }
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    pub fn is_rx_queue_full(&self) -> bool {
+        self.rx_queue.len() >= MAX_QUEUED_PACKETS
+    }
+}
+
+impl Device for TunDevice {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        if self.tx_queue.len() >= MAX_QUEUED_PACKETS {
+            return None;
+        }
+
+        let packet = self.rx_queue.pop_front()?;
+        Some((RxToken(packet), TxToken(&mut self.tx_queue)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        if self.tx_queue.len() >= MAX_QUEUED_PACKETS {
+            return None;
+        }
+
+        Some(TxToken(&mut self.tx_queue))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = self.mtu;
+        caps
+    }
+}
+
+pub struct RxToken(Vec<u8>);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+pub struct TxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut packet = vec![0; len];
+        let ret = f(&mut packet);
+        self.0.push_back(packet);
+        ret
+    }
+}
@@ -0,0 +1,6 @@
+mod device;
+mod server;
+
+pub use server::TunListener;
+pub use server::TunStream;
+pub use server::run_server;
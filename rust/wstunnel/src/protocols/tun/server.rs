@@ -0,0 +1,685 @@
+//! Userspace TCP/IP stack on top of a tun file descriptor, like the one returned by Android VpnService.
+//!
+//! Every TCP connection and UDP flow seen on the device is turned into a stream, so it can be forwarded into a
+//! tunnel like any other listener. TCP is terminated by smoltcp, while UDP datagrams are handled directly as
+//! they don't need any state.
+
+use super::device::TunDevice;
+use ahash::AHashMap;
+use anyhow::Context;
+use bytes::Bytes;
+use futures_util::Stream;
+use log::{debug, warn};
+use smoltcp::iface::{Config, Interface, PollResult, SocketHandle, SocketSet};
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::socket::tcp;
+use smoltcp::time::Instant as StackInstant;
+use smoltcp::wire::{
+    HardwareAddress, IpAddress, IpCidr, IpEndpoint, IpListenEndpoint, IpProtocol, IpRepr, Ipv4Address, Ipv4Packet,
+    Ipv6Address, Ipv6Packet, TcpPacket, UdpPacket, UdpRepr,
+};
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::fd::RawFd;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll, ready};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::AbortHandle;
+use tokio::time::{Interval, Sleep};
+use tokio_fd::AsyncFd;
+use tracing::{error, info};
+
+// Each tcp connection holds a send, a receive and a pipe buffer, so keep them small for phones with many connections
+const TCP_BUFFER_SIZE: usize = 64 * 1024;
+const UDP_HEADER_LEN: usize = 8;
+const UDP_QUEUE_SIZE: usize = 128;
+const MAX_PENDING_FLOWS: usize = 1024;
+// Yield back to the runtime from time to time when the device is busy, to not starve the tunnels
+const MAX_ITERATIONS_PER_POLL: usize = 64;
+
+/// Stream of the flows seen on the tun device, with their original destination
+pub struct TunListener {
+    flows_rx: mpsc::Receiver<(TunStream, SocketAddr)>,
+    stack_task: AbortHandle,
+}
+
+impl Stream for TunListener {
+    type Item = anyhow::Result<(TunStream, SocketAddr)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().flows_rx.poll_recv(cx).map(|x| x.map(Ok))
+    }
+}
+
+impl Drop for TunListener {
+    fn drop(&mut self) {
+        self.stack_task.abort();
+    }
+}
+
+pub enum TunStream {
+    Tcp(DuplexStream),
+    Udp(TunUdpStream),
+}
+
+impl AsyncRead for TunStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Udp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for TunStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Udp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Udp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Udp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+struct UdpDatagram {
+    src: SocketAddr,
+    dst: SocketAddr,
+    payload: Bytes,
+}
+
+/// UDP flow between an application behind the tun device and a destination.
+/// Each read returns a single datagram, and each write sends a single datagram back to the application.
+pub struct TunUdpStream {
+    local: SocketAddr,
+    remote: SocketAddr,
+    datagrams_rx: mpsc::Receiver<Bytes>,
+    replies_tx: mpsc::Sender<UdpDatagram>,
+}
+
+impl AsyncRead for TunUdpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if let Some(datagram) = ready!(self.get_mut().datagrams_rx.poll_recv(cx)) {
+            let len = datagram.len().min(buf.remaining());
+            buf.put_slice(&datagram[..len]);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for TunUdpStream {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let datagram = UdpDatagram {
+            src: self.remote,
+            dst: self.local,
+            payload: Bytes::copy_from_slice(buf),
+        };
+
+        // Like a real udp socket, the datagram is dropped if the stack is not able to keep up
+        match self.replies_tx.try_send(datagram) {
+            Ok(()) | Err(TrySendError::Full(_)) => Poll::Ready(Ok(buf.len())),
+            Err(TrySendError::Closed(_)) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "tun userspace stack is closed",
+            ))),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+struct TcpFlow {
+    handle: SocketHandle,
+    stream: DuplexStream,
+    tunnel_eof: bool,
+    socket_eof: bool,
+}
+
+struct UdpFlow {
+    datagrams_tx: mpsc::Sender<Bytes>,
+    last_activity: Instant,
+}
+
+struct Stack {
+    tun: AsyncFd,
+    read_buf: Vec<u8>,
+    device: TunDevice,
+    iface: Interface,
+    sockets: SocketSet<'static>,
+    tcp_flows: Vec<TcpFlow>,
+    udp_flows: AHashMap<(SocketAddr, SocketAddr), UdpFlow>,
+    udp_timeout: Option<Duration>,
+    udp_replies_tx: mpsc::Sender<UdpDatagram>,
+    udp_replies_rx: mpsc::Receiver<UdpDatagram>,
+    flows_tx: mpsc::Sender<(TunStream, SocketAddr)>,
+    timer: Pin<Box<Sleep>>,
+    udp_cleanup: Interval,
+}
+
+/// Return the transport protocol, the source and destination addresses and the transport payload of an ip packet
+fn parse_packet(packet: &[u8]) -> Option<(IpProtocol, IpAddr, IpAddr, &[u8])> {
+    match packet.first()? >> 4 {
+        4 => {
+            let packet = Ipv4Packet::new_checked(packet).ok()?;
+            // Fragments are left to the stack
+            if packet.more_frags() || packet.frag_offset() != 0 {
+                return None;
+            }
+            Some((
+                packet.next_header(),
+                IpAddr::V4(packet.src_addr()),
+                IpAddr::V4(packet.dst_addr()),
+                packet.payload(),
+            ))
+        }
+        6 => {
+            let packet = Ipv6Packet::new_checked(packet).ok()?;
+            Some((
+                packet.next_header(),
+                IpAddr::V6(packet.src_addr()),
+                IpAddr::V6(packet.dst_addr()),
+                packet.payload(),
+            ))
+        }
+        _ => None,
+    }
+}
+
+fn udp_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let ip_repr = IpRepr::new(
+        IpAddress::from(src.ip()),
+        IpAddress::from(dst.ip()),
+        IpProtocol::Udp,
+        UDP_HEADER_LEN + payload.len(),
+        64,
+    );
+
+    let checksum = ChecksumCapabilities::default();
+    let mut packet = vec![0; ip_repr.buffer_len()];
+    ip_repr.emit(&mut packet[..], &checksum);
+    let repr = UdpRepr {
+        src_port: src.port(),
+        dst_port: dst.port(),
+    };
+    repr.emit(
+        &mut UdpPacket::new_unchecked(&mut packet[ip_repr.header_len()..]),
+        &ip_repr.src_addr(),
+        &ip_repr.dst_addr(),
+        payload.len(),
+        |buf| buf.copy_from_slice(payload),
+        &checksum,
+    );
+
+    packet
+}
+
+impl Stack {
+    fn new(
+        tun: AsyncFd,
+        mtu: usize,
+        udp_timeout: Option<Duration>,
+        flows_tx: mpsc::Sender<(TunStream, SocketAddr)>,
+    ) -> anyhow::Result<Self> {
+        let mut device = TunDevice::new(mtu);
+        let mut config = Config::new(HardwareAddress::Ip);
+        config.random_seed = ahash::RandomState::new().hash_one(mtu);
+        let mut iface = Interface::new(config, &mut device, StackInstant::now());
+
+        // Accept packets for any destination, the stack is impersonating every host the applications talk to
+        iface.update_ip_addrs(|addrs| {
+            let _ = addrs.push(IpCidr::new(IpAddress::v4(0, 0, 0, 1), 0));
+            let _ = addrs.push(IpCidr::new(IpAddress::v6(0, 0, 0, 0, 0, 0, 0, 1), 0));
+        });
+        iface
+            .routes_mut()
+            .add_default_ipv4_route(Ipv4Address::new(0, 0, 0, 1))
+            .context("cannot add default ipv4 route")?;
+        iface
+            .routes_mut()
+            .add_default_ipv6_route(Ipv6Address::new(0, 0, 0, 0, 0, 0, 0, 1))
+            .context("cannot add default ipv6 route")?;
+        iface.set_any_ip(true);
+
+        let (udp_replies_tx, udp_replies_rx) = mpsc::channel(UDP_QUEUE_SIZE);
+        Ok(Self {
+            tun,
+            read_buf: vec![0; u16::MAX as usize],
+            device,
+            iface,
+            sockets: SocketSet::new(vec![]),
+            tcp_flows: Vec::new(),
+            udp_flows: AHashMap::new(),
+            udp_timeout,
+            udp_replies_tx,
+            udp_replies_rx,
+            flows_tx,
+            timer: Box::pin(tokio::time::sleep(Duration::ZERO)),
+            udp_cleanup: tokio::time::interval(Duration::from_secs(1)),
+        })
+    }
+
+    fn poll(&mut self, cx: &mut TaskContext<'_>) -> Poll<anyhow::Result<()>> {
+        for _ in 0..MAX_ITERATIONS_PER_POLL {
+            let mut progress = false;
+
+            // Packets coming from the applications
+            while !self.device.is_rx_queue_full() {
+                let mut buf = ReadBuf::new(&mut self.read_buf);
+                match Pin::new(&mut self.tun).poll_read(cx, &mut buf) {
+                    Poll::Ready(Ok(())) if buf.filled().is_empty() => return Poll::Ready(Ok(())),
+                    Poll::Ready(Ok(())) => {
+                        let packet = buf.filled().to_vec();
+                        self.on_packet(packet);
+                        progress = true;
+                    }
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err).context("cannot read from tun device")),
+                    Poll::Pending => break,
+                }
+            }
+
+            // Udp datagrams coming back from the tunnels
+            while let Poll::Ready(Some(datagram)) = self.udp_replies_rx.poll_recv(cx) {
+                self.on_udp_reply(datagram);
+                progress = true;
+            }
+
+            let now = StackInstant::now();
+            if self.iface.poll(now, &mut self.device, &mut self.sockets) == PollResult::SocketStateChanged {
+                progress = true;
+            }
+            progress |= self.forward_tcp_flows(cx);
+
+            // Packets going back to the applications
+            while let Some(packet) = self.device.tx_queue.front() {
+                match Pin::new(&mut self.tun).poll_write(cx, packet) {
+                    Poll::Ready(Ok(_)) => {
+                        self.device.tx_queue.pop_front();
+                        progress = true;
+                    }
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err).context("cannot write to tun device")),
+                    Poll::Pending => break,
+                }
+            }
+
+            if self.udp_cleanup.poll_tick(cx).is_ready() {
+                self.cleanup_udp_flows();
+            }
+
+            if progress {
+                continue;
+            }
+
+            // Nothing to do until the next packet or the next tcp timer
+            let Some(delay) = self.iface.poll_delay(now, &self.sockets) else {
+                return Poll::Pending;
+            };
+            self.timer
+                .as_mut()
+                .reset(tokio::time::Instant::now() + Duration::from_micros(delay.total_micros()));
+            if self.timer.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+
+    fn on_packet(&mut self, packet: Vec<u8>) {
+        match parse_packet(&packet) {
+            Some((IpProtocol::Udp, src, dst, payload)) => {
+                self.on_udp_packet(src, dst, payload);
+                return;
+            }
+            Some((IpProtocol::Tcp, src, dst, payload)) => self.on_tcp_packet(src, dst, payload),
+            _ => {}
+        }
+
+        self.device.rx_queue.push_back(packet);
+    }
+
+    fn on_tcp_packet(&mut self, src: IpAddr, dst: IpAddr, payload: &[u8]) {
+        let Ok(tcp) = TcpPacket::new_checked(payload) else {
+            return;
+        };
+        if !tcp.syn() || tcp.ack() {
+            return;
+        }
+
+        // A new connection is starting, a socket must be listening on its destination before the stack sees the syn
+        let src = IpEndpoint::from(SocketAddr::new(src, tcp.src_port()));
+        let dst = IpEndpoint::from(SocketAddr::new(dst, tcp.dst_port()));
+        let already_handled = self.tcp_flows.iter().any(|flow| {
+            let socket = self.sockets.get::<tcp::Socket>(flow.handle);
+            match socket.state() {
+                tcp::State::Listen => socket.listen_endpoint() == IpListenEndpoint::from(dst),
+                _ => socket.remote_endpoint() == Some(src) && socket.local_endpoint() == Some(dst),
+            }
+        });
+        if already_handled {
+            return;
+        }
+
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+        );
+        socket.set_nagle_enabled(false);
+        if let Err(err) = socket.listen(dst) {
+            warn!("Cannot accept TCP connection to {dst} on tun device: {err}");
+            return;
+        }
+
+        let (stream, tunnel_stream) = tokio::io::duplex(TCP_BUFFER_SIZE);
+        let destination = SocketAddr::new(dst.addr.into(), dst.port);
+        if self
+            .flows_tx
+            .try_send((TunStream::Tcp(tunnel_stream), destination))
+            .is_err()
+        {
+            warn!("Dropping TCP connection from {src} to {dst} on tun device, too many pending connections");
+            return;
+        }
+
+        debug!("New TCP connection from {src} to {dst} on tun device");
+        self.tcp_flows.push(TcpFlow {
+            handle: self.sockets.add(socket),
+            stream,
+            tunnel_eof: false,
+            socket_eof: false,
+        });
+    }
+
+    /// Move the data between the tcp sockets of the stack and the tunnels. Return true if some progress was made.
+    fn forward_tcp_flows(&mut self, cx: &mut TaskContext<'_>) -> bool {
+        let sockets = &mut self.sockets;
+        let mut progress = false;
+
+        self.tcp_flows.retain_mut(|flow| {
+            let socket = sockets.get_mut::<tcp::Socket>(flow.handle);
+            // Removed only on the next iteration, to let the stack send the last packets (i.e: RST after an abort)
+            if socket.state() == tcp::State::Closed {
+                sockets.remove(flow.handle);
+                return false;
+            }
+
+            // From the application to the tunnel
+            while socket.can_recv() {
+                let ret = socket.recv(|data| match Pin::new(&mut flow.stream).poll_write(cx, data) {
+                    Poll::Ready(Ok(len)) => (len, Ok(len)),
+                    Poll::Ready(Err(err)) => (0, Err(err)),
+                    Poll::Pending => (0, Ok(0)),
+                });
+                match ret {
+                    Ok(Ok(0)) => break,
+                    Ok(Ok(_)) => progress = true,
+                    Ok(Err(_)) | Err(_) => {
+                        // The tunnel is gone
+                        socket.abort();
+                        progress = true;
+                        return true;
+                    }
+                }
+            }
+
+            if !flow.socket_eof
+                && !socket.may_recv()
+                && !socket.can_recv()
+                && !matches!(socket.state(), tcp::State::Listen | tcp::State::SynReceived)
+                && Pin::new(&mut flow.stream).poll_shutdown(cx).is_ready()
+            {
+                flow.socket_eof = true;
+                progress = true;
+            }
+
+            // From the tunnel to the application
+            while !flow.tunnel_eof && socket.can_send() {
+                let ret = socket.send(|buf| {
+                    if buf.is_empty() {
+                        return (0, None);
+                    }
+
+                    let mut read_buf = ReadBuf::new(buf);
+                    match Pin::new(&mut flow.stream).poll_read(cx, &mut read_buf) {
+                        Poll::Ready(Ok(())) => {
+                            let len = read_buf.filled().len();
+                            (len, Some(len))
+                        }
+                        Poll::Ready(Err(_)) => (0, Some(0)),
+                        Poll::Pending => (0, None),
+                    }
+                });
+                match ret {
+                    Ok(Some(0)) | Err(_) => {
+                        flow.tunnel_eof = true;
+                        socket.close();
+                        progress = true;
+                    }
+                    Ok(Some(_)) => progress = true,
+                    Ok(None) => break,
+                }
+            }
+
+            true
+        });
+
+        progress
+    }
+
+    fn on_udp_packet(&mut self, src: IpAddr, dst: IpAddr, payload: &[u8]) {
+        let Ok(udp) = UdpPacket::new_checked(payload) else {
+            return;
+        };
+        let src = SocketAddr::new(src, udp.src_port());
+        let dst = SocketAddr::new(dst, udp.dst_port());
+        let datagram = Bytes::copy_from_slice(udp.payload());
+
+        if let Some(flow) = self.udp_flows.get_mut(&(src, dst))
+            && !flow.datagrams_tx.is_closed()
+        {
+            flow.last_activity = Instant::now();
+            if flow.datagrams_tx.try_send(datagram).is_err() {
+                debug!("Dropping UDP datagram from {src} to {dst} on tun device, tunnel is not keeping up");
+            }
+            return;
+        }
+
+        let (datagrams_tx, datagrams_rx) = mpsc::channel(UDP_QUEUE_SIZE);
+        let stream = TunUdpStream {
+            local: src,
+            remote: dst,
+            datagrams_rx,
+            replies_tx: self.udp_replies_tx.clone(),
+        };
+        if self.flows_tx.try_send((TunStream::Udp(stream), dst)).is_err() {
+            warn!("Dropping UDP flow from {src} to {dst} on tun device, too many pending flows");
+            return;
+        }
+
+        debug!("New UDP flow from {src} to {dst} on tun device");
+        let _ = datagrams_tx.try_send(datagram);
+        self.udp_flows.insert(
+            (src, dst),
+            UdpFlow {
+                datagrams_tx,
+                last_activity: Instant::now(),
+            },
+        );
+    }
+
+    fn on_udp_reply(&mut self, datagram: UdpDatagram) {
+        let UdpDatagram { src, dst, payload } = datagram;
+        if let Some(flow) = self.udp_flows.get_mut(&(dst, src)) {
+            flow.last_activity = Instant::now();
+        }
+
+        let packet = udp_packet(src, dst, &payload);
+        if packet.len() > self.device.mtu() {
+            debug!("Dropping UDP datagram from {src} to {dst} on tun device, it does not fit in the mtu");
+            return;
+        }
+
+        self.device.tx_queue.push_back(packet);
+    }
+
+    fn cleanup_udp_flows(&mut self) {
+        let timeout = self.udp_timeout;
+        self.udp_flows.retain(|_, flow| {
+            !flow.datagrams_tx.is_closed() && timeout.is_none_or(|timeout| flow.last_activity.elapsed() < timeout)
+        });
+    }
+}
+
+pub fn run_server(fd: RawFd, mtu: usize, udp_timeout: Option<Duration>) -> anyhow::Result<TunListener> {
+    info!(
+        "Starting tun userspace stack on fd {fd} with mtu {mtu} and udp timeout of {}s",
+        udp_timeout.unwrap_or(Duration::from_secs(0)).as_secs()
+    );
+
+    let tun = AsyncFd::try_from(fd).with_context(|| format!("Cannot use fd {fd} as a tun device"))?;
+    let (flows_tx, flows_rx) = mpsc::channel(MAX_PENDING_FLOWS);
+    let mut stack = Stack::new(tun, mtu, udp_timeout, flows_tx)?;
+    let stack_task = tokio::spawn(async move {
+        match std::future::poll_fn(|cx| stack.poll(cx)).await {
+            Ok(()) => info!("Tun device on fd {fd} has been closed"),
+            Err(err) => error!("Tun userspace stack on fd {fd} stopped: {err:?}"),
+        }
+    });
+
+    Ok(TunListener {
+        flows_rx,
+        stack_task: stack_task.abort_handle(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+    use smoltcp::wire::{TcpControl, TcpRepr, TcpSeqNumber};
+    use std::os::fd::AsRawFd;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixDatagram;
+
+    // The stack does not care about the kind of fd, as long as each read returns a whole ip packet
+    fn tun_pair() -> (std::os::unix::net::UnixDatagram, UnixDatagram) {
+        let (tun, app) = std::os::unix::net::UnixDatagram::pair().unwrap();
+        app.set_nonblocking(true).unwrap();
+        (tun, UnixDatagram::from_std(app).unwrap())
+    }
+
+    fn syn_packet(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+        let tcp_repr = TcpRepr {
+            src_port: src.port(),
+            dst_port: dst.port(),
+            control: TcpControl::Syn,
+            seq_number: TcpSeqNumber(1000),
+            ack_number: None,
+            window_len: 64240,
+            window_scale: None,
+            max_seg_size: Some(1460),
+            sack_permitted: false,
+            sack_ranges: [None; 3],
+            timestamp: None,
+            payload: &[],
+        };
+        let ip_repr = IpRepr::new(
+            IpAddress::from(src.ip()),
+            IpAddress::from(dst.ip()),
+            IpProtocol::Tcp,
+            tcp_repr.buffer_len(),
+            64,
+        );
+
+        let checksum = ChecksumCapabilities::default();
+        let mut packet = vec![0; ip_repr.buffer_len()];
+        ip_repr.emit(&mut packet[..], &checksum);
+        tcp_repr.emit(
+            &mut TcpPacket::new_unchecked(&mut packet[ip_repr.header_len()..]),
+            &ip_repr.src_addr(),
+            &ip_repr.dst_addr(),
+            &checksum,
+        );
+        packet
+    }
+
+    #[tokio::test]
+    async fn test_udp_flow() {
+        let (tun, app) = tun_pair();
+        let mut listener = run_server(tun.as_raw_fd(), 1500, Some(Duration::from_secs(10))).unwrap();
+        let src: SocketAddr = "10.0.0.2:1234".parse().unwrap();
+        let dst: SocketAddr = "1.1.1.1:53".parse().unwrap();
+
+        app.send(&udp_packet(src, dst, b"hello")).await.unwrap();
+        let (mut stream, destination) = listener.next().await.unwrap().unwrap();
+        assert!(matches!(stream, TunStream::Udp(_)));
+        assert_eq!(destination, dst);
+
+        let mut buf = [0u8; 64];
+        let len = stream.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+
+        // Same flow, no new stream
+        app.send(&udp_packet(src, dst, b"again")).await.unwrap();
+        let len = stream.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"again");
+
+        stream.write_all(b"world").await.unwrap();
+        let mut packet = [0u8; 1500];
+        let len = app.recv(&mut packet).await.unwrap();
+        let (protocol, reply_src, reply_dst, payload) = parse_packet(&packet[..len]).unwrap();
+        assert_eq!((protocol, reply_src, reply_dst), (IpProtocol::Udp, dst.ip(), src.ip()));
+        let udp = UdpPacket::new_checked(payload).unwrap();
+        assert_eq!((udp.src_port(), udp.dst_port()), (dst.port(), src.port()));
+        assert_eq!(udp.payload(), b"world");
+    }
+
+    #[tokio::test]
+    async fn test_tcp_connection() {
+        let (tun, app) = tun_pair();
+        let mut listener = run_server(tun.as_raw_fd(), 1500, None).unwrap();
+        let src: SocketAddr = "[fd00::2]:40000".parse().unwrap();
+        let dst: SocketAddr = "[2606:4700::1111]:443".parse().unwrap();
+
+        app.send(&syn_packet(src, dst)).await.unwrap();
+        // Retransmitted syn must not create a new connection
+        app.send(&syn_packet(src, dst)).await.unwrap();
+        let (stream, destination) = listener.next().await.unwrap().unwrap();
+        assert!(matches!(stream, TunStream::Tcp(_)));
+        assert_eq!(destination, dst);
+
+        let mut packet = [0u8; 1500];
+        let len = app.recv(&mut packet).await.unwrap();
+        let (protocol, reply_src, reply_dst, payload) = parse_packet(&packet[..len]).unwrap();
+        assert_eq!((protocol, reply_src, reply_dst), (IpProtocol::Tcp, dst.ip(), src.ip()));
+        let tcp = TcpPacket::new_checked(payload).unwrap();
+        assert!(tcp.syn() && tcp.ack());
+        assert_eq!(tcp.ack_number(), TcpSeqNumber(1001));
+
+        let next = tokio::time::timeout(Duration::from_millis(100), listener.next()).await;
+        assert!(next.is_err());
+    }
+}
@@ -21,6 +21,8 @@ use tokio::net::UdpSocket;
 use tokio::sync::futures::Notified;
 
 use crate::protocols::dns::DnsResolver;
+use crate::socket_bind::SocketBind;
+use crate::socket_protect::protect_socket;
 use crate::somark::SoMark;
 use tokio::sync::Notify;
 use tokio::time::{Interval, sleep, timeout};
@@ -338,7 +340,9 @@ pub async fn connect(
     host: &Host<String>,
     port: u16,
     connect_timeout: Duration,
+    happy_eyeballs_delay: Duration,
     so_mark: SoMark,
+    socket_bind: &SocketBind,
     dns_resolver: &DnsResolver,
 ) -> anyhow::Result<WsUdpSocket> {
     info!("Opening UDP connection to {}:{}", host, port);
@@ -357,10 +361,11 @@ pub async fn connect(
     let mut join_set = JoinSet::new();
 
     for (ix, addr) in socket_addrs.into_iter().enumerate() {
-        let socket = match &addr {
-            SocketAddr::V4(_) => UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await,
-            SocketAddr::V6(_) => UdpSocket::bind(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)).await,
+        let local = match &addr {
+            SocketAddr::V4(_) => SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)),
         };
+        let socket = socket_bind.bind_udp(local, &addr).and_then(UdpSocket::from_std);
 
         let socket = match socket {
             Ok(socket) => socket,
@@ -373,13 +378,14 @@ pub async fn connect(
         so_mark
             .set_mark(SockRef::from(&socket))
             .context("cannot set SO_MARK on socket")?;
+        protect_socket(SockRef::from(&socket)).context("cannot protect socket")?;
 
         // Spawn the connection attempt in the join set.
-        // We include a delay of ix * 250 milliseconds, as per RFC8305.
+        // We include a delay of ix * happy_eyeballs_delay, as per RFC8305.
         // See https://datatracker.ietf.org/doc/html/rfc8305#section-5
         let fut = async move {
             if ix > 0 {
-                sleep(Duration::from_millis(250 * ix as u64)).await;
+                sleep(happy_eyeballs_delay * ix as u32).await;
             }
 
             debug!("connecting to {}", addr);
@@ -75,9 +75,11 @@ impl RestrictionsRulesReloader {
         Ok(reloader)
     }
 
-    pub fn reload_restrictions_config(&self) {
+    /// Reload the restrictions from the config file. Returns false if there is no config file to reload from,
+    /// and the error if the file cannot be loaded, in which case the old restrictions are kept
+    pub fn reload_restrictions_config(&self) -> anyhow::Result<bool> {
         let restrictions = match &self.state {
-            Static => return,
+            Static => return Ok(false),
             Config(st) => match RestrictionsRules::from_config_file(&st.config_path) {
                 Ok(restrictions) => {
                     info!("Restrictions config file has been reloaded");
@@ -85,12 +87,13 @@ impl RestrictionsRulesReloader {
                 }
                 Err(err) => {
                     error!("Cannot reload restrictions config file, keeping the old one. Error: {:?}", err);
-                    return;
+                    return Err(err);
                 }
             },
         };
 
         self.restrictions.store(Arc::new(restrictions));
+        Ok(true)
     }
 
     pub const fn restrictions_rules(&self) -> &Arc<ArcSwap<RestrictionsRules>> {
@@ -152,7 +155,7 @@ impl RestrictionsRulesReloader {
         if let Some(path) = event.paths.iter().find(|p| p.ends_with(&this.config_path)) {
             match event.kind {
                 EventKind::Create(_) | EventKind::Modify(_) => {
-                    reloader.reload_restrictions_config();
+                    let _ = reloader.reload_restrictions_config();
                 }
                 EventKind::Remove(_) => {
                     warn!("Restriction config file has been removed, trying to re-set a watch for it");
@@ -165,3 +168,27 @@ impl RestrictionsRulesReloader {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload_restrictions_config() {
+        let rules = RestrictionsRules::from_path_prefix(&[], &[]).unwrap();
+        let reloader = RestrictionsRulesReloader::new(rules.clone(), None).unwrap();
+        assert!(!reloader.reload_restrictions_config().unwrap());
+
+        let path = std::env::temp_dir().join(format!("wstunnel-restrictions-{}.yaml", std::process::id()));
+        std::fs::write(&path, "restrictions:\n  - name: reloaded\n    match:\n      - !Any\n    allow: []\n").unwrap();
+        let reloader = RestrictionsRulesReloader::new(rules, Some(path.clone())).unwrap();
+        assert!(reloader.reload_restrictions_config().unwrap());
+        assert_eq!(reloader.restrictions_rules().load().restrictions[0].name, "reloaded");
+
+        // A config that does not parse is reported, and the rules in use are kept
+        std::fs::write(&path, "restrictions: [").unwrap();
+        assert!(reloader.reload_restrictions_config().is_err());
+        assert_eq!(reloader.restrictions_rules().load().restrictions[0].name, "reloaded");
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,297 @@
+use crate::config::HashAlgorithm;
+use anyhow::{Context, anyhow};
+use argon2::Argon2;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use base64::Engine;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, LazyLock};
+
+// Verified and rejected credentials are cached, as password hashes are slow to verify on purpose
+const MAX_CACHED_CREDENTIALS: usize = 1024;
+
+// Unknown users are verified against this hash, so that the response time does not tell which users exist
+static DUMMY_PASSWORD_HASH: LazyLock<String> =
+    LazyLock::new(|| hash_secret("dummy-password", HashAlgorithm::Argon2).expect("bug: cannot hash dummy password"));
+
+/// A user that restrictions can match with `!User [name]`, authenticated by the `Authorization` header of the request.
+/// i.e: `Authorization: Basic base64(name:password)` or `Authorization: Bearer <token>`
+#[derive(Clone, Deserialize)]
+pub struct UserConfig {
+    pub name: String,
+    /// Hash of the password, in argon2 ($argon2id$...), bcrypt ($2b$...) or sha-crypt ($5$... or $6$...) format
+    #[serde(default)]
+    pub password_hash: Option<String>,
+    /// Hex encoded SHA-256 of the bearer token
+    #[serde(default)]
+    pub token_sha256: Option<String>,
+}
+
+// The hashes must not end up in the logs, or in the admin api
+impl fmt::Debug for UserConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let redacted = |secret: &Option<String>| secret.as_ref().map(|_| "<redacted>");
+        f.debug_struct("UserConfig")
+            .field("name", &self.name)
+            .field("password_hash", &redacted(&self.password_hash))
+            .field("token_sha256", &redacted(&self.token_sha256))
+            .finish()
+    }
+}
+
+/// The users of the restrictions config.
+/// A reload of the config creates new users, so the credentials cache never outlives a change of them
+#[derive(Clone, Default, Deserialize)]
+#[serde(try_from = "Vec<UserConfig>")]
+pub struct Users {
+    users: Arc<[UserConfig]>,
+    // Name of the user authenticated by the sha256 of the credentials, or None if they were rejected
+    cache: Arc<Mutex<HashMap<[u8; 32], Option<String>>>>,
+}
+
+impl fmt::Debug for Users {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Users")
+            .field("users", &self.users)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TryFrom<Vec<UserConfig>> for Users {
+    type Error = anyhow::Error;
+
+    fn try_from(users: Vec<UserConfig>) -> Result<Self, Self::Error> {
+        for (ix, user) in users.iter().enumerate() {
+            if users[..ix].iter().any(|u| u.name == user.name) {
+                return Err(anyhow!("Duplicated user {}", user.name));
+            }
+            if user.password_hash.is_none() && user.token_sha256.is_none() {
+                return Err(anyhow!(
+                    "User {} has neither a password_hash nor a token_sha256",
+                    user.name
+                ));
+            }
+            if let Some(hash) = &user.password_hash {
+                validate_password_hash(hash).with_context(|| format!("Invalid password_hash of user {}", user.name))?;
+            }
+            if let Some(token) = &user.token_sha256
+                && (token.len() != 64 || !token.chars().all(|c| c.is_ascii_hexdigit()))
+            {
+                return Err(anyhow!(
+                    "Invalid token_sha256 of user {}, expected 64 hex characters",
+                    user.name
+                ));
+            }
+        }
+
+        Ok(Self {
+            users: users.into(),
+            cache: Default::default(),
+        })
+    }
+}
+
+impl Users {
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.users.iter().any(|u| u.name == name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.users.iter().map(|u| u.name.as_str())
+    }
+
+    /// Result of the last authentication of the value of the `Authorization` header, if it is still cached.
+    /// It is cheap, unlike `authenticate`
+    pub fn cached(&self, authorization: &str) -> Option<Option<String>> {
+        self.cache.lock().get(&cache_key(authorization)).cloned()
+    }
+
+    /// Return the name of the user authenticated by the value of the `Authorization` header, if any.
+    /// Verifying a password hash is cpu intensive, so it must not run on the async runtime
+    pub fn authenticate(&self, authorization: &str) -> Option<String> {
+        let key = cache_key(authorization);
+        if let Some(name) = self.cache.lock().get(&key) {
+            return name.clone();
+        }
+
+        let name = self.verify(authorization);
+        let mut cache = self.cache.lock();
+        if cache.len() >= MAX_CACHED_CREDENTIALS {
+            cache.clear();
+        }
+        cache.insert(key, name.clone());
+        name
+    }
+
+    fn verify(&self, authorization: &str) -> Option<String> {
+        let (scheme, credentials) = authorization.trim().split_once(' ')?;
+        let credentials = credentials.trim();
+
+        if scheme.eq_ignore_ascii_case("bearer") {
+            let token_sha256 = format!("{:x}", Sha256::digest(credentials.as_bytes()));
+            return self
+                .users
+                .iter()
+                .find(|user| {
+                    user.token_sha256
+                        .as_ref()
+                        .is_some_and(|hash| hash.eq_ignore_ascii_case(&token_sha256))
+                })
+                .map(|user| user.name.clone());
+        }
+
+        if scheme.eq_ignore_ascii_case("basic") {
+            let credentials = base64::engine::general_purpose::STANDARD.decode(credentials).ok()?;
+            let (name, password) = std::str::from_utf8(&credentials).ok()?.split_once(':')?;
+            let user = self.users.iter().find(|user| user.name == name);
+            let hash = user.and_then(|user| user.password_hash.as_deref());
+            let is_valid = verify_password(password, hash.unwrap_or(&DUMMY_PASSWORD_HASH));
+            return user
+                .filter(|_| is_valid && hash.is_some())
+                .map(|user| user.name.clone());
+        }
+
+        None
+    }
+}
+
+fn cache_key(authorization: &str) -> [u8; 32] {
+    Sha256::digest(authorization.as_bytes()).into()
+}
+
+fn validate_password_hash(hash: &str) -> anyhow::Result<()> {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).map_err(|err| anyhow!("{err}"))?;
+        return Ok(());
+    }
+
+    if ["$2a$", "$2b$", "$2y$", "$5$", "$6$"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+    {
+        return Ok(());
+    }
+
+    Err(anyhow!(
+        "unsupported format, expected an argon2, bcrypt or sha-crypt hash"
+    ))
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        return PasswordHash::new(hash)
+            .is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok());
+    }
+
+    pwhash::unix::verify(password, hash)
+}
+
+/// Hash a password, or a bearer token, for the `users` section of the restrictions config
+pub fn hash_secret(secret: &str, algorithm: HashAlgorithm) -> anyhow::Result<String> {
+    let hash = match algorithm {
+        HashAlgorithm::Argon2 => Argon2::default()
+            .hash_password(secret.as_bytes(), &SaltString::generate(&mut OsRng))
+            .map_err(|err| anyhow!("Cannot hash password: {err}"))?
+            .to_string(),
+        HashAlgorithm::Bcrypt => pwhash::bcrypt::hash(secret).context("Cannot hash password")?,
+        HashAlgorithm::ShaCrypt => pwhash::sha512_crypt::hash(secret).context("Cannot hash password")?,
+        HashAlgorithm::Sha256 => format!("{:x}", Sha256::digest(secret.as_bytes())),
+    };
+
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn basic(name: &str, password: &str) -> String {
+        format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD.encode(format!("{name}:{password}"))
+        )
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let users = Users::try_from(vec![
+            UserConfig {
+                name: "alice".to_string(),
+                password_hash: Some(hash_secret("alice-pwd", HashAlgorithm::Argon2).unwrap()),
+                token_sha256: None,
+            },
+            UserConfig {
+                name: "bob".to_string(),
+                password_hash: Some(hash_secret("bob-pwd", HashAlgorithm::Bcrypt).unwrap()),
+                token_sha256: Some(hash_secret("bob-token", HashAlgorithm::Sha256).unwrap()),
+            },
+            UserConfig {
+                name: "carol".to_string(),
+                password_hash: Some(hash_secret("carol-pwd", HashAlgorithm::ShaCrypt).unwrap()),
+                token_sha256: None,
+            },
+        ])
+        .unwrap();
+
+        assert_eq!(
+            users.authenticate(&basic("alice", "alice-pwd")).as_deref(),
+            Some("alice")
+        );
+        assert_eq!(users.authenticate(&basic("bob", "bob-pwd")).as_deref(), Some("bob"));
+        assert_eq!(
+            users.authenticate(&basic("carol", "carol-pwd")).as_deref(),
+            Some("carol")
+        );
+        assert_eq!(users.authenticate("Bearer bob-token").as_deref(), Some("bob"));
+
+        // Cached credentials are still verified against the right user
+        assert_eq!(
+            users.authenticate(&basic("alice", "alice-pwd")).as_deref(),
+            Some("alice")
+        );
+        assert_eq!(users.authenticate(&basic("alice", "bob-pwd")), None);
+        assert_eq!(users.authenticate(&basic("dave", "alice-pwd")), None);
+        assert_eq!(users.authenticate("Bearer alice-pwd"), None);
+        assert_eq!(users.authenticate("alice-pwd"), None);
+
+        // Rejected credentials are cached too, so replaying them does not verify a hash again
+        assert_eq!(users.cached(&basic("alice", "bob-pwd")), Some(None));
+        assert_eq!(users.cached(&basic("dave", "alice-pwd")), Some(None));
+        assert_eq!(
+            users.cached(&basic("alice", "alice-pwd")),
+            Some(Some("alice".to_string()))
+        );
+        assert_eq!(users.cached(&basic("alice", "other-pwd")), None);
+
+        let debug = format!("{users:?}");
+        assert!(debug.contains("alice"));
+        assert!(!debug.contains("$argon2") && !debug.contains("$6$"), "{debug}");
+        assert!(
+            !debug.contains(&hash_secret("bob-token", HashAlgorithm::Sha256).unwrap()),
+            "{debug}"
+        );
+    }
+
+    #[test]
+    fn test_invalid_users() {
+        let user = |password_hash: Option<&str>, token_sha256: Option<&str>| UserConfig {
+            name: "alice".to_string(),
+            password_hash: password_hash.map(str::to_string),
+            token_sha256: token_sha256.map(str::to_string),
+        };
+
+        assert!(Users::try_from(vec![user(None, None)]).is_err());
+        assert!(Users::try_from(vec![user(Some("plaintext"), None)]).is_err());
+        assert!(Users::try_from(vec![user(None, Some("deadbeef"))]).is_err());
+        assert!(Users::try_from(vec![user(Some("$6$salt$hash"), None), user(Some("$6$salt$hash"), None)]).is_err());
+        assert!(Users::try_from(vec![user(Some("$6$salt$hash"), None)]).is_ok());
+    }
+}
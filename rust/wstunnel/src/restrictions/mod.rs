@@ -1,3 +1,4 @@
+use anyhow::anyhow;
 use ipnet::IpNet;
 use regex::Regex;
 use std::fs::File;
@@ -9,17 +10,37 @@ use std::str::FromStr;
 use std::vec;
 use types::RestrictionsRules;
 
-use crate::restrictions::types::{default_cidr, default_host};
+use crate::restrictions::types::{MatchConfig, default_cidr, default_host};
 
 pub mod config_reloader;
+pub mod credentials;
 pub mod types;
 
 impl RestrictionsRules {
     pub fn from_config_file(config_path: &Path) -> anyhow::Result<Self> {
         let restrictions: Self = serde_yaml::from_reader(BufReader::new(File::open(config_path)?))?;
+        restrictions.validate_users()?;
         Ok(restrictions)
     }
 
+    // A typo in the name of a user would give a restriction that silently never matches
+    fn validate_users(&self) -> anyhow::Result<()> {
+        for restriction in &self.restrictions {
+            for m in &restriction.r#match {
+                if let MatchConfig::User(names) = m
+                    && let Some(name) = names.iter().find(|name| !self.users.contains(name))
+                {
+                    return Err(anyhow!(
+                        "Restriction {} matches the user {name}, which is not in the users section",
+                        restriction.name
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn from_path_prefix(path_prefixes: &[String], restrict_to: &[(String, u16)]) -> anyhow::Result<Self> {
         let tunnels_restrictions = if restrict_to.is_empty() {
             let r = types::AllowConfig::Tunnel(types::AllowTunnelConfig {
@@ -71,6 +92,7 @@ impl RestrictionsRules {
                 name: "Allow All".to_string(),
                 r#match: vec![types::MatchConfig::Any],
                 allow: tunnels_restrictions,
+                limits: types::LimitsConfig::default(),
             };
             vec![r]
         } else {
@@ -82,19 +104,23 @@ impl RestrictionsRules {
                         name: format!("Allow path prefix {path_prefix}"),
                         r#match: vec![types::MatchConfig::PathPrefix(reg)],
                         allow: tunnels_restrictions.clone(),
+                        limits: types::LimitsConfig::default(),
                     })
                 })
                 .collect::<Result<Vec<_>, anyhow::Error>>()?
         };
 
-        Ok(Self { restrictions })
+        Ok(Self {
+            restrictions,
+            users: Default::default(),
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::restrictions::types::{AllowConfig, MatchConfig};
+    use crate::restrictions::types::AllowConfig;
     use std::net::Ipv4Addr;
 
     #[test]
@@ -216,4 +242,30 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_restriction_rule_with_unknown_user() {
+        let path = std::env::temp_dir().join(format!("wstunnel-restrictions-users-{}.yaml", std::process::id()));
+        let config = |user: &str| {
+            format!(
+                r#"
+restrictions:
+  - name: "team"
+    match:
+      - !User [{user}]
+    allow: []
+users:
+  - name: alice
+    token_sha256: "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"
+"#
+            )
+        };
+
+        std::fs::write(&path, config("alice")).unwrap();
+        assert!(RestrictionsRules::from_config_file(&path).is_ok());
+        std::fs::write(&path, config("alcie")).unwrap();
+        let err = RestrictionsRules::from_config_file(&path).unwrap_err();
+        assert!(err.to_string().contains("alcie"), "{err}");
+        let _ = std::fs::remove_file(&path);
+    }
 }
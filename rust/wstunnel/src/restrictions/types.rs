@@ -1,13 +1,19 @@
+use crate::restrictions::credentials::Users;
 use crate::tunnel::LocalProtocol;
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use jiff::Timestamp;
+use jiff::civil::{Time, Weekday};
+use jiff::tz::TimeZone;
 use regex::Regex;
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::HashMap;
-use std::ops::RangeInclusive;
+use std::ops::{Range, RangeInclusive};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct RestrictionsRules {
     pub restrictions: Vec<RestrictionConfig>,
+    #[serde(default)]
+    pub users: Users,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -16,6 +22,57 @@ pub struct RestrictionConfig {
     #[serde(deserialize_with = "deserialize_non_empty_vec")]
     pub r#match: Vec<MatchConfig>,
     pub allow: Vec<AllowConfig>,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+}
+
+/// Limits of the tunnels accepted by a restriction, for all of them and for those of each client ip.
+/// Unset limits are unlimited
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct LimitsConfig {
+    #[serde(default)]
+    pub total: LimitConfig,
+    #[serde(default)]
+    pub per_client_ip: LimitConfig,
+    #[serde(default)]
+    pub quota: Option<QuotaConfig>,
+}
+
+/// Bytes the tunnels of a restriction can transfer, in both directions, per calendar period in UTC.
+/// Once exhausted, new tunnels are denied until the next period
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct QuotaConfig {
+    pub bytes: u64,
+    pub period: QuotaPeriod,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub enum QuotaPeriod {
+    Daily,
+    Monthly,
+}
+
+/// Rates must be greater than 0, a limit that is not set is unlimited
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct LimitConfig {
+    pub max_concurrent_tunnels: Option<usize>,
+    #[serde(deserialize_with = "deserialize_rate")]
+    #[serde(default)]
+    pub new_tunnels_per_sec: Option<f64>,
+    /// Bytes per second sent by the client
+    #[serde(deserialize_with = "deserialize_bandwidth")]
+    #[serde(default)]
+    pub bandwidth_up: Option<u64>,
+    /// Bytes per second sent to the client
+    #[serde(deserialize_with = "deserialize_bandwidth")]
+    #[serde(default)]
+    pub bandwidth_down: Option<u64>,
+}
+
+impl LimitConfig {
+    pub fn is_unlimited(&self) -> bool {
+        self == &Self::default()
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -25,6 +82,48 @@ pub enum MatchConfig {
     PathPrefix(Regex),
     #[serde(with = "serde_regex")]
     Authorization(Regex),
+    /// Match any of these users of the `users` section, authenticated by the `Authorization` header
+    User(Vec<String>),
+    /// Match the ip of the client, or the one in X-Forwarded-For when the request comes from a --trusted-proxy.
+    /// The header of any other client is ignored, so it cannot claim an ip of the cidrs
+    ClientCidr(Vec<IpNet>),
+    /// Match the common name of the verified mTLS client certificate
+    #[serde(with = "serde_regex")]
+    CertificateCn(Regex),
+    /// Match any subject alternative name (dns, email, uri or ip) of the verified mTLS client certificate
+    #[serde(with = "serde_regex")]
+    CertificateSan(Regex),
+    /// Match any value of the request header, i.e: `!Header { name: X-Team, regex: "^ops$" }`
+    Header {
+        name: String,
+        #[serde(with = "serde_regex")]
+        regex: Regex,
+    },
+    /// Match until this instant, i.e: 2025-12-31T23:59:59Z or 2025-12-31T23:59:59+01:00
+    NotAfter(Timestamp),
+    /// Match from this instant
+    NotBefore(Timestamp),
+    Schedule(ScheduleConfig),
+}
+
+/// Weekly time window, i.e: `days: [Mon, Tue, Wed, Thu, Fri]`, `hours: "08:00..18:00"`, `timezone: Europe/Paris`
+/// The end of the hours is excluded, a window ending before it starts spans midnight and belongs to the day it
+/// starts, i.e: "22:00..06:00" with `days: [Fri]` is active from friday 22:00 to saturday 06:00,
+/// and a window ending when it starts lasts the whole day, which is the default.
+/// Days and hours are those of the local time in the timezone, which defaults to UTC
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScheduleConfig {
+    #[serde(deserialize_with = "deserialize_weekdays")]
+    #[serde(default = "default_weekdays")]
+    pub days: Vec<Weekday>,
+
+    #[serde(deserialize_with = "deserialize_hours")]
+    #[serde(default = "default_hours")]
+    pub hours: Range<Time>,
+
+    #[serde(deserialize_with = "deserialize_timezone")]
+    #[serde(default = "default_timezone")]
+    pub timezone: TimeZone,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -72,14 +171,14 @@ pub struct AllowReverseTunnelConfig {
     pub unix_path: Regex,
 }
 
-#[derive(Debug, Clone, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Deserialize, Serialize, Eq, PartialEq)]
 pub enum TunnelConfigProtocol {
     Tcp,
     Udp,
     Unknown,
 }
 
-#[derive(Debug, Clone, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Deserialize, Serialize, Eq, PartialEq)]
 pub enum ReverseTunnelConfigProtocol {
     Tcp,
     Udp,
@@ -97,6 +196,26 @@ pub fn default_cidr() -> Vec<IpNet> {
     vec![IpNet::V4(Ipv4Net::default()), IpNet::V6(Ipv6Net::default())]
 }
 
+pub fn default_weekdays() -> Vec<Weekday> {
+    vec![
+        Weekday::Monday,
+        Weekday::Tuesday,
+        Weekday::Wednesday,
+        Weekday::Thursday,
+        Weekday::Friday,
+        Weekday::Saturday,
+        Weekday::Sunday,
+    ]
+}
+
+pub fn default_hours() -> Range<Time> {
+    Time::midnight()..Time::midnight()
+}
+
+pub fn default_timezone() -> TimeZone {
+    TimeZone::UTC
+}
+
 fn deserialize_port_range<'de, D>(deserializer: D) -> Result<Vec<RangeInclusive<u16>>, D::Error>
 where
     D: Deserializer<'de>,
@@ -143,6 +262,72 @@ where
         .collect()
 }
 
+fn deserialize_weekdays<'de, D>(deserializer: D) -> Result<Vec<Weekday>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let days = Vec::<String>::deserialize(deserializer)?;
+    days.into_iter()
+        .map(|day| match day.to_ascii_lowercase().as_str() {
+            "mon" | "monday" => Ok(Weekday::Monday),
+            "tue" | "tuesday" => Ok(Weekday::Tuesday),
+            "wed" | "wednesday" => Ok(Weekday::Wednesday),
+            "thu" | "thursday" => Ok(Weekday::Thursday),
+            "fri" | "friday" => Ok(Weekday::Friday),
+            "sat" | "saturday" => Ok(Weekday::Saturday),
+            "sun" | "sunday" => Ok(Weekday::Sunday),
+            _ => Err(serde::de::Error::custom(format!("Invalid day of the week: {day}"))),
+        })
+        .collect()
+}
+
+fn deserialize_hours<'de, D>(deserializer: D) -> Result<Range<Time>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let hours = String::deserialize(deserializer)?;
+    let Some((start, end)) = hours.split_once("..") else {
+        return Err(serde::de::Error::custom(format!(
+            "Invalid hours, expected START..END: {hours}"
+        )));
+    };
+    let start = start.trim().parse::<Time>().map_err(serde::de::Error::custom)?;
+    let end = end.trim().parse::<Time>().map_err(serde::de::Error::custom)?;
+    Ok(start..end)
+}
+
+fn deserialize_timezone<'de, D>(deserializer: D) -> Result<TimeZone, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let name = String::deserialize(deserializer)?;
+    TimeZone::get(&name).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_rate<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<f64>::deserialize(deserializer)? {
+        Some(rate) if !rate.is_finite() || rate <= 0.0 => Err(serde::de::Error::custom(format!(
+            "Invalid rate {rate}, it must be greater than 0. Do not set it for no limit"
+        ))),
+        rate => Ok(rate),
+    }
+}
+
+fn deserialize_bandwidth<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<u64>::deserialize(deserializer)? {
+        Some(0) => Err(serde::de::Error::custom(
+            "Invalid bandwidth 0, it must be greater than 0. Do not set it for no limit",
+        )),
+        bandwidth => Ok(bandwidth),
+    }
+}
+
 fn deserialize_non_empty_vec<'de, D, T>(d: D) -> Result<Vec<T>, D::Error>
 where
     D: Deserializer<'de>,
@@ -163,6 +348,10 @@ impl From<&LocalProtocol> for ReverseTunnelConfigProtocol {
             | LocalProtocol::Udp { .. }
             | LocalProtocol::Stdio { .. }
             | LocalProtocol::Socks5 { .. }
+            | LocalProtocol::Mixed { .. }
+            | LocalProtocol::Dns { .. }
+            | LocalProtocol::FakeDns { .. }
+            | LocalProtocol::Tun { .. }
             | LocalProtocol::TProxyTcp
             | LocalProtocol::TProxyUdp { .. }
             | LocalProtocol::HttpProxy { .. }
@@ -184,6 +373,10 @@ impl From<&LocalProtocol> for TunnelConfigProtocol {
             | LocalProtocol::ReverseUnix { .. }
             | LocalProtocol::Stdio { .. }
             | LocalProtocol::Socks5 { .. }
+            | LocalProtocol::Mixed { .. }
+            | LocalProtocol::Dns { .. }
+            | LocalProtocol::FakeDns { .. }
+            | LocalProtocol::Tun { .. }
             | LocalProtocol::TProxyTcp
             | LocalProtocol::TProxyUdp { .. }
             | LocalProtocol::HttpProxy { .. }
@@ -0,0 +1,175 @@
+//! socket bind - choose the local address or the network interface used by outbound sockets
+//!
+//! Binding to an interface is only available on linux/android (SO_BINDTODEVICE) and apple platforms (IP_BOUND_IF)
+
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Clone, Debug, Default)]
+pub struct SocketBind {
+    address: Option<IpAddr>,
+    interface: Option<String>,
+}
+
+impl SocketBind {
+    pub const NONE: SocketBind = SocketBind {
+        address: None,
+        interface: None,
+    };
+
+    pub const fn new(address: Option<IpAddr>, interface: Option<String>) -> Self {
+        Self { address, interface }
+    }
+
+    /// Bind the socket before it connects to the remote address.
+    /// Fail if the source address is not of the same ip family as the remote address.
+    pub fn bind(&self, socket: SockRef, remote: &SocketAddr) -> io::Result<()> {
+        if let Some(interface) = &self.interface {
+            bind_interface(&socket, interface, remote)?;
+        }
+
+        if let Some(address) = self.source_address(remote)? {
+            socket.bind(&SocketAddr::new(address, 0).into())?;
+        }
+
+        Ok(())
+    }
+
+    /// Create an udp socket to send datagrams to the remote address.
+    /// The socket is bound to the local address, unless a source address is set, in which case only its port is kept.
+    pub fn bind_udp(&self, local: SocketAddr, remote: &SocketAddr) -> io::Result<std::net::UdpSocket> {
+        let local = match self.source_address(remote)? {
+            Some(address) => SocketAddr::new(address, local.port()),
+            None => local,
+        };
+
+        let socket = Socket::new(Domain::for_address(local), Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_nonblocking(true)?;
+        if let Some(interface) = &self.interface {
+            bind_interface(&SockRef::from(&socket), interface, remote)?;
+        }
+        socket.bind(&local.into())?;
+
+        Ok(socket.into())
+    }
+
+    fn source_address(&self, remote: &SocketAddr) -> io::Result<Option<IpAddr>> {
+        match self.address {
+            Some(address) if address.is_ipv4() != remote.is_ipv4() => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("cannot use source address {address} to connect to {remote}"),
+            )),
+            address => Ok(address),
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_interface(socket: &SockRef, interface: &str, _remote: &SocketAddr) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn bind_interface(socket: &SockRef, interface: &str, remote: &SocketAddr) -> io::Result<()> {
+    let index = nix::net::if_::if_nametoindex(interface).map_err(io::Error::from)?;
+    let index = std::num::NonZeroU32::new(index);
+    match remote {
+        SocketAddr::V4(_) => socket.bind_device_by_index_v4(index),
+        SocketAddr::V6(_) => socket.bind_device_by_index_v6(index),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios")))]
+fn bind_interface(_socket: &SockRef, interface: &str, _remote: &SocketAddr) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("cannot bind socket to interface {interface}, not supported on this platform"),
+    ))
+}
+
+// Only linux routes the whole 127.0.0.0/8 to the loopback interface
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::protocols;
+    use crate::protocols::dns::DnsResolver;
+    use crate::somark::SoMark;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, UdpSocket};
+    use url::Host;
+
+    #[tokio::test]
+    async fn test_bind_source_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let host = Host::Ipv4([127, 0, 0, 1].into());
+
+        let socket_bind = SocketBind::new(Some("127.0.0.2".parse().unwrap()), None);
+        let stream = protocols::tcp::connect(
+            &host,
+            port,
+            SoMark::new(None),
+            &socket_bind,
+            Duration::from_secs(1),
+            protocols::tcp::HAPPY_EYEBALLS_DELAY,
+            &DnsResolver::System { prefer_ipv4: false },
+        )
+        .await
+        .unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), peer.ip());
+        assert_eq!(peer.ip(), "127.0.0.2".parse::<IpAddr>().unwrap());
+
+        // Source address of another ip family cannot be used
+        let socket_bind = SocketBind::new(Some("::1".parse().unwrap()), None);
+        let ret = protocols::tcp::connect(
+            &host,
+            port,
+            SoMark::new(None),
+            &socket_bind,
+            Duration::from_secs(1),
+            protocols::tcp::HAPPY_EYEBALLS_DELAY,
+            &DnsResolver::System { prefer_ipv4: false },
+        )
+        .await;
+        assert!(ret.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let socket_bind = SocketBind::new(Some("127.0.0.2".parse().unwrap()), Some("lo".to_string()));
+
+        let socket = socket_bind
+            .bind_udp("0.0.0.0:0".parse().unwrap(), &server_addr)
+            .unwrap();
+        assert_eq!(
+            socket.local_addr().unwrap().ip(),
+            "127.0.0.2".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            SockRef::from(&socket).device().unwrap().as_deref(),
+            Some(b"lo".as_slice())
+        );
+
+        let mut client = protocols::udp::connect(
+            &Host::Ipv4([127, 0, 0, 1].into()),
+            server_addr.port(),
+            Duration::from_secs(1),
+            protocols::tcp::HAPPY_EYEBALLS_DELAY,
+            SoMark::new(None),
+            &socket_bind,
+            &DnsResolver::System { prefer_ipv4: false },
+        )
+        .await
+        .unwrap();
+        client.write_all(b"ping").await.unwrap();
+
+        let mut buf = [0u8; 4];
+        let (_, peer) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(peer.ip(), "127.0.0.2".parse::<IpAddr>().unwrap());
+    }
+}
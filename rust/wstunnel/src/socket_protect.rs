@@ -0,0 +1,99 @@
+//! socket protect - hook to let the host application configure outbound sockets before they connect
+//!
+//! i.e: an Android VPN app must call VpnService.protect on the sockets of the tunnel,
+//! otherwise their traffic would loop back into the VPN. On non unix platforms it's noop
+
+use socket2::SockRef;
+
+#[cfg(unix)]
+pub type SocketProtector = std::sync::Arc<dyn Fn(std::os::fd::RawFd) -> bool + Send + Sync>;
+
+#[cfg(unix)]
+static SOCKET_PROTECTOR: parking_lot::RwLock<Option<SocketProtector>> = parking_lot::RwLock::new(None);
+
+/// Set the hook invoked on every outbound socket before it connects.
+/// If the hook returns false, the socket is not used.
+#[cfg(unix)]
+pub fn set_socket_protector(protector: Option<SocketProtector>) {
+    *SOCKET_PROTECTOR.write() = protector;
+}
+
+#[cfg(not(unix))]
+#[inline]
+pub fn protect_socket(_: SockRef) -> Result<(), std::convert::Infallible> {
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn protect_socket(socket: SockRef) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let Some(protector) = SOCKET_PROTECTOR.read().clone() else {
+        return Ok(());
+    };
+
+    let fd = socket.as_raw_fd();
+    if protector(fd) {
+        Ok(())
+    } else {
+        Err(std::io::Error::other(format!(
+            "socket {fd} has been rejected by the protect hook"
+        )))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::protocols;
+    use crate::protocols::dns::DnsResolver;
+    use crate::socket_bind::SocketBind;
+    use crate::somark::SoMark;
+    use parking_lot::Mutex;
+    use serial_test::serial;
+    use std::os::fd::{AsRawFd, RawFd};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use url::Host;
+
+    // The protector is global, so reset it even if the test fails to not leak it into the other tests
+    struct ProtectorGuard;
+
+    impl Drop for ProtectorGuard {
+        fn drop(&mut self) {
+            set_socket_protector(None);
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_protect_outbound_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let protected_fds = Arc::new(Mutex::new(Vec::<RawFd>::new()));
+        let _guard = ProtectorGuard;
+        set_socket_protector(Some(Arc::new({
+            let protected_fds = protected_fds.clone();
+            move |fd| {
+                protected_fds.lock().push(fd);
+                true
+            }
+        })));
+
+        let stream = protocols::tcp::connect(
+            &Host::Ipv4([127, 0, 0, 1].into()),
+            port,
+            SoMark::new(None),
+            &SocketBind::NONE,
+            Duration::from_secs(1),
+            protocols::tcp::HAPPY_EYEBALLS_DELAY,
+            &DnsResolver::System { prefer_ipv4: false },
+        )
+        .await
+        .unwrap();
+
+        assert!(protected_fds.lock().contains(&stream.as_raw_fd()));
+    }
+}
@@ -3,10 +3,11 @@ use crate::protocols;
 use crate::protocols::dns::DnsResolver;
 use crate::restrictions::types;
 use crate::restrictions::types::{AllowConfig, MatchConfig, RestrictionConfig, RestrictionsRules};
+use crate::socket_bind::SocketBind;
 use crate::somark::SoMark;
 use crate::tunnel::client::{WsClient, WsClientConfig};
 use crate::tunnel::listeners::{TcpTunnelListener, UdpTunnelListener};
-use crate::tunnel::server::{WsServer, WsServerConfig};
+use crate::tunnel::server::{FallbackConfig, WsServer, WsServerConfig};
 use crate::tunnel::transport::{TransportAddr, TransportScheme};
 use bytes::BytesMut;
 use futures_util::StreamExt;
@@ -25,23 +26,47 @@ use url::Host;
 
 #[fixture]
 fn dns_resolver() -> DnsResolver {
-    DnsResolver::new_from_urls(&[], None, SoMark::new(None), true).expect("Cannot create DNS resolver")
+    DnsResolver::new_from_urls(
+        &[],
+        None,
+        SoMark::new(None),
+        SocketBind::NONE,
+        protocols::tcp::HAPPY_EYEBALLS_DELAY,
+        true,
+    )
+    .expect("Cannot create DNS resolver")
 }
 
 #[fixture]
-fn server_no_tls(dns_resolver: DnsResolver) -> WsServer {
-    let server_config = WsServerConfig {
+fn server_config(dns_resolver: DnsResolver) -> WsServerConfig {
+    WsServerConfig {
         socket_so_mark: SoMark::new(None),
         bind: "127.0.0.1:8080".parse().unwrap(),
         websocket_ping_frequency: Some(Duration::from_secs(10)),
+        websocket_pong_timeout: Some(Duration::from_secs(10)),
         timeout_connect: Duration::from_secs(10),
+        happy_eyeballs_delay: protocols::tcp::HAPPY_EYEBALLS_DELAY,
+        tcp_idle_timeout: None,
+        tcp_half_close_timeout: Some(Duration::from_secs(30)),
+        drain_timeout: Duration::from_secs(5),
         websocket_mask_frame: false,
         tls: None,
         dns_resolver,
         restriction_config: None,
         http_proxy: None,
         remote_server_idle_timeout: Duration::from_secs(30),
-    };
+        metrics_bind: None,
+        admin_bind: None,
+        admin_token: None,
+        fallback: None,
+        quota_state_file: None,
+        trusted_proxies: vec![],
+        accept_proxy_protocol: vec![],
+    }
+}
+
+#[fixture]
+fn server_no_tls(server_config: WsServerConfig) -> WsServer {
     WsServer::new(server_config, DefaultTokioExecutor::default())
 }
 
@@ -51,13 +76,20 @@ async fn client_ws(dns_resolver: DnsResolver) -> WsClient {
         remote_addr: TransportAddr::new(TransportScheme::Ws, Host::Ipv4("127.0.0.1".parse().unwrap()), 8080, None)
             .unwrap(),
         socket_so_mark: SoMark::new(None),
+        socket_bind: SocketBind::NONE,
         http_upgrade_path_prefix: "wstunnel".to_string(),
         http_upgrade_credentials: None,
         http_headers: HashMap::new(),
         http_headers_file: None,
         http_header_host: HeaderValue::from_static("127.0.0.1:8080"),
         timeout_connect: Duration::from_secs(10),
+        happy_eyeballs_delay: protocols::tcp::HAPPY_EYEBALLS_DELAY,
+        tcp_idle_timeout: None,
+        tcp_half_close_timeout: Some(Duration::from_secs(30)),
+        drain_timeout: Duration::from_secs(5),
         websocket_ping_frequency: Some(Duration::from_secs(10)),
+        websocket_pong_timeout: Some(Duration::from_secs(10)),
+        keepalive_profile: None,
         websocket_mask_frame: false,
         dns_resolver,
         http_proxy: None,
@@ -103,7 +135,9 @@ fn no_restrictions() -> RestrictionsRules {
             name: "".to_string(),
             r#match: vec![MatchConfig::Any],
             allow: vec![tunnels, reverse_tunnel],
+            limits: Default::default(),
         }],
+        users: Default::default(),
     }
 }
 
@@ -131,7 +165,7 @@ async fn test_tcp_tunnel(
 
     let client_ws = client_ws.await;
 
-    let server = TcpTunnelListener::new(TUNNEL_LISTEN.0, (ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()), false)
+    let server = TcpTunnelListener::new(TUNNEL_LISTEN.0, (ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()), false, None)
         .await
         .unwrap();
     tokio::spawn(async move {
@@ -143,7 +177,9 @@ async fn test_tcp_tunnel(
         &TUNNEL_LISTEN.1,
         TUNNEL_LISTEN.0.port(),
         SoMark::new(None),
+        &SocketBind::NONE,
         Duration::from_secs(10),
+        protocols::tcp::HAPPY_EYEBALLS_DELAY,
         &dns_resolver,
     )
     .await
@@ -161,6 +197,239 @@ async fn test_tcp_tunnel(
     assert_eq!(&buf[..6], b"world!");
 }
 
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_tcp_tunnel_network_changed(
+    #[future] client_ws: WsClient,
+    server_no_tls: WsServer,
+    no_restrictions: RestrictionsRules,
+    dns_resolver: DnsResolver,
+) {
+    let server_h = tokio::spawn(server_no_tls.serve(no_restrictions));
+    defer! { server_h.abort(); };
+
+    let client_ws = client_ws.await;
+
+    let server = TcpTunnelListener::new(TUNNEL_LISTEN.0, (ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()), false, None)
+        .await
+        .unwrap();
+    tokio::spawn({
+        let client_ws = client_ws.clone();
+        async move {
+            client_ws.run_tunnel(server).await.unwrap();
+        }
+    });
+
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false).await.unwrap();
+    let connect = || {
+        protocols::tcp::connect(
+            &TUNNEL_LISTEN.1,
+            TUNNEL_LISTEN.0.port(),
+            SoMark::new(None),
+            &SocketBind::NONE,
+            Duration::from_secs(10),
+            protocols::tcp::HAPPY_EYEBALLS_DELAY,
+            &dns_resolver,
+        )
+    };
+
+    let mut client = connect().await.unwrap();
+    client.write_all(b"Hello").await.unwrap();
+    let mut dd = tcp_listener.next().await.unwrap().unwrap();
+    let mut buf = BytesMut::new();
+    dd.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..5], b"Hello");
+    buf.clear();
+
+    // Tunnels in flight are closed, in both directions
+    client_ws.notify_network_changed(true);
+    assert_eq!(client.read_buf(&mut buf).await.unwrap(), 0);
+    assert_eq!(dd.read_buf(&mut buf).await.unwrap(), 0);
+
+    // New tunnels go through the rebuilt pool
+    let mut client = connect().await.unwrap();
+    client.write_all(b"Hello").await.unwrap();
+    let mut dd = tcp_listener.next().await.unwrap().unwrap();
+    dd.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..5], b"Hello");
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_tcp_tunnel_shutdown(
+    #[future] client_ws: WsClient,
+    server_no_tls: WsServer,
+    no_restrictions: RestrictionsRules,
+    dns_resolver: DnsResolver,
+) {
+    let server_h = tokio::spawn(server_no_tls.clone().serve(no_restrictions));
+    let server_abort = server_h.abort_handle();
+    defer! { server_abort.abort(); };
+
+    let client_ws = client_ws.await;
+
+    let server = TcpTunnelListener::new(TUNNEL_LISTEN.0, (ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()), false, None)
+        .await
+        .unwrap();
+    let tunnel_h = tokio::spawn({
+        let client_ws = client_ws.clone();
+        async move { client_ws.run_tunnel(server).await }
+    });
+
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false).await.unwrap();
+    let mut client = protocols::tcp::connect(
+        &TUNNEL_LISTEN.1,
+        TUNNEL_LISTEN.0.port(),
+        SoMark::new(None),
+        &SocketBind::NONE,
+        Duration::from_secs(10),
+        protocols::tcp::HAPPY_EYEBALLS_DELAY,
+        &dns_resolver,
+    )
+    .await
+    .unwrap();
+
+    client.write_all(b"Hello").await.unwrap();
+    let mut dd = tcp_listener.next().await.unwrap().unwrap();
+    let mut buf = BytesMut::new();
+    dd.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..5], b"Hello");
+    buf.clear();
+
+    // The local listener stops, while the tunnels in flight keep running until they are done
+    let shutdown_h = tokio::spawn({
+        let client_ws = client_ws.clone();
+        async move { client_ws.shutdown().await }
+    });
+    assert!(tunnel_h.await.unwrap().is_ok());
+    client.write_all(b"World").await.unwrap();
+    dd.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..5], b"World");
+    buf.clear();
+    dd.write_all(b"Bye").await.unwrap();
+    client.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..3], b"Bye");
+    buf.clear();
+    assert!(!shutdown_h.is_finished());
+
+    drop(client);
+    drop(dd);
+    assert!(shutdown_h.await.unwrap());
+
+    // The server stops accepting connections
+    server_no_tls.shutdown();
+    tokio::time::timeout(Duration::from_secs(5), server_h)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_fallback_website(
+    mut server_config: WsServerConfig,
+    no_restrictions: RestrictionsRules,
+    dns_resolver: DnsResolver,
+) {
+    let root = std::env::temp_dir().join(format!("wstunnel-fallback-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("index.html"), "<h1>Welcome</h1>").unwrap();
+    defer! { let _ = std::fs::remove_dir_all(&root); };
+
+    server_config.fallback = Some(FallbackConfig::Directory(root.clone()));
+    let server = WsServer::new(server_config, DefaultTokioExecutor::default());
+    let server_h = tokio::spawn(server.serve(no_restrictions));
+    defer! { server_h.abort(); };
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let get = async |path: &str| {
+        let mut cnx = protocols::tcp::connect(
+            &Host::Ipv4(Ipv4Addr::LOCALHOST),
+            8080,
+            SoMark::new(None),
+            &SocketBind::NONE,
+            Duration::from_secs(10),
+            protocols::tcp::HAPPY_EYEBALLS_DELAY,
+            &dns_resolver,
+        )
+        .await
+        .unwrap();
+        let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        cnx.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        cnx.read_to_string(&mut response).await.unwrap();
+        response
+    };
+
+    // Requests that are not a tunnel get the website instead of a bad request
+    let response = get("/").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    assert!(response.ends_with("<h1>Welcome</h1>"), "{response}");
+    assert!(get("/missing.html").await.starts_with("HTTP/1.1 404 Not Found"));
+    assert!(get("/wstunnel/events").await.starts_with("HTTP/1.1 404 Not Found"));
+    assert!(get("/../etc/passwd").await.starts_with("HTTP/1.1 404 Not Found"));
+}
+
+#[rstest]
+#[timeout(Duration::from_secs(10))]
+#[tokio::test]
+#[serial]
+async fn test_tcp_tunnel_paused(
+    #[future] client_ws: WsClient,
+    server_no_tls: WsServer,
+    no_restrictions: RestrictionsRules,
+    dns_resolver: DnsResolver,
+) {
+    let server_h = tokio::spawn(server_no_tls.serve(no_restrictions));
+    defer! { server_h.abort(); };
+
+    let client_ws = client_ws.await;
+    client_ws.pause();
+
+    let server = TcpTunnelListener::new(TUNNEL_LISTEN.0, (ENDPOINT_LISTEN.1, ENDPOINT_LISTEN.0.port()), false, None)
+        .await
+        .unwrap();
+    tokio::spawn({
+        let client_ws = client_ws.clone();
+        async move {
+            client_ws.run_tunnel(server).await.unwrap();
+        }
+    });
+
+    // Local listener is still bound, but the tunnel waits for the client to be resumed
+    let mut tcp_listener = protocols::tcp::run_server(ENDPOINT_LISTEN.0, false).await.unwrap();
+    let mut client = protocols::tcp::connect(
+        &TUNNEL_LISTEN.1,
+        TUNNEL_LISTEN.0.port(),
+        SoMark::new(None),
+        &SocketBind::NONE,
+        Duration::from_secs(10),
+        protocols::tcp::HAPPY_EYEBALLS_DELAY,
+        &dns_resolver,
+    )
+    .await
+    .unwrap();
+    client.write_all(b"Hello").await.unwrap();
+    assert!(
+        tokio::time::timeout(Duration::from_millis(500), tcp_listener.next())
+            .await
+            .is_err()
+    );
+
+    client_ws.resume();
+    let mut dd = tcp_listener.next().await.unwrap().unwrap();
+    let mut buf = BytesMut::new();
+    dd.read_buf(&mut buf).await.unwrap();
+    assert_eq!(&buf[..5], b"Hello");
+}
+
 #[rstest]
 #[timeout(Duration::from_secs(10))]
 #[tokio::test]
@@ -190,7 +459,9 @@ async fn test_udp_tunnel(
         &TUNNEL_LISTEN.1,
         TUNNEL_LISTEN.0.port(),
         Duration::from_secs(10),
+        protocols::tcp::HAPPY_EYEBALLS_DELAY,
         SoMark::new(None),
+        &SocketBind::NONE,
         &dns_resolver,
     )
     .await
@@ -1,36 +1,63 @@
 use crate::executor::{DefaultTokioExecutor, TokioExecutorRef};
 use crate::tunnel;
-use crate::tunnel::RemoteAddr;
 use crate::tunnel::client::WsClientConfig;
 use crate::tunnel::client::cnx_pool::WsConnection;
 use crate::tunnel::connectors::TunnelConnector;
 use crate::tunnel::listeners::TunnelListener;
+use crate::tunnel::shutdown::Shutdown;
 use crate::tunnel::tls_reloader::TlsReloader;
+use crate::tunnel::transport::bandwidth::BandwidthLimit;
+use crate::tunnel::transport::idle_timeout::IdleTimeout;
 use crate::tunnel::transport::io::{TunnelReader, TunnelWriter};
+use crate::tunnel::transport::keepalive::{AdaptiveKeepalive, Keepalive};
 use crate::tunnel::transport::{TransportScheme, jwt_token_to_tunnel};
-use anyhow::Context;
+use crate::tunnel::{LocalProtocol, RemoteAddr};
+use anyhow::{Context, anyhow};
+use arc_swap::ArcSwap;
 use futures_util::pin_mut;
 use hyper::header::COOKIE;
 use log::debug;
+use parking_lot::Mutex;
 use std::cmp::min;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
 use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::sync::oneshot;
+use tokio::select;
+use tokio::sync::{oneshot, watch};
+use tokio::time::Instant;
 use tokio_stream::StreamExt;
-use tracing::{Instrument, Level, Span, error, event, span};
+use tracing::{Instrument, Level, Span, error, event, info, span, warn};
 use url::Host;
 use uuid::Uuid;
 
 #[derive(Clone)]
 pub struct WsClient<E: TokioExecutorRef = DefaultTokioExecutor> {
     pub config: Arc<WsClientConfig>,
-    pub cnx_pool: bb8::Pool<WsConnection>,
+    cnx_pool: Arc<ArcSwap<bb8::Pool<WsConnection>>>,
+    connection_min_idle: u32,
+    connection_retry_max_backoff: Duration,
     reverse_tunnel_connection_retry_max_backoff: Duration,
+    network_changed: Arc<watch::Sender<()>>,
+    streams_closed: Arc<watch::Sender<()>>,
+    paused: Arc<watch::Sender<bool>>,
+    keepalive: Option<Arc<AdaptiveKeepalive>>,
+    last_activity: Arc<Mutex<Instant>>,
+    pool_scaled_down: Arc<AtomicBool>,
+    shutdown: Shutdown,
     _tls_reloader: Arc<TlsReloader>,
     pub(crate) executor: E,
 }
 
+fn cnx_pool_builder(connection_min_idle: u32, connection_retry_max_backoff: Duration) -> bb8::Builder<WsConnection> {
+    bb8::Pool::builder()
+        .max_size(1000)
+        .min_idle(Some(connection_min_idle))
+        .max_lifetime(Some(Duration::from_secs(30)))
+        .connection_timeout(connection_retry_max_backoff)
+        .retry_connection(true)
+}
+
 impl<E: TokioExecutorRef> WsClient<E> {
     pub async fn new(
         config: WsClientConfig,
@@ -42,22 +69,185 @@ impl<E: TokioExecutorRef> WsClient<E> {
         let config = Arc::new(config);
         let cnx = WsConnection::new(config.clone());
         let tls_reloader = TlsReloader::new_for_client(config.clone()).with_context(|| "Cannot create tls reloader")?;
-        let cnx_pool = bb8::Pool::builder()
-            .max_size(1000)
-            .min_idle(Some(connection_min_idle))
-            .max_lifetime(Some(Duration::from_secs(30)))
-            .connection_timeout(connection_retry_max_backoff)
-            .retry_connection(true)
+        let cnx_pool = cnx_pool_builder(connection_min_idle, connection_retry_max_backoff)
             .build(cnx)
             .await?;
 
-        Ok(Self {
+        let client = Self {
+            keepalive: config
+                .keepalive_profile
+                .map(|profile| Arc::new(AdaptiveKeepalive::new(profile))),
             config,
-            cnx_pool,
+            cnx_pool: Arc::new(ArcSwap::from_pointee(cnx_pool)),
+            connection_min_idle,
+            connection_retry_max_backoff,
             reverse_tunnel_connection_retry_max_backoff,
+            network_changed: Arc::new(watch::Sender::new(())),
+            streams_closed: Arc::new(watch::Sender::new(())),
+            paused: Arc::new(watch::Sender::new(false)),
+            last_activity: Arc::new(Mutex::new(Instant::now())),
+            pool_scaled_down: Arc::new(AtomicBool::new(false)),
+            shutdown: Shutdown::new(),
             _tls_reloader: Arc::new(tls_reloader),
             executor,
-        })
+        };
+
+        if let Some(profile) = client.config.keepalive_profile {
+            client.executor.spawn(scale_down_idle_cnx_pool(
+                client.downgrade(),
+                profile.pool_idle_timeout(),
+            ));
+        }
+
+        Ok(client)
+    }
+
+    // Keep a handle on the client without preventing its connection pool from being dropped
+    fn downgrade(&self) -> WeakWsClient {
+        WeakWsClient {
+            config: self.config.clone(),
+            cnx_pool: Arc::downgrade(&self.cnx_pool),
+            connection_retry_max_backoff: self.connection_retry_max_backoff,
+            last_activity: self.last_activity.clone(),
+            pool_scaled_down: self.pool_scaled_down.clone(),
+        }
+    }
+
+    /// Keepalive to use for a new tunnel
+    pub fn keepalive(&self) -> Keepalive {
+        Keepalive {
+            ping_frequency: match &self.keepalive {
+                Some(keepalive) => Some(keepalive.profile().min_ping_frequency()),
+                None => self.config.websocket_ping_frequency,
+            },
+            pong_timeout: self.config.websocket_pong_timeout,
+            adaptive: self.keepalive.clone(),
+            paused: Some(self.paused.subscribe()),
+        }
+    }
+
+    // Idle timeout of a new tunnel, only tcp streams have one
+    fn idle_timeout(&self, protocol: &LocalProtocol) -> IdleTimeout {
+        match protocol {
+            LocalProtocol::Tcp { idle_timeout, .. } => IdleTimeout::new(
+                idle_timeout.or(self.config.tcp_idle_timeout),
+                self.config.tcp_half_close_timeout,
+            ),
+            LocalProtocol::ReverseTcp => {
+                IdleTimeout::new(self.config.tcp_idle_timeout, self.config.tcp_half_close_timeout)
+            }
+            _ => IdleTimeout::default(),
+        }
+    }
+
+    // A tunnel is being opened, bring back the idle connections of the pool if they have been closed
+    fn mark_activity(&self) {
+        if self.keepalive.is_none() {
+            return;
+        }
+
+        *self.last_activity.lock() = Instant::now();
+        if self.pool_scaled_down.swap(false, Ordering::Relaxed) {
+            debug!("Tunnel activity, restoring idle connections of the pool");
+            self.rebuild_cnx_pool();
+        }
+    }
+
+    /// Pool used by the tunnels, it is rebuilt when the network changes so do not keep it around
+    pub fn cnx_pool(&self) -> Arc<bb8::Pool<WsConnection>> {
+        self.cnx_pool.load_full()
+    }
+
+    /// Notify the client that the network of the device has changed (i.e: switch between wifi and cellular).
+    /// Idle connections to the server are dropped and the pool is rebuilt, dns caches are flushed, and reverse tunnels
+    /// reconnect right away instead of waiting for their backoff. If close_streams is true, tunnels in flight are closed too
+    pub fn notify_network_changed(&self, close_streams: bool) {
+        info!("Network has changed, reconnecting to the server");
+        let client = self.clone();
+        // The pool spawns its connection tasks, so it must be created from within the runtime
+        self.executor.spawn(async move {
+            client.config.dns_resolver.clear_cache();
+            if let Some(keepalive) = &client.keepalive {
+                keepalive.reset();
+            }
+            client.rebuild_cnx_pool();
+
+            client.network_changed.send_replace(());
+            if close_streams {
+                client.streams_closed.send_replace(());
+            }
+        });
+    }
+
+    /// Pause the client, i.e: when the device goes to sleep. Local listeners stay bound but new tunnels wait to be resumed,
+    /// up to the connect timeout. Idle connections to the server are closed, reverse tunnels stop reconnecting
+    /// and tunnels in flight stop sending pings
+    pub fn pause(&self) {
+        if self.paused.send_replace(true) {
+            return;
+        }
+
+        info!("Pausing client");
+        let client = self.clone();
+        self.executor.spawn(async move { client.rebuild_cnx_pool() });
+    }
+
+    pub fn resume(&self) {
+        if !self.paused.send_replace(false) {
+            return;
+        }
+
+        info!("Resuming client");
+        let client = self.clone();
+        self.executor.spawn(async move { client.rebuild_cnx_pool() });
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Gracefully stop the client. Local listeners and reverse tunnels stop accepting new connections,
+    /// tunnels in flight are given the drain timeout to finish, after which they are forcibly closed.
+    /// Return false if some tunnels were still running after the drain timeout
+    pub async fn shutdown(&self) -> bool {
+        info!(
+            "Shutting down client, draining {} tunnels",
+            self.shutdown.active_streams()
+        );
+        let drained = self.shutdown.drain(self.config.drain_timeout).await;
+        if !drained {
+            warn!(
+                "Drain timeout of {:?} elapsed, forcing close of {} tunnels",
+                self.config.drain_timeout,
+                self.shutdown.active_streams()
+            );
+        }
+
+        drained
+    }
+
+    // Drop the connections of the pool and create a new one. Must be called from within the runtime,
+    // as the pool spawns its connection tasks
+    fn rebuild_cnx_pool(&self) {
+        // Don't keep idle connections opened to the server while paused or without activity
+        let min_idle = if self.is_paused() || self.pool_scaled_down.load(Ordering::Relaxed) {
+            0
+        } else {
+            self.connection_min_idle
+        };
+        let cnx_pool = cnx_pool_builder(min_idle, self.connection_retry_max_backoff)
+            .build_unchecked(WsConnection::new(self.config.clone()));
+        self.cnx_pool.store(Arc::new(cnx_pool));
+    }
+
+    // Resolve when the tunnels in flight must be closed, after a change of network
+    fn on_streams_closed(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut streams_closed = self.streams_closed.subscribe();
+        async move {
+            if streams_closed.changed().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
     }
 
     pub async fn connect_to_server<R, W>(
@@ -70,6 +260,24 @@ impl<E: TokioExecutorRef> WsClient<E> {
         R: AsyncRead + Send + 'static,
         W: AsyncWrite + Send + 'static,
     {
+        // While paused, hold the new tunnel until the client is resumed
+        let mut paused = self.paused.subscribe();
+        let is_paused = *paused.borrow_and_update();
+        if is_paused {
+            debug!("Client is paused, waiting to be resumed");
+            if !matches!(
+                tokio::time::timeout(self.config.timeout_connect, paused.wait_for(|paused| !*paused)).await,
+                Ok(Ok(_))
+            ) {
+                return Err(anyhow!(
+                    "Client is paused, dropping tunnel to {}:{}",
+                    remote_cfg.host,
+                    remote_cfg.port
+                ));
+            }
+        }
+        self.mark_activity();
+
         // Connect to server with the correct protocol
         let (ws_rx, ws_tx, response) = match self.config.remote_addr.scheme() {
             TransportScheme::Ws | TransportScheme::Wss => {
@@ -87,24 +295,60 @@ impl<E: TokioExecutorRef> WsClient<E> {
         debug!("Server response: {response:?}");
         let (local_rx, local_tx) = duplex_stream;
         let (close_tx, close_rx) = oneshot::channel::<()>();
+        let idle_timeout = self.idle_timeout(&remote_cfg.protocol);
 
         // Forward local tx to websocket tx
-        let ping_frequency = self.config.websocket_ping_frequency;
+        let local_to_remote = super::super::transport::io::propagate_local_to_remote(
+            local_rx,
+            ws_tx,
+            close_tx,
+            idle_timeout.clone(),
+            BandwidthLimit::default(),
+            self.shutdown.clone(),
+        );
+        let streams_closed = self.on_streams_closed();
         self.executor.spawn(
-            super::super::transport::io::propagate_local_to_remote(local_rx, ws_tx, close_tx, ping_frequency)
-                .instrument(Span::current()),
+            async move {
+                select! {
+                    _ = local_to_remote => {},
+                    _ = streams_closed => {},
+                }
+            }
+            .instrument(Span::current()),
         );
 
         // Forward websocket rx to local rx
-        let _ = super::super::transport::io::propagate_remote_to_local(local_tx, ws_rx, close_rx).await;
+        let remote_to_local = super::super::transport::io::propagate_remote_to_local(
+            local_tx,
+            ws_rx,
+            close_rx,
+            idle_timeout,
+            BandwidthLimit::default(),
+            self.shutdown.clone(),
+        );
+        let streams_closed = self.on_streams_closed();
+        select! {
+            _ = remote_to_local => {},
+            _ = streams_closed => info!("Closing tunnel due to a change of network"),
+        }
 
         Ok(())
     }
 
     pub async fn run_tunnel(self, tunnel_listener: impl TunnelListener) -> anyhow::Result<()> {
         pin_mut!(tunnel_listener);
+        let shutdown_triggered = self.shutdown.triggered();
+        pin_mut!(shutdown_triggered);
         // everybody who connects to the local socket gets their own tunnel
-        while let Some(cnx) = tunnel_listener.next().await {
+        loop {
+            let cnx = select! {
+                biased;
+                _ = &mut shutdown_triggered => break,
+                cnx = tunnel_listener.next() => cnx,
+            };
+            let Some(cnx) = cnx else {
+                break;
+            };
             let (cnx_stream, remote_addr) = match cnx {
                 Ok((cnx_stream, remote_addr)) => (cnx_stream, remote_addr),
                 Err(err) => {
@@ -151,7 +395,21 @@ impl<E: TokioExecutorRef> WsClient<E> {
         }
 
         let mut reconnect_delay = new_reconnect_delay(self.reverse_tunnel_connection_retry_max_backoff);
+        let mut network_changed = self.network_changed.subscribe();
+        let mut paused = self.paused.subscribe();
         loop {
+            if self.shutdown.is_triggered() {
+                return Ok(());
+            }
+
+            // Stop reconnecting to the server while paused
+            let is_paused = *paused.borrow_and_update();
+            if is_paused {
+                let _ = paused.wait_for(|paused| !*paused).await;
+                reconnect_delay = new_reconnect_delay(self.reverse_tunnel_connection_retry_max_backoff);
+            }
+            self.mark_activity();
+
             let client = self.clone();
             let request_id = Uuid::now_v7();
             let span = span!(
@@ -160,34 +418,51 @@ impl<E: TokioExecutorRef> WsClient<E> {
                 id = request_id.to_string(),
                 remote = format!("{}:{}", remote_addr.host, remote_addr.port)
             );
+
+            // Don't wait for the backoff if the network has changed since the last attempt
+            if network_changed.has_changed().unwrap_or(false) {
+                network_changed.mark_unchanged();
+                reconnect_delay = new_reconnect_delay(self.reverse_tunnel_connection_retry_max_backoff);
+            }
+
             // Correctly configure tunnel cfg
-            let (ws_rx, ws_tx, response) = match client.config.remote_addr.scheme() {
-                TransportScheme::Ws | TransportScheme::Wss => {
-                    match tunnel::transport::websocket::connect(request_id, &client, &remote_addr)
-                        .instrument(span.clone())
-                        .await
-                    {
-                        Ok((r, w, response)) => (TunnelReader::Websocket(r), TunnelWriter::Websocket(w), response),
-                        Err(err) => {
-                            let reconnect_delay = reconnect_delay();
-                            event!(parent: &span, Level::ERROR, "Retrying in {:?}, cannot connect to remote server: {:?}", reconnect_delay, err);
-                            tokio::time::sleep(reconnect_delay).await;
-                            continue;
-                        }
+            let connect = async {
+                match client.config.remote_addr.scheme() {
+                    TransportScheme::Ws | TransportScheme::Wss => {
+                        tunnel::transport::websocket::connect(request_id, &client, &remote_addr)
+                            .await
+                            .map(|(r, w, response)| (TunnelReader::Websocket(r), TunnelWriter::Websocket(w), response))
+                    }
+                    TransportScheme::Http | TransportScheme::Https => {
+                        tunnel::transport::http2::connect(request_id, &client, &remote_addr)
+                            .await
+                            .map(|(r, w, response)| (TunnelReader::Http2(r), TunnelWriter::Http2(w), response))
                     }
                 }
-                TransportScheme::Http | TransportScheme::Https => {
-                    match tunnel::transport::http2::connect(request_id, &client, &remote_addr)
-                        .instrument(span.clone())
-                        .await
-                    {
-                        Ok((r, w, response)) => (TunnelReader::Http2(r), TunnelWriter::Http2(w), response),
-                        Err(err) => {
-                            let reconnect_delay = reconnect_delay();
-                            event!(parent: &span, Level::ERROR, "Retrying in {:?}, cannot connect to remote server: {:?}", reconnect_delay, err);
-                            tokio::time::sleep(reconnect_delay).await;
-                            continue;
+            };
+
+            // The pending request is likely going through a dead network, so retry right away when it changes
+            let (ws_rx, ws_tx, response) = select! {
+                biased;
+                _ = self.shutdown.triggered() => return Ok(()),
+                _ = network_changed.changed() => {
+                    event!(parent: &span, Level::INFO, "Network has changed, reconnecting to remote server");
+                    reconnect_delay = new_reconnect_delay(self.reverse_tunnel_connection_retry_max_backoff);
+                    continue;
+                }
+                _ = async { paused.wait_for(|paused| *paused).await.map(|_| ()) } => {
+                    event!(parent: &span, Level::INFO, "Client is paused, stopping reverse tunnel until resumed");
+                    continue;
+                }
+                ret = connect.instrument(span.clone()) => match ret {
+                    Ok(ret) => ret,
+                    Err(err) => {
+                        let delay = reconnect_delay();
+                        event!(parent: &span, Level::ERROR, "Retrying in {:?}, cannot connect to remote server: {:?}", delay, err);
+                        if let Ok(Ok(())) = tokio::time::timeout(delay, network_changed.changed()).await {
+                            reconnect_delay = new_reconnect_delay(self.reverse_tunnel_connection_retry_max_backoff);
                         }
+                        continue;
                     }
                 }
             };
@@ -215,17 +490,73 @@ impl<E: TokioExecutorRef> WsClient<E> {
             };
 
             let (close_tx, close_rx) = oneshot::channel::<()>();
-            self.executor.spawn({
-                let ping_frequency = client.config.websocket_ping_frequency;
-                super::super::transport::io::propagate_local_to_remote(local_rx, ws_tx, close_tx, ping_frequency)
-                    .instrument(span.clone())
-            });
+            let idle_timeout = self.idle_timeout(&remote.as_ref().unwrap_or(&remote_addr).protocol);
+            self.executor.spawn(
+                super::super::transport::io::propagate_local_to_remote(
+                    local_rx,
+                    ws_tx,
+                    close_tx,
+                    idle_timeout.clone(),
+                    BandwidthLimit::default(),
+                    self.shutdown.clone(),
+                )
+                .instrument(span.clone()),
+            );
 
             // Forward websocket rx to local rx
+            let streams_closed = self.on_streams_closed();
+            let remote_to_local = super::super::transport::io::propagate_remote_to_local(
+                local_tx,
+                ws_rx,
+                close_rx,
+                idle_timeout,
+                BandwidthLimit::default(),
+                self.shutdown.clone(),
+            );
             self.executor.spawn(
-                super::super::transport::io::propagate_remote_to_local(local_tx, ws_rx, close_rx)
-                    .instrument(span.clone()),
+                async move {
+                    select! {
+                        _ = remote_to_local => {},
+                        _ = streams_closed => info!("Closing tunnel due to a change of network"),
+                    }
+                }
+                .instrument(span.clone()),
             );
         }
     }
 }
+
+struct WeakWsClient {
+    config: Arc<WsClientConfig>,
+    cnx_pool: Weak<ArcSwap<bb8::Pool<WsConnection>>>,
+    connection_retry_max_backoff: Duration,
+    last_activity: Arc<Mutex<Instant>>,
+    pool_scaled_down: Arc<AtomicBool>,
+}
+
+// Close the idle connections of the pool when no tunnel has been opened during idle_timeout, to let the radio sleep.
+// They are restored on the next tunnel. Stops when the client is dropped
+async fn scale_down_idle_cnx_pool(client: WeakWsClient, idle_timeout: Duration) {
+    loop {
+        let deadline = if client.pool_scaled_down.load(Ordering::Relaxed) {
+            Instant::now() + idle_timeout
+        } else {
+            *client.last_activity.lock() + idle_timeout
+        };
+        tokio::time::sleep_until(deadline).await;
+
+        let Some(cnx_pool) = client.cnx_pool.upgrade() else {
+            return;
+        };
+        if client.pool_scaled_down.load(Ordering::Relaxed) || client.last_activity.lock().elapsed() < idle_timeout {
+            continue;
+        }
+
+        info!("No tunnel opened for {idle_timeout:?}, closing idle connections to the server");
+        client.pool_scaled_down.store(true, Ordering::Relaxed);
+        cnx_pool.store(Arc::new(
+            cnx_pool_builder(0, client.connection_retry_max_backoff)
+                .build_unchecked(WsConnection::new(client.config.clone())),
+        ));
+    }
+}
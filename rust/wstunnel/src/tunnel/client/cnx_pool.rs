@@ -39,7 +39,9 @@ impl ManageConnection for WsConnection {
                 self.remote_addr.host(),
                 self.remote_addr.port(),
                 self.socket_so_mark,
+                &self.socket_bind,
                 timeout,
+                self.happy_eyeballs_delay,
                 &self.dns_resolver,
             )
             .await?
@@ -48,7 +50,9 @@ impl ManageConnection for WsConnection {
                 self.remote_addr.host(),
                 self.remote_addr.port(),
                 self.socket_so_mark,
+                &self.socket_bind,
                 timeout,
+                self.happy_eyeballs_delay,
                 &self.dns_resolver,
             )
             .await?
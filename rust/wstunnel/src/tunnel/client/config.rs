@@ -1,6 +1,8 @@
 use crate::protocols::dns::DnsResolver;
+use crate::socket_bind::SocketBind;
 use crate::somark::SoMark;
 use crate::tunnel::transport::TransportAddr;
+use crate::tunnel::transport::keepalive::KeepaliveProfile;
 use hyper::header::{HeaderName, HeaderValue};
 use parking_lot::RwLock;
 use std::collections::HashMap;
@@ -16,13 +18,20 @@ use url::{Host, Url};
 pub struct WsClientConfig {
     pub remote_addr: TransportAddr,
     pub socket_so_mark: SoMark,
+    pub socket_bind: SocketBind,
     pub http_upgrade_path_prefix: String,
     pub http_upgrade_credentials: Option<HeaderValue>,
     pub http_headers: HashMap<HeaderName, HeaderValue>,
     pub http_headers_file: Option<PathBuf>,
     pub http_header_host: HeaderValue,
     pub timeout_connect: Duration,
+    pub happy_eyeballs_delay: Duration,
+    pub tcp_idle_timeout: Option<Duration>,
+    pub tcp_half_close_timeout: Option<Duration>,
+    pub drain_timeout: Duration,
     pub websocket_ping_frequency: Option<Duration>,
+    pub websocket_pong_timeout: Option<Duration>,
+    pub keepalive_profile: Option<KeepaliveProfile>,
     pub websocket_mask_frame: bool,
     pub http_proxy: Option<Url>,
     pub dns_resolver: DnsResolver,
@@ -12,6 +12,7 @@ use crate::protocols;
 use crate::protocols::dns::DnsResolver;
 use crate::protocols::udp;
 use crate::protocols::udp::WsUdpSocket;
+use crate::socket_bind::SocketBind;
 use crate::somark::SoMark;
 use crate::tunnel::connectors::TunnelConnector;
 use crate::tunnel::{LocalProtocol, RemoteAddr};
@@ -19,14 +20,21 @@ use crate::tunnel::{LocalProtocol, RemoteAddr};
 pub struct Socks5TunnelConnector<'a> {
     so_mark: SoMark,
     connect_timeout: Duration,
+    happy_eyeballs_delay: Duration,
     dns_resolver: &'a DnsResolver,
 }
 
 impl Socks5TunnelConnector<'_> {
-    pub fn new(so_mark: SoMark, connect_timeout: Duration, dns_resolver: &DnsResolver) -> Socks5TunnelConnector<'_> {
+    pub fn new(
+        so_mark: SoMark,
+        connect_timeout: Duration,
+        happy_eyeballs_delay: Duration,
+        dns_resolver: &DnsResolver,
+    ) -> Socks5TunnelConnector<'_> {
         Socks5TunnelConnector {
             so_mark,
             connect_timeout,
+            happy_eyeballs_delay,
             dns_resolver,
         }
     }
@@ -42,12 +50,14 @@ impl TunnelConnector for Socks5TunnelConnector<'_> {
         };
 
         match remote.protocol {
-            LocalProtocol::Tcp { proxy_protocol: _ } => {
+            LocalProtocol::Tcp { .. } => {
                 let stream = protocols::tcp::connect(
                     &remote.host,
                     remote.port,
                     self.so_mark,
+                    &SocketBind::NONE,
                     self.connect_timeout,
+                    self.happy_eyeballs_delay,
                     self.dns_resolver,
                 )
                 .await?;
@@ -55,9 +65,16 @@ impl TunnelConnector for Socks5TunnelConnector<'_> {
                 Ok((Socks5Reader::Tcp(reader), Socks5Writer::Tcp(writer)))
             }
             LocalProtocol::Udp { .. } => {
-                let stream =
-                    udp::connect(&remote.host, remote.port, self.connect_timeout, self.so_mark, self.dns_resolver)
-                        .await?;
+                let stream = udp::connect(
+                    &remote.host,
+                    remote.port,
+                    self.connect_timeout,
+                    self.happy_eyeballs_delay,
+                    self.so_mark,
+                    &SocketBind::NONE,
+                    self.dns_resolver,
+                )
+                .await?;
                 Ok((Socks5Reader::Udp(stream.clone()), Socks5Writer::Udp(stream)))
             }
             _ => Err(anyhow!("Invalid protocol for reverse socks5 {:?}", remote.protocol)),
@@ -74,13 +91,15 @@ impl TunnelConnector for Socks5TunnelConnector<'_> {
         };
 
         match remote.protocol {
-            LocalProtocol::Tcp { proxy_protocol: _ } => {
+            LocalProtocol::Tcp { .. } => {
                 let stream = protocols::tcp::connect_with_http_proxy(
                     proxy,
                     &remote.host,
                     remote.port,
                     self.so_mark,
+                    &SocketBind::NONE,
                     self.connect_timeout,
+                    self.happy_eyeballs_delay,
                     self.dns_resolver,
                 )
                 .await?;
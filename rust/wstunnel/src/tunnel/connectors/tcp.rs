@@ -5,6 +5,7 @@ use url::{Host, Url};
 
 use crate::protocols;
 use crate::protocols::dns::DnsResolver;
+use crate::socket_bind::SocketBind;
 use crate::somark::SoMark;
 use crate::tunnel::RemoteAddr;
 use crate::tunnel::connectors::TunnelConnector;
@@ -14,6 +15,7 @@ pub struct TcpTunnelConnector<'a> {
     port: u16,
     so_mark: SoMark,
     connect_timeout: Duration,
+    happy_eyeballs_delay: Duration,
     dns_resolver: &'a DnsResolver,
 }
 
@@ -23,6 +25,7 @@ impl<'a> TcpTunnelConnector<'a> {
         port: u16,
         so_mark: SoMark,
         connect_timeout: Duration,
+        happy_eyeballs_delay: Duration,
         dns_resolver: &'a DnsResolver,
     ) -> TcpTunnelConnector<'a> {
         TcpTunnelConnector {
@@ -30,6 +33,7 @@ impl<'a> TcpTunnelConnector<'a> {
             port,
             so_mark,
             connect_timeout,
+            happy_eyeballs_delay,
             dns_resolver,
         }
     }
@@ -45,7 +49,16 @@ impl TunnelConnector for TcpTunnelConnector<'_> {
             None => (self.host, self.port),
         };
 
-        let stream = protocols::tcp::connect(host, port, self.so_mark, self.connect_timeout, self.dns_resolver).await?;
+        let stream = protocols::tcp::connect(
+            host,
+            port,
+            self.so_mark,
+            &SocketBind::NONE,
+            self.connect_timeout,
+            self.happy_eyeballs_delay,
+            self.dns_resolver,
+        )
+        .await?;
         Ok(stream.into_split())
     }
 
@@ -64,7 +77,9 @@ impl TunnelConnector for TcpTunnelConnector<'_> {
             host,
             port,
             self.so_mark,
+            &SocketBind::NONE,
             self.connect_timeout,
+            self.happy_eyeballs_delay,
             self.dns_resolver,
         )
         .await?;
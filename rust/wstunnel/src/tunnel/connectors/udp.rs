@@ -5,6 +5,7 @@ use url::Host;
 use crate::protocols;
 use crate::protocols::dns::DnsResolver;
 use crate::protocols::udp::WsUdpSocket;
+use crate::socket_bind::SocketBind;
 use crate::somark::SoMark;
 use crate::tunnel::RemoteAddr;
 use crate::tunnel::connectors::TunnelConnector;
@@ -14,6 +15,7 @@ pub struct UdpTunnelConnector<'a> {
     port: u16,
     so_mark: SoMark,
     connect_timeout: Duration,
+    happy_eyeballs_delay: Duration,
     dns_resolver: &'a DnsResolver,
 }
 
@@ -23,6 +25,7 @@ impl<'a> UdpTunnelConnector<'a> {
         port: u16,
         so_mark: SoMark,
         connect_timeout: Duration,
+        happy_eyeballs_delay: Duration,
         dns_resolver: &'a DnsResolver,
     ) -> UdpTunnelConnector<'a> {
         UdpTunnelConnector {
@@ -30,6 +33,7 @@ impl<'a> UdpTunnelConnector<'a> {
             port,
             so_mark,
             connect_timeout,
+            happy_eyeballs_delay,
             dns_resolver,
         }
     }
@@ -40,9 +44,16 @@ impl TunnelConnector for UdpTunnelConnector<'_> {
     type Writer = WsUdpSocket;
 
     async fn connect(&self, _: &Option<RemoteAddr>) -> anyhow::Result<(Self::Reader, Self::Writer)> {
-        let stream =
-            protocols::udp::connect(self.host, self.port, self.connect_timeout, self.so_mark, self.dns_resolver)
-                .await?;
+        let stream = protocols::udp::connect(
+            self.host,
+            self.port,
+            self.connect_timeout,
+            self.happy_eyeballs_delay,
+            self.so_mark,
+            &SocketBind::NONE,
+            self.dns_resolver,
+        )
+        .await?;
 
         Ok((stream.clone(), stream))
     }
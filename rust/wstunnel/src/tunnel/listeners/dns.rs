@@ -0,0 +1,70 @@
+use crate::protocols::dns;
+use crate::protocols::dns::DnsListener;
+use crate::tunnel::{LocalProtocol, RemoteAddr};
+use anyhow::{Context, anyhow};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Poll, ready};
+use std::time::Duration;
+use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+use tokio_stream::Stream;
+use url::Host;
+
+pub struct DnsTunnelListener {
+    listener: DnsListener,
+    dest: (Host, u16),
+    protocol: LocalProtocol,
+}
+
+impl DnsTunnelListener {
+    pub async fn new(
+        bind_addr: SocketAddr,
+        dest: (Host, u16),
+        timeout: Option<Duration>,
+        over_tcp: bool,
+    ) -> anyhow::Result<Self> {
+        const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+        let listener = dns::run_server(bind_addr, timeout.unwrap_or(DEFAULT_QUERY_TIMEOUT), over_tcp)
+            .await
+            .with_context(|| anyhow!("Cannot start DNS server on {bind_addr}"))?;
+
+        let protocol = if over_tcp {
+            LocalProtocol::Tcp {
+                proxy_protocol: false,
+                idle_timeout: None,
+            }
+        } else {
+            LocalProtocol::Udp { timeout }
+        };
+
+        Ok(Self {
+            listener,
+            dest,
+            protocol,
+        })
+    }
+}
+
+impl Stream for DnsTunnelListener {
+    type Item = anyhow::Result<((ReadHalf<DuplexStream>, WriteHalf<DuplexStream>), RemoteAddr)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let ret = ready!(Pin::new(&mut this.listener).poll_next(cx));
+        let ret = match ret {
+            Some(Ok(stream)) => {
+                let (host, port) = this.dest.clone();
+                let remote = RemoteAddr {
+                    protocol: this.protocol.clone(),
+                    host,
+                    port,
+                };
+                Some(anyhow::Ok((tokio::io::split(stream), remote)))
+            }
+            Some(Err(err)) => Some(Err(err)),
+            None => None,
+        };
+        Poll::Ready(ret)
+    }
+}
@@ -1,12 +1,11 @@
 use crate::protocols::http_proxy;
-use crate::protocols::http_proxy::HttpProxyListener;
+use crate::protocols::http_proxy::{HttpProxyListener, HttpProxyReadHalf, HttpProxyWriteHalf};
 use crate::tunnel::{LocalProtocol, RemoteAddr};
 use anyhow::{Context, anyhow};
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::{Poll, ready};
 use std::time::Duration;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio_stream::Stream;
 
 pub struct HttpProxyTunnelListener {
@@ -33,7 +32,7 @@ impl HttpProxyTunnelListener {
 }
 
 impl Stream for HttpProxyTunnelListener {
-    type Item = anyhow::Result<((OwnedReadHalf, OwnedWriteHalf), RemoteAddr)>;
+    type Item = anyhow::Result<((HttpProxyReadHalf, HttpProxyWriteHalf), RemoteAddr)>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
         let this = self.get_mut();
@@ -42,6 +41,7 @@ impl Stream for HttpProxyTunnelListener {
             Some(Ok((stream, (host, port)))) => {
                 let protocol = LocalProtocol::Tcp {
                     proxy_protocol: this.proxy_protocol,
+                    idle_timeout: None,
                 };
                 Some(anyhow::Ok((stream.into_split(), RemoteAddr { protocol, host, port })))
             }
@@ -2,9 +2,12 @@ mod tcp;
 #[cfg(target_os = "linux")]
 mod tproxy;
 
+mod dns;
 mod http_proxy;
 mod socks5;
 mod stdio;
+#[cfg(unix)]
+mod tun;
 mod udp;
 #[cfg(unix)]
 mod unix_sock;
@@ -14,12 +17,15 @@ pub use tproxy::TproxyTcpTunnelListener;
 #[cfg(target_os = "linux")]
 pub use tproxy::new_tproxy_udp;
 
+pub use dns::DnsTunnelListener;
 pub use http_proxy::HttpProxyTunnelListener;
 pub use socks5::Socks5TunnelListener;
 pub use stdio::new_stdio_listener;
 pub use tcp::TcpTunnelListener;
 pub use udp::UdpTunnelListener;
 
+#[cfg(unix)]
+pub use tun::TunTunnelListener;
 #[cfg(unix)]
 pub use unix_sock::UnixTunnelListener;
 
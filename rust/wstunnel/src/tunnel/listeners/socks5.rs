@@ -24,6 +24,19 @@ impl Socks5TunnelListener {
 
         Ok(Self { listener })
     }
+
+    /// Same as a socks5 listener, but also accepts HTTP proxy requests on the same port
+    pub async fn new_mixed(
+        bind_addr: SocketAddr,
+        timeout: Option<Duration>,
+        credentials: Option<(String, String)>,
+    ) -> anyhow::Result<Self> {
+        let listener = socks5::run_mixed_server(bind_addr, timeout, credentials)
+            .await
+            .with_context(|| anyhow!("Cannot start mixed proxy server on {bind_addr}"))?;
+
+        Ok(Self { listener })
+    }
 }
 
 impl Stream for Socks5TunnelListener {
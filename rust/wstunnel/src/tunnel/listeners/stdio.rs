@@ -56,6 +56,7 @@ where
                     RemoteAddr {
                         protocol: LocalProtocol::Tcp {
                             proxy_protocol: this.proxy_protocol,
+                            idle_timeout: None,
                         },
                         host,
                         port,
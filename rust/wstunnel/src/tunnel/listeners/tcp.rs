@@ -4,6 +4,7 @@ use anyhow::{Context, anyhow};
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::{Poll, ready};
+use std::time::Duration;
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio_stream::Stream;
 use tokio_stream::wrappers::TcpListenerStream;
@@ -13,10 +14,16 @@ pub struct TcpTunnelListener {
     listener: TcpListenerStream,
     dest: (Host, u16),
     proxy_protocol: bool,
+    idle_timeout: Option<Duration>,
 }
 
 impl TcpTunnelListener {
-    pub async fn new(bind_addr: SocketAddr, dest: (Host, u16), proxy_protocol: bool) -> anyhow::Result<Self> {
+    pub async fn new(
+        bind_addr: SocketAddr,
+        dest: (Host, u16),
+        proxy_protocol: bool,
+        idle_timeout: Option<Duration>,
+    ) -> anyhow::Result<Self> {
         let listener = protocols::tcp::run_server(bind_addr, false)
             .await
             .with_context(|| anyhow!("Cannot start TCP server on {bind_addr}"))?;
@@ -25,6 +32,7 @@ impl TcpTunnelListener {
             listener,
             dest,
             proxy_protocol,
+            idle_timeout,
         })
     }
 }
@@ -43,6 +51,7 @@ impl Stream for TcpTunnelListener {
                     RemoteAddr {
                         protocol: LocalProtocol::Tcp {
                             proxy_protocol: this.proxy_protocol,
+                            idle_timeout: this.idle_timeout,
                         },
                         host,
                         port,
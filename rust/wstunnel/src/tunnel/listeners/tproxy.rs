@@ -1,4 +1,5 @@
 use crate::protocols;
+use crate::protocols::dns::FakeIpPool;
 use crate::protocols::udp;
 use crate::protocols::udp::{UdpStream, UdpStreamWriter};
 use crate::tunnel::{LocalProtocol, RemoteAddr, to_host_port};
@@ -6,19 +7,33 @@ use anyhow::{Context, anyhow};
 use std::io;
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Poll, ready};
 use std::time::Duration;
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio_stream::Stream;
 use tokio_stream::wrappers::TcpListenerStream;
+use url::Host;
+
+fn destination(fake_ip_pool: &Option<Arc<FakeIpPool>>, addr: SocketAddr) -> (Host, u16) {
+    match fake_ip_pool {
+        Some(pool) => pool.to_host_port(addr),
+        None => to_host_port(addr),
+    }
+}
 
 pub struct TproxyTcpTunnelListener {
     listener: TcpListenerStream,
     proxy_protocol: bool,
+    fake_ip_pool: Option<Arc<FakeIpPool>>,
 }
 
 impl TproxyTcpTunnelListener {
-    pub async fn new(bind_addr: SocketAddr, proxy_protocol: bool) -> anyhow::Result<Self> {
+    pub async fn new(
+        bind_addr: SocketAddr,
+        proxy_protocol: bool,
+        fake_ip_pool: Option<Arc<FakeIpPool>>,
+    ) -> anyhow::Result<Self> {
         let listener = protocols::tcp::run_server(bind_addr, true)
             .await
             .with_context(|| anyhow!("Cannot start TProxy TCP server on {bind_addr}"))?;
@@ -26,6 +41,7 @@ impl TproxyTcpTunnelListener {
         Ok(Self {
             listener,
             proxy_protocol,
+            fake_ip_pool,
         })
     }
 }
@@ -38,12 +54,13 @@ impl Stream for TproxyTcpTunnelListener {
         let ret = ready!(Pin::new(&mut this.listener).poll_next(cx));
         let ret = match ret {
             Some(Ok(stream)) => {
-                let (host, port) = to_host_port(stream.local_addr().unwrap());
+                let (host, port) = destination(&this.fake_ip_pool, stream.local_addr().unwrap());
                 Some(anyhow::Ok((
                     stream.into_split(),
                     RemoteAddr {
                         protocol: LocalProtocol::Tcp {
                             proxy_protocol: this.proxy_protocol,
+                            idle_timeout: None,
                         },
                         host,
                         port,
@@ -64,17 +81,23 @@ where
 {
     listener: S,
     timeout: Option<Duration>,
+    fake_ip_pool: Option<Arc<FakeIpPool>>,
 }
 
 pub async fn new_tproxy_udp(
     bind_addr: SocketAddr,
     timeout: Option<Duration>,
+    fake_ip_pool: Option<Arc<FakeIpPool>>,
 ) -> anyhow::Result<TProxyUdpTunnelListener<impl Stream<Item = io::Result<UdpStream>>>> {
     let listener = udp::run_server(bind_addr, timeout, udp::configure_tproxy, udp::mk_send_socket_tproxy)
         .await
         .with_context(|| anyhow!("Cannot start TProxy UDP server on {bind_addr}"))?;
 
-    Ok(TProxyUdpTunnelListener { listener, timeout })
+    Ok(TProxyUdpTunnelListener {
+        listener,
+        timeout,
+        fake_ip_pool,
+    })
 }
 
 impl<S> Stream for TProxyUdpTunnelListener<S>
@@ -88,7 +111,7 @@ where
         let ret = ready!(unsafe { Pin::new_unchecked(&mut this.listener) }.poll_next(cx));
         let ret = match ret {
             Some(Ok(stream)) => {
-                let (host, port) = to_host_port(stream.local_addr().unwrap());
+                let (host, port) = destination(&this.fake_ip_pool, stream.local_addr().unwrap());
                 let stream_writer = stream.writer();
                 Some(anyhow::Ok((
                     (stream, stream_writer),
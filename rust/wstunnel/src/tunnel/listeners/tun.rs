@@ -0,0 +1,50 @@
+use crate::protocols::tun;
+use crate::protocols::tun::{TunListener, TunStream};
+use crate::tunnel::{LocalProtocol, RemoteAddr, to_host_port};
+use anyhow::{Context, anyhow};
+use std::os::fd::RawFd;
+use std::pin::Pin;
+use std::task::{Poll, ready};
+use std::time::Duration;
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio_stream::Stream;
+
+pub struct TunTunnelListener {
+    listener: TunListener,
+    timeout: Option<Duration>,
+}
+
+impl TunTunnelListener {
+    pub fn new(fd: RawFd, mtu: u16, timeout: Option<Duration>) -> anyhow::Result<Self> {
+        let listener = tun::run_server(fd, mtu as usize, timeout)
+            .with_context(|| anyhow!("Cannot start userspace stack on tun fd {fd}"))?;
+
+        Ok(Self { listener, timeout })
+    }
+}
+
+impl Stream for TunTunnelListener {
+    type Item = anyhow::Result<((ReadHalf<TunStream>, WriteHalf<TunStream>), RemoteAddr)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let ret = ready!(Pin::new(&mut this.listener).poll_next(cx));
+        let ret = match ret {
+            Some(Ok((stream, destination))) => {
+                let protocol = match stream {
+                    TunStream::Tcp(_) => LocalProtocol::Tcp {
+                        proxy_protocol: false,
+                        idle_timeout: None,
+                    },
+                    TunStream::Udp(_) => LocalProtocol::Udp { timeout: this.timeout },
+                };
+                let (host, port) = to_host_port(destination);
+                let remote = RemoteAddr { protocol, host, port };
+                Some(anyhow::Ok((tokio::io::split(stream), remote)))
+            }
+            Some(Err(err)) => Some(Err(err)),
+            None => None,
+        };
+        Poll::Ready(ret)
+    }
+}
@@ -43,6 +43,7 @@ impl Stream for UnixTunnelListener {
                     RemoteAddr {
                         protocol: LocalProtocol::Tcp {
                             proxy_protocol: this.proxy_protocol,
+                            idle_timeout: None,
                         },
                         host,
                         port,
@@ -2,9 +2,11 @@ pub mod client;
 pub mod connectors;
 pub mod listeners;
 pub mod server;
+pub mod shutdown;
 mod tls_reloader;
 pub mod transport;
 
+use ipnet::Ipv4Net;
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
@@ -16,6 +18,7 @@ use url::Host;
 pub enum LocalProtocol {
     Tcp {
         proxy_protocol: bool,
+        idle_timeout: Option<Duration>,
     },
     Udp {
         timeout: Option<Duration>,
@@ -27,6 +30,22 @@ pub enum LocalProtocol {
         timeout: Option<Duration>,
         credentials: Option<(String, String)>,
     },
+    Mixed {
+        timeout: Option<Duration>,
+        credentials: Option<(String, String)>,
+    },
+    Dns {
+        timeout: Option<Duration>,
+        over_tcp: bool,
+    },
+    FakeDns {
+        pool: Ipv4Net,
+    },
+    Tun {
+        fd: i32,
+        mtu: u16,
+        timeout: Option<Duration>,
+    },
     TProxyTcp,
     TProxyUdp {
         timeout: Option<Duration>,
@@ -0,0 +1,287 @@
+use crate::tunnel::RemoteAddr;
+use crate::tunnel::server::limits::TunnelPermit;
+use crate::tunnel::server::metrics::{ServerMetrics, protocol_label};
+use crate::tunnel::shutdown::Shutdown;
+use parking_lot::Mutex;
+use pin_project::pin_project;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::gauge::Gauge;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll, ready};
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use uuid::Uuid;
+
+/// A tunnel currently open on the server, as listed by the admin API
+#[derive(Debug)]
+pub struct ActiveTunnel {
+    pub id: Uuid,
+    pub client_addr: SocketAddr,
+    pub restriction: String,
+    pub protocol: &'static str,
+    pub destination: String,
+    pub started_at: SystemTime,
+    bytes_to_client: AtomicU64,
+    bytes_from_client: AtomicU64,
+    kill: Shutdown,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ActiveTunnelView {
+    pub id: Uuid,
+    pub client_addr: SocketAddr,
+    pub restriction: String,
+    pub protocol: &'static str,
+    pub destination: String,
+    pub started_at_unix_sec: u64,
+    pub bytes_to_client: u64,
+    pub bytes_from_client: u64,
+}
+
+impl ActiveTunnel {
+    pub fn view(&self) -> ActiveTunnelView {
+        ActiveTunnelView {
+            id: self.id,
+            client_addr: self.client_addr,
+            restriction: self.restriction.clone(),
+            protocol: self.protocol,
+            destination: self.destination.clone(),
+            started_at_unix_sec: self
+                .started_at
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            bytes_to_client: self.bytes_to_client.load(Ordering::Relaxed),
+            bytes_from_client: self.bytes_from_client.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Registry of the tunnels currently open on the server
+#[derive(Debug, Default)]
+pub struct ActiveTunnels {
+    tunnels: Mutex<BTreeMap<Uuid, Arc<ActiveTunnel>>>,
+}
+
+impl ActiveTunnels {
+    pub fn list(&self) -> Vec<ActiveTunnelView> {
+        self.tunnels.lock().values().map(|tunnel| tunnel.view()).collect()
+    }
+
+    /// Close the tunnel, its local stream is seen as closed and the tunnel shuts down gracefully.
+    /// Return false if there is no such tunnel
+    pub fn kill(&self, id: &Uuid) -> bool {
+        let Some(tunnel) = self.tunnels.lock().get(id).cloned() else {
+            return false;
+        };
+        tunnel.kill.trigger();
+        true
+    }
+
+    /// Register a new tunnel, and wrap its local stream to account the bytes transferred.
+    /// The tunnel is unregistered, and its permit released, once both halves of the stream are dropped
+    #[allow(clippy::too_many_arguments)]
+    pub fn track<R: AsyncRead, W: AsyncWrite>(
+        self: &Arc<Self>,
+        metrics: &ServerMetrics,
+        client_addr: SocketAddr,
+        restriction: &str,
+        remote: &RemoteAddr,
+        permit: TunnelPermit,
+        local_rx: R,
+        local_tx: W,
+    ) -> (TrackedRead<R>, TrackedWrite<W>) {
+        let tunnel = Arc::new(ActiveTunnel {
+            id: Uuid::now_v7(),
+            client_addr,
+            restriction: restriction.to_string(),
+            protocol: protocol_label(&remote.protocol),
+            destination: format!("{}:{}", remote.host, remote.port),
+            started_at: SystemTime::now(),
+            bytes_to_client: AtomicU64::new(0),
+            bytes_from_client: AtomicU64::new(0),
+            kill: Shutdown::new(),
+        });
+        self.tunnels.lock().insert(tunnel.id, tunnel.clone());
+
+        let guard = Arc::new(TunnelGuard {
+            registry: Arc::downgrade(self),
+            tunnel: tunnel.clone(),
+            active: metrics.tunnel_opened(&remote.protocol),
+            permit,
+        });
+
+        (
+            TrackedRead {
+                inner: local_rx,
+                bytes: metrics.transferred_bytes(restriction, "to_client"),
+                killed: Box::pin(tunnel.kill.triggered()),
+                guard: guard.clone(),
+            },
+            TrackedWrite {
+                inner: local_tx,
+                bytes: metrics.transferred_bytes(restriction, "from_client"),
+                killed: Box::pin(tunnel.kill.triggered()),
+                guard,
+            },
+        )
+    }
+}
+
+struct TunnelGuard {
+    registry: Weak<ActiveTunnels>,
+    tunnel: Arc<ActiveTunnel>,
+    active: Gauge,
+    permit: TunnelPermit,
+}
+
+impl TunnelGuard {
+    // Register the waker to be woken up when the tunnel is killed.
+    // The future must not be polled again once it has completed
+    fn is_killed(&self, killed: &mut Killed, cx: &mut Context<'_>) -> bool {
+        self.tunnel.kill.is_triggered() || killed.as_mut().poll(cx).is_ready()
+    }
+}
+
+impl Drop for TunnelGuard {
+    fn drop(&mut self) {
+        self.active.dec();
+        if let Some(registry) = self.registry.upgrade() {
+            registry.tunnels.lock().remove(&self.tunnel.id);
+        }
+    }
+}
+
+type Killed = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+#[pin_project]
+pub struct TrackedRead<R> {
+    #[pin]
+    inner: R,
+    bytes: Counter,
+    killed: Killed,
+    guard: Arc<TunnelGuard>,
+}
+
+impl<R: AsyncRead> AsyncRead for TrackedRead<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        // A killed tunnel reads as EOF, so the tunnel is closed as if the local stream ended
+        if this.guard.is_killed(this.killed, cx) {
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+        ready!(this.inner.poll_read(cx, buf))?;
+        let read = (buf.filled().len() - filled) as u64;
+        this.bytes.inc_by(read);
+        this.guard.tunnel.bytes_to_client.fetch_add(read, Ordering::Relaxed);
+        this.guard.permit.account(read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[pin_project]
+pub struct TrackedWrite<W> {
+    #[pin]
+    inner: W,
+    bytes: Counter,
+    killed: Killed,
+    guard: Arc<TunnelGuard>,
+}
+
+impl<W: AsyncWrite> AsyncWrite for TrackedWrite<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        if this.guard.is_killed(this.killed, cx) {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::ConnectionAborted, "tunnel killed")));
+        }
+
+        let written = ready!(this.inner.poll_write(cx, buf))?;
+        this.bytes.inc_by(written as u64);
+        this.guard
+            .tunnel
+            .bytes_from_client
+            .fetch_add(written as u64, Ordering::Relaxed);
+        this.guard.permit.account(written as u64);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::LocalProtocol;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use url::Host;
+
+    #[tokio::test]
+    async fn test_track_and_kill_tunnel() {
+        let metrics = ServerMetrics::default();
+        let tunnels = Arc::new(ActiveTunnels::default());
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                idle_timeout: None,
+            },
+            host: Host::Domain("example.com".to_string()),
+            port: 443,
+        };
+        let (local, mut peer) = tokio::io::duplex(1024);
+        let (local_rx, local_tx) = tokio::io::split(local);
+        let (mut local_rx, mut local_tx) = tunnels.track(
+            &metrics,
+            "127.0.0.1:1234".parse().unwrap(),
+            "allow-all",
+            &remote,
+            TunnelPermit::default(),
+            local_rx,
+            local_tx,
+        );
+
+        local_tx.write_all(b"hello").await.unwrap();
+        peer.write_all(b"world!").await.unwrap();
+        let mut buf = [0u8; 6];
+        local_rx.read_exact(&mut buf).await.unwrap();
+
+        let listed = tunnels.list();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].destination, "example.com:443");
+        assert_eq!(listed[0].bytes_from_client, 5);
+        assert_eq!(listed[0].bytes_to_client, 6);
+        assert!(
+            metrics
+                .encode()
+                .contains("wstunnel_transferred_bytes_total{restriction=\"allow-all\",direction=\"from_client\"} 5\n")
+        );
+
+        // A killed tunnel reads as EOF even if the local stream is still open
+        assert!(!tunnels.kill(&Uuid::nil()));
+        assert!(tunnels.kill(&listed[0].id));
+        assert_eq!(local_rx.read(&mut buf).await.unwrap(), 0);
+        assert!(local_tx.write_all(b"hello").await.is_err());
+
+        drop((local_rx, local_tx));
+        assert!(tunnels.list().is_empty());
+        assert!(
+            metrics
+                .encode()
+                .contains("wstunnel_active_tunnels{protocol=\"tcp\"} 0\n")
+        );
+    }
+}
@@ -0,0 +1,339 @@
+use crate::restrictions::config_reloader::RestrictionsRulesReloader;
+use crate::restrictions::types::{
+    AllowConfig, LimitsConfig, MatchConfig, RestrictionConfig, RestrictionsRules, ReverseTunnelConfigProtocol,
+    TunnelConfigProtocol,
+};
+use crate::tunnel::server::active_tunnels::ActiveTunnels;
+use crate::tunnel::server::quotas::QuotaTracker;
+use crate::tunnel::server::server::{close_reverse_tunnel_listener, reverse_tunnel_listeners};
+use crate::tunnel::shutdown::Shutdown;
+use anyhow::Context;
+use hyper::body::Incoming;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use ipnet::IpNet;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Admin API of the server, every request must carry an `Authorization: Bearer <token>` header
+///
+/// GET    /tunnels                   list the active tunnels
+/// DELETE /tunnels/<id>              close a tunnel
+/// GET    /reverse-tunnels           list the reverse tunnel listeners
+/// DELETE /reverse-tunnels/<bind>    unbind a reverse tunnel listener, i.e: /reverse-tunnels/0.0.0.0:8000
+/// GET    /restrictions              dump the effective restrictions, without the secrets they contain
+/// GET    /quotas                    list the traffic used by the restrictions having a quota
+/// POST   /restrictions/reload       reload the restrictions config file, 409 if the server has none
+#[derive(Clone)]
+pub struct AdminApi {
+    pub token: Arc<str>,
+    pub tunnels: Arc<ActiveTunnels>,
+    pub quotas: Arc<QuotaTracker>,
+    pub restrictions: RestrictionsRulesReloader,
+}
+
+#[derive(Serialize)]
+struct ReverseTunnelListenerView {
+    protocol: &'static str,
+    bind: SocketAddr,
+}
+
+// Path prefixes, authorization values and headers are often secrets, and users only show their name
+const REDACTED: &str = "<redacted>";
+
+#[derive(Serialize)]
+struct RestrictionsView<'a> {
+    restrictions: Vec<RestrictionView<'a>>,
+    users: Vec<&'a str>,
+}
+
+#[derive(Serialize)]
+struct RestrictionView<'a> {
+    name: &'a str,
+    r#match: Vec<MatchView<'a>>,
+    allow: Vec<AllowView<'a>>,
+    limits: &'a LimitsConfig,
+}
+
+#[derive(Serialize)]
+enum MatchView<'a> {
+    Any,
+    PathPrefix(&'static str),
+    Authorization(&'static str),
+    User(&'a [String]),
+    ClientCidr(&'a [IpNet]),
+    CertificateCn(&'a str),
+    CertificateSan(&'a str),
+    Header {
+        name: &'a str,
+        regex: &'static str,
+    },
+    NotAfter(String),
+    NotBefore(String),
+    Schedule {
+        days: Vec<String>,
+        hours: String,
+        timezone: Option<&'a str>,
+    },
+}
+
+#[derive(Serialize)]
+enum AllowView<'a> {
+    ReverseTunnel {
+        protocol: &'a [ReverseTunnelConfigProtocol],
+        port: &'a [RangeInclusive<u16>],
+        port_mapping: &'a HashMap<u16, u16>,
+        cidr: &'a [IpNet],
+        unix_path: &'a str,
+    },
+    Tunnel {
+        protocol: &'a [TunnelConfigProtocol],
+        port: &'a [RangeInclusive<u16>],
+        host: &'a str,
+        cidr: &'a [IpNet],
+    },
+}
+
+impl<'a> From<&'a RestrictionsRules> for RestrictionsView<'a> {
+    fn from(rules: &'a RestrictionsRules) -> Self {
+        Self {
+            restrictions: rules.restrictions.iter().map(RestrictionView::from).collect(),
+            users: rules.users.names().collect(),
+        }
+    }
+}
+
+impl<'a> From<&'a RestrictionConfig> for RestrictionView<'a> {
+    fn from(restriction: &'a RestrictionConfig) -> Self {
+        Self {
+            name: &restriction.name,
+            r#match: restriction.r#match.iter().map(MatchView::from).collect(),
+            allow: restriction.allow.iter().map(AllowView::from).collect(),
+            limits: &restriction.limits,
+        }
+    }
+}
+
+impl<'a> From<&'a MatchConfig> for MatchView<'a> {
+    fn from(config: &'a MatchConfig) -> Self {
+        match config {
+            MatchConfig::Any => Self::Any,
+            MatchConfig::PathPrefix(_) => Self::PathPrefix(REDACTED),
+            MatchConfig::Authorization(_) => Self::Authorization(REDACTED),
+            MatchConfig::User(names) => Self::User(names),
+            MatchConfig::ClientCidr(cidrs) => Self::ClientCidr(cidrs),
+            MatchConfig::CertificateCn(regex) => Self::CertificateCn(regex.as_str()),
+            MatchConfig::CertificateSan(regex) => Self::CertificateSan(regex.as_str()),
+            MatchConfig::Header { name, regex: _ } => Self::Header { name, regex: REDACTED },
+            MatchConfig::NotAfter(ts) => Self::NotAfter(ts.to_string()),
+            MatchConfig::NotBefore(ts) => Self::NotBefore(ts.to_string()),
+            MatchConfig::Schedule(schedule) => Self::Schedule {
+                days: schedule.days.iter().map(|day| format!("{day:?}")).collect(),
+                hours: format!("{}..{}", schedule.hours.start, schedule.hours.end),
+                timezone: schedule.timezone.iana_name(),
+            },
+        }
+    }
+}
+
+impl<'a> From<&'a AllowConfig> for AllowView<'a> {
+    fn from(config: &'a AllowConfig) -> Self {
+        match config {
+            AllowConfig::ReverseTunnel(allow) => Self::ReverseTunnel {
+                protocol: &allow.protocol,
+                port: &allow.port,
+                port_mapping: &allow.port_mapping,
+                cidr: &allow.cidr,
+                unix_path: allow.unix_path.as_str(),
+            },
+            AllowConfig::Tunnel(allow) => Self::Tunnel {
+                protocol: &allow.protocol,
+                port: &allow.port,
+                host: allow.host.as_str(),
+                cidr: &allow.cidr,
+            },
+        }
+    }
+}
+
+impl AdminApi {
+    fn is_authorized(&self, req: &Request<Incoming>) -> bool {
+        let Some(token) = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+        else {
+            return false;
+        };
+
+        constant_time_eq(token.as_bytes(), self.token.as_bytes())
+    }
+
+    fn handle(&self, req: &Request<Incoming>) -> Response<String> {
+        if !self.is_authorized(req) {
+            warn!("Rejecting unauthorized admin request {} {}", req.method(), req.uri());
+            return text_response(StatusCode::UNAUTHORIZED, "unauthorized");
+        }
+
+        let path = req.uri().path().trim_end_matches('/');
+        match (req.method(), path) {
+            (&Method::GET, "/tunnels") => json_response(&self.tunnels.list()),
+            (&Method::DELETE, path) if path.starts_with("/tunnels/") => {
+                let Ok(id) = Uuid::parse_str(&path["/tunnels/".len()..]) else {
+                    return text_response(StatusCode::BAD_REQUEST, "invalid tunnel id");
+                };
+                if !self.tunnels.kill(&id) {
+                    return text_response(StatusCode::NOT_FOUND, "no such tunnel");
+                }
+                info!("Tunnel {id} closed by admin request");
+                text_response(StatusCode::OK, "tunnel closed")
+            }
+            (&Method::GET, "/reverse-tunnels") => {
+                let listeners = reverse_tunnel_listeners()
+                    .into_iter()
+                    .map(|(protocol, bind)| ReverseTunnelListenerView { protocol, bind })
+                    .collect::<Vec<_>>();
+                json_response(&listeners)
+            }
+            (&Method::DELETE, path) if path.starts_with("/reverse-tunnels/") => {
+                let Ok(bind) = path["/reverse-tunnels/".len()..].parse::<SocketAddr>() else {
+                    return text_response(StatusCode::BAD_REQUEST, "invalid listener address");
+                };
+                if !close_reverse_tunnel_listener(&bind) {
+                    return text_response(StatusCode::NOT_FOUND, "no such listener");
+                }
+                info!("Reverse tunnel listener {bind} closed by admin request");
+                text_response(StatusCode::OK, "listener closed")
+            }
+            (&Method::GET, "/restrictions") => {
+                let restrictions = self.restrictions.restrictions_rules().load();
+                json_response(&RestrictionsView::from(restrictions.as_ref()))
+            }
+            (&Method::GET, "/quotas") => {
+                let restrictions = self.restrictions.restrictions_rules().load();
+                json_response(&self.quotas.usage(&restrictions))
+            }
+            (&Method::POST, "/restrictions/reload") => {
+                info!("Reloading restrictions by admin request");
+                match self.restrictions.reload_restrictions_config() {
+                    Ok(true) => text_response(StatusCode::OK, "restrictions reloaded"),
+                    Ok(false) => text_response(StatusCode::CONFLICT, "no restrictions config file to reload"),
+                    Err(err) => text_response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        &format!("cannot reload restrictions, keeping the old ones: {err:#}"),
+                    ),
+                }
+            }
+            _ => text_response(StatusCode::NOT_FOUND, "not found"),
+        }
+    }
+}
+
+/// Serve the admin API until the server shuts down
+pub async fn serve_admin(bind: SocketAddr, api: AdminApi, shutdown: Shutdown) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(bind)
+        .await
+        .with_context(|| format!("Failed to bind admin listener on {bind}"))?;
+    info!("Serving admin API on http://{bind}");
+
+    let shutdown_triggered = shutdown.triggered();
+    tokio::pin!(shutdown_triggered);
+    loop {
+        let stream = tokio::select! {
+            _ = &mut shutdown_triggered => return Ok(()),
+            cnx = listener.accept() => match cnx {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    warn!("Error while accepting admin connection {:?}", err);
+                    continue;
+                }
+            },
+        };
+
+        let api = api.clone();
+        let service =
+            service_fn(move |req: Request<Incoming>| std::future::ready(Ok::<_, hyper::Error>(api.handle(&req))));
+        tokio::spawn(async move {
+            let _ = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+    }
+}
+
+fn text_response(status: StatusCode, body: &str) -> Response<String> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(format!("{body}\n"))
+        .expect("bug: failed to build response")
+}
+
+fn json_response(body: &impl Serialize) -> Response<String> {
+    match serde_json::to_string_pretty(body) {
+        Ok(body) => Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .expect("bug: failed to build response"),
+        Err(err) => text_response(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+    }
+}
+
+// Do not leak the length of the matching prefix of the token through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+
+    #[test]
+    fn test_restrictions_view_is_redacted() {
+        let config = r#"
+restrictions:
+  - name: "team"
+    match:
+      - !PathPrefix "^secret-prefix$"
+      - !Authorization "^Bearer secret-token$"
+      - !Header { name: X-Team, regex: "^secret-team$" }
+      - !User [alice]
+    allow:
+      - !Tunnel
+        host: "^internal$"
+users:
+  - name: alice
+    token_sha256: "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"
+"#;
+        let rules: RestrictionsRules = serde_yaml::from_str(config).unwrap();
+        let view = serde_json::to_string(&RestrictionsView::from(&rules)).unwrap();
+
+        assert!(!view.contains("secret"), "{view}");
+        assert!(!view.contains("2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"));
+        assert!(view.contains("X-Team"));
+        assert!(view.contains("alice"));
+        assert!(view.contains("^internal$"));
+    }
+}
@@ -0,0 +1,244 @@
+use crate::executor::TokioExecutorRef;
+use crate::protocols;
+use crate::socket_bind::SocketBind;
+use crate::tunnel::server::utils::{HttpResponse, bad_request};
+use crate::tunnel::server::{WsServer, WsServerConfig};
+use anyhow::{Context, anyhow};
+use bytes::BytesMut;
+use futures_util::stream;
+use http_body_util::{BodyExt, Either, Empty, StreamBody};
+use hyper::body::{Frame, Incoming};
+use hyper::header::{
+    CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, HOST, HeaderMap, HeaderValue, PROXY_AUTHORIZATION, TE, TRAILER,
+    TRANSFER_ENCODING, UPGRADE,
+};
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tracing::{debug, warn};
+use url::{Host, Url};
+
+const FILE_CHUNK_SIZE: usize = 16 * 1024;
+
+/// What to answer to requests that are not a valid tunnel, so the server looks like a regular website to active probers
+#[derive(Debug, Clone)]
+pub enum FallbackConfig {
+    /// Serve the static files of this directory
+    Directory(PathBuf),
+    /// Forward the request to this upstream http server
+    ReverseProxy(Url),
+}
+
+pub(super) async fn serve_fallback(server: &WsServer<impl TokioExecutorRef>, req: Request<Incoming>) -> HttpResponse {
+    let ret = match &server.config.fallback {
+        None => return bad_request(),
+        Some(FallbackConfig::Directory(root)) => serve_directory(root, &req).await,
+        Some(FallbackConfig::ReverseProxy(upstream)) => {
+            reverse_proxy(&server.config, &server.executor, upstream, req).await
+        }
+    };
+
+    ret.unwrap_or_else(|err| {
+        warn!("Fallback failed to answer request: {err:?}");
+        status_response(StatusCode::BAD_GATEWAY)
+    })
+}
+
+async fn serve_directory(root: &Path, req: &Request<Incoming>) -> anyhow::Result<HttpResponse> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Ok(status_response(StatusCode::METHOD_NOT_ALLOWED));
+    }
+
+    let Some(mut path) = resolve_path(root, req.uri().path()) else {
+        return Ok(status_response(StatusCode::NOT_FOUND));
+    };
+    if tokio::fs::metadata(&path).await.is_ok_and(|m| m.is_dir()) {
+        path.push("index.html");
+    }
+
+    let (file, len) = match open_in_root(root, &path).await {
+        Ok(file) => file,
+        Err(err) => {
+            debug!("Fallback cannot serve {:?}: {err:#}", path);
+            return Ok(status_response(StatusCode::NOT_FOUND));
+        }
+    };
+
+    // Files are streamed, so a probe does not load a whole file in memory
+    let body = if req.method() == Method::HEAD {
+        Empty::new().map_err(|err| match err {}).boxed()
+    } else {
+        let chunks = stream::try_unfold(file, |mut file| async move {
+            let mut chunk = BytesMut::with_capacity(FILE_CHUNK_SIZE);
+            let len = file.read_buf(&mut chunk).await?;
+            Ok::<_, anyhow::Error>((len > 0).then(|| (Frame::data(chunk.freeze()), file)))
+        });
+        StreamBody::new(chunks).boxed()
+    };
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, content_type(&path))
+        .header(CONTENT_LENGTH, len)
+        .body(Either::Right(body))?)
+}
+
+// The check of resolve_path does not see the symlinks, the file they point to must be under the root too
+async fn open_in_root(root: &Path, path: &Path) -> anyhow::Result<(File, u64)> {
+    let root = tokio::fs::canonicalize(root).await?;
+    let path = tokio::fs::canonicalize(path).await?;
+    if !path.starts_with(&root) {
+        return Err(anyhow!("{path:?} is outside of the fallback directory"));
+    }
+
+    let file = File::open(&path).await?;
+    let metadata = file.metadata().await?;
+    if !metadata.is_file() {
+        return Err(anyhow!("{path:?} is not a file"));
+    }
+
+    Ok((file, metadata.len()))
+}
+
+// Map the request path under the root directory, refusing to escape it
+fn resolve_path(root: &Path, uri_path: &str) -> Option<PathBuf> {
+    let decoded = urlencoding::decode(uri_path).ok()?;
+    let mut path = root.to_path_buf();
+    for component in Path::new(decoded.as_ref()).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(path)
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff2") => "font/woff2",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+async fn reverse_proxy(
+    config: &WsServerConfig,
+    executor: &impl TokioExecutorRef,
+    upstream: &Url,
+    mut req: Request<Incoming>,
+) -> anyhow::Result<HttpResponse> {
+    let host = upstream
+        .host()
+        .ok_or_else(|| anyhow!("Fallback upstream {upstream} has no host"))?
+        .to_owned();
+    let port = upstream.port_or_known_default().unwrap_or(80);
+    let stream = protocols::tcp::connect(
+        &host,
+        port,
+        config.socket_so_mark,
+        &SocketBind::NONE,
+        config.timeout_connect,
+        config.happy_eyeballs_delay,
+        &config.dns_resolver,
+    )
+    .await?;
+
+    let (mut sender, cnx) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .with_context(|| format!("Cannot do http handshake with fallback upstream {upstream}"))?;
+    executor.spawn(async move {
+        if let Err(err) = cnx.await {
+            debug!("Fallback upstream connection closed: {err:?}");
+        }
+    });
+
+    // The upstream is reached with an origin-form request, on its own virtual host
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    *req.uri_mut() = path.parse()?;
+    *req.version_mut() = hyper::Version::HTTP_11;
+    let authority = match (&host, upstream.port()) {
+        (Host::Ipv6(ip), Some(port)) => format!("[{ip}]:{port}"),
+        (Host::Ipv6(ip), None) => format!("[{ip}]"),
+        (host, Some(port)) => format!("{host}:{port}"),
+        (host, None) => host.to_string(),
+    };
+    req.headers_mut().insert(HOST, HeaderValue::from_str(&authority)?);
+    remove_hop_by_hop_headers(req.headers_mut());
+
+    let mut response = sender.send_request(req).await?;
+    remove_hop_by_hop_headers(response.headers_mut());
+    Ok(response.map(|body| Either::Right(body.map_err(anyhow::Error::from).boxed())))
+}
+
+// Connection specific headers are not forwarded, i.e: an upgrade request must not be upgraded by the upstream
+fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    for header in [CONNECTION, UPGRADE, TE, TRAILER, TRANSFER_ENCODING, PROXY_AUTHORIZATION] {
+        headers.remove(header);
+    }
+    headers.remove("keep-alive");
+    headers.remove("proxy-connection");
+}
+
+fn status_response(status: StatusCode) -> HttpResponse {
+    let reason = status.canonical_reason().unwrap_or_default();
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(Either::Left(format!(
+            "<html><head><title>{} {reason}</title></head><body><h1>{reason}</h1></body></html>\n",
+            status.as_u16()
+        )))
+        .expect("bug: failed to build response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case("/", Some("/srv/www") ; "root")]
+    #[test_case("/css/style.css", Some("/srv/www/css/style.css") ; "nested file")]
+    #[test_case("/a%20b.html", Some("/srv/www/a b.html") ; "percent encoded")]
+    #[test_case("/../etc/passwd", None ; "parent dir")]
+    #[test_case("/css/%2e%2e/%2e%2e/etc/passwd", None ; "encoded parent dir")]
+    fn test_resolve_path(uri_path: &str, expected: Option<&str>) {
+        assert_eq!(
+            resolve_path(Path::new("/srv/www"), uri_path),
+            expected.map(PathBuf::from)
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_open_in_root_refuses_symlink_escaping_it() {
+        let dir = std::env::temp_dir().join(format!("wstunnel-fallback-root-{}", std::process::id()));
+        let root = dir.join("www");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("index.html"), "hello").unwrap();
+        std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret.txt"), root.join("leak.txt")).unwrap();
+        std::os::unix::fs::symlink(root.join("index.html"), root.join("home.html")).unwrap();
+
+        assert_eq!(open_in_root(&root, &root.join("index.html")).await.unwrap().1, 5);
+        assert_eq!(open_in_root(&root, &root.join("home.html")).await.unwrap().1, 5);
+        assert!(open_in_root(&root, &root.join("leak.txt")).await.is_err());
+        assert!(open_in_root(&root, &root).await.is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
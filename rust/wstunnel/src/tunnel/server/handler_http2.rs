@@ -1,7 +1,8 @@
 use crate::executor::TokioExecutorRef;
 use crate::restrictions::types::RestrictionsRules;
 use crate::tunnel::server::WsServer;
-use crate::tunnel::server::utils::{HttpResponse, bad_request, inject_cookie};
+use crate::tunnel::server::fallback::serve_fallback;
+use crate::tunnel::server::utils::{ClientCertificate, HttpResponse, TunnelRequestError, bad_request, inject_cookie};
 use crate::tunnel::transport;
 use crate::tunnel::transport::http2::{Http2TunnelRead, Http2TunnelWrite};
 use bytes::Bytes;
@@ -20,16 +21,17 @@ use tracing::{Instrument, Span};
 pub(super) async fn http_server_upgrade(
     server: WsServer<impl TokioExecutorRef>,
     restrictions: Arc<RestrictionsRules>,
-    restrict_path_prefix: Option<String>,
+    client_cert: Option<ClientCertificate>,
     client_addr: SocketAddr,
     mut req: Request<Incoming>,
 ) -> HttpResponse {
-    let (remote_addr, local_rx, local_tx, need_cookie) = match server
-        .handle_tunnel_request(restrictions, restrict_path_prefix, client_addr, &req)
+    let (remote_addr, local_rx, local_tx, need_cookie, bandwidth) = match server
+        .handle_tunnel_request(restrictions, client_cert, client_addr, &req)
         .await
     {
         Ok(ret) => ret,
-        Err(err) => return err,
+        Err(TunnelRequestError::NotATunnel) => return serve_fallback(&server, req).await,
+        Err(TunnelRequestError::Failed(err)) => return err,
     };
 
     let req_content_type = req.headers_mut().remove(CONTENT_TYPE);
@@ -45,14 +47,29 @@ pub(super) async fn http_server_upgrade(
         .expect("bug: failed to build response");
 
     let (close_tx, close_rx) = oneshot::channel::<()>();
+    let idle_timeout = server.idle_timeout(&remote_addr.protocol);
     server.executor.spawn(
-        transport::io::propagate_remote_to_local(local_tx, Http2TunnelRead::new(ws_rx, None), close_rx)
-            .instrument(Span::current()),
+        transport::io::propagate_remote_to_local(
+            local_tx,
+            Http2TunnelRead::new(ws_rx, None),
+            close_rx,
+            idle_timeout.clone(),
+            bandwidth.up,
+            server.shutdown.clone(),
+        )
+        .instrument(Span::current()),
     );
 
     server.executor.spawn(
-        transport::io::propagate_local_to_remote(local_rx, Http2TunnelWrite::new(ws_tx), close_tx, None)
-            .instrument(Span::current()),
+        transport::io::propagate_local_to_remote(
+            local_rx,
+            Http2TunnelWrite::new(ws_tx),
+            close_tx,
+            idle_timeout,
+            bandwidth.down,
+            server.shutdown.clone(),
+        )
+        .instrument(Span::current()),
     );
 
     if need_cookie && inject_cookie(&mut response, &remote_addr).is_err() {
@@ -1,8 +1,10 @@
 use crate::executor::TokioExecutorRef;
 use crate::restrictions::types::RestrictionsRules;
 use crate::tunnel::server::WsServer;
-use crate::tunnel::server::utils::{HttpResponse, bad_request, inject_cookie};
+use crate::tunnel::server::fallback::serve_fallback;
+use crate::tunnel::server::utils::{ClientCertificate, HttpResponse, TunnelRequestError, bad_request, inject_cookie};
 use crate::tunnel::transport;
+use crate::tunnel::transport::keepalive::Keepalive;
 use crate::tunnel::transport::websocket::mk_websocket_tunnel;
 use fastwebsockets::Role;
 use http_body_util::Either;
@@ -18,22 +20,23 @@ use tracing::{Instrument, Span, error, warn};
 pub(super) async fn ws_server_upgrade(
     server: WsServer<impl TokioExecutorRef>,
     restrictions: Arc<RestrictionsRules>,
-    restrict_path_prefix: Option<String>,
+    client_cert: Option<ClientCertificate>,
     client_addr: SocketAddr,
     mut req: Request<Incoming>,
 ) -> HttpResponse {
     if !fastwebsockets::upgrade::is_upgrade_request(&req) {
         warn!("Rejecting connection with bad upgrade request: {}", req.uri());
-        return bad_request();
+        return serve_fallback(&server, req).await;
     }
 
     let mask_frame = server.config.websocket_mask_frame;
-    let (remote_addr, local_rx, local_tx, need_cookie) = match server
-        .handle_tunnel_request(restrictions, restrict_path_prefix, client_addr, &req)
+    let (remote_addr, local_rx, local_tx, need_cookie, bandwidth) = match server
+        .handle_tunnel_request(restrictions, client_cert, client_addr, &req)
         .await
     {
         Ok(ret) => ret,
-        Err(err) => return err,
+        Err(TunnelRequestError::NotATunnel) => return serve_fallback(&server, req).await,
+        Err(TunnelRequestError::Failed(err)) => return err,
     };
 
     let (response, fut) = match fastwebsockets::upgrade::upgrade(&mut req) {
@@ -45,10 +48,20 @@ pub(super) async fn ws_server_upgrade(
     };
 
     let executor = server.executor.clone();
+    let idle_timeout = server.idle_timeout(&remote_addr.protocol);
     server.executor.spawn(
         async move {
             let (ws_rx, ws_tx) = match fut.await {
-                Ok(ws) => match mk_websocket_tunnel(ws, Role::Server, mask_frame) {
+                Ok(ws) => match mk_websocket_tunnel(
+                    ws,
+                    Role::Server,
+                    mask_frame,
+                    Keepalive::fixed(
+                        server.config.websocket_ping_frequency,
+                        server.config.websocket_pong_timeout,
+                    ),
+                    &executor,
+                ) {
                     Ok(ws) => ws,
                     Err(err) => {
                         error!("Error during http upgrade request: {:?}", err);
@@ -62,14 +75,25 @@ pub(super) async fn ws_server_upgrade(
             };
             let (close_tx, close_rx) = oneshot::channel::<()>();
 
-            executor
-                .spawn(transport::io::propagate_remote_to_local(local_tx, ws_rx, close_rx).instrument(Span::current()));
+            executor.spawn(
+                transport::io::propagate_remote_to_local(
+                    local_tx,
+                    ws_rx,
+                    close_rx,
+                    idle_timeout.clone(),
+                    bandwidth.up,
+                    server.shutdown.clone(),
+                )
+                .instrument(Span::current()),
+            );
 
             let _ = transport::io::propagate_local_to_remote(
                 local_rx,
                 ws_tx,
                 close_tx,
-                server.config.websocket_ping_frequency,
+                idle_timeout,
+                bandwidth.down,
+                server.shutdown.clone(),
             )
             .await;
             Ok(())
@@ -0,0 +1,264 @@
+use crate::restrictions::types::{LimitConfig, RestrictionConfig};
+use crate::tunnel::server::quotas::QuotaUsage;
+use crate::tunnel::transport::bandwidth::{BandwidthLimit, TokenBucket};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Above this number of client ips tracked for a restriction, the idle ones are forgotten
+const MAX_IDLE_CLIENT_IPS: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    ConcurrentTunnels,
+    NewTunnelsRate,
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ConcurrentTunnels => write!(f, "too many concurrent tunnels"),
+            Self::NewTunnelsRate => write!(f, "too many new tunnels per second"),
+        }
+    }
+}
+
+/// Enforce the limits of the restrictions on the new tunnels.
+/// The state is kept by restriction name, so it survives a reload of the restrictions config
+#[derive(Debug, Default)]
+pub struct TunnelLimiter {
+    restrictions: Mutex<HashMap<String, RestrictionLimiter>>,
+}
+
+#[derive(Debug, Default)]
+struct RestrictionLimiter {
+    total: LimiterState,
+    per_client_ip: HashMap<IpAddr, LimiterState>,
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    config: LimitConfig,
+    active: Arc<AtomicUsize>,
+    new_tunnels: Option<TokenBucket>,
+    bandwidth_up: Option<Arc<TokenBucket>>,
+    bandwidth_down: Option<Arc<TokenBucket>>,
+}
+
+impl LimiterState {
+    // Buckets are re-created when the limits change, but the count of active tunnels is kept
+    fn update(&mut self, config: &LimitConfig) {
+        if &self.config == config {
+            return;
+        }
+
+        self.config = config.clone();
+        self.new_tunnels = config
+            .new_tunnels_per_sec
+            .map(|rate| TokenBucket::new(rate, rate.max(1.0)));
+        self.bandwidth_up = config
+            .bandwidth_up
+            .map(|rate| Arc::new(TokenBucket::new(rate as f64, rate as f64)));
+        self.bandwidth_down = config
+            .bandwidth_down
+            .map(|rate| Arc::new(TokenBucket::new(rate as f64, rate as f64)));
+    }
+
+    fn check(&self) -> Result<(), LimitExceeded> {
+        if let Some(max) = self.config.max_concurrent_tunnels
+            && self.active.load(Ordering::Relaxed) >= max
+        {
+            return Err(LimitExceeded::ConcurrentTunnels);
+        }
+
+        if let Some(bucket) = &self.new_tunnels
+            && bucket.available() < 1.0
+        {
+            return Err(LimitExceeded::NewTunnelsRate);
+        }
+
+        Ok(())
+    }
+
+    fn acquire(&self) -> Arc<AtomicUsize> {
+        if let Some(bucket) = &self.new_tunnels {
+            bucket.try_acquire(1.0);
+        }
+        self.active.fetch_add(1, Ordering::Relaxed);
+        self.active.clone()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.active.load(Ordering::Relaxed) == 0 && self.new_tunnels.as_ref().is_none_or(TokenBucket::is_full)
+    }
+}
+
+/// A tunnel accepted by the limits of its restriction, it counts as active until dropped
+#[derive(Debug, Default)]
+pub struct TunnelPermit {
+    active: Vec<Arc<AtomicUsize>>,
+    quota: Option<Arc<QuotaUsage>>,
+    pub bandwidth: TunnelBandwidth,
+}
+
+impl TunnelPermit {
+    pub fn with_quota(mut self, quota: Option<Arc<QuotaUsage>>) -> Self {
+        self.quota = quota;
+        self
+    }
+
+    /// Account the bytes transferred by the tunnel against the quota of its restriction
+    pub fn account(&self, bytes: u64) {
+        if let Some(quota) = &self.quota {
+            quota.add(bytes);
+        }
+    }
+}
+
+impl Drop for TunnelPermit {
+    fn drop(&mut self) {
+        for active in &self.active {
+            active.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Bandwidth caps of a tunnel, up is what is sent by the client
+#[derive(Debug, Clone, Default)]
+pub struct TunnelBandwidth {
+    pub up: BandwidthLimit,
+    pub down: BandwidthLimit,
+}
+
+impl TunnelLimiter {
+    pub fn acquire(&self, restriction: &RestrictionConfig, client_ip: IpAddr) -> Result<TunnelPermit, LimitExceeded> {
+        let limits = &restriction.limits;
+        if limits.total.is_unlimited() && limits.per_client_ip.is_unlimited() {
+            return Ok(TunnelPermit::default());
+        }
+
+        let mut restrictions = self.restrictions.lock();
+        let limiter = restrictions.entry(restriction.name.clone()).or_default();
+        if limiter.per_client_ip.len() > MAX_IDLE_CLIENT_IPS {
+            limiter.per_client_ip.retain(|_, state| !state.is_idle());
+        }
+
+        limiter.total.update(&limits.total);
+        let client = limiter.per_client_ip.entry(client_ip).or_default();
+        client.update(&limits.per_client_ip);
+
+        // Check everything before taking anything, to not account a rejected tunnel
+        limiter.total.check()?;
+        client.check()?;
+
+        let states = [&limiter.total, &*client];
+        let bandwidth = TunnelBandwidth {
+            up: BandwidthLimit::new(states.iter().filter_map(|s| s.bandwidth_up.clone()).collect()),
+            down: BandwidthLimit::new(states.iter().filter_map(|s| s.bandwidth_down.clone()).collect()),
+        };
+        Ok(TunnelPermit {
+            active: states.iter().map(|s| s.acquire()).collect(),
+            quota: None,
+            bandwidth,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::restrictions::types::{LimitsConfig, MatchConfig};
+
+    fn restriction(limits: LimitsConfig) -> RestrictionConfig {
+        RestrictionConfig {
+            name: "limited".to_string(),
+            r#match: vec![MatchConfig::Any],
+            allow: vec![],
+            limits,
+        }
+    }
+
+    #[test]
+    fn test_max_concurrent_tunnels() {
+        let limiter = TunnelLimiter::default();
+        let restriction = restriction(LimitsConfig {
+            total: LimitConfig {
+                max_concurrent_tunnels: Some(3),
+                ..Default::default()
+            },
+            per_client_ip: LimitConfig {
+                max_concurrent_tunnels: Some(2),
+                bandwidth_down: Some(1024),
+                ..Default::default()
+            },
+            quota: None,
+        });
+        let client1: IpAddr = "10.0.0.1".parse().unwrap();
+        let client2: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let permit = limiter.acquire(&restriction, client1).unwrap();
+        assert!(permit.bandwidth.up.is_unlimited());
+        assert!(!permit.bandwidth.down.is_unlimited());
+        let _permit2 = limiter.acquire(&restriction, client1).unwrap();
+        assert_eq!(
+            limiter.acquire(&restriction, client1).unwrap_err(),
+            LimitExceeded::ConcurrentTunnels
+        );
+
+        let _permit3 = limiter.acquire(&restriction, client2).unwrap();
+        assert_eq!(
+            limiter.acquire(&restriction, client2).unwrap_err(),
+            LimitExceeded::ConcurrentTunnels
+        );
+
+        // A closed tunnel frees its slot
+        drop(permit);
+        assert!(limiter.acquire(&restriction, client2).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_limits() {
+        let limits = |yaml: &str| serde_yaml::from_str::<LimitConfig>(yaml);
+
+        assert_eq!(
+            limits("{new_tunnels_per_sec: 0.5, bandwidth_up: 1024}").unwrap(),
+            LimitConfig {
+                new_tunnels_per_sec: Some(0.5),
+                bandwidth_up: Some(1024),
+                ..Default::default()
+            }
+        );
+        assert!(limits("{}").unwrap().is_unlimited());
+        assert!(limits("{bandwidth_up: 0}").is_err());
+        assert!(limits("{bandwidth_down: 0}").is_err());
+        assert!(limits("{new_tunnels_per_sec: 0}").is_err());
+        assert!(limits("{new_tunnels_per_sec: -1.0}").is_err());
+        assert!(limits("{new_tunnels_per_sec: .nan}").is_err());
+        assert!(limits("{new_tunnels_per_sec: .inf}").is_err());
+    }
+
+    #[test]
+    fn test_new_tunnels_rate() {
+        let limiter = TunnelLimiter::default();
+        let restriction = restriction(LimitsConfig {
+            total: LimitConfig::default(),
+            per_client_ip: LimitConfig {
+                new_tunnels_per_sec: Some(0.001),
+                ..Default::default()
+            },
+            quota: None,
+        });
+        let client1: IpAddr = "10.0.0.1".parse().unwrap();
+        let client2: IpAddr = "10.0.0.2".parse().unwrap();
+
+        assert!(limiter.acquire(&restriction, client1).is_ok());
+        assert_eq!(
+            limiter.acquire(&restriction, client1).unwrap_err(),
+            LimitExceeded::NewTunnelsRate
+        );
+        assert!(limiter.acquire(&restriction, client2).is_ok());
+    }
+}
@@ -0,0 +1,324 @@
+use crate::tunnel::LocalProtocol;
+use crate::tunnel::shutdown::Shutdown;
+use anyhow::Context;
+use hyper::body::Incoming;
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+use std::fmt::{Debug, Formatter};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+use url::Host;
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum RejectReason {
+    BadPathPrefix,
+    BadJwt,
+    RestrictionDenied,
+    RateLimited,
+    QuotaExceeded,
+    ConnectFailed,
+}
+
+impl RejectReason {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::BadPathPrefix => "bad_path_prefix",
+            Self::BadJwt => "bad_jwt",
+            Self::RestrictionDenied => "restriction_denied",
+            Self::RateLimited => "rate_limited",
+            Self::QuotaExceeded => "quota_exceeded",
+            Self::ConnectFailed => "connect_failed",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ReasonLabels {
+    reason: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct UserLabels {
+    user: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ProtocolLabels {
+    protocol: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct TransferLabels {
+    restriction: String,
+    direction: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ConnectorLabels {
+    protocol: &'static str,
+    destination: &'static str,
+}
+
+/// Operational metrics of the server, exposed in Prometheus text format on the metrics listener
+pub struct ServerMetrics {
+    registry: Registry,
+    upgrades_accepted: Counter,
+    user_upgrades_accepted: Family<UserLabels, Counter>,
+    upgrades_rejected: Family<ReasonLabels, Counter>,
+    active_tunnels: Family<ProtocolLabels, Gauge>,
+    transferred_bytes: Family<TransferLabels, Counter>,
+    tls_handshake_failures: Counter,
+    reverse_tunnel_listeners: Family<ProtocolLabels, Gauge>,
+    connector_failures: Family<ConnectorLabels, Counter>,
+}
+
+impl Debug for ServerMetrics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerMetrics").finish_non_exhaustive()
+    }
+}
+
+impl Default for ServerMetrics {
+    fn default() -> Self {
+        let mut metrics = Self {
+            registry: Registry::with_prefix("wstunnel"),
+            upgrades_accepted: Counter::default(),
+            user_upgrades_accepted: Family::default(),
+            upgrades_rejected: Family::default(),
+            active_tunnels: Family::default(),
+            transferred_bytes: Family::default(),
+            tls_handshake_failures: Counter::default(),
+            reverse_tunnel_listeners: Family::default(),
+            connector_failures: Family::default(),
+        };
+
+        let registry = &mut metrics.registry;
+        registry.register(
+            "upgrade_accepted",
+            "Upgrade requests accepted",
+            metrics.upgrades_accepted.clone(),
+        );
+        registry.register(
+            "user_upgrade_accepted",
+            "Upgrade requests accepted by authenticated user",
+            metrics.user_upgrades_accepted.clone(),
+        );
+        registry.register(
+            "upgrade_rejected",
+            "Upgrade requests rejected by reason",
+            metrics.upgrades_rejected.clone(),
+        );
+        registry.register(
+            "active_tunnels",
+            "Tunnels currently open by protocol",
+            metrics.active_tunnels.clone(),
+        );
+        registry.register(
+            "transferred_bytes",
+            "Bytes transferred by restriction name and direction",
+            metrics.transferred_bytes.clone(),
+        );
+        registry.register(
+            "tls_handshake_failures",
+            "TLS handshakes failed",
+            metrics.tls_handshake_failures.clone(),
+        );
+        registry.register(
+            "reverse_tunnel_listeners",
+            "Reverse tunnel listeners currently bound by protocol",
+            metrics.reverse_tunnel_listeners.clone(),
+        );
+        registry.register(
+            "connector_failures",
+            "Failed connections to tunnel destinations by protocol and destination class",
+            metrics.connector_failures.clone(),
+        );
+
+        metrics
+    }
+}
+
+impl ServerMetrics {
+    pub fn encode(&self) -> String {
+        let mut out = String::new();
+        // Writing into a String cannot fail
+        let _ = prometheus_client::encoding::text::encode(&mut out, &self.registry);
+        out
+    }
+
+    pub fn upgrade_accepted(&self, user: Option<&str>) {
+        self.upgrades_accepted.inc();
+        if let Some(user) = user {
+            self.user_upgrades_accepted
+                .get_or_create(&UserLabels { user: user.to_string() })
+                .inc();
+        }
+    }
+
+    pub fn upgrade_rejected(&self, reason: RejectReason) {
+        self.upgrades_rejected
+            .get_or_create(&ReasonLabels {
+                reason: reason.as_str(),
+            })
+            .inc();
+    }
+
+    pub fn tls_handshake_failed(&self) {
+        self.tls_handshake_failures.inc();
+    }
+
+    pub fn connector_failed(&self, protocol: &LocalProtocol, host: &Host) {
+        let labels = ConnectorLabels {
+            protocol: protocol_label(protocol),
+            destination: destination_class(host),
+        };
+        self.connector_failures.get_or_create(&labels).inc();
+    }
+
+    /// Gauge of the reverse tunnel listeners, to be incremented while a listener is bound
+    pub fn reverse_tunnel_listeners(&self, protocol: &'static str) -> Gauge {
+        self.reverse_tunnel_listeners
+            .get_or_create(&ProtocolLabels { protocol })
+            .clone()
+    }
+
+    /// Gauge of the tunnels currently open, already incremented for the new tunnel
+    pub fn tunnel_opened(&self, protocol: &LocalProtocol) -> Gauge {
+        let active = self
+            .active_tunnels
+            .get_or_create(&ProtocolLabels {
+                protocol: protocol_label(protocol),
+            })
+            .clone();
+        active.inc();
+        active
+    }
+
+    pub fn transferred_bytes(&self, restriction: &str, direction: &'static str) -> Counter {
+        self.transferred_bytes
+            .get_or_create(&TransferLabels {
+                restriction: restriction.to_string(),
+                direction,
+            })
+            .clone()
+    }
+}
+
+/// Serve the metrics in Prometheus text format on /metrics until the server shuts down
+pub async fn serve_metrics(bind: SocketAddr, metrics: Arc<ServerMetrics>, shutdown: Shutdown) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(bind)
+        .await
+        .with_context(|| format!("Failed to bind metrics listener on {bind}"))?;
+    info!("Serving metrics on http://{bind}/metrics");
+
+    let shutdown_triggered = shutdown.triggered();
+    tokio::pin!(shutdown_triggered);
+    loop {
+        let stream = tokio::select! {
+            _ = &mut shutdown_triggered => return Ok(()),
+            cnx = listener.accept() => match cnx {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    warn!("Error while accepting metrics connection {:?}", err);
+                    continue;
+                }
+            },
+        };
+
+        let metrics = metrics.clone();
+        let service = service_fn(move |req: Request<Incoming>| {
+            let response = match (req.method(), req.uri().path()) {
+                (&Method::GET, "/metrics") => Response::builder()
+                    .header(
+                        CONTENT_TYPE,
+                        "application/openmetrics-text; version=1.0.0; charset=utf-8",
+                    )
+                    .body(metrics.encode()),
+                _ => Response::builder().status(StatusCode::NOT_FOUND).body(String::new()),
+            };
+            std::future::ready(response)
+        });
+        tokio::spawn(async move {
+            let _ = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+    }
+}
+
+pub const fn protocol_label(protocol: &LocalProtocol) -> &'static str {
+    match protocol {
+        LocalProtocol::Tcp { .. } => "tcp",
+        LocalProtocol::Udp { .. } => "udp",
+        LocalProtocol::Stdio { .. } => "stdio",
+        LocalProtocol::Socks5 { .. } => "socks5",
+        LocalProtocol::Mixed { .. } => "mixed",
+        LocalProtocol::Dns { .. } => "dns",
+        LocalProtocol::FakeDns { .. } => "fake_dns",
+        LocalProtocol::Tun { .. } => "tun",
+        LocalProtocol::TProxyTcp => "tproxy_tcp",
+        LocalProtocol::TProxyUdp { .. } => "tproxy_udp",
+        LocalProtocol::HttpProxy { .. } => "http_proxy",
+        LocalProtocol::ReverseTcp => "reverse_tcp",
+        LocalProtocol::ReverseUdp { .. } => "reverse_udp",
+        LocalProtocol::ReverseSocks5 { .. } => "reverse_socks5",
+        LocalProtocol::ReverseHttpProxy { .. } => "reverse_http_proxy",
+        LocalProtocol::ReverseUnix { .. } => "reverse_unix",
+        LocalProtocol::Unix { .. } => "unix",
+    }
+}
+
+// Keep the cardinality low, the destination itself is in the logs
+fn destination_class(host: &Host) -> &'static str {
+    match host {
+        Host::Domain(_) => "domain",
+        Host::Ipv4(ip) if ip.is_loopback() => "loopback",
+        Host::Ipv4(ip) if ip.is_private() || ip.is_link_local() => "private",
+        Host::Ipv6(ip) if ip.is_loopback() => "loopback",
+        Host::Ipv6(ip) if ip.is_unique_local() || ip.is_unicast_link_local() => "private",
+        Host::Ipv4(_) | Host::Ipv6(_) => "public",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let metrics = ServerMetrics::default();
+        let protocol = LocalProtocol::Tcp {
+            proxy_protocol: false,
+            idle_timeout: None,
+        };
+        let active = metrics.tunnel_opened(&protocol);
+        metrics.transferred_bytes("allow-all", "to_client").inc_by(6);
+        metrics.upgrade_rejected(RejectReason::BadJwt);
+        metrics.connector_failed(&protocol, &Host::Ipv4("10.0.0.1".parse().unwrap()));
+
+        let out = metrics.encode();
+        assert!(out.contains("wstunnel_active_tunnels{protocol=\"tcp\"} 1\n"));
+        assert!(
+            out.contains("wstunnel_transferred_bytes_total{restriction=\"allow-all\",direction=\"to_client\"} 6\n")
+        );
+        assert!(out.contains("wstunnel_upgrade_rejected_total{reason=\"bad_jwt\"} 1\n"));
+        assert!(out.contains("wstunnel_connector_failures_total{protocol=\"tcp\",destination=\"private\"} 1\n"));
+
+        active.dec();
+        assert!(
+            metrics
+                .encode()
+                .contains("wstunnel_active_tunnels{protocol=\"tcp\"} 0\n")
+        );
+    }
+}
@@ -1,10 +1,18 @@
 #![allow(clippy::module_inception)]
+mod active_tunnels;
+mod admin;
+mod fallback;
 mod handler_http2;
 mod handler_websocket;
+mod limits;
+mod metrics;
+mod proxy_protocol;
+mod quotas;
 mod reverse_tunnel;
 mod server;
 mod utils;
 
+pub use fallback::FallbackConfig;
 pub use server::TlsServerConfig;
 pub use server::WsServer;
 pub use server::WsServerConfig;
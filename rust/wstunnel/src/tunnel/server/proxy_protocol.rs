@@ -0,0 +1,114 @@
+use anyhow::anyhow;
+use ppp::{v1, v2};
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// Shorter than any header, v1 ones are at least "PROXY UNKNOWN\r\n"
+const PREFIX_LEN: usize = 8;
+const V1_MAX_LEN: usize = 107;
+const V2_FIXED_LEN: usize = 16;
+
+/// Read the PROXY protocol header, v1 or v2, at the start of a connection accepted from a load balancer.
+/// Return the address of the client, or None if the load balancer does not relay one, i.e: for its own health checks.
+/// Nothing past the header is read, so the stream can be handed to TLS or http as is
+pub(super) async fn read_proxy_header(stream: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Option<SocketAddr>> {
+    let mut header = vec![0; PREFIX_LEN];
+    stream.read_exact(&mut header).await?;
+
+    if header.starts_with(v1::PROTOCOL_PREFIX.as_bytes()) {
+        while !header.ends_with(v1::PROTOCOL_SUFFIX.as_bytes()) {
+            if header.len() >= V1_MAX_LEN {
+                return Err(anyhow!("PROXY protocol v1 header is too long"));
+            }
+            header.push(stream.read_u8().await?);
+        }
+
+        let header = v1::Header::try_from(header.as_slice())
+            .map_err(|err| anyhow!("Invalid PROXY protocol v1 header: {err}"))?;
+        return Ok(match header.addresses {
+            v1::Addresses::Tcp4(addr) => Some(SocketAddr::from((addr.source_address, addr.source_port))),
+            v1::Addresses::Tcp6(addr) => Some(SocketAddr::from((addr.source_address, addr.source_port))),
+            v1::Addresses::Unknown => None,
+        });
+    }
+
+    if v2::PROTOCOL_PREFIX.starts_with(&header) {
+        // The fixed part of the header ends with the length of the addresses and TLVs that follow
+        header.resize(V2_FIXED_LEN, 0);
+        stream.read_exact(&mut header[PREFIX_LEN..]).await?;
+        let len = u16::from_be_bytes([header[V2_FIXED_LEN - 2], header[V2_FIXED_LEN - 1]]);
+        header.resize(V2_FIXED_LEN + len as usize, 0);
+        stream.read_exact(&mut header[V2_FIXED_LEN..]).await?;
+
+        let header = v2::Header::try_from(header.as_slice())
+            .map_err(|err| anyhow!("Invalid PROXY protocol v2 header: {err}"))?;
+        return Ok(match (header.command, header.addresses) {
+            (v2::Command::Proxy, v2::Addresses::IPv4(addr)) => {
+                Some(SocketAddr::from((addr.source_address, addr.source_port)))
+            }
+            (v2::Command::Proxy, v2::Addresses::IPv6(addr)) => {
+                Some(SocketAddr::from((addr.source_address, addr.source_port)))
+            }
+            _ => None,
+        });
+    }
+
+    Err(anyhow!("Connection does not start with a PROXY protocol header"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(b"PROXY TCP4 1.2.3.4 10.0.0.1 4711 443\r\nGET /" as &[u8], Some("1.2.3.4:4711") ; "v1 tcp4")]
+    #[test_case(b"PROXY TCP6 2001:db8::1 2001:db8::2 4711 443\r\nGET /", Some("[2001:db8::1]:4711") ; "v1 tcp6")]
+    #[test_case(b"PROXY UNKNOWN\r\nGET /", None ; "v1 unknown")]
+    #[tokio::test]
+    async fn test_read_proxy_header_v1(input: &[u8], expected: Option<&str>) {
+        let mut stream = input;
+        let addr = read_proxy_header(&mut stream).await.unwrap();
+        assert_eq!(addr, expected.map(|addr| addr.parse().unwrap()));
+        assert_eq!(stream, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn test_read_proxy_header_v2() {
+        let client: SocketAddr = "[2001:db8::1]:4711".parse().unwrap();
+        let server: SocketAddr = "[2001:db8::2]:443".parse().unwrap();
+        let mut input = v2::Builder::with_addresses(
+            v2::Version::Two | v2::Command::Proxy,
+            v2::Protocol::Stream,
+            (client, server),
+        )
+        .write_tlv(v2::Type::NoOp, b"padding")
+        .unwrap()
+        .build()
+        .unwrap();
+        input.extend_from_slice(b"GET /");
+
+        let mut stream = input.as_slice();
+        let addr = read_proxy_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some(client));
+        assert_eq!(stream, b"GET /");
+
+        // Health checks of the load balancer do not relay a client
+        let input = v2::Builder::new(
+            v2::Version::Two | v2::Command::Local,
+            v2::AddressFamily::Unspecified | v2::Protocol::Unspecified,
+        )
+        .build()
+        .unwrap();
+        let addr = read_proxy_header(&mut input.as_slice()).await.unwrap();
+        assert_eq!(addr, None);
+    }
+
+    #[test_case(b"GET / HTTP/1.1\r\n\r\n" ; "no header")]
+    #[test_case(b"PROXY TCP4 1.2.3.4 10.0.0.1 4711\r\n" ; "v1 missing port")]
+    #[test_case(b"PROXY TCP4 1.2.3.4 10.0.0.1 4711 443 and garbage that never ends with a new line, so it is way too long to be a header" ; "v1 too long")]
+    #[test_case(b"PROXY" ; "truncated")]
+    #[tokio::test]
+    async fn test_read_proxy_header_invalid(input: &[u8]) {
+        assert!(read_proxy_header(&mut &input[..]).await.is_err());
+    }
+}
@@ -0,0 +1,245 @@
+use crate::restrictions::types::{QuotaPeriod, RestrictionConfig, RestrictionsRules};
+use crate::tunnel::shutdown::Shutdown;
+use anyhow::{Context, anyhow};
+use jiff::Timestamp;
+use jiff::tz::TimeZone;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Traffic used by the tunnels of a restriction during the current period of its quota
+#[derive(Debug, Default)]
+pub struct QuotaUsage {
+    period: Mutex<String>,
+    bytes: AtomicU64,
+}
+
+impl QuotaUsage {
+    pub fn add(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    // Bytes used during the period, the usage is reset when a new period starts
+    fn used(&self, period: &str) -> u64 {
+        let mut current = self.period.lock();
+        if *current != period {
+            *current = period.to_string();
+            self.bytes.store(0, Ordering::Relaxed);
+        }
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PersistedUsage {
+    period: String,
+    bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QuotaUsageView {
+    pub restriction: String,
+    pub period: String,
+    pub used_bytes: u64,
+    pub quota_bytes: u64,
+}
+
+/// Traffic usage of the restrictions having a quota, by restriction name.
+/// It is saved to the state file, if any, so the usage survives a restart of the server
+#[derive(Debug, Default)]
+pub struct QuotaTracker {
+    state_file: Option<PathBuf>,
+    usages: Mutex<HashMap<String, Arc<QuotaUsage>>>,
+}
+
+impl QuotaTracker {
+    pub fn load(state_file: Option<PathBuf>) -> Self {
+        let mut usages = HashMap::new();
+        if let Some(path) = &state_file {
+            match std::fs::read(path) {
+                Ok(content) => match serde_json::from_slice::<BTreeMap<String, PersistedUsage>>(&content) {
+                    Ok(persisted) => {
+                        usages = persisted
+                            .into_iter()
+                            .map(|(name, usage)| {
+                                let usage = QuotaUsage {
+                                    period: Mutex::new(usage.period),
+                                    bytes: AtomicU64::new(usage.bytes),
+                                };
+                                (name, Arc::new(usage))
+                            })
+                            .collect();
+                    }
+                    Err(err) => error!("Cannot parse quota state file {:?}, starting from scratch: {err}", path),
+                },
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => error!("Cannot read quota state file {:?}, starting from scratch: {err}", path),
+            }
+        }
+
+        Self {
+            state_file,
+            usages: Mutex::new(usages),
+        }
+    }
+
+    /// Return the usage to account the traffic of a new tunnel against, or None if the restriction has no quota.
+    /// Fail if the quota of the current period is exhausted
+    pub fn acquire(&self, restriction: &RestrictionConfig) -> anyhow::Result<Option<Arc<QuotaUsage>>> {
+        let Some(quota) = &restriction.limits.quota else {
+            return Ok(None);
+        };
+
+        let usage = self.usages.lock().entry(restriction.name.clone()).or_default().clone();
+        let used = usage.used(&current_period(quota.period));
+        if used >= quota.bytes {
+            return Err(anyhow!("quota of {} bytes exhausted, {used} bytes used", quota.bytes));
+        }
+
+        Ok(Some(usage))
+    }
+
+    /// Usage of the current period of the restrictions having a quota
+    pub fn usage(&self, restrictions: &RestrictionsRules) -> Vec<QuotaUsageView> {
+        let mut usages = self.usages.lock();
+        restrictions
+            .restrictions
+            .iter()
+            .filter_map(|restriction| {
+                let quota = restriction.limits.quota.as_ref()?;
+                let period = current_period(quota.period);
+                let usage = usages.entry(restriction.name.clone()).or_default();
+                Some(QuotaUsageView {
+                    restriction: restriction.name.clone(),
+                    used_bytes: usage.used(&period),
+                    period,
+                    quota_bytes: quota.bytes,
+                })
+            })
+            .collect()
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.state_file else {
+            return Ok(());
+        };
+
+        let persisted = self
+            .usages
+            .lock()
+            .iter()
+            .map(|(name, usage)| {
+                let usage = PersistedUsage {
+                    period: usage.period.lock().clone(),
+                    bytes: usage.bytes.load(Ordering::Relaxed),
+                };
+                (name.clone(), usage)
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        // Write then rename, to never leave a truncated state file behind
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(&persisted)?)
+            .with_context(|| format!("Cannot write quota state file {tmp_path:?}"))?;
+        std::fs::rename(&tmp_path, path).with_context(|| format!("Cannot write quota state file {path:?}"))?;
+        Ok(())
+    }
+
+    /// Save the usage periodically until the server shuts down
+    pub async fn run_persistence(self: Arc<Self>, shutdown: Shutdown) {
+        let Some(path) = &self.state_file else {
+            return;
+        };
+        info!("Saving quota usage to {:?} every {:?}", path, SAVE_INTERVAL);
+
+        let shutdown_triggered = shutdown.triggered();
+        tokio::pin!(shutdown_triggered);
+        let mut interval = tokio::time::interval(SAVE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = &mut shutdown_triggered => return,
+                _ = interval.tick() => {}
+            }
+
+            if let Err(err) = self.save() {
+                warn!("Cannot save quota usage: {err:?}");
+            }
+        }
+    }
+}
+
+fn current_period(period: QuotaPeriod) -> String {
+    let date = Timestamp::now().to_zoned(TimeZone::UTC).date();
+    match period {
+        QuotaPeriod::Daily => date.to_string(),
+        QuotaPeriod::Monthly => format!("{:04}-{:02}", date.year(), date.month()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::restrictions::types::{LimitsConfig, MatchConfig, QuotaConfig};
+
+    #[test]
+    fn test_quota_is_persisted() {
+        let path = std::env::temp_dir().join(format!("wstunnel-quotas-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let restriction = RestrictionConfig {
+            name: "friend".to_string(),
+            r#match: vec![MatchConfig::Any],
+            allow: vec![],
+            limits: LimitsConfig {
+                quota: Some(QuotaConfig {
+                    bytes: 100,
+                    period: QuotaPeriod::Monthly,
+                }),
+                ..Default::default()
+            },
+        };
+
+        let quotas = QuotaTracker::load(Some(path.clone()));
+        quotas.acquire(&restriction).unwrap().unwrap().add(60);
+        quotas.acquire(&restriction).unwrap().unwrap().add(60);
+        assert!(quotas.acquire(&restriction).is_err());
+        quotas.save().unwrap();
+
+        // The usage survives a restart
+        let quotas = QuotaTracker::load(Some(path.clone()));
+        assert!(quotas.acquire(&restriction).is_err());
+        let rules = RestrictionsRules {
+            restrictions: vec![restriction],
+            users: Default::default(),
+        };
+        let usage = quotas.usage(&rules);
+        assert_eq!(usage.len(), 1);
+        assert_eq!(usage[0].used_bytes, 120);
+        assert_eq!(usage[0].period, current_period(QuotaPeriod::Monthly));
+
+        // A new period starts from scratch
+        let rules = RestrictionsRules {
+            restrictions: vec![RestrictionConfig {
+                limits: LimitsConfig {
+                    quota: Some(QuotaConfig {
+                        bytes: 100,
+                        period: QuotaPeriod::Daily,
+                    }),
+                    ..Default::default()
+                },
+                ..rules.restrictions[0].clone()
+            }],
+            users: Default::default(),
+        };
+        assert!(quotas.acquire(&rules.restrictions[0]).unwrap().is_some());
+        assert_eq!(quotas.usage(&rules)[0].used_bytes, 0);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
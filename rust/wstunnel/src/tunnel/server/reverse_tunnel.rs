@@ -6,6 +6,7 @@ use anyhow::anyhow;
 use futures_util::{StreamExt, pin_mut};
 use log::warn;
 use parking_lot::Mutex;
+use prometheus_client::metrics::gauge::Gauge;
 use std::future::Future;
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -49,11 +50,24 @@ impl<T: TunnelListener> ReverseTunnelServer<T> {
         }
     }
 
+    pub fn listeners(&self) -> Vec<SocketAddr> {
+        self.servers.lock().keys().copied().collect()
+    }
+
+    /// Unbind the listener, clients waiting for a connection on it get an error.
+    /// Return false if there is no listener on this address
+    pub fn close_listener(&self, bind_addr: &SocketAddr) -> bool {
+        // Drop the item outside the lock, as it aborts the listening task
+        let item = self.servers.lock().remove(bind_addr);
+        item.is_some()
+    }
+
     pub async fn run_listening_server(
         &self,
         executor: &impl TokioExecutorRef,
         bind_addr: SocketAddr,
         idle_timeout: Duration,
+        listeners: Gauge,
         gen_listening_server: impl Future<Output = anyhow::Result<T>>,
     ) -> anyhow::Result<((<T as TunnelListener>::Reader, <T as TunnelListener>::Writer), RemoteAddr)>
     where
@@ -75,8 +89,16 @@ impl<T: TunnelListener> ReverseTunnelServer<T> {
             let local_srv2 = bind_addr;
 
             let fut = async move {
+                listeners.inc();
+                // The listener may have been closed, and another one created on the same address meanwhile,
+                // so only the item of this task is removed
+                let task_id = tokio::task::id();
                 scopeguard::defer!({
-                    server.lock().remove(&local_srv2);
+                    listeners.dec();
+                    let mut server = server.lock();
+                    if server.get(&local_srv2).is_some_and(|item| item.server_task.id() == task_id) {
+                        server.remove(&local_srv2);
+                    }
                 });
 
                 let mut timer = time::interval(idle_timeout);
@@ -130,3 +152,46 @@ impl<T: TunnelListener> ReverseTunnelServer<T> {
         Ok(cnx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::DefaultTokioExecutor;
+    use crate::tunnel::listeners::TcpTunnelListener;
+    use futures_util::FutureExt;
+    use url::Host;
+
+    #[tokio::test]
+    async fn test_closed_listener_does_not_remove_its_replacement() {
+        let server = ReverseTunnelServer::<TcpTunnelListener>::new();
+        let executor = DefaultTokioExecutor::default();
+        let bind: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+        let listener = || async {
+            TcpTunnelListener::new(
+                "127.0.0.1:0".parse().unwrap(),
+                (Host::Domain("localhost".to_string()), 80),
+                false,
+                None,
+            )
+            .await
+        };
+        let idle_timeout = Duration::from_secs(60);
+
+        let old = listener().await.unwrap();
+        let cnx = server.run_listening_server(&executor, bind, idle_timeout, Gauge::default(), async { Ok(old) });
+        assert!(cnx.now_or_never().is_none());
+        tokio::task::yield_now().await;
+
+        // The old task only unwinds after the new listener is registered
+        assert!(server.close_listener(&bind));
+        let new = listener().await.unwrap();
+        let cnx = server.run_listening_server(&executor, bind, idle_timeout, Gauge::default(), async { Ok(new) });
+        assert!(cnx.now_or_never().is_none());
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+
+        assert_eq!(server.listeners(), vec![bind]);
+        assert!(server.close_listener(&bind));
+    }
+}
@@ -7,42 +7,94 @@ use crate::restrictions::types::{RestrictionConfig, RestrictionsRules};
 use crate::somark::SoMark;
 use crate::tunnel::connectors::{TcpTunnelConnector, TunnelConnector, UdpTunnelConnector};
 use crate::tunnel::listeners::{HttpProxyTunnelListener, Socks5TunnelListener, TcpTunnelListener, UdpTunnelListener};
+use crate::tunnel::server::active_tunnels::ActiveTunnels;
+use crate::tunnel::server::admin::{AdminApi, serve_admin};
+use crate::tunnel::server::fallback::{FallbackConfig, serve_fallback};
 use crate::tunnel::server::handler_http2::http_server_upgrade;
 use crate::tunnel::server::handler_websocket::ws_server_upgrade;
+use crate::tunnel::server::limits::{TunnelBandwidth, TunnelLimiter};
+use crate::tunnel::server::metrics::{RejectReason, ServerMetrics, serve_metrics};
+use crate::tunnel::server::proxy_protocol::read_proxy_header;
+use crate::tunnel::server::quotas::QuotaTracker;
 use crate::tunnel::server::reverse_tunnel::ReverseTunnelServer;
 use crate::tunnel::server::utils::{
-    HttpResponse, bad_request, extract_authorization, extract_path_prefix, extract_tunnel_info,
-    extract_x_forwarded_for, find_mapped_port, validate_tunnel,
+    ClientCertificate, MatchContext, TunnelRequestError, bad_request, extract_authorization, extract_forwarded_for,
+    extract_path_prefix, extract_tunnel_info, find_mapped_port, too_many_requests, validate_tunnel,
 };
+use crate::tunnel::shutdown::Shutdown;
 use crate::tunnel::tls_reloader::TlsReloader;
+use crate::tunnel::transport::idle_timeout::IdleTimeout;
 use crate::tunnel::{LocalProtocol, RemoteAddr, try_to_sock_addr};
 use ahash::AHasher;
 use anyhow::{Context, anyhow};
 use arc_swap::ArcSwap;
 use futures_util::FutureExt;
-use http_body_util::Either;
 use hyper::body::Incoming;
 use hyper::server::conn::{http1, http2};
 use hyper::service::service_fn;
-use hyper::{Request, StatusCode, Version, http};
+use hyper::{Request, Version};
 use hyper_util::rt::{TokioExecutor, TokioTimer};
+use ipnet::IpNet;
 use parking_lot::Mutex;
 use socket2::SockRef;
+use std::cmp::min;
 use std::fmt;
 use std::fmt::{Debug, Formatter};
 use std::hash::{Hash, Hasher};
 use std::net::{Ipv6Addr, SocketAddr};
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 use std::pin::Pin;
 use std::sync::{Arc, LazyLock};
+use std::thread::available_parallelism;
 use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
 use tokio_rustls::TlsAcceptor;
 use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
 use tracing::{Instrument, Level, Span, error, info, span, warn};
 use url::{Host, Url};
 
+// Load balancers send the PROXY protocol header right away, a connection that does not is dropped
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Reverse tunnel listeners are shared by all the clients asking for the same bind address
+static REVERSE_TCP_SERVERS: LazyLock<ReverseTunnelServer<TcpTunnelListener>> = LazyLock::new(ReverseTunnelServer::new);
+static REVERSE_UDP_SERVERS: LazyLock<ReverseTunnelServer<UdpTunnelListener>> = LazyLock::new(ReverseTunnelServer::new);
+static REVERSE_SOCKS5_SERVERS: LazyLock<ReverseTunnelServer<Socks5TunnelListener>> =
+    LazyLock::new(ReverseTunnelServer::new);
+static REVERSE_HTTP_PROXY_SERVERS: LazyLock<ReverseTunnelServer<HttpProxyTunnelListener>> =
+    LazyLock::new(ReverseTunnelServer::new);
+#[cfg(unix)]
+static REVERSE_UNIX_SERVERS: LazyLock<ReverseTunnelServer<crate::tunnel::listeners::UnixTunnelListener>> =
+    LazyLock::new(ReverseTunnelServer::new);
+
+/// Reverse tunnel listeners currently bound, with their protocol
+pub(super) fn reverse_tunnel_listeners() -> Vec<(&'static str, SocketAddr)> {
+    let mut listeners = vec![];
+    listeners.extend(REVERSE_TCP_SERVERS.listeners().into_iter().map(|l| ("reverse_tcp", l)));
+    listeners.extend(REVERSE_UDP_SERVERS.listeners().into_iter().map(|l| ("reverse_udp", l)));
+    listeners.extend(REVERSE_SOCKS5_SERVERS.listeners().into_iter().map(|l| ("reverse_socks5", l)));
+    listeners.extend(REVERSE_HTTP_PROXY_SERVERS.listeners().into_iter().map(|l| ("reverse_http_proxy", l)));
+    #[cfg(unix)]
+    listeners.extend(REVERSE_UNIX_SERVERS.listeners().into_iter().map(|l| ("reverse_unix", l)));
+    listeners
+}
+
+/// Close the reverse tunnel listener bound on this address, whatever its protocol
+pub(super) fn close_reverse_tunnel_listener(bind: &SocketAddr) -> bool {
+    let mut closed = REVERSE_TCP_SERVERS.close_listener(bind);
+    closed |= REVERSE_UDP_SERVERS.close_listener(bind);
+    closed |= REVERSE_SOCKS5_SERVERS.close_listener(bind);
+    closed |= REVERSE_HTTP_PROXY_SERVERS.close_listener(bind);
+    #[cfg(unix)]
+    {
+        closed |= REVERSE_UNIX_SERVERS.close_listener(bind);
+    }
+    closed
+}
+
 #[derive(Debug)]
 pub struct TlsServerConfig {
     pub tls_certificate: Mutex<Vec<CertificateDer<'static>>>,
@@ -57,34 +109,81 @@ pub struct WsServerConfig {
     pub socket_so_mark: SoMark,
     pub bind: SocketAddr,
     pub websocket_ping_frequency: Option<Duration>,
+    pub websocket_pong_timeout: Option<Duration>,
     pub timeout_connect: Duration,
+    pub happy_eyeballs_delay: Duration,
+    pub tcp_idle_timeout: Option<Duration>,
+    pub tcp_half_close_timeout: Option<Duration>,
+    pub drain_timeout: Duration,
     pub websocket_mask_frame: bool,
     pub tls: Option<TlsServerConfig>,
     pub dns_resolver: DnsResolver,
     pub restriction_config: Option<PathBuf>,
     pub http_proxy: Option<Url>,
     pub remote_server_idle_timeout: Duration,
+    pub metrics_bind: Option<SocketAddr>,
+    pub admin_bind: Option<SocketAddr>,
+    pub admin_token: Option<String>,
+    pub fallback: Option<FallbackConfig>,
+    pub quota_state_file: Option<PathBuf>,
+    pub trusted_proxies: Vec<IpNet>,
+    pub accept_proxy_protocol: Vec<IpNet>,
 }
 
 #[derive(Clone)]
 pub struct WsServer<E: crate::TokioExecutorRef = DefaultTokioExecutor> {
     pub config: Arc<WsServerConfig>,
     pub executor: E,
+    pub shutdown: Shutdown,
+    pub(super) metrics: Arc<ServerMetrics>,
+    pub(super) tunnels: Arc<ActiveTunnels>,
+    pub(super) limiter: Arc<TunnelLimiter>,
+    pub(super) quotas: Arc<QuotaTracker>,
+    pub(super) password_checks: Arc<Semaphore>,
 }
 
 impl<E: crate::TokioExecutorRef> WsServer<E> {
     pub fn new(config: WsServerConfig, executor: E) -> Self {
+        let quotas = QuotaTracker::load(config.quota_state_file.clone());
         Self {
             config: Arc::new(config),
             executor,
+            shutdown: Shutdown::new(),
+            metrics: Arc::new(ServerMetrics::default()),
+            tunnels: Arc::new(ActiveTunnels::default()),
+            limiter: Arc::new(TunnelLimiter::default()),
+            quotas: Arc::new(quotas),
+            password_checks: Arc::new(Semaphore::new(available_parallelism().map_or(1, NonZeroUsize::get))),
         }
     }
 
+    /// Stop accepting new connections and drain the tunnels in flight, serve() returns once they are done
+    /// or after the drain timeout
+    pub fn shutdown(&self) {
+        self.shutdown.trigger();
+    }
+
+    // Idle timeout of a new tunnel, only tcp streams have one. A tunnel can only ask for a shorter one than the server
+    pub(super) fn idle_timeout(&self, protocol: &LocalProtocol) -> IdleTimeout {
+        let timeout = match protocol {
+            LocalProtocol::Tcp { idle_timeout, .. } => {
+                match (idle_timeout.filter(|d| !d.is_zero()), self.config.tcp_idle_timeout) {
+                    (Some(tunnel), Some(server)) => Some(min(tunnel, server)),
+                    (tunnel, server) => tunnel.or(server),
+                }
+            }
+            LocalProtocol::ReverseTcp => self.config.tcp_idle_timeout,
+            _ => return IdleTimeout::default(),
+        };
+
+        IdleTimeout::new(timeout, self.config.tcp_half_close_timeout)
+    }
+
     pub(super) async fn handle_tunnel_request(
         &self,
         restrictions: Arc<RestrictionsRules>,
-        restrict_path_prefix: Option<String>,
-        mut client_addr: SocketAddr,
+        client_cert: Option<ClientCertificate>,
+        peer_addr: SocketAddr,
         req: &Request<Incoming>,
     ) -> Result<
         (
@@ -92,61 +191,157 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
             Pin<Box<dyn AsyncRead + Send>>,
             Pin<Box<dyn AsyncWrite + Send>>,
             bool,
+            TunnelBandwidth,
         ),
-        HttpResponse,
+        TunnelRequestError,
     > {
-        if let Some((x_forward_for, x_forward_for_str)) = extract_x_forwarded_for(req) {
-            info!("Request X-Forwarded-For: {x_forward_for:?}");
-            Span::current().record("forwarded_for", x_forward_for_str);
-            client_addr.set_ip(x_forward_for);
+        // The forwarding headers are only looked at when the peer is a trusted proxy, as any client can set them.
+        // The restrictions match and the limits count the client by this address, so it must never be spoofable
+        let client_addr = match extract_forwarded_for(req.headers(), peer_addr.ip(), &self.config.trusted_proxies) {
+            Some(forwarded_for) => {
+                info!("Request forwarded for: {forwarded_for:?}");
+                Span::current().record("forwarded_for", forwarded_for.to_string());
+                SocketAddr::new(forwarded_for, peer_addr.port())
+            }
+            None => peer_addr,
         };
 
         let path_prefix = extract_path_prefix(req.uri().path()).map_err(|err| {
             warn!("Rejecting connection with {err}: {}", req.uri());
-            bad_request()
+            self.metrics.upgrade_rejected(RejectReason::BadPathPrefix);
+            TunnelRequestError::NotATunnel
         })?;
 
-        if let Some(restrict_path) = restrict_path_prefix
+        if let Some(restrict_path) = client_cert.as_ref().and_then(|cert| cert.cn.as_deref())
             && path_prefix != restrict_path
         {
             warn!(
                 "Client requested upgrade path '{path_prefix}' does not match upgrade path restriction '{restrict_path}' (mTLS, etc.)"
             );
-            return Err(bad_request());
+            self.metrics.upgrade_rejected(RejectReason::BadPathPrefix);
+            return Err(TunnelRequestError::NotATunnel);
         }
 
         let jwt = extract_tunnel_info(req).map_err(|err| {
             warn!("{}", err);
-            bad_request()
+            self.metrics.upgrade_rejected(RejectReason::BadJwt);
+            TunnelRequestError::NotATunnel
         })?;
 
         Span::current().record("id", &jwt.claims.id);
         Span::current().record("remote", format!("{}:{}", jwt.claims.r, jwt.claims.rp));
         let remote = RemoteAddr::try_from(jwt.claims).map_err(|err| {
             warn!("Rejecting connection with bad tunnel info: {err} {}", req.uri());
-            bad_request()
+            self.metrics.upgrade_rejected(RejectReason::BadJwt);
+            TunnelRequestError::NotATunnel
         })?;
 
         let authorization = extract_authorization(req);
-        let restriction = validate_tunnel(&remote, path_prefix, authorization, &restrictions).ok_or_else(|| {
+        let user = match authorization {
+            Some(authorization) if !restrictions.users.is_empty() => match restrictions.users.cached(authorization) {
+                Some(user) => user,
+                // Verifying a password hash is cpu intensive, so it does not run on the async runtime, and only a few
+                // run at once. A flood of bad credentials would otherwise take all the cpus and the blocking threads
+                None => {
+                    let _permit = self.password_checks.acquire().await;
+                    let (users, authorization) = (restrictions.users.clone(), authorization.to_string());
+                    tokio::task::spawn_blocking(move || users.authenticate(&authorization))
+                        .await
+                        .unwrap_or_default()
+                }
+            },
+            _ => None,
+        };
+        if let Some(user) = &user {
+            Span::current().record("user", user.as_str());
+        }
+
+        let request = MatchContext {
+            path_prefix,
+            authorization,
+            user: user.as_deref(),
+            client_ip: client_addr.ip(),
+            client_cert: client_cert.as_ref(),
+            headers: req.headers(),
+        };
+        let restriction = validate_tunnel(&remote, &request, &restrictions).ok_or_else(|| {
             warn!("Rejecting connection with not allowed destination: {remote:?}");
-            bad_request()
+            self.metrics.upgrade_rejected(RejectReason::RestrictionDenied);
+            TunnelRequestError::NotATunnel
         })?;
-        info!("Tunnel accepted due to matched restriction: {}", restriction.name);
+        match &user {
+            Some(user) => info!(
+                "Tunnel accepted due to matched restriction: {} for user {user}",
+                restriction.name
+            ),
+            None => info!("Tunnel accepted due to matched restriction: {}", restriction.name),
+        }
+
+        let quota = self.quotas.acquire(restriction).map_err(|err| {
+            warn!("Rejecting connection exceeding the quota of restriction {}: {err}", restriction.name);
+            self.metrics.upgrade_rejected(RejectReason::QuotaExceeded);
+            TunnelRequestError::Failed(too_many_requests())
+        })?;
+        let permit = self.limiter.acquire(restriction, client_addr.ip()).map_err(|err| {
+            warn!("Rejecting connection exceeding the limits of restriction {}: {err}", restriction.name);
+            self.metrics.upgrade_rejected(RejectReason::RateLimited);
+            TunnelRequestError::Failed(too_many_requests())
+        })?;
+        let permit = permit.with_quota(quota);
+        let bandwidth = permit.bandwidth.clone();
 
         let req_protocol = remote.protocol.clone();
+        let req_host = remote.host.clone();
         let inject_cookie = req_protocol.is_dynamic_reverse_tunnel();
         let tunnel = self
             .exec_tunnel(restriction, remote, client_addr)
             .await
             .map_err(|err| {
                 warn!("Rejecting connection with bad upgrade request: {err} {}", req.uri());
-                bad_request()
+                self.metrics.upgrade_rejected(RejectReason::ConnectFailed);
+                if !req_protocol.is_reverse_tunnel() {
+                    self.metrics.connector_failed(&req_protocol, &req_host);
+                }
+                TunnelRequestError::Failed(bad_request())
             })?;
 
         let (remote_addr, local_rx, local_tx) = tunnel;
         info!("connected to {:?} {}:{}", req_protocol, remote_addr.host, remote_addr.port);
-        Ok((remote_addr, local_rx, local_tx, inject_cookie))
+        self.metrics.upgrade_accepted(user.as_deref());
+        let (local_rx, local_tx) = self.tunnels.track(
+            &self.metrics,
+            client_addr,
+            &restriction.name,
+            &remote_addr,
+            permit,
+            local_rx,
+            local_tx,
+        );
+        Ok((remote_addr, Box::pin(local_rx), Box::pin(local_tx), inject_cookie, bandwidth))
+    }
+
+    /// Address of the client of the connection, relayed in a PROXY protocol header if the peer is a trusted load balancer
+    async fn read_proxy_protocol(&self, stream: &mut TcpStream, peer_addr: SocketAddr) -> anyhow::Result<SocketAddr> {
+        let peer_ip = peer_addr.ip().to_canonical();
+        if !self
+            .config
+            .accept_proxy_protocol
+            .iter()
+            .any(|cidr| cidr.contains(&peer_ip))
+        {
+            return Ok(peer_addr);
+        }
+
+        let client_addr = tokio::time::timeout(PROXY_HEADER_TIMEOUT, read_proxy_header(stream))
+            .await
+            .context("Timeout while reading PROXY protocol header")??;
+        match client_addr {
+            Some(client_addr) => {
+                info!("PROXY protocol header relays client {client_addr}");
+                Ok(client_addr)
+            }
+            None => Ok(peer_addr),
+        }
     }
 
     async fn exec_tunnel(
@@ -162,6 +357,7 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
                     remote.port,
                     self.config.socket_so_mark,
                     timeout.unwrap_or(Duration::from_secs(10)),
+                    self.config.happy_eyeballs_delay,
                     &self.config.dns_resolver,
                 );
                 let (rx, tx) = match &self.config.http_proxy {
@@ -171,12 +367,13 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
 
                 Ok((remote, Box::pin(rx), Box::pin(tx)))
             }
-            LocalProtocol::Tcp { proxy_protocol } => {
+            LocalProtocol::Tcp { proxy_protocol, .. } => {
                 let connector = TcpTunnelConnector::new(
                     &remote.host,
                     remote.port,
                     self.config.socket_so_mark,
                     Duration::from_secs(10),
+                    self.config.happy_eyeballs_delay,
                     &self.config.dns_resolver,
                 );
                 let (rx, mut tx) = match &self.config.http_proxy {
@@ -197,18 +394,16 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
                 Ok((remote, Box::pin(rx), Box::pin(tx)))
             }
             LocalProtocol::ReverseTcp => {
-                static SERVERS: LazyLock<ReverseTunnelServer<TcpTunnelListener>> =
-                    LazyLock::new(ReverseTunnelServer::new);
-
                 let remote_port = find_mapped_port(remote.port, restriction);
                 let local_srv = (remote.host, remote_port);
                 let bind = try_to_sock_addr(local_srv.clone())?;
-                let listening_server = async { TcpTunnelListener::new(bind, local_srv.clone(), false).await };
-                let ((local_rx, local_tx), remote) = SERVERS
+                let listening_server = async { TcpTunnelListener::new(bind, local_srv.clone(), false, None).await };
+                let ((local_rx, local_tx), remote) = REVERSE_TCP_SERVERS
                     .run_listening_server(
                         &self.executor,
                         bind,
                         self.config.remote_server_idle_timeout,
+                        self.metrics.reverse_tunnel_listeners("reverse_tcp"),
                         listening_server,
                     )
                     .await?;
@@ -216,36 +411,32 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
                 Ok((remote, Box::pin(local_rx), Box::pin(local_tx)))
             }
             LocalProtocol::ReverseUdp { timeout } => {
-                static SERVERS: LazyLock<ReverseTunnelServer<UdpTunnelListener>> =
-                    LazyLock::new(ReverseTunnelServer::new);
-
                 let remote_port = find_mapped_port(remote.port, restriction);
                 let local_srv = (remote.host, remote_port);
                 let bind = try_to_sock_addr(local_srv.clone())?;
                 let listening_server = async { UdpTunnelListener::new(bind, local_srv.clone(), timeout).await };
-                let ((local_rx, local_tx), remote) = SERVERS
+                let ((local_rx, local_tx), remote) = REVERSE_UDP_SERVERS
                     .run_listening_server(
                         &self.executor,
                         bind,
                         self.config.remote_server_idle_timeout,
+                        self.metrics.reverse_tunnel_listeners("reverse_udp"),
                         listening_server,
                     )
                     .await?;
                 Ok((remote, Box::pin(local_rx), Box::pin(local_tx)))
             }
             LocalProtocol::ReverseSocks5 { timeout, credentials } => {
-                static SERVERS: LazyLock<ReverseTunnelServer<Socks5TunnelListener>> =
-                    LazyLock::new(ReverseTunnelServer::new);
-
                 let remote_port = find_mapped_port(remote.port, restriction);
                 let local_srv = (remote.host, remote_port);
                 let bind = try_to_sock_addr(local_srv.clone())?;
                 let listening_server = async { Socks5TunnelListener::new(bind, timeout, credentials).await };
-                let ((local_rx, local_tx), remote) = SERVERS
+                let ((local_rx, local_tx), remote) = REVERSE_SOCKS5_SERVERS
                     .run_listening_server(
                         &self.executor,
                         bind,
                         self.config.remote_server_idle_timeout,
+                        self.metrics.reverse_tunnel_listeners("reverse_socks5"),
                         listening_server,
                     )
                     .await?;
@@ -253,18 +444,16 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
                 Ok((remote, Box::pin(local_rx), Box::pin(local_tx)))
             }
             LocalProtocol::ReverseHttpProxy { timeout, credentials } => {
-                static SERVERS: LazyLock<ReverseTunnelServer<HttpProxyTunnelListener>> =
-                    LazyLock::new(ReverseTunnelServer::new);
-
                 let remote_port = find_mapped_port(remote.port, restriction);
                 let local_srv = (remote.host, remote_port);
                 let bind = try_to_sock_addr(local_srv.clone())?;
                 let listening_server = async { HttpProxyTunnelListener::new(bind, timeout, credentials, false).await };
-                let ((local_rx, local_tx), remote) = SERVERS
+                let ((local_rx, local_tx), remote) = REVERSE_HTTP_PROXY_SERVERS
                     .run_listening_server(
                         &self.executor,
                         bind,
                         self.config.remote_server_idle_timeout,
+                        self.metrics.reverse_tunnel_listeners("reverse_http_proxy"),
                         listening_server,
                     )
                     .await?;
@@ -274,8 +463,6 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
             #[cfg(unix)]
             LocalProtocol::ReverseUnix { ref path } => {
                 use crate::tunnel::listeners::UnixTunnelListener;
-                static SERVERS: LazyLock<ReverseTunnelServer<UnixTunnelListener>> =
-                    LazyLock::new(ReverseTunnelServer::new);
 
                 // we hash the unix socket path to generate a unique host
                 let host = {
@@ -287,11 +474,12 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
                 let local_srv = (host, 0);
                 let bind = try_to_sock_addr(local_srv.clone())?;
                 let listening_server = async { UnixTunnelListener::new(path, local_srv, false).await };
-                let ((local_rx, local_tx), remote) = SERVERS
+                let ((local_rx, local_tx), remote) = REVERSE_UNIX_SERVERS
                     .run_listening_server(
                         &self.executor,
                         bind,
                         self.config.remote_server_idle_timeout,
+                        self.metrics.reverse_tunnel_listeners("reverse_unix"),
                         listening_server,
                     )
                     .await?;
@@ -305,6 +493,10 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
             }
             LocalProtocol::Stdio { .. }
             | LocalProtocol::Socks5 { .. }
+            | LocalProtocol::Mixed { .. }
+            | LocalProtocol::Dns { .. }
+            | LocalProtocol::FakeDns { .. }
+            | LocalProtocol::Tun { .. }
             | LocalProtocol::TProxyTcp
             | LocalProtocol::TProxyUdp { .. }
             | LocalProtocol::HttpProxy { .. }
@@ -321,13 +513,13 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
         // setup upgrade request handler
         let mk_websocket_upgrade_fn = |server: WsServer<_>,
                                        restrictions: Arc<ArcSwap<RestrictionsRules>>,
-                                       restrict_path: Option<String>,
+                                       client_cert: Option<ClientCertificate>,
                                        client_addr: SocketAddr| {
             move |req: Request<Incoming>| {
                 ws_server_upgrade(
                     server.clone(),
                     restrictions.load().clone(),
-                    restrict_path.clone(),
+                    client_cert.clone(),
                     client_addr,
                     req,
                 )
@@ -338,13 +530,13 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
 
         let mk_http_upgrade_fn = |server: WsServer<_>,
                                   restrictions: Arc<ArcSwap<RestrictionsRules>>,
-                                  restrict_path: Option<String>,
+                                  client_cert: Option<ClientCertificate>,
                                   client_addr: SocketAddr| {
             move |req: Request<Incoming>| {
                 http_server_upgrade(
                     server.clone(),
                     restrictions.load().clone(),
-                    restrict_path.clone(),
+                    client_cert.clone(),
                     client_addr,
                     req,
                 )
@@ -355,22 +547,22 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
 
         let mk_auto_upgrade_fn = |server: WsServer<_>,
                                   restrictions: Arc<ArcSwap<RestrictionsRules>>,
-                                  restrict_path: Option<String>,
+                                  client_cert: Option<ClientCertificate>,
                                   client_addr: SocketAddr| {
             move |req: Request<Incoming>| {
                 let server = server.clone();
                 let restrictions = restrictions.clone();
-                let restrict_path = restrict_path.clone();
+                let client_cert = client_cert.clone();
                 async move {
                     if fastwebsockets::upgrade::is_upgrade_request(&req) {
-                        ws_server_upgrade(server.clone(), restrictions.load().clone(), restrict_path, client_addr, req)
+                        ws_server_upgrade(server.clone(), restrictions.load().clone(), client_cert, client_addr, req)
                             .map::<anyhow::Result<_>, _>(Ok)
                             .await
                     } else if req.version() == Version::HTTP_2 {
                         http_server_upgrade(
                             server.clone(),
                             restrictions.load().clone(),
-                            restrict_path.clone(),
+                            client_cert.clone(),
                             client_addr,
                             req,
                         )
@@ -378,10 +570,7 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
                         .await
                     } else {
                         error!("Invalid protocol version request, got {:?} while expecting either websocket http1 upgrade or http2", req.version());
-                        Ok(http::Response::builder()
-                            .status(StatusCode::BAD_REQUEST)
-                            .body(Either::Left("Invalid protocol request".to_string()))
-                            .unwrap())
+                        Ok(serve_fallback(&server, req).await)
                     }
                 }
                     .instrument(mk_span())
@@ -403,14 +592,47 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
             None
         };
 
+        if let Some(metrics_bind) = self.config.metrics_bind {
+            let fut = serve_metrics(metrics_bind, self.metrics.clone(), self.shutdown.clone());
+            self.executor.spawn(async move {
+                if let Err(err) = fut.await {
+                    error!("Metrics listener stopped: {err:?}");
+                }
+            });
+        }
+
+        self.executor.spawn(self.quotas.clone().run_persistence(self.shutdown.clone()));
+
         // Bind server and run forever to serve incoming connections.
         let restrictions = RestrictionsRulesReloader::new(restrictions, self.config.restriction_config.clone())?;
+        if let (Some(admin_bind), Some(token)) = (self.config.admin_bind, &self.config.admin_token) {
+            let api = AdminApi {
+                token: Arc::from(token.as_str()),
+                tunnels: self.tunnels.clone(),
+                quotas: self.quotas.clone(),
+                restrictions: restrictions.clone(),
+            };
+            let fut = serve_admin(admin_bind, api, self.shutdown.clone());
+            self.executor.spawn(async move {
+                if let Err(err) = fut.await {
+                    error!("Admin API listener stopped: {err:?}");
+                }
+            });
+        }
+
         let listener = TcpListener::bind(&self.config.bind)
             .await
             .with_context(|| format!("Failed to bind to socket on {}", self.config.bind))?;
 
+        let shutdown_triggered = self.shutdown.triggered();
+        tokio::pin!(shutdown_triggered);
         loop {
-            let (stream, peer_addr) = match listener.accept().await {
+            let accepted = tokio::select! {
+                biased;
+                _ = &mut shutdown_triggered => break,
+                accepted = listener.accept() => accepted,
+            };
+            let (stream, peer_addr) = match accepted {
                 Ok(ret) => ret,
                 Err(err) => {
                     warn!("Error while accepting connection {:?}", err);
@@ -425,6 +647,7 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
             }
 
             let server = self.clone();
+            let shutdown = self.shutdown.clone();
             let restrictions = restrictions.restrictions_rules().clone();
 
             // Check if we need to enable TLS or not
@@ -433,21 +656,34 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
                     // Reload TLS certificate if needed
                     let tls_acceptor = tls.tls_acceptor().clone();
                     let fut = async move {
+                        let mut stream = stream;
+                        let client_addr = match server.read_proxy_protocol(&mut stream, peer_addr).await {
+                            Ok(client_addr) => client_addr,
+                            Err(err) => {
+                                warn!("Rejecting connection with bad PROXY protocol header: {err:?}");
+                                return;
+                            }
+                        };
+
                         info!("Doing TLS handshake");
                         let tls_stream = match tls_acceptor.accept(stream).await {
                             Ok(tls_stream) => hyper_util::rt::TokioIo::new(tls_stream),
                             Err(err) => {
                                 error!("error while accepting TLS connection {}", err);
+                                server.metrics.tls_handshake_failed();
                                 return;
                             }
                         };
 
                         let tls_ctx = tls_stream.inner().get_ref().1;
-                        // extract client certificate common name if any
-                        let restrict_path = tls_ctx
+                        // extract client certificate common name and alternative names if any
+                        let client_cert = tls_ctx
                             .peer_certificates()
                             .and_then(tls::find_leaf_certificate)
-                            .and_then(|c| tls::cn_from_certificate(&c));
+                            .map(|c| ClientCertificate {
+                                cn: tls::cn_from_certificate(&c),
+                                sans: tls::sans_from_certificate(&c),
+                            });
                         match tls_ctx.alpn_protocol() {
                             // http2
                             Some(b"h2") => {
@@ -456,18 +692,30 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
                                 if let Some(ping) = server.config.websocket_ping_frequency {
                                     conn_builder.keep_alive_interval(ping);
                                 }
+                                if let Some(pong_timeout) = server.config.websocket_pong_timeout {
+                                    conn_builder.keep_alive_timeout(pong_timeout);
+                                }
 
                                 let http_upgrade_fn =
-                                    mk_http_upgrade_fn(server, restrictions, restrict_path, peer_addr);
+                                    mk_http_upgrade_fn(server, restrictions, client_cert, client_addr);
                                 let con_fut = conn_builder.serve_connection(tls_stream, service_fn(http_upgrade_fn));
-                                if let Err(e) = con_fut.await {
+                                tokio::pin!(con_fut);
+                                let ret = tokio::select! {
+                                    ret = con_fut.as_mut() => ret,
+                                    _ = shutdown.triggered() => {
+                                        // Send a GOAWAY to the client and let the streams in flight finish
+                                        con_fut.as_mut().graceful_shutdown();
+                                        con_fut.await
+                                    }
+                                };
+                                if let Err(e) = ret {
                                     error!("Error while upgrading cnx to http: {:?}", e);
                                 }
                             }
                             // websocket
                             _ => {
                                 let websocket_upgrade_fn =
-                                    mk_websocket_upgrade_fn(server, restrictions, restrict_path, peer_addr);
+                                    mk_websocket_upgrade_fn(server, restrictions, client_cert, client_addr);
                                 let conn_fut = http1::Builder::new()
                                     .timer(TokioTimer::new())
                                     // https://github.com/erebe/wstunnel/issues/358
@@ -475,8 +723,16 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
                                     .header_read_timeout(None)
                                     .serve_connection(tls_stream, service_fn(websocket_upgrade_fn))
                                     .with_upgrades();
-
-                                if let Err(e) = conn_fut.await {
+                                tokio::pin!(conn_fut);
+                                let ret = tokio::select! {
+                                    ret = conn_fut.as_mut() => ret,
+                                    _ = shutdown.triggered() => {
+                                        conn_fut.as_mut().graceful_shutdown();
+                                        conn_fut.await
+                                    }
+                                };
+
+                                if let Err(e) = ret {
                                     error!("Error while upgrading cnx: {:?}", e);
                                 }
                             }
@@ -489,17 +745,38 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
                 // HTTP without TLS
                 None => {
                     let fut = async move {
+                        let mut stream = stream;
+                        let client_addr = match server.read_proxy_protocol(&mut stream, peer_addr).await {
+                            Ok(client_addr) => client_addr,
+                            Err(err) => {
+                                warn!("Rejecting connection with bad PROXY protocol header: {err:?}");
+                                return;
+                            }
+                        };
+
                         let stream = hyper_util::rt::TokioIo::new(stream);
                         let mut conn_fut = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new());
                         if let Some(ping) = server.config.websocket_ping_frequency {
                             conn_fut.http2().keep_alive_interval(ping);
                         }
+                        if let Some(pong_timeout) = server.config.websocket_pong_timeout {
+                            conn_fut.http2().keep_alive_timeout(pong_timeout);
+                        }
 
-                        let websocket_upgrade_fn = mk_auto_upgrade_fn(server, restrictions, None, peer_addr);
+                        let websocket_upgrade_fn = mk_auto_upgrade_fn(server, restrictions, None, client_addr);
                         let upgradable =
                             conn_fut.serve_connection_with_upgrades(stream, service_fn(websocket_upgrade_fn));
 
-                        if let Err(e) = upgradable.await {
+                        tokio::pin!(upgradable);
+                        let ret = tokio::select! {
+                            ret = upgradable.as_mut() => ret,
+                            _ = shutdown.triggered() => {
+                                upgradable.as_mut().graceful_shutdown();
+                                upgradable.await
+                            }
+                        };
+
+                        if let Err(e) = ret {
                             error!("Error while upgrading cnx to websocket: {:?}", e);
                         }
                     }
@@ -509,6 +786,21 @@ impl<E: crate::TokioExecutorRef> WsServer<E> {
                 }
             }
         }
+
+        drop(listener);
+        info!("Shutting down server, draining {} tunnels", self.shutdown.active_streams());
+        if !self.shutdown.drain(self.config.drain_timeout).await {
+            warn!(
+                "Drain timeout of {:?} elapsed, forcing close of {} tunnels",
+                self.config.drain_timeout,
+                self.shutdown.active_streams()
+            );
+        }
+        if let Err(err) = self.quotas.save() {
+            warn!("Cannot save quota usage: {err:?}");
+        }
+
+        Ok(())
     }
 }
 
@@ -518,7 +810,8 @@ fn mk_span() -> Span {
         "tunnel",
         id = tracing::field::Empty,
         remote = tracing::field::Empty,
-        forwarded_for = tracing::field::Empty
+        forwarded_for = tracing::field::Empty,
+        user = tracing::field::Empty
     )
 }
 
@@ -528,11 +821,21 @@ impl Debug for WsServerConfig {
             .field("socket_so_mark", &self.socket_so_mark)
             .field("bind", &self.bind)
             .field("websocket_ping_frequency", &self.websocket_ping_frequency)
+            .field("websocket_pong_timeout", &self.websocket_pong_timeout)
             .field("timeout_connect", &self.timeout_connect)
+            .field("tcp_idle_timeout", &self.tcp_idle_timeout)
+            .field("tcp_half_close_timeout", &self.tcp_half_close_timeout)
+            .field("drain_timeout", &self.drain_timeout)
             .field("websocket_mask_frame", &self.websocket_mask_frame)
             .field("restriction_config", &self.restriction_config)
             .field("tls", &self.tls.is_some())
             .field("remote_server_idle_timeout", &self.remote_server_idle_timeout)
+            .field("metrics_bind", &self.metrics_bind)
+            .field("admin_bind", &self.admin_bind)
+            .field("fallback", &self.fallback)
+            .field("quota_state_file", &self.quota_state_file)
+            .field("trusted_proxies", &self.trusted_proxies)
+            .field("accept_proxy_protocol", &self.accept_proxy_protocol)
             .field(
                 "mTLS",
                 &self
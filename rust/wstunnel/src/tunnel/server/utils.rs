@@ -1,7 +1,7 @@
 use crate::LocalProtocol;
 use crate::restrictions::types::{
     AllowConfig, AllowReverseTunnelConfig, AllowTunnelConfig, MatchConfig, RestrictionConfig, RestrictionsRules,
-    ReverseTunnelConfigProtocol, TunnelConfigProtocol,
+    ReverseTunnelConfigProtocol, ScheduleConfig, TunnelConfigProtocol,
 };
 use crate::tunnel::RemoteAddr;
 use crate::tunnel::transport::{JWT_HEADER_PREFIX, JwtTunnelConfig, jwt_token_to_tunnel, tunnel_to_jwt_token};
@@ -11,16 +11,30 @@ use derive_more::{Display, Error};
 use http_body_util::Either;
 use http_body_util::combinators::BoxBody;
 use hyper::body::{Body, Incoming};
-use hyper::header::{AUTHORIZATION, COOKIE, HeaderValue, SEC_WEBSOCKET_PROTOCOL};
+use hyper::header::{AUTHORIZATION, COOKIE, FORWARDED, HeaderMap, HeaderValue, SEC_WEBSOCKET_PROTOCOL};
 use hyper::{Request, Response, StatusCode, http};
+use ipnet::IpNet;
+use jiff::Timestamp;
+use jiff::civil::Weekday;
 use jsonwebtoken::TokenData;
-use std::net::IpAddr;
+use std::cmp::Ordering;
+use std::net::{IpAddr, SocketAddr};
 use tracing::{error, info};
 use url::Host;
 use uuid::Uuid;
 
 pub type HttpResponse = Response<Either<String, BoxBody<Bytes, anyhow::Error>>>;
 
+const X_FORWARDED_FOR: &str = "X-Forwarded-For";
+
+/// A request that could not be turned into a tunnel
+pub(super) enum TunnelRequestError {
+    /// Not a valid tunnel request, i.e: wrong path prefix or jwt, it is answered by the fallback website if any
+    NotATunnel,
+    /// A valid tunnel request that failed
+    Failed(HttpResponse),
+}
+
 pub(super) fn bad_request() -> HttpResponse {
     http::Response::builder()
         .status(StatusCode::BAD_REQUEST)
@@ -28,6 +42,13 @@ pub(super) fn bad_request() -> HttpResponse {
         .unwrap()
 }
 
+pub(super) fn too_many_requests() -> HttpResponse {
+    http::Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .body(Either::Left("Too many requests".to_string()))
+        .unwrap()
+}
+
 /// Checks if the requested (remote) port has been mapped in the configuration to another port.
 /// If it is not mapped the original port number is returned.
 #[inline]
@@ -56,15 +77,63 @@ pub(super) fn extract_authorization(req: &Request<Incoming>) -> Option<&str> {
     req.headers().get(AUTHORIZATION)?.to_str().ok()
 }
 
-#[inline]
-pub(super) fn extract_x_forwarded_for(req: &Request<Incoming>) -> Option<(IpAddr, &str)> {
-    let x_forward_for = req.headers().get("X-Forwarded-For")?;
+/// Ip of the client behind the trusted proxies, from the `X-Forwarded-For` header or else the `Forwarded` one (RFC 7239).
+/// Headers are ignored if the peer is not a trusted proxy. The chain is walked from right to left, i.e: from the proxy
+/// closest to the server, and the first ip that is not a trusted proxy is the client. Everything on its left can be
+/// spoofed by the client, so it is never looked at
+pub(super) fn extract_forwarded_for(headers: &HeaderMap, peer: IpAddr, trusted_proxies: &[IpNet]) -> Option<IpAddr> {
+    let is_trusted = |ip: &IpAddr| {
+        let ip = ip.to_canonical();
+        trusted_proxies.iter().any(|cidr| cidr.contains(&ip))
+    };
+    if !is_trusted(&peer) {
+        return None;
+    }
 
     // X-Forwarded-For: <client>, <proxy1>, <proxy2>
-    let x_forward_for = x_forward_for.to_str().unwrap_or_default();
-    let x_forward_for = x_forward_for.split_once(',').map(|x| x.0).unwrap_or(x_forward_for);
-    let ip: Option<IpAddr> = x_forward_for.parse().ok();
-    ip.map(|ip| (ip, x_forward_for))
+    // Forwarded: for=<client>;proto=https, for="[<proxy1>]:4711"
+    let chain: Vec<&str> = if headers.contains_key(X_FORWARDED_FOR) {
+        headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .flat_map(|header| header.to_str().unwrap_or_default().split(','))
+            .collect()
+    } else {
+        headers
+            .get_all(FORWARDED)
+            .iter()
+            .flat_map(|header| header.to_str().unwrap_or_default().split(','))
+            .filter_map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                    .map(|(_, value)| value.trim().trim_matches('"'))
+            })
+            .collect()
+    };
+
+    let mut client = None;
+    for hop in chain.iter().rev() {
+        // An unknown or obfuscated hop hides everything on its left, the last trusted proxy is as far as we can go
+        let Some(ip) = parse_forwarded_ip(hop.trim()) else {
+            break;
+        };
+        client = Some(ip);
+        if !is_trusted(&ip) {
+            break;
+        }
+    }
+
+    client
+}
+
+// Proxies may add the port of the client, i.e: 192.0.2.43:4711 or [2001:db8::1]:4711
+fn parse_forwarded_ip(hop: &str) -> Option<IpAddr> {
+    hop.parse::<IpAddr>()
+        .or_else(|_| hop.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| hop.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>())
+        .ok()
 }
 
 #[inline]
@@ -110,18 +179,75 @@ pub(super) fn extract_tunnel_info(req: &Request<Incoming>) -> anyhow::Result<Tok
     })
 }
 
+/// Identity of the client from its verified mTLS certificate
+#[derive(Debug, Clone, Default)]
+pub(super) struct ClientCertificate {
+    pub cn: Option<String>,
+    pub sans: Vec<String>,
+}
+
+/// What the restrictions can match a tunnel request on
+pub(super) struct MatchContext<'a> {
+    pub path_prefix: &'a str,
+    pub authorization: Option<&'a str>,
+    /// User of the `users` section authenticated by the authorization, if any
+    pub user: Option<&'a str>,
+    /// Ip of the peer, or of the client relayed by a trusted proxy. Never taken from the headers of an untrusted peer
+    pub client_ip: IpAddr,
+    pub client_cert: Option<&'a ClientCertificate>,
+    pub headers: &'a HeaderMap,
+}
+
 impl RestrictionConfig {
-    /// Returns true if the parameters match the restriction config
+    /// Returns true if the request matches the restriction config
     #[inline]
-    fn filter(self: &RestrictionConfig, path_prefix: &str, authorization_header_val: Option<&str>) -> bool {
+    fn filter(self: &RestrictionConfig, request: &MatchContext) -> bool {
+        let now = Timestamp::now();
         self.r#match.iter().all(|m| match m {
             MatchConfig::Any => true,
-            MatchConfig::PathPrefix(path) => path.is_match(path_prefix),
-            MatchConfig::Authorization(auth) => authorization_header_val.is_some_and(|val| auth.is_match(val)),
+            MatchConfig::PathPrefix(path) => path.is_match(request.path_prefix),
+            MatchConfig::Authorization(auth) => request.authorization.is_some_and(|val| auth.is_match(val)),
+            MatchConfig::User(users) => request.user.is_some_and(|user| users.iter().any(|name| name == user)),
+            MatchConfig::NotAfter(not_after) => now <= *not_after,
+            MatchConfig::NotBefore(not_before) => now >= *not_before,
+            MatchConfig::Schedule(schedule) => schedule.is_active(now),
+            MatchConfig::ClientCidr(cidrs) => {
+                let client_ip = request.client_ip.to_canonical();
+                cidrs.iter().any(|cidr| cidr.contains(&client_ip))
+            }
+            MatchConfig::CertificateCn(cn) => request
+                .client_cert
+                .and_then(|cert| cert.cn.as_deref())
+                .is_some_and(|val| cn.is_match(val)),
+            MatchConfig::CertificateSan(san) => request
+                .client_cert
+                .is_some_and(|cert| cert.sans.iter().any(|val| san.is_match(val))),
+            MatchConfig::Header { name, regex } => request
+                .headers
+                .get_all(name.as_str())
+                .iter()
+                .any(|val| val.to_str().is_ok_and(|val| regex.is_match(val))),
         })
     }
 }
 
+impl ScheduleConfig {
+    /// Returns true if the instant falls within the weekly time window.
+    /// A window spanning midnight belongs to the day it starts, i.e: the monday night ends on tuesday morning
+    fn is_active(&self, now: Timestamp) -> bool {
+        let now = now.to_zoned(self.timezone.clone());
+        let (start, end, time) = (self.hours.start, self.hours.end, now.time());
+        let is_day = |weekday: Weekday| self.days.contains(&weekday);
+        match start.cmp(&end) {
+            Ordering::Less => is_day(now.weekday()) && start <= time && time < end,
+            Ordering::Equal => is_day(now.weekday()),
+            Ordering::Greater => {
+                (time >= start && is_day(now.weekday())) || (time < end && is_day(now.weekday().previous()))
+            }
+        }
+    }
+}
+
 impl AllowReverseTunnelConfig {
     #[inline]
     fn is_allowed(&self, remote: &RemoteAddr) -> bool {
@@ -200,14 +326,13 @@ impl AllowConfig {
 #[inline]
 pub(super) fn validate_tunnel<'a>(
     remote: &RemoteAddr,
-    path_prefix: &str,
-    authorization: Option<&str>,
+    request: &MatchContext,
     restrictions: &'a RestrictionsRules,
 ) -> Option<&'a RestrictionConfig> {
     restrictions
         .restrictions
         .iter()
-        .filter(|restriction| restriction.filter(path_prefix, authorization))
+        .filter(|restriction| restriction.filter(request))
         .find(|restriction| restriction.allow.iter().any(|allow| allow.is_allowed(remote)))
 }
 
@@ -224,12 +349,28 @@ pub(super) fn inject_cookie(response: &mut http::Response<impl Body>, remote_add
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::HashAlgorithm;
+    use crate::restrictions::credentials::hash_secret;
     use crate::restrictions::types::{AllowReverseTunnelConfig, AllowTunnelConfig, default_cidr, default_host};
     use crate::tunnel::LocalProtocol;
     use ipnet::{IpNet, Ipv4Net};
     use regex::Regex;
     use std::net::Ipv6Addr;
     use std::path::PathBuf;
+    use std::sync::LazyLock;
+    use test_case::test_case;
+
+    fn match_context(authorization: Option<&str>) -> MatchContext<'_> {
+        static NO_HEADERS: LazyLock<HeaderMap> = LazyLock::new(HeaderMap::new);
+        MatchContext {
+            path_prefix: "/doesnt/matter",
+            authorization,
+            user: None,
+            client_ip: IpAddr::from([127, 0, 0, 1]),
+            client_cert: None,
+            headers: &NO_HEADERS,
+        }
+    }
 
     #[test]
     fn test_validate_tunnel() {
@@ -245,6 +386,7 @@ mod tests {
                         cidr: vec![IpNet::from(Ipv4Net::new([127, 0, 0, 1].into(), 24).unwrap())],
                         host: Regex::new("example.com").unwrap(),
                     })],
+                    limits: Default::default(),
                 },
                 // reverse tunnel
                 RestrictionConfig {
@@ -257,17 +399,22 @@ mod tests {
                         port_mapping: Default::default(),
                         unix_path: default_host(),
                     })],
+                    limits: Default::default(),
                 },
             ],
+            users: Default::default(),
         };
 
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                idle_timeout: None,
+            },
             host: Host::Ipv4([127, 0, 0, 1].into()),
             port: 80,
         };
         assert_eq!(
-            validate_tunnel(&remote, "/doesnt/matter", None, &restrictions)
+            validate_tunnel(&remote, &match_context(None), &restrictions)
                 .unwrap()
                 .name,
             restrictions.restrictions[0].name
@@ -279,51 +426,66 @@ mod tests {
             port: 80,
         };
         assert_eq!(
-            validate_tunnel(&remote, "/doesnt/matter", None, &restrictions)
+            validate_tunnel(&remote, &match_context(None), &restrictions)
                 .unwrap()
                 .name,
             restrictions.restrictions[1].name
         );
 
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                idle_timeout: None,
+            },
             host: Host::Ipv4([127, 0, 0, 1].into()),
             port: 81,
         };
-        assert!(validate_tunnel(&remote, "/doesnt/matter", None, &restrictions).is_none());
+        assert!(validate_tunnel(&remote, &match_context(None), &restrictions).is_none());
 
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                idle_timeout: None,
+            },
             host: Host::Ipv4([127, 0, 1, 1].into()),
             port: 80,
         };
-        assert!(validate_tunnel(&remote, "/doesnt/matter", None, &restrictions).is_none());
+        assert!(validate_tunnel(&remote, &match_context(None), &restrictions).is_none());
 
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                idle_timeout: None,
+            },
             host: Host::Domain("example.com".into()),
             port: 80,
         };
         assert_eq!(
-            validate_tunnel(&remote, "/doesnt/matter", None, &restrictions)
+            validate_tunnel(&remote, &match_context(None), &restrictions)
                 .unwrap()
                 .name,
             restrictions.restrictions[0].name
         );
 
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                idle_timeout: None,
+            },
             host: Host::Domain("not.com".into()),
             port: 80,
         };
-        assert!(validate_tunnel(&remote, "/doesnt/matter", None, &restrictions).is_none());
+        assert!(validate_tunnel(&remote, &match_context(None), &restrictions).is_none());
 
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                idle_timeout: None,
+            },
             host: Host::Ipv6(Ipv6Addr::LOCALHOST),
             port: 80,
         };
-        assert!(validate_tunnel(&remote, "/doesnt/matter", None, &restrictions).is_none());
+        assert!(validate_tunnel(&remote, &match_context(None), &restrictions).is_none());
     }
 
     #[test]
@@ -340,22 +502,253 @@ mod tests {
                     cidr: default_cidr(),
                     host: default_host(),
                 })],
+                limits: Default::default(),
             }],
+            users: Default::default(),
         };
 
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                idle_timeout: None,
+            },
             host: Host::Ipv4([127, 0, 0, 1].into()),
             port: 80,
         };
         assert_eq!(
-            validate_tunnel(&remote, "/doesnt/matter", Some("Bearer the-bearer-token"), &restrictions)
+            validate_tunnel(&remote, &match_context(Some("Bearer the-bearer-token")), &restrictions)
                 .unwrap()
                 .name,
             restrictions.restrictions[0].name
         );
-        assert!(validate_tunnel(&remote, "/doesnt/matter", Some("Bearer other-bearer-token"), &restrictions).is_none());
-        assert!(validate_tunnel(&remote, "/doesnt/matter", None, &restrictions).is_none());
+        assert!(validate_tunnel(&remote, &match_context(Some("Bearer other-bearer-token")), &restrictions).is_none());
+        assert!(validate_tunnel(&remote, &match_context(None), &restrictions).is_none());
+    }
+
+    #[test]
+    fn test_schedule_is_active() {
+        let schedule: ScheduleConfig =
+            serde_yaml::from_str("{days: [Mon, friday], hours: \"22:00..06:00\", timezone: UTC}").unwrap();
+        let at = |datetime: &str| datetime.parse::<Timestamp>().unwrap();
+
+        // 2025-01-06 is a monday, its morning is the end of the sunday night
+        assert!(schedule.is_active(at("2025-01-06T23:00:00Z")));
+        assert!(!schedule.is_active(at("2025-01-06T05:59:59Z")));
+        assert!(schedule.is_active(at("2025-01-07T05:59:59Z")));
+        assert!(!schedule.is_active(at("2025-01-07T06:00:00Z")));
+        assert!(!schedule.is_active(at("2025-01-07T23:00:00Z")));
+        assert!(!schedule.is_active(at("2025-01-08T05:00:00Z")));
+        assert!(schedule.is_active(at("2025-01-10T22:00:00Z")));
+        assert!(schedule.is_active(at("2025-01-11T03:00:00Z")));
+
+        let schedule: ScheduleConfig = serde_yaml::from_str("{hours: \"08:00..18:00\"}").unwrap();
+        assert!(schedule.is_active(at("2025-01-11T12:00:00Z")));
+        assert!(!schedule.is_active(at("2025-01-11T07:59:00Z")));
+        assert!(!schedule.is_active(at("2025-01-11T18:00:00Z")));
+
+        assert!(serde_yaml::from_str::<ScheduleConfig>("{days: [Someday]}").is_err());
+        assert!(serde_yaml::from_str::<ScheduleConfig>("{hours: \"08:00\"}").is_err());
+    }
+
+    #[test]
+    fn test_validate_tunnel_with_expiry() {
+        let restrictions: RestrictionsRules = serde_yaml::from_str(
+            r#"
+restrictions:
+  - name: expired
+    match:
+      - !NotAfter 2000-01-01T00:00:00Z
+    allow:
+      - !Tunnel {}
+  - name: not yet
+    match:
+      - !NotBefore 2999-01-01T00:00:00+01:00
+    allow:
+      - !Tunnel {}
+  - name: current
+    match:
+      - !NotBefore 2000-01-01T00:00:00Z
+      - !NotAfter 2999-01-01T00:00:00Z
+    allow:
+      - !Tunnel {}
+"#,
+        )
+        .unwrap();
+
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                idle_timeout: None,
+            },
+            host: Host::Ipv4([127, 0, 0, 1].into()),
+            port: 80,
+        };
+        assert_eq!(
+            validate_tunnel(&remote, &match_context(None), &restrictions)
+                .unwrap()
+                .name,
+            "current"
+        );
+    }
+
+    #[test]
+    fn test_validate_tunnel_with_client_identity() {
+        let restrictions: RestrictionsRules = serde_yaml::from_str(
+            r#"
+restrictions:
+  - name: office
+    match:
+      - !ClientCidr [10.0.0.0/8, "fd00::/8"]
+      - !Header { name: X-Team, regex: "^ops$" }
+    allow:
+      - !Tunnel {}
+  - name: laptop
+    match:
+      - !CertificateCn "^laptop-[0-9]+$"
+      - !CertificateSan "@example.com$"
+    allow:
+      - !Tunnel {}
+"#,
+        )
+        .unwrap();
+
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                idle_timeout: None,
+            },
+            host: Host::Ipv4([127, 0, 0, 1].into()),
+            port: 80,
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("x-team", HeaderValue::from_static("ops"));
+        let cert = ClientCertificate {
+            cn: Some("laptop-42".to_string()),
+            sans: vec!["laptop-42.example.com".to_string(), "alice@example.com".to_string()],
+        };
+        let request = MatchContext {
+            client_ip: IpAddr::from([10, 1, 2, 3]),
+            headers: &headers,
+            ..match_context(None)
+        };
+        assert_eq!(
+            validate_tunnel(&remote, &request, &restrictions).unwrap().name,
+            "office"
+        );
+
+        // IPv4 clients accepted on a dual stack socket are matched by their IPv4 address
+        let request = MatchContext {
+            client_ip: "::ffff:10.1.2.3".parse().unwrap(),
+            headers: &headers,
+            ..match_context(None)
+        };
+        assert_eq!(
+            validate_tunnel(&remote, &request, &restrictions).unwrap().name,
+            "office"
+        );
+
+        let request = MatchContext {
+            client_ip: IpAddr::from([192, 168, 1, 1]),
+            headers: &headers,
+            client_cert: Some(&cert),
+            ..match_context(None)
+        };
+        assert_eq!(
+            validate_tunnel(&remote, &request, &restrictions).unwrap().name,
+            "laptop"
+        );
+
+        let request = MatchContext {
+            client_ip: IpAddr::from([10, 1, 2, 3]),
+            ..match_context(None)
+        };
+        assert!(validate_tunnel(&remote, &request, &restrictions).is_none());
+
+        let cert = ClientCertificate {
+            sans: vec!["laptop-42.example.org".to_string()],
+            ..cert
+        };
+        let request = MatchContext {
+            client_cert: Some(&cert),
+            ..match_context(None)
+        };
+        assert!(validate_tunnel(&remote, &request, &restrictions).is_none());
+
+        // A client cannot get in the cidr by claiming an ip in X-Forwarded-For, unless it is a trusted proxy
+        let trusted_proxies: Vec<IpNet> = vec!["192.168.0.0/16".parse().unwrap()];
+        headers.insert("x-forwarded-for", HeaderValue::from_static("10.1.2.3"));
+        let client_ip = |peer: IpAddr| extract_forwarded_for(&headers, peer, &trusted_proxies).unwrap_or(peer);
+        let request = MatchContext {
+            client_ip: client_ip(IpAddr::from([203, 0, 113, 1])),
+            headers: &headers,
+            ..match_context(None)
+        };
+        assert!(validate_tunnel(&remote, &request, &restrictions).is_none());
+        let request = MatchContext {
+            client_ip: client_ip(IpAddr::from([192, 168, 1, 1])),
+            headers: &headers,
+            ..match_context(None)
+        };
+        assert_eq!(
+            validate_tunnel(&remote, &request, &restrictions).unwrap().name,
+            "office"
+        );
+    }
+
+    #[test]
+    fn test_validate_tunnel_with_user() {
+        let restrictions: RestrictionsRules = serde_yaml::from_str(&format!(
+            r#"
+users:
+  - name: alice
+    password_hash: "{}"
+  - name: bob
+    token_sha256: "{}"
+restrictions:
+  - name: alice
+    match:
+      - !User [alice]
+    allow:
+      - !Tunnel {{}}
+  - name: everyone
+    match:
+      - !User [alice, bob]
+    allow:
+      - !Tunnel {{ port: ["443"] }}
+"#,
+            hash_secret("alice-pwd", HashAlgorithm::ShaCrypt).unwrap(),
+            hash_secret("bob-token", HashAlgorithm::Sha256).unwrap(),
+        ))
+        .unwrap();
+
+        let remote = RemoteAddr {
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                idle_timeout: None,
+            },
+            host: Host::Ipv4([127, 0, 0, 1].into()),
+            port: 443,
+        };
+        let alice = restrictions.users.authenticate("Basic YWxpY2U6YWxpY2UtcHdk");
+        assert_eq!(alice.as_deref(), Some("alice"));
+        let request = MatchContext {
+            user: alice.as_deref(),
+            ..match_context(None)
+        };
+        assert_eq!(validate_tunnel(&remote, &request, &restrictions).unwrap().name, "alice");
+
+        let bob = restrictions.users.authenticate("Bearer bob-token");
+        let request = MatchContext {
+            user: bob.as_deref(),
+            ..match_context(None)
+        };
+        assert_eq!(
+            validate_tunnel(&remote, &request, &restrictions).unwrap().name,
+            "everyone"
+        );
+        let remote = RemoteAddr { port: 80, ..remote };
+        assert!(validate_tunnel(&remote, &request, &restrictions).is_none());
+        assert!(validate_tunnel(&remote, &match_context(Some("Bearer bob-token")), &restrictions).is_none());
     }
 
     #[test]
@@ -434,7 +827,10 @@ mod tests {
 
         // wrong protocol - local
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                idle_timeout: None,
+            },
             host: Host::Ipv4([127, 0, 0, 1].into()),
             port: 80,
         };
@@ -500,7 +896,10 @@ mod tests {
         };
 
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                idle_timeout: None,
+            },
             host: Host::Ipv4([127, 0, 0, 1].into()),
             port: 80,
         };
@@ -509,7 +908,10 @@ mod tests {
 
         // another ip on the same subnet
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                idle_timeout: None,
+            },
             host: Host::Ipv4([127, 0, 1, 1].into()),
             port: 80,
         };
@@ -518,7 +920,10 @@ mod tests {
 
         // host is domain
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                idle_timeout: None,
+            },
             host: Host::Domain("example.com".into()),
             port: 80,
         };
@@ -537,7 +942,10 @@ mod tests {
 
         // wrong IP
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                idle_timeout: None,
+            },
             host: Host::Ipv4([127, 0, 1, 1].into()),
             port: 80,
         };
@@ -546,7 +954,10 @@ mod tests {
 
         // ipv6
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                idle_timeout: None,
+            },
             host: Host::Ipv6(Ipv6Addr::LOCALHOST),
             port: 80,
         };
@@ -555,7 +966,10 @@ mod tests {
 
         // wrong port
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                idle_timeout: None,
+            },
             host: Host::Ipv4([127, 0, 0, 1].into()),
             port: 81,
         };
@@ -582,7 +996,10 @@ mod tests {
 
         // wrong host
         let remote = RemoteAddr {
-            protocol: LocalProtocol::Tcp { proxy_protocol: false },
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+                idle_timeout: None,
+            },
             host: Host::Domain("not.com".into()),
             port: 80,
         };
@@ -590,6 +1007,31 @@ mod tests {
         assert!(!AllowConfig::from(config.clone()).is_allowed(&remote));
     }
 
+    #[test_case("10.0.0.1", &[("X-Forwarded-For", "1.2.3.4")], None ; "untrusted peer")]
+    #[test_case("127.0.0.1", &[("X-Forwarded-For", "1.2.3.4")], Some("1.2.3.4") ; "trusted peer")]
+    #[test_case("::ffff:127.0.0.1", &[("X-Forwarded-For", "1.2.3.4")], Some("1.2.3.4") ; "ipv4 mapped peer")]
+    #[test_case("127.0.0.1", &[("X-Forwarded-For", "6.6.6.6, 1.2.3.4, 192.168.1.1")], Some("1.2.3.4") ; "spoofed chain")]
+    #[test_case("127.0.0.1", &[("X-Forwarded-For", "6.6.6.6"), ("X-Forwarded-For", "1.2.3.4")], Some("1.2.3.4") ; "several headers")]
+    #[test_case("127.0.0.1", &[("X-Forwarded-For", "192.168.1.2, 192.168.1.1")], Some("192.168.1.2") ; "only proxies")]
+    #[test_case("127.0.0.1", &[("X-Forwarded-For", "1.2.3.4, unknown, 192.168.1.1")], Some("192.168.1.1") ; "unknown hop")]
+    #[test_case("127.0.0.1", &[("X-Forwarded-For", "1.2.3.4:4711")], Some("1.2.3.4") ; "with port")]
+    #[test_case("127.0.0.1", &[], None ; "no header")]
+    #[test_case("127.0.0.1", &[("Forwarded", "for=6.6.6.6, for=\"[2001:db8::1]:4711\";proto=https")], Some("2001:db8::1") ; "forwarded")]
+    #[test_case("127.0.0.1", &[("Forwarded", "proto=https;For=1.2.3.4;by=192.168.1.1")], Some("1.2.3.4") ; "forwarded pairs")]
+    #[test_case("127.0.0.1", &[("Forwarded", "for=_hidden")], None ; "forwarded obfuscated")]
+    fn test_extract_forwarded_for(peer: &str, headers: &[(&'static str, &'static str)], expected: Option<&str>) {
+        let trusted_proxies: Vec<IpNet> = vec!["127.0.0.0/8".parse().unwrap(), "192.168.0.0/16".parse().unwrap()];
+        let mut header_map = HeaderMap::new();
+        for (name, value) in headers {
+            header_map.append(*name, HeaderValue::from_static(value));
+        }
+
+        assert_eq!(
+            extract_forwarded_for(&header_map, peer.parse().unwrap(), &trusted_proxies),
+            expected.map(|ip| ip.parse().unwrap())
+        );
+    }
+
     #[test]
     fn test_extract_path_prefix_happy_path() {
         assert_eq!(extract_path_prefix("/prefix/events"), Ok("prefix"));
@@ -0,0 +1,130 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{Notify, watch};
+
+/// Graceful shutdown of the tunnels. Once triggered, listeners stop accepting new connections and tunnels in flight
+/// keep running. They are given a drain timeout to finish before being forcibly closed.
+#[derive(Clone, Debug)]
+pub struct Shutdown {
+    triggered: Arc<watch::Sender<bool>>,
+    force_closed: Arc<watch::Sender<bool>>,
+    active_streams: Arc<AtomicUsize>,
+    drained: Arc<Notify>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            triggered: Arc::new(watch::Sender::new(false)),
+            force_closed: Arc::new(watch::Sender::new(false)),
+            active_streams: Arc::new(AtomicUsize::new(0)),
+            drained: Arc::new(Notify::new()),
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trigger(&self) {
+        self.triggered.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.triggered.borrow()
+    }
+
+    /// Resolve once the shutdown has been triggered
+    pub fn triggered(&self) -> impl Future<Output = ()> + Send + 'static {
+        wait_for_true(self.triggered.subscribe())
+    }
+
+    /// Resolve once the drain timeout has elapsed, the tunnels still in flight must be closed
+    pub fn force_closed(&self) -> impl Future<Output = ()> + Send + 'static {
+        wait_for_true(self.force_closed.subscribe())
+    }
+
+    pub fn active_streams(&self) -> usize {
+        self.active_streams.load(Ordering::Relaxed)
+    }
+
+    /// Count a tunnel direction as in flight, until the guard is dropped
+    pub fn track_stream(&self) -> StreamGuard {
+        self.active_streams.fetch_add(1, Ordering::Relaxed);
+        StreamGuard {
+            active_streams: self.active_streams.clone(),
+            drained: self.drained.clone(),
+        }
+    }
+
+    /// Trigger the shutdown and wait for the tunnels in flight to finish, up to drain_timeout.
+    /// Return false if some tunnels are still running after the timeout
+    pub async fn drain(&self, drain_timeout: Duration) -> bool {
+        self.trigger();
+        let drained = async {
+            loop {
+                let drained = self.drained.notified();
+                if self.active_streams() == 0 {
+                    return;
+                }
+                drained.await;
+            }
+        };
+
+        if tokio::time::timeout(drain_timeout, drained).await.is_err() {
+            self.force_closed.send_replace(true);
+            return false;
+        }
+
+        true
+    }
+}
+
+async fn wait_for_true(mut rx: watch::Receiver<bool>) {
+    let _ = rx.wait_for(|value| *value).await;
+}
+
+pub struct StreamGuard {
+    active_streams: Arc<AtomicUsize>,
+    drained: Arc<Notify>,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        if self.active_streams.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.drained.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain() {
+        // The tunnels in flight are waited for
+        let shutdown = Shutdown::new();
+        let stream = shutdown.track_stream();
+        let drain = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.drain(Duration::from_secs(5)).await }
+        });
+        shutdown.triggered().await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!drain.is_finished());
+        drop(stream);
+        assert!(drain.await.unwrap());
+
+        // Then forcibly closed after the drain timeout
+        let shutdown = Shutdown::new();
+        let _stream = shutdown.track_stream();
+        assert!(!shutdown.drain(Duration::from_millis(50)).await);
+        tokio::time::timeout(Duration::from_secs(1), shutdown.force_closed())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Token bucket refilled at `rate` tokens per second, holding at most `burst` tokens
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    /// A new bucket starts full
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            state: Mutex::new(BucketState {
+                tokens: burst,
+                updated_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Tokens currently available, negative if the bucket is in debt
+    pub fn available(&self) -> f64 {
+        self.available_at(Instant::now())
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.available() >= self.burst
+    }
+
+    /// Take the tokens only if they are all available
+    pub fn try_acquire(&self, tokens: f64) -> bool {
+        self.try_acquire_at(tokens, Instant::now())
+    }
+
+    /// Take the tokens even if they are not available yet, the bucket goes in debt.
+    /// Return how long to wait for the debt to be paid back
+    pub fn reserve(&self, tokens: f64) -> Duration {
+        self.reserve_at(tokens, Instant::now())
+    }
+
+    fn available_at(&self, now: Instant) -> f64 {
+        let mut state = self.state.lock();
+        self.refill(&mut state, now);
+        state.tokens
+    }
+
+    fn try_acquire_at(&self, tokens: f64, now: Instant) -> bool {
+        let mut state = self.state.lock();
+        self.refill(&mut state, now);
+        if state.tokens < tokens {
+            return false;
+        }
+
+        state.tokens -= tokens;
+        true
+    }
+
+    fn reserve_at(&self, tokens: f64, now: Instant) -> Duration {
+        let mut state = self.state.lock();
+        self.refill(&mut state, now);
+        state.tokens -= tokens;
+        if state.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+
+        Duration::from_secs_f64(-state.tokens / self.rate)
+    }
+
+    fn refill(&self, state: &mut BucketState, now: Instant) {
+        let elapsed = now.saturating_duration_since(state.updated_at);
+        state.tokens = (state.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        state.updated_at = now;
+    }
+}
+
+/// Bandwidth cap of one direction of a tunnel, in bytes per second.
+/// A tunnel can be capped by several buckets at once, i.e: one shared by all the tunnels of a restriction
+/// and one shared by the tunnels of the same client ip
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthLimit {
+    buckets: Vec<Arc<TokenBucket>>,
+}
+
+impl BandwidthLimit {
+    pub fn new(buckets: Vec<Arc<TokenBucket>>) -> Self {
+        Self { buckets }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /// Account the bytes transferred, and wait until the slowest bucket allows them
+    pub async fn consume(&self, bytes: usize) {
+        let wait = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.reserve(bytes as f64))
+            .max()
+            .unwrap_or(Duration::ZERO);
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(10.0, 20.0);
+        let now = Instant::now();
+
+        assert!(bucket.try_acquire_at(15.0, now));
+        assert!(!bucket.try_acquire_at(10.0, now));
+        assert!(bucket.try_acquire_at(10.0, now + Duration::from_millis(500)));
+
+        // Going in debt must be paid back before tokens are available again
+        assert_eq!(
+            bucket.reserve_at(25.0, now + Duration::from_secs(1)),
+            Duration::from_secs(2)
+        );
+        assert!(bucket.available_at(now + Duration::from_secs(2)) < 0.0);
+        assert!(bucket.try_acquire_at(1.0, now + Duration::from_millis(3200)));
+
+        // Never refill above the burst
+        assert_eq!(bucket.available_at(now + Duration::from_secs(3600)), 20.0);
+    }
+}
@@ -17,7 +17,6 @@ use std::io;
 use std::io::ErrorKind;
 use std::ops::DerefMut;
 use std::sync::Arc;
-use std::time::Duration;
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio::sync::{Notify, mpsc};
 use tokio::task::AbortHandle;
@@ -103,10 +102,6 @@ impl TunnelWrite for Http2TunnelWrite {
         ret
     }
 
-    async fn ping(&mut self) -> Result<(), io::Error> {
-        Ok(())
-    }
-
     async fn close(&mut self) -> Result<(), io::Error> {
         Ok(())
     }
@@ -125,7 +120,8 @@ pub async fn connect(
     client: &WsClient<impl crate::TokioExecutorRef>,
     dest_addr: &RemoteAddr,
 ) -> anyhow::Result<(Http2TunnelRead, Http2TunnelWrite, Parts)> {
-    let mut pooled_cnx = match client.cnx_pool.get().await {
+    let cnx_pool = client.cnx_pool();
+    let mut pooled_cnx = match cnx_pool.get().await {
         Ok(cnx) => Ok(cnx),
         Err(err) => Err(anyhow!("failed to get a connection to the server from the pool: {err:?}")),
     }?;
@@ -203,17 +199,30 @@ pub async fn connect(
     })?;
     debug!("with HTTP upgrade request {req:?}");
     let transport = pooled_cnx.deref_mut().take().unwrap();
-    let (mut request_sender, cnx) = hyper::client::conn::http2::Builder::new(TokioExecutor::new())
+    // Pings are handled by hyper at the connection level, and are only sent after the connection has been idle
+    let keepalive = client.keepalive();
+    let ping_frequency = keepalive.idle_ping_frequency();
+    let mut cnx_builder = hyper::client::conn::http2::Builder::new(TokioExecutor::new());
+    cnx_builder
         .timer(TokioTimer::new())
         .adaptive_window(true)
-        .keep_alive_interval(client.config.websocket_ping_frequency)
-        .keep_alive_timeout(Duration::from_secs(10))
-        .keep_alive_while_idle(false)
+        .keep_alive_interval(ping_frequency)
+        .keep_alive_while_idle(false);
+    if let Some(pong_timeout) = keepalive.pong_timeout {
+        cnx_builder.keep_alive_timeout(pong_timeout);
+    }
+    let (mut request_sender, cnx) = cnx_builder
         .handshake(TokioIo::new(transport))
         .await
         .with_context(|| format!("failed to do http2 handshake with the server {:?}", client.config.remote_addr))?;
     let cnx_poller = client.executor.spawn(async move {
         if let Err(err) = cnx.await {
+            // The ping sent after the connection has been idle was not answered
+            if err.is_timeout()
+                && let Some(ping_frequency) = ping_frequency
+            {
+                keepalive.on_ping_failed(ping_frequency);
+            }
             error!("{err:?}")
         }
     });
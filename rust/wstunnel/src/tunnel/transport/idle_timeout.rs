@@ -0,0 +1,163 @@
+use std::cmp::min;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Idle timeout of a tunnel, shared by both directions of the stream.
+/// The tunnel is closed when no data has been transferred in either direction during timeout,
+/// or when one direction has been closed (half-close) for longer than half_close_timeout.
+#[derive(Clone, Debug, Default)]
+pub struct IdleTimeout {
+    inner: Option<Arc<IdleTimeoutInner>>,
+}
+
+#[derive(Debug)]
+struct IdleTimeoutInner {
+    timeout: Option<Duration>,
+    half_close_timeout: Option<Duration>,
+    created_at: Instant,
+    // Milliseconds elapsed since created_at
+    last_activity_ms: AtomicU64,
+    half_closed_at_ms: AtomicU64,
+    half_closed: Notify,
+}
+
+impl IdleTimeout {
+    pub fn new(timeout: Option<Duration>, half_close_timeout: Option<Duration>) -> Self {
+        let timeout = timeout.filter(|d| !d.is_zero());
+        let half_close_timeout = half_close_timeout.filter(|d| !d.is_zero());
+        if timeout.is_none() && half_close_timeout.is_none() {
+            return Self::default();
+        }
+
+        Self {
+            inner: Some(Arc::new(IdleTimeoutInner {
+                timeout,
+                half_close_timeout,
+                created_at: Instant::now(),
+                last_activity_ms: AtomicU64::new(0),
+                half_closed_at_ms: AtomicU64::new(u64::MAX),
+                half_closed: Notify::new(),
+            })),
+        }
+    }
+
+    pub fn on_activity(&self) {
+        if let Some(inner) = &self.inner
+            && inner.timeout.is_some()
+        {
+            inner.last_activity_ms.store(inner.elapsed_ms(), Ordering::Relaxed);
+        }
+    }
+
+    /// Whether the other direction of the tunnel keeps running after a half-close, until half_close_timeout.
+    /// Without a half_close_timeout, the tunnel is closed as soon as one of its direction is done
+    pub fn allows_half_close(&self) -> bool {
+        self.inner
+            .as_ref()
+            .is_some_and(|inner| inner.half_close_timeout.is_some())
+    }
+
+    /// One direction of the tunnel is done, the other one must finish within half_close_timeout
+    pub fn on_half_close(&self) {
+        if let Some(inner) = &self.inner {
+            let _ = inner.half_closed_at_ms.compare_exchange(
+                u64::MAX,
+                inner.elapsed_ms(),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+            inner.half_closed.notify_waiters();
+        }
+    }
+
+    /// Resolve when the tunnel must be closed, never if there is no timeout
+    pub async fn expired(&self) {
+        let Some(inner) = &self.inner else {
+            return std::future::pending().await;
+        };
+
+        loop {
+            // Register before reading the state to not miss a half-close happening in between
+            let half_closed = inner.half_closed.notified();
+
+            let idle_deadline = inner
+                .timeout
+                .map(|timeout| inner.at(inner.last_activity_ms.load(Ordering::Relaxed)) + timeout);
+            let half_close_deadline = match inner.half_closed_at_ms.load(Ordering::Relaxed) {
+                u64::MAX => None,
+                half_closed_at => inner
+                    .half_close_timeout
+                    .map(|timeout| inner.at(half_closed_at) + timeout),
+            };
+            let deadline = match (idle_deadline, half_close_deadline) {
+                (Some(a), Some(b)) => Some(min(a, b)),
+                (a, b) => a.or(b),
+            };
+
+            match deadline {
+                Some(deadline) if deadline <= Instant::now() => return,
+                Some(deadline) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(deadline) => {},
+                        _ = half_closed => {},
+                    }
+                }
+                None => half_closed.await,
+            }
+        }
+    }
+}
+
+impl IdleTimeoutInner {
+    fn elapsed_ms(&self) -> u64 {
+        self.created_at.elapsed().as_millis() as u64
+    }
+
+    fn at(&self, elapsed_ms: u64) -> Instant {
+        self.created_at + Duration::from_millis(elapsed_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let timeout = Duration::from_millis(200);
+
+        // Activity pushes back the deadline
+        let idle_timeout = IdleTimeout::new(Some(timeout), None);
+        let start = Instant::now();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        idle_timeout.on_activity();
+        idle_timeout.expired().await;
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        // Half-close wakes up the waiter with a shorter deadline
+        let idle_timeout = IdleTimeout::new(Some(Duration::from_secs(3600)), Some(Duration::from_millis(50)));
+        let start = Instant::now();
+        let expired = tokio::spawn({
+            let idle_timeout = idle_timeout.clone();
+            async move { idle_timeout.expired().await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        idle_timeout.on_half_close();
+        tokio::time::timeout(Duration::from_secs(5), expired)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        // Disabled
+        let idle_timeout = IdleTimeout::new(Some(Duration::ZERO), None);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), idle_timeout.expired())
+                .await
+                .is_err()
+        );
+    }
+}
@@ -1,16 +1,19 @@
+use crate::tunnel::shutdown::Shutdown;
+use crate::tunnel::transport::bandwidth::BandwidthLimit;
 use crate::tunnel::transport::http2::{Http2TunnelRead, Http2TunnelWrite};
+use crate::tunnel::transport::idle_timeout::IdleTimeout;
 use crate::tunnel::transport::websocket::{WebsocketTunnelRead, WebsocketTunnelWrite};
 use bytes::{BufMut, BytesMut};
 use futures_util::{FutureExt, pin_mut};
+use pin_project::pin_project;
 use std::future::Future;
 use std::io::ErrorKind;
 use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::select;
 use tokio::sync::{Notify, oneshot};
-use tokio::time::Instant;
 use tracing::log::debug;
 use tracing::{error, info, warn};
 
@@ -19,7 +22,6 @@ pub(super) static MAX_PACKET_LENGTH: usize = 64 * 1024;
 pub trait TunnelWrite: Send + 'static {
     fn buf_mut(&mut self) -> &mut BytesMut;
     fn write(&mut self) -> impl Future<Output = Result<(), std::io::Error>> + Send;
-    fn ping(&mut self) -> impl Future<Output = Result<(), std::io::Error>> + Send;
     fn close(&mut self) -> impl Future<Output = Result<(), std::io::Error>> + Send;
     fn pending_operations_notify(&mut self) -> Arc<Notify>;
     fn handle_pending_operations(&mut self) -> impl Future<Output = Result<(), std::io::Error>> + Send;
@@ -66,13 +68,6 @@ impl TunnelWrite for TunnelWriter {
         }
     }
 
-    async fn ping(&mut self) -> Result<(), std::io::Error> {
-        match self {
-            Self::Websocket(s) => s.ping().await,
-            Self::Http2(s) => s.ping().await,
-        }
-    }
-
     async fn close(&mut self) -> Result<(), std::io::Error> {
         match self {
             Self::Websocket(s) => s.close().await,
@@ -99,26 +94,27 @@ pub async fn propagate_local_to_remote(
     local_rx: impl AsyncRead,
     mut ws_tx: impl TunnelWrite,
     mut close_tx: oneshot::Sender<()>,
-    ping_frequency: Option<Duration>,
+    idle_timeout: IdleTimeout,
+    bandwidth: BandwidthLimit,
+    shutdown: Shutdown,
 ) -> anyhow::Result<()> {
     let _guard = scopeguard::guard((), |_| {
         info!("Closing local => remote tunnel");
     });
+    let _stream_guard = shutdown.track_stream();
 
     static MAX_PACKET_LENGTH: usize = 64 * 1024;
 
-    // We do our own pin_mut! to avoid shadowing timeout and be able to reset it, on next loop iteration
-    // We reuse the future to avoid creating a timer in the tight loop
-    let frequency = ping_frequency.unwrap_or(Duration::from_secs(3600 * 24));
-    let start_at = Instant::now().checked_add(frequency).unwrap_or_else(Instant::now);
-    let timeout = tokio::time::interval_at(start_at, frequency);
     let should_close = close_tx.closed().fuse();
+    let idle_expired = idle_timeout.expired();
+    let force_closed = shutdown.force_closed();
     let notify = ws_tx.pending_operations_notify();
     let mut has_pending_operations = notify.notified();
     let mut has_pending_operations_pin = unsafe { Pin::new_unchecked(&mut has_pending_operations) };
 
-    pin_mut!(timeout);
     pin_mut!(should_close);
+    pin_mut!(idle_expired);
+    pin_mut!(force_closed);
     pin_mut!(local_rx);
     loop {
         debug_assert!(
@@ -143,16 +139,28 @@ pub async fn propagate_local_to_remote(
 
             read_len = local_rx.read_buf(ws_tx.buf_mut()) => read_len,
 
-            _ = &mut should_close => break,
-
-            _ = timeout.tick(), if ping_frequency.is_some() => {
-                debug!("sending ping to keep connection alive");
-                ws_tx.ping().await?;
+            // The remote => local direction is done, keep forwarding local data until the half-close timeout
+            _ = &mut should_close => {
+                if !idle_timeout.allows_half_close() {
+                    break;
+                }
                 continue;
             }
+
+            _ = &mut idle_expired => {
+                info!("Closing idle tunnel");
+                break;
+            }
+
+            // On shutdown, the tunnel is given the drain timeout to finish by itself
+            _ = &mut force_closed => {
+                info!("Closing tunnel due to shutdown, drain timeout elapsed");
+                break;
+            }
         };
 
-        let _read_len = match read_len {
+        idle_timeout.on_activity();
+        let read_len = match read_len {
             Ok(0) => break,
             Ok(read_len) => read_len,
             Err(err) => {
@@ -160,6 +168,7 @@ pub async fn propagate_local_to_remote(
                 break;
             }
         };
+        bandwidth.consume(read_len).await;
 
         //debug!("read {} wasted {}% usable {} capa {}", read_len, 100 - (read_len * 100 / buffer.capacity()), buffer.as_slice().len(), buffer.capacity());
         if let Err(err) = ws_tx.write().await {
@@ -169,6 +178,7 @@ pub async fn propagate_local_to_remote(
     }
 
     // Send normal close
+    idle_timeout.on_half_close();
     let _ = ws_tx.close().await;
 
     Ok(())
@@ -178,19 +188,45 @@ pub async fn propagate_remote_to_local(
     local_tx: impl AsyncWrite + Send,
     mut ws_rx: impl TunnelRead,
     mut close_rx: oneshot::Receiver<()>,
+    idle_timeout: IdleTimeout,
+    bandwidth: BandwidthLimit,
+    shutdown: Shutdown,
 ) -> anyhow::Result<()> {
     let _guard = scopeguard::guard((), |_| {
         info!("Closing local <= remote tunnel");
     });
+    let _stream_guard = shutdown.track_stream();
 
+    let idle_expired = idle_timeout.expired();
+    let force_closed = shutdown.force_closed();
+    let local_tx = CountingWrite {
+        inner: local_tx,
+        written: 0,
+    };
+    let mut local_to_remote_closed = false;
+    pin_mut!(idle_expired);
+    pin_mut!(force_closed);
     pin_mut!(local_tx);
     loop {
         let msg = select! {
             biased;
             msg = ws_rx.copy(&mut local_tx) => msg,
-            _ = &mut close_rx => break,
+            // The local => remote direction is done, keep forwarding remote data until the half-close timeout
+            _ = &mut close_rx, if !local_to_remote_closed => {
+                if !idle_timeout.allows_half_close() {
+                    break;
+                }
+                local_to_remote_closed = true;
+                continue;
+            }
+            _ = &mut idle_expired => {
+                info!("Closing idle tunnel");
+                break;
+            }
+            _ = &mut force_closed => break,
         };
 
+        idle_timeout.on_activity();
         if let Err(err) = msg {
             match err.kind() {
                 ErrorKind::NotConnected => debug!("Connection closed frame received"),
@@ -199,7 +235,108 @@ pub async fn propagate_remote_to_local(
             }
             break;
         }
+
+        let written = std::mem::take(local_tx.as_mut().project().written);
+        bandwidth.consume(written).await;
     }
+    // Propagate the end of stream to the local side, i.e: send a FIN for tcp
+    let _ = local_tx.as_mut().shutdown().await;
+    idle_timeout.on_half_close();
 
     Ok(())
 }
+
+// Count the bytes of the messages copied to the local stream, to account them against the bandwidth cap
+#[pin_project]
+struct CountingWrite<W> {
+    #[pin]
+    inner: W,
+    written: usize,
+}
+
+impl<W: AsyncWrite> AsyncWrite for CountingWrite<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, std::io::Error>> {
+        let this = self.project();
+        let written = ready!(this.inner.poll_write(cx, buf))?;
+        *this.written += written;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+    use tokio::time::Instant;
+
+    struct ChannelReader(mpsc::Receiver<Bytes>);
+
+    impl TunnelRead for ChannelReader {
+        async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<(), std::io::Error> {
+            match self.0.recv().await {
+                Some(data) => writer.write_all(&data).await,
+                None => Err(std::io::Error::new(ErrorKind::BrokenPipe, "closed")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_half_close_keeps_other_direction_until_timeout() {
+        let half_close_timeout = Duration::from_millis(300);
+        let (mut app, local) = tokio::io::duplex(1024);
+        let (local_rx, local_tx) = tokio::io::split(local);
+        let (ws_tx, mut remote_rx) = mpsc::channel::<Bytes>(16);
+        let (remote_tx, ws_rx) = mpsc::channel::<Bytes>(16);
+        let (close_tx, close_rx) = oneshot::channel();
+        let idle_timeout = IdleTimeout::new(None, Some(half_close_timeout));
+
+        tokio::spawn(propagate_local_to_remote(
+            local_rx,
+            Http2TunnelWrite::new(ws_tx),
+            close_tx,
+            idle_timeout.clone(),
+            BandwidthLimit::default(),
+            Shutdown::new(),
+        ));
+        tokio::spawn(propagate_remote_to_local(
+            local_tx,
+            ChannelReader(ws_rx),
+            close_rx,
+            idle_timeout,
+            BandwidthLimit::default(),
+            Shutdown::new(),
+        ));
+
+        // The local side is done writing, the local => remote direction ends
+        let start = Instant::now();
+        app.write_all(b"bye").await.unwrap();
+        app.shutdown().await.unwrap();
+        assert_eq!(remote_rx.recv().await.as_deref(), Some(&b"bye"[..]));
+        assert!(remote_rx.recv().await.is_none());
+
+        // Data still flows from the remote to the local side
+        let mut buf = [0u8; 16];
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        remote_tx.send(Bytes::from_static(b"hello")).await.unwrap();
+        let len = app.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+
+        // Until the half-close timeout closes it, even if the remote is still connected
+        let len = tokio::time::timeout(Duration::from_secs(5), app.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(len, 0);
+        assert!(start.elapsed() >= half_close_timeout);
+    }
+}
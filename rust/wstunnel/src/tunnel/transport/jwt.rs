@@ -51,6 +51,10 @@ impl JwtTunnelConfig {
                 LocalProtocol::Unix { .. } => unreachable!("canont use unix as destination protocol"),
                 LocalProtocol::Socks5 { .. } => unreachable!("cannot use socks5 as destination protocol"),
                 LocalProtocol::HttpProxy { .. } => unreachable!("cannot use http proxy as destination protocol"),
+                LocalProtocol::Mixed { .. } => unreachable!("cannot use mixed proxy as destination protocol"),
+                LocalProtocol::Dns { .. } => unreachable!("cannot use dns as destination protocol"),
+                LocalProtocol::FakeDns { .. } => unreachable!("cannot use fake dns as destination protocol"),
+                LocalProtocol::Tun { .. } => unreachable!("cannot use tun as destination protocol"),
             },
             r: dest.host.to_string(),
             rp: dest.port,
@@ -0,0 +1,190 @@
+use serde::Deserialize;
+use std::cmp::{max, min};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Named keepalive policies of the client, trading how fast a dead connection is detected against battery usage
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum KeepaliveProfile {
+    Aggressive,
+    Balanced,
+    Battery,
+}
+
+impl KeepaliveProfile {
+    /// Frequency of the pings while the tunnel has traffic
+    pub const fn min_ping_frequency(self) -> Duration {
+        match self {
+            Self::Aggressive => Duration::from_secs(10),
+            Self::Balanced => Duration::from_secs(30),
+            Self::Battery => Duration::from_secs(60),
+        }
+    }
+
+    /// Frequency the pings back off to while the tunnel is idle
+    pub const fn max_ping_frequency(self) -> Duration {
+        match self {
+            Self::Aggressive => Duration::from_secs(30),
+            Self::Balanced => Duration::from_secs(120),
+            Self::Battery => Duration::from_secs(300),
+        }
+    }
+
+    /// Idle connections of the pool are closed when no tunnel has been opened during this delay
+    pub const fn pool_idle_timeout(self) -> Duration {
+        match self {
+            Self::Aggressive => Duration::from_secs(30 * 60),
+            Self::Balanced => Duration::from_secs(10 * 60),
+            Self::Battery => Duration::from_secs(2 * 60),
+        }
+    }
+}
+
+/// Adaptive ping frequency shared by all the tunnels of a client.
+/// The frequency backs off while a tunnel is idle, and stays below the NAT timeout detected on the network,
+/// which is probed by letting the frequency grow until a ping fails.
+#[derive(Debug)]
+pub struct AdaptiveKeepalive {
+    profile: KeepaliveProfile,
+    nat_timeout_ms: AtomicU64,
+}
+
+impl AdaptiveKeepalive {
+    pub fn new(profile: KeepaliveProfile) -> Self {
+        Self {
+            profile,
+            nat_timeout_ms: AtomicU64::new(u64::MAX),
+        }
+    }
+
+    pub fn profile(&self) -> KeepaliveProfile {
+        self.profile
+    }
+
+    pub fn max_ping_frequency(&self) -> Duration {
+        let nat_timeout = Duration::from_millis(self.nat_timeout_ms.load(Ordering::Relaxed));
+        min(self.profile.max_ping_frequency(), nat_timeout)
+    }
+
+    /// The tunnel died after being idle for this duration, the NAT in between has likely dropped the connection
+    pub fn on_connection_lost(&self, idle: Duration) {
+        if idle <= self.profile.min_ping_frequency() {
+            return;
+        }
+
+        let nat_timeout = max(idle / 2, self.profile.min_ping_frequency());
+        self.nat_timeout_ms
+            .fetch_min(nat_timeout.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Forget the NAT timeout detected, i.e: when the network has changed
+    pub fn reset(&self) {
+        self.nat_timeout_ms.store(u64::MAX, Ordering::Relaxed);
+    }
+}
+
+/// Keepalive of a tunnel
+#[derive(Clone, Debug, Default)]
+pub struct Keepalive {
+    // Initial frequency of the pings, None to disable them
+    pub ping_frequency: Option<Duration>,
+    // The connection is considered dead if a ping is not answered within this delay, None to wait forever
+    pub pong_timeout: Option<Duration>,
+    // When set, the frequency adapts to the traffic of the tunnel
+    pub adaptive: Option<Arc<AdaptiveKeepalive>>,
+    // Pings are not sent while it is true
+    pub paused: Option<watch::Receiver<bool>>,
+}
+
+impl Keepalive {
+    pub fn fixed(ping_frequency: Option<Duration>, pong_timeout: Option<Duration>) -> Self {
+        Self {
+            ping_frequency,
+            pong_timeout,
+            adaptive: None,
+            paused: None,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.as_ref().is_some_and(|paused| *paused.borrow())
+    }
+
+    /// Frequency of the next ping, given the current one and if there has been traffic since the last ping
+    pub fn next_ping_frequency(&self, frequency: Duration, has_traffic: bool) -> Duration {
+        let Some(adaptive) = &self.adaptive else {
+            return frequency;
+        };
+
+        if has_traffic {
+            min(adaptive.profile().min_ping_frequency(), adaptive.max_ping_frequency())
+        } else {
+            min(frequency * 2, adaptive.max_ping_frequency())
+        }
+    }
+
+    /// Frequency of the pings of a connection only pinging after being idle for this duration, i.e: http2.
+    /// It stays below the NAT timeout detected on the network
+    pub fn idle_ping_frequency(&self) -> Option<Duration> {
+        match &self.adaptive {
+            Some(adaptive) => Some(adaptive.max_ping_frequency()),
+            None => self.ping_frequency,
+        }
+    }
+
+    /// A ping has not been answered after the connection has been idle for this duration
+    pub fn on_ping_failed(&self, idle: Duration) {
+        if let Some(adaptive) = &self.adaptive {
+            adaptive.on_connection_lost(idle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_ping_frequency() {
+        let adaptive = Arc::new(AdaptiveKeepalive::new(KeepaliveProfile::Balanced));
+        let keepalive = Keepalive {
+            ping_frequency: Some(KeepaliveProfile::Balanced.min_ping_frequency()),
+            pong_timeout: None,
+            adaptive: Some(adaptive.clone()),
+            paused: None,
+        };
+
+        // Back off while idle, up to the max of the profile
+        let frequency = keepalive.next_ping_frequency(Duration::from_secs(30), false);
+        assert_eq!(frequency, Duration::from_secs(60));
+        let frequency = keepalive.next_ping_frequency(frequency, false);
+        assert_eq!(frequency, Duration::from_secs(120));
+        let frequency = keepalive.next_ping_frequency(frequency, false);
+        assert_eq!(frequency, Duration::from_secs(120));
+        assert_eq!(keepalive.next_ping_frequency(frequency, true), Duration::from_secs(30));
+
+        // A ping lost after 120s of inactivity caps the frequency below the detected NAT timeout
+        keepalive.on_ping_failed(Duration::from_secs(120));
+        assert_eq!(
+            keepalive.next_ping_frequency(Duration::from_secs(60), false),
+            Duration::from_secs(60)
+        );
+
+        adaptive.reset();
+        assert_eq!(
+            keepalive.next_ping_frequency(Duration::from_secs(60), false),
+            Duration::from_secs(120)
+        );
+
+        // Fixed frequency never changes
+        let keepalive = Keepalive::fixed(Some(Duration::from_secs(30)), None);
+        assert_eq!(
+            keepalive.next_ping_frequency(Duration::from_secs(30), false),
+            Duration::from_secs(30)
+        );
+    }
+}
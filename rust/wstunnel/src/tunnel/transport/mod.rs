@@ -6,9 +6,12 @@ use std::str::FromStr;
 
 use tracing::error;
 
+pub mod bandwidth;
 pub mod http2;
+pub mod idle_timeout;
 pub mod io;
 mod jwt;
+pub mod keepalive;
 mod types;
 pub mod websocket;
 
@@ -1,12 +1,15 @@
 use super::io::{MAX_PACKET_LENGTH, TunnelRead, TunnelWrite};
+use crate::executor::TokioExecutorRef;
 use crate::tunnel::RemoteAddr;
 use crate::tunnel::client::WsClient;
 use crate::tunnel::client::l4_transport_stream::{TransportReadHalf, TransportStream, TransportWriteHalf};
 use crate::tunnel::transport::headers_from_file;
 use crate::tunnel::transport::jwt::{JWT_HEADER_PREFIX, tunnel_to_jwt_token};
+use crate::tunnel::transport::keepalive::Keepalive;
 use anyhow::{Context, anyhow};
 use bytes::{Bytes, BytesMut};
 use fastwebsockets::{CloseCode, Frame, OpCode, Payload, Role, WebSocket, WebSocketRead, WebSocketWrite};
+use futures_util::pin_mut;
 use http_body_util::Empty;
 use hyper::Request;
 use hyper::header::{AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, UPGRADE};
@@ -15,40 +18,38 @@ use hyper::http::response::Parts;
 use hyper::upgrade::Upgraded;
 use hyper_util::rt::TokioExecutor;
 use hyper_util::rt::TokioIo;
-use log::debug;
+use log::{debug, warn};
 use std::io;
 use std::io::ErrorKind;
 use std::ops::DerefMut;
-use std::sync::Arc;
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::Relaxed;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio::sync::Notify;
+use tokio::select;
 use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{Mutex, Notify, watch};
+use tokio::time::Instant;
 use tokio_rustls::server::TlsStream;
-use tracing::trace;
+use tracing::{Instrument, Span, trace};
 use uuid::Uuid;
 
 pub struct WebsocketTunnelWrite {
-    inner: WebSocketWrite<TransportWriteHalf>,
+    inner: Arc<Mutex<WebSocketWrite<TransportWriteHalf>>>,
     buf: BytesMut,
-    pending_operations: Receiver<Frame<'static>>,
-    pending_ops_notify: Arc<Notify>,
-    in_flight_ping: AtomicUsize,
+    connection: Arc<WebsocketConnection>,
+    dead: watch::Receiver<bool>,
 }
 
 impl WebsocketTunnelWrite {
-    pub fn new(
-        ws: WebSocketWrite<TransportWriteHalf>,
-        (pending_operations, notify): (Receiver<Frame<'static>>, Arc<Notify>),
-    ) -> Self {
+    fn new(inner: Arc<Mutex<WebSocketWrite<TransportWriteHalf>>>, connection: Arc<WebsocketConnection>) -> Self {
         Self {
-            inner: ws,
+            inner,
             buf: BytesMut::with_capacity(MAX_PACKET_LENGTH),
-            pending_operations,
-            pending_ops_notify: notify,
-            in_flight_ping: AtomicUsize::new(0),
+            dead: connection.dead.subscribe(),
+            connection,
         }
     }
 }
@@ -61,9 +62,10 @@ impl TunnelWrite for WebsocketTunnelWrite {
     async fn write(&mut self) -> Result<(), io::Error> {
         let read_len = self.buf.len();
         let buf = &mut self.buf;
+        self.connection.has_traffic.store(true, Relaxed);
 
-        let ret = self
-            .inner
+        let mut inner = self.inner.lock().await;
+        let ret = inner
             .write_frame(Frame::binary(Payload::BorrowedMut(&mut buf[..read_len])))
             .await;
 
@@ -74,9 +76,10 @@ impl TunnelWrite for WebsocketTunnelWrite {
         // It is needed to call poll_flush to ensure that the data is written to the underlying stream.
         // In case of a TLS stream, it may still be buffered in the TLS layer if not flushed.
         // https://docs.rs/tokio-rustls/latest/tokio_rustls/#why-do-i-need-to-call-poll_flush
-        if let Err(err) = self.inner.flush().await {
+        if let Err(err) = inner.flush().await {
             return Err(io::Error::new(ErrorKind::ConnectionAborted, err));
         }
+        drop(inner);
 
         // If the buffer has been completely filled with previous read, Grows it !
         // For the buffer to not be a bottleneck when the TCP window scale.
@@ -99,27 +102,8 @@ impl TunnelWrite for WebsocketTunnelWrite {
         Ok(())
     }
 
-    async fn ping(&mut self) -> Result<(), io::Error> {
-        if self.in_flight_ping.fetch_add(1, Relaxed) >= 3 {
-            return Err(io::Error::new(
-                ErrorKind::ConnectionAborted,
-                "too many in flight/un-answered pings",
-            ));
-        }
-
-        if let Err(err) = self
-            .inner
-            .write_frame(Frame::new(true, OpCode::Ping, None, Payload::BorrowedMut(&mut [])))
-            .await
-        {
-            return Err(io::Error::new(ErrorKind::BrokenPipe, err));
-        }
-
-        Ok(())
-    }
-
     async fn close(&mut self) -> Result<(), io::Error> {
-        if let Err(err) = self.inner.write_frame(Frame::close(1000, &[])).await {
+        if let Err(err) = self.inner.lock().await.write_frame(Frame::close(1000, &[])).await {
             return Err(io::Error::new(ErrorKind::BrokenPipe, err));
         }
 
@@ -127,30 +111,13 @@ impl TunnelWrite for WebsocketTunnelWrite {
     }
 
     fn pending_operations_notify(&mut self) -> Arc<Notify> {
-        self.pending_ops_notify.clone()
+        self.connection.dead_notify.clone()
     }
 
+    // Control frames are handled by the keepalive task of the connection, only its death is reported here
     async fn handle_pending_operations(&mut self) -> Result<(), io::Error> {
-        while let Ok(frame) = self.pending_operations.try_recv() {
-            debug!("received frame {:?}", frame.opcode);
-            match frame.opcode {
-                OpCode::Close => {
-                    if self.inner.write_frame(frame).await.is_err() {
-                        return Err(io::Error::new(ErrorKind::ConnectionAborted, "cannot send close frame"));
-                    }
-                }
-                OpCode::Ping => {
-                    debug!("sending pong frame");
-                    if self.inner.write_frame(Frame::pong(frame.payload)).await.is_err() {
-                        return Err(io::Error::new(ErrorKind::ConnectionAborted, "cannot send pong frame"));
-                    }
-                }
-                OpCode::Pong => {
-                    debug!("received pong frame");
-                    self.in_flight_ping.fetch_sub(1, Relaxed);
-                }
-                OpCode::Continuation | OpCode::Text | OpCode::Binary => unreachable!(),
-            }
+        if *self.dead.borrow() {
+            return Err(io::Error::new(ErrorKind::TimedOut, "pong not received from the peer"));
         }
 
         Ok(())
@@ -159,22 +126,23 @@ impl TunnelWrite for WebsocketTunnelWrite {
 
 pub struct WebsocketTunnelRead {
     inner: WebSocketRead<TransportReadHalf>,
-    pending_operations: Sender<Frame<'static>>,
-    notify_pending_ops: Arc<Notify>,
+    control_frames: Sender<Frame<'static>>,
+    connection: Arc<WebsocketConnection>,
+    dead: watch::Receiver<bool>,
 }
 
 impl WebsocketTunnelRead {
-    pub fn new(ws: WebSocketRead<TransportReadHalf>) -> (Self, (Receiver<Frame<'static>>, Arc<Notify>)) {
-        let (tx, rx) = tokio::sync::mpsc::channel(10);
-        let notify = Arc::new(Notify::new());
-        (
-            Self {
-                inner: ws,
-                pending_operations: tx,
-                notify_pending_ops: notify.clone(),
-            },
-            (rx, notify),
-        )
+    fn new(
+        ws: WebSocketRead<TransportReadHalf>,
+        control_frames: Sender<Frame<'static>>,
+        connection: Arc<WebsocketConnection>,
+    ) -> Self {
+        Self {
+            inner: ws,
+            control_frames,
+            dead: connection.dead.subscribe(),
+            connection,
+        }
     }
 }
 
@@ -185,8 +153,15 @@ fn frame_reader(_: Frame<'_>) -> futures_util::future::Ready<anyhow::Result<()>>
 
 impl TunnelRead for WebsocketTunnelRead {
     async fn copy(&mut self, mut writer: impl AsyncWrite + Unpin + Send) -> Result<(), io::Error> {
+        let mut frame_reader = frame_reader;
         loop {
-            let msg = match self.inner.read_frame(&mut frame_reader).await {
+            let msg = select! {
+                msg = self.inner.read_frame(&mut frame_reader) => msg,
+                _ = self.dead.wait_for(|dead| *dead) => {
+                    return Err(io::Error::new(ErrorKind::TimedOut, "pong not received from the peer"));
+                }
+            };
+            let msg = match msg {
                 Ok(msg) => msg,
                 Err(err) => return Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
             };
@@ -194,53 +169,160 @@ impl TunnelRead for WebsocketTunnelRead {
             trace!("receive ws frame {:?} {:?}", msg.opcode, msg.payload);
             match msg.opcode {
                 OpCode::Continuation | OpCode::Text | OpCode::Binary => {
+                    self.connection.has_traffic.store(true, Relaxed);
                     return match writer.write_all(msg.payload.as_ref()).await {
                         Ok(_) => Ok(()),
                         Err(err) => Err(io::Error::new(ErrorKind::ConnectionAborted, err)),
                     };
                 }
+                // The close frame is answered when our own direction of the tunnel is done,
+                // as nothing can be written anymore after it, i.e: to not cut a half-closed tunnel
                 OpCode::Close => {
                     let _ = self
-                        .pending_operations
+                        .control_frames
                         .send(Frame::close(CloseCode::Normal.into(), &[]))
                         .await;
-                    self.notify_pending_ops.notify_waiters();
                     return Err(io::Error::new(ErrorKind::NotConnected, "websocket close"));
                 }
-                OpCode::Ping => {
-                    if self
-                        .pending_operations
-                        .send(Frame::new(true, msg.opcode, None, Payload::Owned(msg.payload.to_owned())))
-                        .await
-                        .is_err()
-                    {
-                        return Err(io::Error::new(ErrorKind::ConnectionAborted, "cannot send ping"));
+                OpCode::Ping | OpCode::Pong => {
+                    let frame = Frame::new(true, msg.opcode, None, Payload::Owned(msg.payload.to_owned()));
+                    // The keepalive task is gone once nothing can be written anymore on the connection
+                    let _ = self.control_frames.send(frame).await;
+                }
+            };
+        }
+    }
+}
+
+/// State of a websocket connection, shared by the tunnel and the keepalive task of the connection
+#[derive(Debug)]
+struct WebsocketConnection {
+    has_traffic: AtomicBool,
+    // Set when the peer has stopped answering our pings
+    dead: watch::Sender<bool>,
+    dead_notify: Arc<Notify>,
+}
+
+impl WebsocketConnection {
+    fn on_dead(&self) {
+        self.dead.send_replace(true);
+        self.dead_notify.notify_one();
+    }
+}
+
+// Number of pings without a pong after which the connection is considered dead, when there is no pong timeout
+const MAX_UNANSWERED_PINGS: usize = 3;
+
+/// Keepalive of a websocket connection, answering the pings of the peer and sending ours.
+/// The connection is considered dead when our pings are not answered, which is also used to detect the NAT timeout
+/// of the network, as the pings are sent less often while the connection is idle.
+async fn run_keepalive(
+    ws_tx: Weak<Mutex<WebSocketWrite<TransportWriteHalf>>>,
+    mut control_frames: Receiver<Frame<'static>>,
+    connection: Arc<WebsocketConnection>,
+    keepalive: Keepalive,
+) {
+    let mut frequency = keepalive.ping_frequency.unwrap_or(Duration::from_secs(3600 * 24));
+    let ping_timer = tokio::time::sleep(frequency);
+    let pong_timer = tokio::time::sleep(frequency);
+    // When the oldest unanswered ping has been sent, and how long the connection was idle before it
+    let mut ping_in_flight: Option<(Instant, Duration)> = None;
+    let mut unanswered_pings = 0;
+    // Once the peer has sent its close, it cannot answer our pings anymore
+    let mut peer_closed = false;
+    pin_mut!(ping_timer);
+    pin_mut!(pong_timer);
+
+    loop {
+        select! {
+            biased;
+
+            frame = control_frames.recv() => {
+                let Some(frame) = frame else {
+                    return;
+                };
+                match frame.opcode {
+                    OpCode::Ping => {
+                        debug!("sending pong frame");
+                        if write_control_frame(&ws_tx, Frame::pong(frame.payload)).await.is_err() {
+                            return;
+                        }
+                    }
+                    OpCode::Pong => {
+                        debug!("received pong frame");
+                        ping_in_flight = None;
+                        unanswered_pings = 0;
                     }
-                    self.notify_pending_ops.notify_waiters();
+                    OpCode::Close => peer_closed = true,
+                    OpCode::Continuation | OpCode::Text | OpCode::Binary => unreachable!(),
                 }
-                OpCode::Pong => {
-                    if self
-                        .pending_operations
-                        .send(Frame::pong(Payload::Borrowed(&[])))
-                        .await
-                        .is_err()
-                    {
-                        return Err(io::Error::new(ErrorKind::ConnectionAborted, "cannot send pong"));
+            }
+
+            _ = &mut ping_timer, if keepalive.ping_frequency.is_some() && !peer_closed => {
+                let has_traffic = connection.has_traffic.swap(false, Relaxed);
+                let idle = if has_traffic { Duration::ZERO } else { frequency };
+                frequency = keepalive.next_ping_frequency(frequency, has_traffic);
+                ping_timer.as_mut().reset(Instant::now() + frequency);
+                if keepalive.is_paused() {
+                    continue;
+                }
+
+                if keepalive.pong_timeout.is_none() && unanswered_pings >= MAX_UNANSWERED_PINGS {
+                    warn!("{unanswered_pings} pings not answered by the peer, closing dead connection");
+                    keepalive.on_ping_failed(ping_in_flight.map_or(idle, |(_, idle)| idle));
+                    connection.on_dead();
+                    return;
+                }
+
+                debug!("sending ping to keep connection alive, next one in {:?}", frequency);
+                // A ping that cannot be written says nothing about the network, the connection is already closed
+                let ping = Frame::new(true, OpCode::Ping, None, Payload::Borrowed(&[]));
+                if let Err(err) = write_control_frame(&ws_tx, ping).await {
+                    debug!("cannot send ping, stopping keepalive: {err}");
+                    return;
+                }
+                unanswered_pings += 1;
+                if ping_in_flight.is_none() {
+                    ping_in_flight = Some((Instant::now(), idle));
+                    if let Some(pong_timeout) = keepalive.pong_timeout {
+                        pong_timer.as_mut().reset(Instant::now() + pong_timeout);
                     }
-                    self.notify_pending_ops.notify_waiters();
                 }
-            };
+            }
+
+            _ = &mut pong_timer, if keepalive.pong_timeout.is_some() && ping_in_flight.is_some() && !peer_closed => {
+                let delay = keepalive.pong_timeout.unwrap_or_default();
+                warn!("No pong received from the peer within {:?}, closing dead connection", delay);
+                keepalive.on_ping_failed(ping_in_flight.map_or(Duration::ZERO, |(_, idle)| idle));
+                connection.on_dead();
+                return;
+            }
         }
     }
 }
 
+async fn write_control_frame(
+    ws_tx: &Weak<Mutex<WebSocketWrite<TransportWriteHalf>>>,
+    frame: Frame<'_>,
+) -> anyhow::Result<()> {
+    let Some(ws_tx) = ws_tx.upgrade() else {
+        return Err(anyhow!("websocket tunnel is closed"));
+    };
+
+    let mut ws_tx = ws_tx.lock().await;
+    ws_tx.write_frame(frame).await?;
+    ws_tx.flush().await?;
+    Ok(())
+}
+
 pub async fn connect(
     request_id: Uuid,
     client: &WsClient<impl crate::TokioExecutorRef>,
     dest_addr: &RemoteAddr,
 ) -> anyhow::Result<(WebsocketTunnelRead, WebsocketTunnelWrite, Parts)> {
     let client_cfg = &client.config;
-    let mut pooled_cnx = match client.cnx_pool.get().await {
+    let cnx_pool = client.cnx_pool();
+    let mut pooled_cnx = match cnx_pool.get().await {
         Ok(cnx) => Ok(cnx),
         Err(err) => Err(anyhow!("failed to get a connection to the server from the pool: {err:?}")),
     }?;
@@ -303,7 +385,13 @@ pub async fn connect(
         .await
         .with_context(|| format!("failed to do websocket handshake with the server {:?}", client_cfg.remote_addr))?;
 
-    let (ws_rx, ws_tx) = mk_websocket_tunnel(ws, Role::Client, client_cfg.websocket_mask_frame)?;
+    let (ws_rx, ws_tx) = mk_websocket_tunnel(
+        ws,
+        Role::Client,
+        client_cfg.websocket_mask_frame,
+        client.keepalive(),
+        &client.executor,
+    )?;
     Ok((ws_rx, ws_tx, response.into_parts().0))
 }
 
@@ -311,6 +399,8 @@ pub fn mk_websocket_tunnel(
     ws: WebSocket<TokioIo<Upgraded>>,
     role: Role,
     mask_frame: bool,
+    keepalive: Keepalive,
+    executor: &impl TokioExecutorRef,
 ) -> anyhow::Result<(WebsocketTunnelRead, WebsocketTunnelWrite)> {
     let mut ws = match role {
         Role::Client => {
@@ -339,11 +429,79 @@ pub fn mk_websocket_tunnel(
         }
     };
 
+    ws.set_auto_apply_mask(mask_frame);
+    Ok(split_websocket_tunnel(ws, keepalive, executor))
+}
+
+// Split the websocket in the 2 directions of the tunnel, and start the keepalive of the connection
+fn split_websocket_tunnel(
+    mut ws: WebSocket<TransportStream>,
+    keepalive: Keepalive,
+    executor: &impl TokioExecutorRef,
+) -> (WebsocketTunnelRead, WebsocketTunnelWrite) {
     ws.set_auto_pong(false);
     ws.set_auto_close(false);
-    ws.set_auto_apply_mask(mask_frame);
     let (ws_rx, ws_tx) = ws.split(|x| x.into_split());
 
-    let (ws_rx, pending_ops) = WebsocketTunnelRead::new(ws_rx);
-    Ok((ws_rx, WebsocketTunnelWrite::new(ws_tx, pending_ops)))
+    let connection = Arc::new(WebsocketConnection {
+        has_traffic: AtomicBool::new(false),
+        dead: watch::Sender::new(false),
+        dead_notify: Arc::new(Notify::new()),
+    });
+    let ws_tx = Arc::new(Mutex::new(ws_tx));
+    let (control_frames_tx, control_frames_rx) = tokio::sync::mpsc::channel(10);
+    executor.spawn(
+        run_keepalive(Arc::downgrade(&ws_tx), control_frames_rx, connection.clone(), keepalive)
+            .instrument(Span::current()),
+    );
+
+    (
+        WebsocketTunnelRead::new(ws_rx, control_frames_tx, connection.clone()),
+        WebsocketTunnelWrite::new(ws_tx, connection),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::DefaultTokioExecutor;
+    use tokio::net::TcpListener;
+
+    async fn websocket_pair() -> (WebSocket<TransportStream>, WebSocket<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (
+            WebSocket::after_handshake(TransportStream::from_tcp(client, Bytes::new()), Role::Client),
+            WebSocket::after_handshake(server, Role::Server),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_closes_connection_without_pong() {
+        let keepalive = || Keepalive::fixed(Some(Duration::from_millis(10)), Some(Duration::from_millis(50)));
+        let mut local = Vec::new();
+
+        // The peer answers the pings, the connection stays alive
+        let (ws, mut peer) = websocket_pair().await;
+        let (mut ws_rx, mut ws_tx) = split_websocket_tunnel(ws, keepalive(), &DefaultTokioExecutor::default());
+        tokio::spawn(async move { while peer.read_frame().await.is_ok() {} });
+        assert!(
+            tokio::time::timeout(Duration::from_millis(300), ws_rx.copy(&mut local))
+                .await
+                .is_err()
+        );
+        assert!(ws_tx.handle_pending_operations().await.is_ok());
+
+        // The peer stops answering, both directions of the tunnel are notified that the connection is dead
+        let (ws, _peer) = websocket_pair().await;
+        let (mut ws_rx, mut ws_tx) = split_websocket_tunnel(ws, keepalive(), &DefaultTokioExecutor::default());
+        let err = tokio::time::timeout(Duration::from_secs(5), ws_rx.copy(&mut local))
+            .await
+            .expect("dead connection must be detected")
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        let err = ws_tx.handle_pending_operations().await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
 }